@@ -0,0 +1,13 @@
+fn main() {
+    // only the gRPC front end has generated code; plain builds need no protobuf tooling
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/sdcs.proto");
+        // protox compiles the proto in Rust, so building doesn't need protoc installed
+        let descriptors =
+            protox::compile(["proto/sdcs.proto"], ["proto"]).expect("proto/sdcs.proto compiles");
+        tonic_build::configure()
+            .compile_fds(descriptors)
+            .expect("gRPC code generates");
+    }
+}
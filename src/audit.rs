@@ -0,0 +1,86 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// One mutation as written to the audit log, a JSON object per line.
+#[derive(Serialize)]
+pub struct AuditRecord<'a> {
+    /// Milliseconds since the Unix epoch when the owner applied the change.
+    pub ts_ms: u64,
+    /// Address of the client that asked for the change (not of a forwarding peer).
+    pub client: &'a str,
+    pub op: &'a str,
+    /// Storage key, including any namespace prefix.
+    pub key: &'a str,
+    /// Whether the key held a value before and after the change.
+    pub old: bool,
+    pub new: bool,
+}
+
+/// Append-only trail of every change applied to this node's keys, for answering who changed
+/// a key and when. When the file passes `max_bytes` it is moved to `{path}.1` (replacing the
+/// previous one) and a fresh file is started, so at most two files' worth is kept.
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    // the open file and how many bytes it holds
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditLog {
+    /// Open (or create) the log at `path`, appending to what's already there.
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(AuditLog {
+            path,
+            max_bytes,
+            file: Mutex::new((file, len)),
+        })
+    }
+
+    /// Append a record for a change by `client`, timestamped now.
+    /// A failed write is logged and otherwise ignored; it never fails the change itself.
+    pub fn record(&self, client: &str, op: &str, key: &str, old: bool, new: bool) {
+        let record = AuditRecord {
+            ts_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            client,
+            op,
+            key,
+            old,
+            new,
+        };
+        let mut line = serde_json::to_vec(&record).unwrap();
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = self.append(&mut file, &line) {
+            eprintln!("audit log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn append(&self, file: &mut (File, u64), line: &[u8]) -> io::Result<()> {
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, rotated)?;
+            *file = (
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+                0,
+            );
+        }
+        file.0.write_all(line)?;
+        file.1 += line.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Slots each key sets; four keeps false positives near 2% at one key per ten slots.
+const HASHES: u64 = 4;
+
+/// Counting Bloom filter over the keys a store holds, so most lookups of absent keys can be
+/// answered without taking a lock. Counters (rather than bits) let deletes clear a key again.
+/// A "maybe" can be wrong and must fall through to the real lookup; a "no" never is.
+pub struct KeyFilter {
+    counters: Vec<AtomicU16>,
+    // lookups answered "no" outright, and "maybe"s the store then didn't have
+    negatives: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+/// What `/stats` reports about the key filter.
+#[derive(Serialize)]
+pub struct KeyFilterStats {
+    pub slots: usize,
+    pub negatives: u64,
+    pub false_positives: u64,
+}
+
+impl KeyFilter {
+    /// Filter with `slots` counters (at least one). Size it around ten per expected key.
+    pub fn new(slots: usize) -> Self {
+        KeyFilter {
+            counters: (0..slots.max(1)).map(|_| AtomicU16::new(0)).collect(),
+            negatives: AtomicU64::new(0),
+            false_positives: AtomicU64::new(0),
+        }
+    }
+
+    /// Counter positions for `key`, by double hashing one seeded hash.
+    fn slots(&self, key: &str) -> impl Iterator<Item = usize> + '_ {
+        // seeded so the positions don't line up with peer ownership, which is also seahash
+        let h = seahash::hash_seeded(
+            key.as_bytes(),
+            0x5bd1e995,
+            0x27d4eb2f,
+            0x165667b1,
+            0x9e3779b9,
+        );
+        let (a, b) = (h >> 32, (h & 0xffff_ffff) | 1);
+        let len = self.counters.len() as u64;
+        (0..HASHES).map(move |i| (a.wrapping_add(i.wrapping_mul(b)) % len) as usize)
+    }
+
+    /// Record that the store now holds `key`. Call before it becomes visible.
+    pub fn insert(&self, key: &str) {
+        for slot in self.slots(key) {
+            // a saturated counter stays put; it can no longer tell how many keys share it
+            let _ = self.counters[slot].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < u16::MAX).then_some(n + 1)
+            });
+        }
+    }
+
+    /// Record that the store no longer holds `key`. Call after it is gone.
+    pub fn remove(&self, key: &str) {
+        for slot in self.slots(key) {
+            let _ = self.counters[slot].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n > 0 && n < u16::MAX).then(|| n - 1)
+            });
+        }
+    }
+
+    /// False if the store definitely doesn't hold `key`.
+    pub fn may_contain(&self, key: &str) -> bool {
+        let maybe = self
+            .slots(key)
+            .all(|slot| self.counters[slot].load(Ordering::SeqCst) > 0);
+        if !maybe {
+            self.negatives.fetch_add(1, Ordering::Relaxed);
+        }
+        maybe
+    }
+
+    /// `may_contain` without counting toward the stats, for consistency checks.
+    pub fn covers(&self, key: &str) -> bool {
+        self.slots(key)
+            .all(|slot| self.counters[slot].load(Ordering::SeqCst) > 0)
+    }
+
+    /// Reset every counter to what exactly `keys` would set, e.g. after counts drifted.
+    /// Slots are rewritten one at a time, never passing through zero for a slot some key in
+    /// `keys` uses, so lock-free readers of those keys keep getting "maybe" throughout.
+    pub fn recount<'a>(&self, keys: impl Iterator<Item = &'a str>) {
+        let mut counts = vec![0u16; self.counters.len()];
+        for key in keys {
+            for slot in self.slots(key) {
+                counts[slot] = counts[slot].saturating_add(1);
+            }
+        }
+        for (counter, n) in self.counters.iter().zip(counts) {
+            counter.store(n, Ordering::SeqCst);
+        }
+    }
+
+    /// Note that a "maybe" for some key turned out to be absent.
+    pub fn false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> KeyFilterStats {
+        KeyFilterStats {
+            slots: self.counters.len(),
+            negatives: self.negatives.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,34 +1,1894 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::borrow::Cow;
+use std::collections::hash_map::{self, DefaultHasher};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, TryLockError};
+use std::time::{Duration, Instant};
 
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::bloom::{KeyFilter, KeyFilterStats};
+use crate::metrics::{Histogram, LOCK_WAIT_BUCKETS};
+
+/// A stored value plus the namespace it was written under, if any.
+/// The value is kept serialized so reads can hand it out without re-encoding it.
+struct Entry {
+    raw: Stored,
+    namespace: Option<String>,
+    version: u64,
+    immutable: bool,
+    // response headers GETs replay, as written with `X-Store-Headers`
+    headers: Vec<(String, String)>,
+    // past this the entry is treated as absent and dropped the next time its key is touched
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// Serialized JSON as held in memory: as-is, or lz4-compressed when compression is on and
+/// the value is large enough to be worth it.
+enum Stored {
+    Plain(String),
+    Compressed(Vec<u8>),
+}
+
+impl Stored {
+    /// The serialized JSON, decompressed if necessary.
+    fn text(&self) -> Cow<'_, str> {
+        match self {
+            Stored::Plain(raw) => Cow::Borrowed(raw),
+            Stored::Compressed(bytes) => {
+                let raw = lz4_flex::decompress_size_prepended(bytes)
+                    .expect("cache holds only data it compressed itself");
+                Cow::Owned(String::from_utf8(raw).expect("cache compresses only UTF-8 JSON"))
+            }
+        }
+    }
+
+    /// Bytes this value occupies in memory.
+    fn len(&self) -> usize {
+        match self {
+            Stored::Plain(raw) => raw.len(),
+            Stored::Compressed(bytes) => bytes.len(),
+        }
+    }
+}
+
+/// A stored value as its serialized JSON, with the metadata reads report alongside it.
+pub struct RawEntry {
+    pub raw: String,
+    pub version: u64,
+    /// The writer promised this value never changes, so responses may be cached.
+    pub immutable: bool,
+    /// Response headers to send with the value, in the order they were written.
+    pub headers: Vec<(String, String)>,
+    /// When the value expires, if it was written with a TTL.
+    pub expires_at: Option<Instant>,
+}
+
+/// One stored entry as `Cache::export` hands it out.
+pub struct ExportedEntry {
+    pub key: String,
+    pub namespace: Option<String>,
+    pub entry: RawEntry,
+}
+
+/// Iterator over every live entry of a cache; see `Cache::export`.
+pub struct Export {
+    cache: Cache,
+    next_shard: usize,
+    batch: std::vec::IntoIter<ExportedEntry>,
+}
+
+impl Iterator for Export {
+    type Item = ExportedEntry;
+
+    fn next(&mut self) -> Option<ExportedEntry> {
+        loop {
+            if let Some(entry) = self.batch.next() {
+                return Some(entry);
+            }
+            let shard = self.cache.0.shards.get(self.next_shard)?;
+            self.next_shard += 1;
+            let now = Instant::now();
+            let batch: Vec<ExportedEntry> = shard
+                .lock()
+                .iter()
+                .filter(|(_, e)| !e.expired(now))
+                .map(|(key, e)| ExportedEntry {
+                    key: key.clone(),
+                    namespace: e.namespace.clone(),
+                    entry: RawEntry {
+                        raw: e.raw.text().into_owned(),
+                        version: e.version,
+                        immutable: e.immutable,
+                        headers: e.headers.clone(),
+                        expires_at: e.expires_at,
+                    },
+                })
+                .collect();
+            self.batch = batch.into_iter();
+        }
+    }
+}
+
+/// Optional behaviour for `Cache::set_with`.
+#[derive(Default)]
+pub struct SetOptions {
+    /// Namespace the key belongs to, recorded for per-namespace stats.
+    pub namespace: Option<String>,
+    /// Only write if the entry's current version equals this (optimistic concurrency).
+    pub if_version: Option<u64>,
+    /// Only write if the key holds no value; otherwise fail with its current version.
+    pub if_absent: bool,
+    /// Mark the value write-once so reads can let HTTP caches keep it.
+    pub immutable: bool,
+    /// Expire the value this long after the write; None keeps it until deleted.
+    pub ttl: Option<Duration>,
+    /// Response headers GETs of the value should carry.
+    pub headers: Vec<(String, String)>,
+}
+
+/// One write in a `Cache::transact` batch.
+pub struct TxnOp {
+    pub key: String,
+    /// Value to store, or None to delete the key.
+    pub value: Option<Value>,
+    /// Only apply the batch if the key's version at this point in it equals this.
+    pub if_version: Option<u64>,
+    /// Namespace the key belongs to, recorded for per-namespace stats.
+    pub namespace: Option<String>,
+    /// Expire a stored value this long after the batch applies; None keeps it until deleted.
+    pub ttl: Option<Duration>,
+    /// Mark a stored value write-once, as `SetOptions::immutable` does.
+    pub immutable: bool,
+    /// Response headers GETs of a stored value should carry.
+    pub headers: Vec<(String, String)>,
+}
+
+/// What one `TxnOp` did: the key's new version (None for a delete) and whether it held a value
+/// just before the op.
+pub type TxnResult = (Option<u64>, bool);
+
+/// Why a conditional write was refused.
+#[derive(Debug, PartialEq)]
+pub enum WriteError {
+    /// The entry's version (None if absent) didn't match the expected one.
+    VersionMismatch { current: Option<u64> },
+    /// A list operation found a value that isn't a JSON array.
+    NotAnArray,
+    /// A `push_with` check found the grown array too large to store.
+    TooLarge,
+    /// A `push_with` check refused the grown array, for the reasons given.
+    Rejected(Vec<String>),
+    /// The write would take its namespace past the quota set with `Cache::set_quota`.
+    QuotaExceeded,
+}
+
+/// Caps on what one namespace may hold; None leaves that measure unlimited.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct Quota {
+    pub max_entries: Option<usize>,
+    /// Counted like `UsageStats::bytes`.
+    pub max_bytes: Option<usize>,
+}
+
+/// Running totals for one namespace, kept up to date by every write so quotas can be checked
+/// without scanning the shards.
+#[derive(Default)]
+struct NamespaceUsage {
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+    quota: RwLock<Quota>,
+}
+
+impl NamespaceUsage {
+    /// Add `entries` and `bytes` (either may be negative) to the totals. Refused, changing
+    /// nothing, if that grows either past its quota; shrinking is always allowed.
+    fn charge(&self, entries: isize, bytes: isize) -> bool {
+        let quota = *self.quota.read().unwrap();
+        if !adjust(&self.entries, entries, quota.max_entries) {
+            return false;
+        }
+        if !adjust(&self.bytes, bytes, quota.max_bytes) {
+            adjust(&self.entries, -entries, None);
+            return false;
+        }
+        true
+    }
+
+    /// Take back an earlier `charge`, whatever the quota now says.
+    fn undo(&self, entries: isize, bytes: isize) {
+        adjust(&self.entries, -entries, None);
+        adjust(&self.bytes, -bytes, None);
+    }
+}
+
+/// Add `delta` to `counter` unless that grows it past `max`. Returns whether it was added.
+fn adjust(counter: &AtomicUsize, delta: isize, max: Option<usize>) -> bool {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            let next = n.saturating_add_signed(delta);
+            (delta <= 0 || max.is_none_or(|max| next <= max)).then_some(next)
+        })
+        .is_ok()
+}
+
+/// Entry count and approximate size (key plus value as held in memory) of a group of keys.
+/// With compression on, compressed values count at their compressed size.
+#[derive(Default, Serialize)]
+pub struct UsageStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Snapshot of what the cache currently holds.
+#[derive(Serialize)]
+pub struct CacheStats {
+    #[serde(flatten)]
+    pub total: UsageStats,
+    pub namespaces: HashMap<String, UsageStats>,
+    /// Present when the cache was built with a key filter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_filter: Option<KeyFilterStats>,
+}
+
+/// One lock stripe of the cache.
+struct Shard {
+    entries: Mutex<HashMap<String, Entry>>,
+    // times a lock found the shard already held and had to wait
+    contended: AtomicU64,
+    // how long those waits took; one histogram shared by every shard
+    waits: Arc<Histogram>,
+}
+
+impl Shard {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        match self.entries.try_lock() {
+            Ok(guard) => guard,
+            // only a lock that has to wait is timed, keeping the uncontended path clock-free
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                let started = Instant::now();
+                let guard = self.entries.lock().unwrap();
+                self.waits.observe(started.elapsed());
+                guard
+            }
+            // lock again only to fail the same way a plain lock would
+            Err(TryLockError::Poisoned(_)) => self.entries.lock().unwrap(),
+        }
+    }
+}
+
+/// Load on one shard, for spotting hot ones.
+#[derive(Serialize)]
+pub struct ShardStats {
+    pub entries: usize,
+    /// Lock acquisitions that had to wait for another holder; approximate.
+    pub contended: u64,
+}
+
+/// What `Cache::self_check` found wrong with the cache's bookkeeping.
+#[derive(Default, Serialize)]
+pub struct SelfCheck {
+    /// Entries held, expired or not.
+    pub entries: usize,
+    /// Entries past their TTL that no read or purge has dropped yet.
+    pub expired: usize,
+    /// Entries sitting in a shard other than the one their key hashes to, where no lookup
+    /// would find them.
+    pub misplaced: usize,
+    /// Namespaces whose running totals (which quotas are checked against) disagree with
+    /// what they actually hold.
+    pub namespaces: Vec<NamespaceDrift>,
+    /// Stored keys the key filter would answer "absent" for.
+    pub key_filter_misses: usize,
+    /// Whether the problems above were fixed.
+    pub repaired: bool,
+}
+
+/// One namespace's running totals next to a fresh count.
+#[derive(Serialize)]
+pub struct NamespaceDrift {
+    pub namespace: String,
+    pub recorded: UsageStats,
+    pub actual: UsageStats,
+}
+
+impl SelfCheck {
+    /// Whether any check failed.
+    pub fn is_clean(&self) -> bool {
+        self.expired == 0
+            && self.misplaced == 0
+            && self.namespaces.is_empty()
+            && self.key_filter_misses == 0
+    }
+}
+
+/// Number of lock stripes used by `Cache::new`.
+pub const DEFAULT_SHARDS: usize = 64;
+
+/// Rewrites a value on its way into the cache, given its storage key; see `Cache::with_transform`.
+pub type Transform = Box<dyn Fn(&str, Value) -> Value + Send + Sync>;
+
+/// How `Cache::with_options` builds a cache.
+pub struct CacheOptions {
+    /// Lock stripes (at least one).
+    pub shards: usize,
+    /// Store values of at least this many serialized bytes lz4-compressed; None stores all as-is.
+    pub compress_min_bytes: Option<usize>,
+    /// Counters in a Bloom filter over the stored keys, so reads of absent keys usually skip
+    /// the shard lock; 0 builds no filter. Around ten per expected key keeps its guesses good.
+    pub key_filter_slots: usize,
+    /// Applied to every value set, before it is stored.
+    pub transform: Option<Transform>,
+    /// Remember the keys of entries dropped for having expired, for `take_expired` to hand out.
+    /// Whoever turns this on must drain them, or the list grows with every expiry.
+    pub record_expiries: bool,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            shards: DEFAULT_SHARDS,
+            compress_min_bytes: None,
+            key_filter_slots: 0,
+            transform: None,
+            record_expiries: false,
+        }
+    }
+}
+
 /// Simple thread-safe in-memory cache wrapper.
 /// Provides a small API for get/set/delete so server logic doesn't manipulate the lock directly.
+/// Keys are striped over independently locked shards, so operations on different keys
+/// rarely contend while operations on the same key always serialize.
 #[derive(Clone)]
-pub struct Cache(Arc<Mutex<HashMap<String, Value>>>);
+pub struct Cache(Arc<Inner>);
+
+struct Inner {
+    shards: Vec<Shard>,
+    lock_waits: Arc<Histogram>,
+    // versions come from one node-wide counter so a recreated key never reuses an old version
+    next_version: AtomicU64,
+    // values at least this many serialized bytes are stored compressed; None stores everything as-is
+    compress_min_bytes: Option<usize>,
+    // guesses whether a key is present without locking; updated under the key's shard lock
+    filter: Option<KeyFilter>,
+    // keyed by namespace; entries are only ever added, so accounting takes the read lock
+    namespaces: RwLock<HashMap<String, Arc<NamespaceUsage>>>,
+    transform: Option<Transform>,
+    // `wait_for_raw` callers parked on the key they wait for, woken when it's written;
+    // `waiting` counts them so writes skip the lock entirely while nobody waits
+    waiters: Mutex<HashMap<String, Waiters>>,
+    waiting: AtomicUsize,
+    // keys dropped for having expired since the last `take_expired`; None when not recorded
+    expiries: Option<Mutex<Vec<String>>>,
+}
+
+/// The `wait_for_raw` callers blocked on one key.
+struct Waiters {
+    count: usize,
+    written: Arc<Condvar>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Cache {
     /// Create a new empty cache.
     pub fn new() -> Self {
-        Cache(Arc::new(Mutex::new(HashMap::new())))
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Create a new empty cache striped over `shards` locks (at least one).
+    pub fn with_shards(shards: usize) -> Self {
+        Self::with_options(CacheOptions {
+            shards,
+            ..CacheOptions::default()
+        })
+    }
+
+    /// Create a new empty cache that stores values of at least `min_bytes` serialized bytes
+    /// lz4-compressed, trading CPU on every read and write for memory. Smaller values are stored
+    /// as-is, since compressing them saves little and costs the same.
+    pub fn with_compression(min_bytes: usize) -> Self {
+        Self::with_options(CacheOptions {
+            compress_min_bytes: Some(min_bytes),
+            ..CacheOptions::default()
+        })
+    }
+
+    /// Create a new empty cache that passes every value set through `transform`, with its
+    /// storage key, and stores what it returns, e.g. to strip a field or stamp a write time.
+    /// It runs on whichever node owns the key, under no lock. Sets, `mput` and `txn` writes go
+    /// through it; `push` and `list_remove` edit stored arrays in place and don't.
+    pub fn with_transform(transform: Transform) -> Self {
+        Self::with_options(CacheOptions {
+            transform: Some(transform),
+            ..CacheOptions::default()
+        })
+    }
+
+    /// Create a new empty cache built as `opts` describes.
+    pub fn with_options(opts: CacheOptions) -> Self {
+        let lock_waits = Arc::new(Histogram::with_buckets(LOCK_WAIT_BUCKETS));
+        Cache(Arc::new(Inner {
+            shards: (0..opts.shards.max(1))
+                .map(|_| Shard {
+                    entries: Mutex::new(HashMap::new()),
+                    contended: AtomicU64::new(0),
+                    waits: lock_waits.clone(),
+                })
+                .collect(),
+            lock_waits,
+            next_version: AtomicU64::new(1),
+            compress_min_bytes: opts.compress_min_bytes,
+            filter: (opts.key_filter_slots > 0).then(|| KeyFilter::new(opts.key_filter_slots)),
+            namespaces: RwLock::new(HashMap::new()),
+            transform: opts.transform,
+            waiters: Mutex::new(HashMap::new()),
+            waiting: AtomicUsize::new(0),
+            expiries: opts.record_expiries.then(|| Mutex::new(Vec::new())),
+        }))
+    }
+
+    /// `value` as the transform, if any, rewrites it for `key`.
+    fn transformed(&self, key: &str, value: Value) -> Value {
+        match &self.0.transform {
+            Some(transform) => transform(key, value),
+            None => value,
+        }
+    }
+
+    /// Prepare serialized JSON for storage, compressing it if it's large enough.
+    fn encode(&self, raw: String) -> Stored {
+        match self.0.compress_min_bytes {
+            Some(min) if raw.len() >= min => {
+                Stored::Compressed(lz4_flex::compress_prepend_size(raw.as_bytes()))
+            }
+            _ => Stored::Plain(raw),
+        }
+    }
+
+    /// Index of the shard holding `key`.
+    fn shard_index(&self, key: &str) -> usize {
+        // a different hash than peer ownership, so one node's keys still cover every shard
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) % self.0.shards.len()
+    }
+
+    /// Lock the shard holding `key`, first dropping `key` if it has expired,
+    /// so callers only ever see live entries for it.
+    fn shard(&self, key: &str) -> MutexGuard<'_, HashMap<String, Entry>> {
+        let mut guard = self.0.shards[self.shard_index(key)].lock();
+        self.drop_expired(&mut guard, key);
+        guard
+    }
+
+    /// Whether the key filter rules `key` out, in which case no lock need be taken to read it.
+    fn surely_absent(&self, key: &str) -> bool {
+        self.0.filter.as_ref().is_some_and(|f| !f.may_contain(key))
+    }
+
+    /// Finish a read the filter didn't rule out, counting it if the key wasn't there after all.
+    fn looked_up<T>(&self, found: Option<T>) -> Option<T> {
+        if found.is_none()
+            && let Some(filter) = &self.0.filter
+        {
+            filter.false_positive();
+        }
+        found
+    }
+
+    /// Record in the key filter that `key` was just added. Call under the key's shard lock.
+    fn added(&self, key: &str) {
+        if let Some(filter) = &self.0.filter {
+            filter.insert(key);
+        }
+    }
+
+    /// Record in the key filter that `key` was just removed. Call under the key's shard lock.
+    fn removed(&self, key: &str) {
+        if let Some(filter) = &self.0.filter {
+            filter.remove(key);
+        }
+    }
+
+    /// Remove `key` from a locked shard if its entry has expired.
+    fn drop_expired(&self, shard: &mut HashMap<String, Entry>, key: &str) {
+        if shard.get(key).is_some_and(|e| e.expired(Instant::now()))
+            && let Some(old) = shard.remove(key)
+        {
+            self.release(key, &old);
+            self.removed(key);
+            self.expired_dropped(key);
+        }
+    }
+
+    /// Record that `key` was just dropped for having expired, if expiries are recorded.
+    fn expired_dropped(&self, key: &str) {
+        if let Some(expiries) = &self.0.expiries {
+            expiries.lock().unwrap().push(key.to_string());
+        }
+    }
+
+    /// Storage keys of the entries dropped for having expired since the last call, whether a
+    /// read found them expired or a purge swept them. Always empty unless the cache was built
+    /// with `record_expiries`.
+    pub fn take_expired(&self) -> Vec<String> {
+        match &self.0.expiries {
+            Some(expiries) => std::mem::take(&mut *expiries.lock().unwrap()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Totals for `namespace`, created on first use.
+    fn namespace_usage(&self, namespace: &str) -> Arc<NamespaceUsage> {
+        let known = self.0.namespaces.read().unwrap().get(namespace).cloned();
+        match known {
+            Some(usage) => usage,
+            None => self
+                .0
+                .namespaces
+                .write()
+                .unwrap()
+                .entry(namespace.to_string())
+                .or_default()
+                .clone(),
+        }
+    }
+
+    /// Cap what `namespace` may hold in this cache, replacing any quota it had. Writes that would
+    /// take it past the cap fail with `WriteError::QuotaExceeded`; deletes and writes that shrink
+    /// it always succeed, even while it is over a cap lowered beneath what it already holds.
+    pub fn set_quota(&self, namespace: &str, quota: Quota) {
+        *self.namespace_usage(namespace).quota.write().unwrap() = quota;
+    }
+
+    /// Charge `namespace` for `entries` more entries and `bytes` more bytes, if its quota allows.
+    fn charge(&self, namespace: &str, entries: isize, bytes: isize) -> Result<(), WriteError> {
+        if self.namespace_usage(namespace).charge(entries, bytes) {
+            Ok(())
+        } else {
+            Err(WriteError::QuotaExceeded)
+        }
+    }
+
+    /// Move `key`'s share of the namespace totals from its `old` entry to its replacement,
+    /// given as the replacement's namespace and stored size (None if the key is going away).
+    /// Call under the key's shard lock, before changing the entry; on Err nothing was charged.
+    fn account(
+        &self,
+        key: &str,
+        old: Option<&Entry>,
+        new: Option<(Option<&str>, usize)>,
+    ) -> Result<(), WriteError> {
+        let old = old.and_then(|e| Some((e.namespace.as_deref()?, key.len() + e.raw.len())));
+        let new = new.and_then(|(ns, len)| Some((ns?, key.len() + len)));
+        match (old, new) {
+            (Some((was, old_bytes)), Some((ns, bytes))) if was == ns => {
+                self.charge(ns, 0, bytes as isize - old_bytes as isize)
+            }
+            _ => {
+                if let Some((ns, bytes)) = new {
+                    self.charge(ns, 1, bytes as isize)?;
+                }
+                if let Some((was, old_bytes)) = old {
+                    self.namespace_usage(was).undo(1, old_bytes as isize);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Take `key`'s removed entry `old` out of its namespace's totals.
+    fn release(&self, key: &str, old: &Entry) {
+        if let Some(ns) = &old.namespace {
+            let bytes = key.len() + old.raw.len();
+            self.namespace_usage(ns).undo(1, bytes as isize);
+        }
+    }
+
+    /// Build the storage key for `key` inside `namespace`.
+    pub fn namespaced_key(namespace: &str, key: &str) -> String {
+        format!("{}:{}", namespace, key)
+    }
+
+    /// Set a key to a JSON value. Returns the entry's new version.
+    pub fn set(&self, key: String, value: Value) -> u64 {
+        self.set_with(key, value, SetOptions::default()).unwrap().0
+    }
+
+    /// Set `key` inside `namespace`; it is stored under `namespace:key`. Returns the new version,
+    /// or `WriteError::QuotaExceeded` if the namespace has no room for it.
+    pub fn set_namespaced(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: Value,
+    ) -> Result<u64, WriteError> {
+        let opts = SetOptions {
+            namespace: Some(namespace.to_string()),
+            ..SetOptions::default()
+        };
+        self.set_with(Self::namespaced_key(namespace, key), value, opts)
+            .map(|(version, _)| version)
+    }
+
+    /// Set a key with extra options, checked and applied under the key's lock.
+    /// Returns the entry's new version and whether it replaced an existing value.
+    pub fn set_with(
+        &self,
+        key: String,
+        value: Value,
+        opts: SetOptions,
+    ) -> Result<(u64, bool), WriteError> {
+        // transform and compress before taking the lock so other writers to the shard don't wait
+        let raw = self.encode(self.transformed(&key, value).to_string());
+        let mut guard = self.shard(&key);
+        let current = guard.get(&key).map(|e| e.version);
+        if opts
+            .if_version
+            .is_some_and(|expected| current != Some(expected))
+            || (opts.if_absent && current.is_some())
+        {
+            return Err(WriteError::VersionMismatch { current });
+        }
+        self.account(
+            &key,
+            guard.get(&key),
+            Some((opts.namespace.as_deref(), raw.len())),
+        )?;
+        let version = self.0.next_version.fetch_add(1, Ordering::Relaxed);
+        if !guard.contains_key(&key) {
+            self.added(&key);
+        }
+        // counted under the shard lock, so a waiter not counted yet will see this write itself
+        let wake = (self.0.waiting.load(Ordering::SeqCst) > 0).then(|| key.clone());
+        let replaced = guard
+            .insert(
+                key,
+                Entry {
+                    raw,
+                    namespace: opts.namespace,
+                    version,
+                    immutable: opts.immutable,
+                    headers: opts.headers,
+                    expires_at: opts.ttl.map(|ttl| Instant::now() + ttl),
+                },
+            )
+            .is_some();
+        drop(guard);
+        if let Some(key) = wake {
+            self.notify_write(&key);
+        }
+        Ok((version, replaced))
+    }
+
+    /// Append `item` to the array stored at `key`, starting a new array if the key is absent.
+    /// Returns the array's new length and the entry's new version.
+    pub fn push(&self, key: &str, item: Value) -> Result<(usize, u64), WriteError> {
+        self.push_with(key, item, SetOptions::default(), |_, _| Ok(()))
+            .map(|(len, version, _)| (len, version))
+    }
+
+    /// `push` with the same options as `set_with`; `namespace` applies if the array is created.
+    /// `check` sees the array as it would be stored, with its serialized size, and may refuse
+    /// it (say with `WriteError::TooLarge`); it runs under the key's lock, so the array can't
+    /// grow past it in the meantime. Also returns whether the array already existed.
+    pub fn push_with(
+        &self,
+        key: &str,
+        item: Value,
+        opts: SetOptions,
+        check: impl FnOnce(&Value, usize) -> Result<(), WriteError>,
+    ) -> Result<(usize, u64, bool), WriteError> {
+        let mut guard = self.shard(key);
+        let current = guard.get(key);
+        let version = current.map(|e| e.version);
+        if opts
+            .if_version
+            .is_some_and(|expected| version != Some(expected))
+            || (opts.if_absent && version.is_some())
+        {
+            return Err(WriteError::VersionMismatch { current: version });
+        }
+        let mut items = match current.map(|e| parse_stored(&e.raw.text())) {
+            None => Vec::new(),
+            Some(Value::Array(items)) => items,
+            Some(_) => return Err(WriteError::NotAnArray),
+        };
+        items.push(item);
+        let len = items.len();
+        let array = Value::Array(items);
+        let text = array.to_string();
+        check(&array, text.len())?;
+        let raw = self.encode(text);
+        let namespace = match current {
+            Some(old) => old.namespace.as_deref(),
+            None => opts.namespace.as_deref(),
+        };
+        self.account(key, current, Some((namespace, raw.len())))?;
+        // an existing array keeps its namespace, flags, headers and expiry
+        let (namespace, immutable, headers, expires_at, existed) = match guard.remove(key) {
+            Some(old) => (
+                old.namespace,
+                old.immutable,
+                old.headers,
+                old.expires_at,
+                true,
+            ),
+            None => (
+                opts.namespace,
+                opts.immutable,
+                opts.headers,
+                opts.ttl.map(|ttl| Instant::now() + ttl),
+                false,
+            ),
+        };
+        if !existed {
+            self.added(key);
+        }
+        let version = self.0.next_version.fetch_add(1, Ordering::Relaxed);
+        guard.insert(
+            key.to_string(),
+            Entry {
+                raw,
+                namespace,
+                version,
+                immutable,
+                headers,
+                expires_at,
+            },
+        );
+        drop(guard);
+        self.notify_write(key);
+        Ok((len, version, existed))
+    }
+
+    /// Remove every element equal to `item` from the array stored at `key`.
+    /// Returns how many were removed (0 if the key is absent).
+    pub fn list_remove(&self, key: &str, item: &Value) -> Result<usize, WriteError> {
+        let mut guard = self.shard(key);
+        let Some(entry) = guard.get_mut(key) else {
+            return Ok(0);
+        };
+        let Value::Array(mut items) = parse_stored(&entry.raw.text()) else {
+            return Err(WriteError::NotAnArray);
+        };
+        let before = items.len();
+        items.retain(|v| v != item);
+        let removed = before - items.len();
+        if removed > 0 {
+            let raw = self.encode(Value::Array(items).to_string());
+            let namespace = entry.namespace.as_deref();
+            self.account(key, Some(entry), Some((namespace, raw.len())))?;
+            entry.raw = raw;
+            entry.version = self.0.next_version.fetch_add(1, Ordering::Relaxed);
+            drop(guard);
+            self.notify_write(key);
+        }
+        Ok(removed)
+    }
+
+    /// Apply `ops` in order as one atomic batch: either every op is applied, or (if an
+    /// `if_version` doesn't match) none is. Readers never see part of a batch.
+    /// Returns what each op did; on a mismatch, the failing op's index and the error.
+    pub fn transact(&self, ops: Vec<TxnOp>) -> Result<Vec<TxnResult>, (usize, WriteError)> {
+        let staged: Vec<(TxnOp, Option<Stored>)> = ops
+            .into_iter()
+            .map(|mut op| {
+                let raw = op
+                    .value
+                    .take()
+                    .map(|v| self.encode(self.transformed(&op.key, v).to_string()));
+                (op, raw)
+            })
+            .collect();
+        // lock every shard involved, always in index order so concurrent batches can't deadlock
+        let mut guards: BTreeMap<usize, MutexGuard<'_, HashMap<String, Entry>>> = BTreeMap::new();
+        for (op, _) in &staged {
+            let idx = self.shard_index(&op.key);
+            let guard = guards
+                .entry(idx)
+                .or_insert_with(|| self.0.shards[idx].lock());
+            self.drop_expired(guard, &op.key);
+        }
+
+        // check every condition against the batch's own earlier ops before touching anything
+        let mut versions: HashMap<&str, Option<u64>> = HashMap::new();
+        // namespace and stored size of each key so far, and the batch's net change per namespace
+        // (entries, bytes, last op that added to it)
+        let mut sizes: HashMap<&str, Option<(Option<String>, usize)>> = HashMap::new();
+        let mut deltas: HashMap<String, (isize, isize, usize)> = HashMap::new();
+        let mut results = Vec::with_capacity(staged.len());
+        for (i, (op, raw)) in staged.iter().enumerate() {
+            let current = match versions.get(op.key.as_str()) {
+                Some(version) => *version,
+                None => guards[&self.shard_index(&op.key)]
+                    .get(&op.key)
+                    .map(|e| e.version),
+            };
+            if let Some(expected) = op.if_version
+                && current != Some(expected)
+            {
+                return Err((i, WriteError::VersionMismatch { current }));
+            }
+            let version = raw
+                .as_ref()
+                .map(|_| self.0.next_version.fetch_add(1, Ordering::Relaxed));
+            versions.insert(&op.key, version);
+            results.push((version, current.is_some()));
+
+            let old = match sizes.get(op.key.as_str()) {
+                Some(size) => size.clone(),
+                None => guards[&self.shard_index(&op.key)]
+                    .get(&op.key)
+                    .map(|e| (e.namespace.clone(), e.raw.len())),
+            };
+            let new = raw.as_ref().map(|raw| (op.namespace.clone(), raw.len()));
+            for (size, sign) in [(&old, -1), (&new, 1)] {
+                if let Some((Some(ns), len)) = size {
+                    let delta = deltas.entry(ns.clone()).or_insert((0, 0, i));
+                    delta.0 += sign;
+                    delta.1 += sign * (op.key.len() + len) as isize;
+                    if sign > 0 {
+                        delta.2 = i;
+                    }
+                }
+            }
+            sizes.insert(&op.key, new);
+        }
+        drop(versions);
+        drop(sizes);
+
+        // charge each namespace its net change; one over quota undoes the rest and fails the batch
+        let mut charged: Vec<(&str, isize, isize)> = Vec::new();
+        for (ns, (entries, bytes, index)) in &deltas {
+            if self.charge(ns, *entries, *bytes).is_err() {
+                for (ns, entries, bytes) in charged {
+                    self.namespace_usage(ns).undo(entries, bytes);
+                }
+                return Err((*index, WriteError::QuotaExceeded));
+            }
+            charged.push((ns, *entries, *bytes));
+        }
+        drop(charged);
+
+        // counted under the shard locks, as in `set_with`
+        let waited = self.0.waiting.load(Ordering::SeqCst) > 0;
+        let mut written = Vec::new();
+        let now = Instant::now();
+        for ((op, raw), (version, _)) in staged.into_iter().zip(&results) {
+            let guard = guards.get_mut(&self.shard_index(&op.key)).unwrap();
+            match (raw, version) {
+                (Some(raw), Some(version)) => {
+                    if !guard.contains_key(&op.key) {
+                        self.added(&op.key);
+                    }
+                    if waited {
+                        written.push(op.key.clone());
+                    }
+                    guard.insert(
+                        op.key,
+                        Entry {
+                            raw,
+                            namespace: op.namespace,
+                            version: *version,
+                            immutable: op.immutable,
+                            headers: op.headers,
+                            expires_at: op.ttl.map(|ttl| now + ttl),
+                        },
+                    );
+                }
+                _ => {
+                    if guard.remove(&op.key).is_some() {
+                        self.removed(&op.key);
+                    }
+                }
+            }
+        }
+        drop(guards);
+        for key in &written {
+            self.notify_write(key);
+        }
+        Ok(results)
     }
 
-    /// Set a key to a JSON value.
-    pub fn set(&self, key: String, value: Value) {
-        let mut guard = self.0.lock().unwrap();
-        guard.insert(key, value);
+    /// Wake whoever is blocked in `wait_for_raw` on `key`. Call after the write, outside the
+    /// shard lock; only writes that can make a key appear need to.
+    fn notify_write(&self, key: &str) {
+        // a waiter counts itself before its last check of the key, so a zero here means any
+        // waiter still to come will see this write
+        if self.0.waiting.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        if let Some(waiters) = self.0.waiters.lock().unwrap().get(key) {
+            waiters.written.notify_all();
+        }
     }
 
     /// Get a value by key. Returns a cloned Value if present.
     pub fn get(&self, key: &str) -> Option<Value> {
-        let guard = self.0.lock().unwrap();
-        guard.get(key).cloned()
+        if self.surely_absent(key) {
+            return None;
+        }
+        let guard = self.shard(key);
+        self.looked_up(guard.get(key).map(|e| parse_stored(&e.raw.text())))
+    }
+
+    /// Get a value and its current version by key.
+    pub fn get_versioned(&self, key: &str) -> Option<(Value, u64)> {
+        if self.surely_absent(key) {
+            return None;
+        }
+        let guard = self.shard(key);
+        self.looked_up(
+            guard
+                .get(key)
+                .map(|e| (parse_stored(&e.raw.text()), e.version)),
+        )
+    }
+
+    /// Get a value's serialized JSON and metadata by key, without parsing it.
+    pub fn get_raw(&self, key: &str) -> Option<RawEntry> {
+        if self.surely_absent(key) {
+            return None;
+        }
+        let guard = self.shard(key);
+        self.looked_up(guard.get(key).map(|e| RawEntry {
+            raw: e.raw.text().into_owned(),
+            version: e.version,
+            immutable: e.immutable,
+            headers: e.headers.clone(),
+            expires_at: e.expires_at,
+        }))
+    }
+
+    /// Like `get_raw`, but if `key` is absent block up to `timeout` for someone to write it.
+    pub fn wait_for_raw(&self, key: &str, timeout: Duration) -> Option<RawEntry> {
+        let deadline = Instant::now() + timeout;
+        self.0.waiting.fetch_add(1, Ordering::SeqCst);
+        // Checking the key while holding `waiters` closes the gap between a miss and the wait:
+        // a writer can't signal until we're parked on the condvar.
+        let mut waiters = self.0.waiters.lock().unwrap();
+        let written = {
+            let entry = waiters.entry(key.to_string()).or_insert_with(|| Waiters {
+                count: 0,
+                written: Arc::new(Condvar::new()),
+            });
+            entry.count += 1;
+            Arc::clone(&entry.written)
+        };
+        let found = loop {
+            if let Some(found) = self.get_raw(key) {
+                break Some(found);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break None;
+            }
+            waiters = written.wait_timeout(waiters, left).unwrap().0;
+        };
+        if let Some(entry) = waiters.get_mut(key) {
+            entry.count -= 1;
+            if entry.count == 0 {
+                waiters.remove(key);
+            }
+        }
+        drop(waiters);
+        self.0.waiting.fetch_sub(1, Ordering::SeqCst);
+        found
+    }
+
+    /// Every key starting with `prefix` that was written in `namespace`, with its value. None
+    /// matches only keys written outside any namespace, so a scan there doesn't see tenants'
+    /// keys even when their storage keys happen to start with `prefix`.
+    pub fn scan_prefix(&self, prefix: &str, namespace: Option<&str>) -> Vec<(String, Value)> {
+        let now = Instant::now();
+        let mut found = Vec::new();
+        for shard in self.0.shards.iter() {
+            let guard = shard.lock();
+            for (key, entry) in guard.iter() {
+                if key.starts_with(prefix)
+                    && entry.namespace.as_deref() == namespace
+                    && !entry.expired(now)
+                {
+                    found.push((key.clone(), parse_stored(&entry.raw.text())));
+                }
+            }
+        }
+        found
+    }
+
+    /// Delete every key starting with `prefix` that was written in `namespace` (None: outside
+    /// any namespace, as for `scan_prefix`), one shard at a time. Returns the keys removed;
+    /// expired matches are dropped too, as expired, but not returned, since they were already
+    /// absent. Keys written under the prefix while it runs may survive if their shard was
+    /// already done.
+    pub fn delete_prefix(&self, prefix: &str, namespace: Option<&str>) -> Vec<String> {
+        let now = Instant::now();
+        let mut removed = Vec::new();
+        for shard in self.0.shards.iter() {
+            let mut guard = shard.lock();
+            let matching: Vec<String> = guard
+                .iter()
+                .filter(|(key, entry)| {
+                    key.starts_with(prefix) && entry.namespace.as_deref() == namespace
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in matching {
+                if let Some(old) = guard.remove(&key) {
+                    self.release(&key, &old);
+                    self.removed(&key);
+                    if old.expired(now) {
+                        self.expired_dropped(&key);
+                    } else {
+                        removed.push(key);
+                    }
+                }
+            }
+        }
+        removed
     }
 
     /// Delete a key. Returns 1 if removed, 0 if not present.
     pub fn delete(&self, key: &str) -> usize {
-        let mut guard = self.0.lock().unwrap();
-        if guard.remove(key).is_some() { 1 } else { 0 }
+        self.delete_if(key, None).unwrap_or(0)
+    }
+
+    /// Delete, but only if the key is currently at `if_version` (when given).
+    /// Returns 1 if removed, 0 if not present.
+    pub fn delete_if(&self, key: &str, if_version: Option<u64>) -> Result<usize, WriteError> {
+        let mut guard = self.shard(key);
+        if let Some(expected) = if_version {
+            let current = guard.get(key).map(|e| e.version);
+            if current != Some(expected) {
+                return Err(WriteError::VersionMismatch { current });
+            }
+        }
+        if let Some(old) = guard.remove(key) {
+            self.release(key, &old);
+            self.removed(key);
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Whether every shard lock can still be taken (none was poisoned by a panicking writer).
+    pub fn is_healthy(&self) -> bool {
+        self.0
+            .shards
+            .iter()
+            .all(|shard| shard.entries.lock().is_ok())
+    }
+
+    /// Drop every expired entry, returning how many were dropped. Expired entries already read as
+    /// absent; this only reclaims the memory of ones nobody has touched since.
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut purged = 0;
+        for shard in self.0.shards.iter() {
+            let mut guard = shard.lock();
+            let before = guard.len();
+            guard.retain(|key, entry| {
+                let expired = entry.expired(now);
+                if expired {
+                    self.release(key, entry);
+                    self.removed(key);
+                    self.expired_dropped(key);
+                }
+                !expired
+            });
+            purged += before - guard.len();
+        }
+        purged
+    }
+
+    /// Every live entry, walked one shard at a time: only one shard's entries are copied out at
+    /// once and no lock is held between shards, so a walk of a large cache stays small and
+    /// doesn't stall writers. Writes made during the walk may or may not be seen.
+    pub fn export(&self) -> Export {
+        Export {
+            cache: self.clone(),
+            next_shard: 0,
+            batch: Vec::new().into_iter(),
+        }
+    }
+
+    /// Check the cache's bookkeeping against what it actually holds, and with `repair` bring it
+    /// back in line: purge lingering expired entries, move misplaced ones to their shard, reset
+    /// namespace totals to a fresh count and recount the key filter. Holds every shard lock
+    /// throughout, so the cache is frozen for as long as a full scan takes.
+    pub fn self_check(&self, repair: bool) -> SelfCheck {
+        let now = Instant::now();
+        // writers charge namespaces under their shard locks, so with all of them held the
+        // totals can't move while they're compared
+        let mut guards: Vec<_> = self.0.shards.iter().map(Shard::lock).collect();
+        let mut report = SelfCheck::default();
+        for (idx, guard) in guards.iter().enumerate() {
+            report.entries += guard.len();
+            report.expired += guard.values().filter(|e| e.expired(now)).count();
+            report.misplaced += guard.keys().filter(|k| self.shard_index(k) != idx).count();
+        }
+
+        if repair {
+            for guard in guards.iter_mut() {
+                guard.retain(|key, entry| {
+                    let expired = entry.expired(now);
+                    if expired {
+                        self.release(key, entry);
+                        self.removed(key);
+                        self.expired_dropped(key);
+                    }
+                    !expired
+                });
+            }
+            for idx in 0..guards.len() {
+                let misplaced: Vec<String> = guards[idx]
+                    .keys()
+                    .filter(|k| self.shard_index(k) != idx)
+                    .cloned()
+                    .collect();
+                for key in misplaced {
+                    let entry = guards[idx].remove(&key).unwrap();
+                    let home = self.shard_index(&key);
+                    // the copy lookups could find wins over the one they couldn't
+                    match guards[home].entry(key) {
+                        hash_map::Entry::Occupied(found) => self.release(found.key(), &entry),
+                        hash_map::Entry::Vacant(slot) => {
+                            slot.insert(entry);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut actual: HashMap<String, UsageStats> = HashMap::new();
+        for guard in &guards {
+            for (key, entry) in guard.iter() {
+                if let Some(ns) = &entry.namespace {
+                    let usage = actual.entry(ns.clone()).or_default();
+                    usage.count += 1;
+                    usage.bytes += key.len() + entry.raw.len();
+                }
+            }
+        }
+        let known = self.0.namespaces.read().unwrap().clone();
+        let mut names: Vec<String> = known.keys().chain(actual.keys()).cloned().collect();
+        names.sort();
+        names.dedup();
+        for ns in names {
+            let usage = self.namespace_usage(&ns);
+            let recorded = UsageStats {
+                count: usage.entries.load(Ordering::SeqCst),
+                bytes: usage.bytes.load(Ordering::SeqCst),
+            };
+            let fresh = actual.remove(&ns).unwrap_or_default();
+            if recorded.count != fresh.count || recorded.bytes != fresh.bytes {
+                if repair {
+                    usage.entries.store(fresh.count, Ordering::SeqCst);
+                    usage.bytes.store(fresh.bytes, Ordering::SeqCst);
+                }
+                report.namespaces.push(NamespaceDrift {
+                    namespace: ns,
+                    recorded,
+                    actual: fresh,
+                });
+            }
+        }
+
+        if let Some(filter) = &self.0.filter {
+            let keys = || guards.iter().flat_map(|g| g.keys().map(String::as_str));
+            report.key_filter_misses = keys().filter(|k| !filter.covers(k)).count();
+            if repair {
+                filter.recount(keys());
+            }
+        }
+        report.repaired = repair && !report.is_clean();
+        report
+    }
+
+    /// How long shard lock acquisitions waited for another holder, across all shards.
+    /// Acquisitions that didn't wait aren't recorded.
+    pub fn lock_waits(&self) -> &Histogram {
+        &self.0.lock_waits
+    }
+
+    /// Live entries and lock contention in each shard, in shard order.
+    pub fn shard_stats(&self) -> Vec<ShardStats> {
+        let now = Instant::now();
+        self.0
+            .shards
+            .iter()
+            .map(|shard| {
+                let entries = shard.lock().values().filter(|e| !e.expired(now)).count();
+                ShardStats {
+                    entries,
+                    contended: shard.contended.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    /// Count entries and their sizes, overall and per namespace.
+    pub fn stats(&self) -> CacheStats {
+        let now = Instant::now();
+        let mut stats = CacheStats {
+            total: UsageStats::default(),
+            namespaces: HashMap::new(),
+            key_filter: self.0.filter.as_ref().map(KeyFilter::stats),
+        };
+        for shard in self.0.shards.iter() {
+            let guard = shard.lock();
+            for (key, entry) in guard.iter().filter(|(_, e)| !e.expired(now)) {
+                let bytes = key.len() + entry.raw.len();
+                stats.total.count += 1;
+                stats.total.bytes += bytes;
+                if let Some(ns) = &entry.namespace {
+                    let usage = stats.namespaces.entry(ns.clone()).or_default();
+                    usage.count += 1;
+                    usage.bytes += bytes;
+                }
+            }
+        }
+        stats
+    }
+}
+
+/// Parse a value this cache serialized itself.
+fn parse_stored(raw: &str) -> Value {
+    serde_json::from_str(raw).expect("cache holds only serialized JSON values")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn stats_group_entries_by_namespace() {
+        let cache = Cache::new();
+        cache.set("plain".to_string(), json!(1));
+        cache.set_namespaced("a", "k", json!("xy")).unwrap();
+        cache.set_namespaced("a", "other", json!(2)).unwrap();
+        cache.set_namespaced("b", "k", json!(3)).unwrap();
+        assert_eq!(cache.get("a:k"), Some(json!("xy")));
+        assert_eq!(cache.get("b:k"), Some(json!(3)));
+
+        let stats = cache.stats();
+        assert_eq!(stats.total.count, 4);
+        assert_eq!(stats.namespaces["a"].count, 2);
+        // key plus serialized value
+        assert_eq!(stats.namespaces["b"].bytes, "b:k".len() + "3".len());
+        assert!(!stats.namespaces.contains_key("plain"));
+    }
+
+    #[test]
+    fn every_shard_count_behaves_like_one_map() {
+        for shards in [0, 1, 3, DEFAULT_SHARDS] {
+            let cache = Cache::with_shards(shards);
+            for i in 0..100 {
+                cache.set(format!("k{}", i), json!(i));
+            }
+            assert_eq!(cache.get("k42"), Some(json!(42)));
+            assert_eq!(cache.delete("k42"), 1);
+            assert_eq!(cache.delete("k42"), 0);
+            assert_eq!(cache.stats().total.count, 99);
+        }
+    }
+
+    #[test]
+    fn concurrent_writers_of_different_keys_all_land() {
+        let cache = Cache::new();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let cache = &cache;
+                s.spawn(move || {
+                    for i in 0..200 {
+                        cache.set(format!("t{}-{}", t, i), json!(i));
+                    }
+                });
+            }
+        });
+        assert_eq!(cache.stats().total.count, 8 * 200);
+    }
+
+    #[test]
+    fn set_with_checks_the_expected_version() {
+        let cache = Cache::new();
+        let only_if = |version| SetOptions {
+            if_version: Some(version),
+            ..SetOptions::default()
+        };
+        let v1 = cache.set("k".to_string(), json!(1));
+        assert!(matches!(
+            cache.set_with("k".to_string(), json!(2), only_if(v1 + 100)),
+            Err(WriteError::VersionMismatch { .. })
+        ));
+        let (v2, replaced) = cache
+            .set_with("k".to_string(), json!(2), only_if(v1))
+            .unwrap();
+        assert!(v2 > v1);
+        assert!(replaced);
+        assert_eq!(cache.get_versioned("k"), Some((json!(2), v2)));
+
+        // a recreated key gets a fresh version, so an old ETag can't match it
+        cache.delete("k");
+        let v3 = cache.set("k".to_string(), json!(3));
+        assert!(v3 > v2);
+    }
+
+    #[test]
+    fn values_are_kept_as_their_serialized_text() {
+        let cache = Cache::new();
+        let version = cache.set("k".to_string(), json!({"a": [1, 2.5, "x"], "b": null}));
+        let entry = cache.get_raw("k").unwrap();
+        assert_eq!(entry.raw, r#"{"a":[1,2.5,"x"],"b":null}"#);
+        assert_eq!(entry.version, version);
+        assert_eq!(cache.get("k"), Some(json!({"a": [1, 2.5, "x"], "b": null})));
+        assert_eq!(cache.stats().total.bytes, "k".len() + entry.raw.len());
+    }
+
+    #[test]
+    fn immutability_is_kept_by_later_array_writes() {
+        let cache = Cache::new();
+        let opts = SetOptions {
+            immutable: true,
+            ..SetOptions::default()
+        };
+        cache.set_with("k".to_string(), json!([]), opts).unwrap();
+        assert!(cache.get_raw("k").unwrap().immutable);
+        cache.push("k", json!(1)).unwrap();
+        assert!(cache.get_raw("k").unwrap().immutable);
+        cache.set("plain".to_string(), json!(1));
+        assert!(!cache.get_raw("plain").unwrap().immutable);
+    }
+
+    #[test]
+    fn dropped_expiries_are_recorded_only_when_asked_for() {
+        let expiring = || SetOptions {
+            ttl: Some(Duration::from_millis(10)),
+            ..SetOptions::default()
+        };
+        let plain = Cache::new();
+        plain
+            .set_with("a".to_string(), json!(1), expiring())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(plain.purge_expired(), 1);
+        assert!(plain.take_expired().is_empty());
+
+        let cache = Cache::with_options(CacheOptions {
+            record_expiries: true,
+            ..CacheOptions::default()
+        });
+        for key in ["read", "swept", "prefix:1"] {
+            cache
+                .set_with(key.to_string(), json!(1), expiring())
+                .unwrap();
+        }
+        cache.set("live".to_string(), json!(1));
+        std::thread::sleep(Duration::from_millis(20));
+        // a read, a purge and a prefix delete each drop an expired entry
+        assert_eq!(cache.get("read"), None);
+        assert_eq!(cache.delete_prefix("prefix:", None), Vec::<String>::new());
+        assert_eq!(cache.purge_expired(), 1);
+        let mut expired = cache.take_expired();
+        expired.sort();
+        assert_eq!(expired, vec!["prefix:1", "read", "swept"]);
+        // deletes aren't expiries, and each expiry is handed out once
+        cache.delete("live");
+        assert!(cache.take_expired().is_empty());
+    }
+
+    #[test]
+    fn delete_prefix_counts_only_live_matches_in_its_namespace() {
+        let cache = Cache::new();
+        for key in ["user:1", "user:2", "other"] {
+            cache.set(key.to_string(), json!(1));
+        }
+        let expiring = SetOptions {
+            ttl: Some(Duration::from_millis(10)),
+            ..SetOptions::default()
+        };
+        cache
+            .set_with("user:old".to_string(), json!(1), expiring)
+            .unwrap();
+        let tenant = SetOptions {
+            namespace: Some("user".to_string()),
+            ..SetOptions::default()
+        };
+        let skey = Cache::namespaced_key("user", "x");
+        cache.set_with(skey.clone(), json!(1), tenant).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let mut removed = cache.delete_prefix("user:", None);
+        removed.sort();
+        assert_eq!(removed, vec!["user:1", "user:2"]);
+        // the expired match went too, uncounted; the tenant's key and the rest stay
+        assert_eq!(cache.stats().total.count, 2);
+        assert!(cache.get_raw(&skey).is_some());
+        assert!(cache.get_raw("other").is_some());
+        assert_eq!(cache.delete_prefix("user:", Some("user")), vec![skey]);
+    }
+
+    #[test]
+    fn scan_prefix_stays_within_its_namespace() {
+        let cache = Cache::new();
+        cache.set("user:1".to_string(), json!(1));
+        cache.set("other".to_string(), json!(2));
+        let opts = SetOptions {
+            namespace: Some("user".to_string()),
+            ..SetOptions::default()
+        };
+        // stored as "user:x", which also starts with "user:"
+        let skey = Cache::namespaced_key("user", "x");
+        cache.set_with(skey.clone(), json!(3), opts).unwrap();
+
+        assert_eq!(
+            cache.scan_prefix("user:", None),
+            vec![("user:1".to_string(), json!(1))]
+        );
+        assert_eq!(
+            cache.scan_prefix("user:", Some("user")),
+            vec![(skey, json!(3))]
+        );
+    }
+
+    #[test]
+    fn large_values_are_compressed_and_read_back_unchanged() {
+        let cache = Cache::with_compression(64);
+        let big = json!({"text": "a".repeat(1000)});
+        cache.set("big".to_string(), big.clone());
+        cache.set("small".to_string(), json!("tiny"));
+        assert_eq!(cache.get("big"), Some(big.clone()));
+        assert_eq!(cache.get_raw("big").unwrap().raw, big.to_string());
+        assert_eq!(cache.get("small"), Some(json!("tiny")));
+        // repeated text compresses far below its serialized size
+        assert!(cache.stats().total.bytes < 200);
+
+        let list = json!(["b".repeat(100)]);
+        cache.set("list".to_string(), list);
+        cache.push("list", json!(1)).unwrap();
+        assert_eq!(cache.get("list"), Some(json!(["b".repeat(100), 1])));
+    }
+
+    #[test]
+    fn transact_applies_all_or_nothing() {
+        let cache = Cache::new();
+        let v1 = cache.set("a".to_string(), json!(1));
+        let op = |key: &str, value: Option<Value>, if_version| TxnOp {
+            key: key.to_string(),
+            value,
+            if_version,
+            namespace: None,
+            ttl: None,
+            immutable: false,
+            headers: Vec::new(),
+        };
+
+        // one failed condition leaves every key untouched
+        let refused = cache
+            .transact(vec![
+                op("a", Some(json!(2)), Some(v1)),
+                op("b", Some(json!("new")), Some(999)),
+            ])
+            .unwrap_err();
+        assert_eq!(refused, (1, WriteError::VersionMismatch { current: None }));
+        assert_eq!(cache.get("a"), Some(json!(1)));
+        assert_eq!(cache.get("b"), None);
+
+        // a later op sees the batch's own earlier writes
+        let versions = cache
+            .transact(vec![op("a", Some(json!(2)), Some(v1)), op("a", None, None)])
+            .unwrap();
+        // each op also reports whether the key held a value just before it
+        assert!(versions[0].0.unwrap() > v1 && versions[0].1);
+        assert_eq!(versions[1], (None, true));
+        assert_eq!(cache.get("a"), None);
+
+        let written = cache.transact(vec![op("c", Some(json!(3)), None)]).unwrap();
+        let stale = op("c", Some(json!(4)), Some(written[0].0.unwrap() + 1));
+        assert_eq!(
+            cache.transact(vec![op("d", Some(json!(5)), None), stale]),
+            Err((
+                1,
+                WriteError::VersionMismatch {
+                    current: written[0].0
+                }
+            ))
+        );
+        assert_eq!(cache.get("d"), None);
+    }
+
+    #[test]
+    fn wait_for_raw_returns_once_the_key_is_written() {
+        let cache = Cache::new();
+        let started = Instant::now();
+        let found = std::thread::scope(|s| {
+            let waiter = s.spawn(|| cache.wait_for_raw("k", Duration::from_secs(5)));
+            std::thread::sleep(Duration::from_millis(50));
+            // a write to another key doesn't end the wait
+            cache.set("other".to_string(), json!(0));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            cache.set("k".to_string(), json!(1));
+            waiter.join().unwrap()
+        });
+        assert_eq!(found.map(|entry| entry.raw), Some("1".to_string()));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(cache.0.waiters.lock().unwrap().is_empty());
+        assert_eq!(cache.0.waiting.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn wait_for_raw_gives_up_after_the_timeout() {
+        let cache = Cache::new();
+        let started = Instant::now();
+        assert!(cache.wait_for_raw("k", Duration::from_millis(50)).is_none());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        cache.set("k".to_string(), json!(1));
+        // present keys return at once
+        assert!(cache.wait_for_raw("k", Duration::from_secs(5)).is_some());
+    }
+
+    #[test]
+    fn entries_expire_after_their_ttl() {
+        let cache = Cache::new();
+        let short = SetOptions {
+            ttl: Some(Duration::from_millis(30)),
+            ..SetOptions::default()
+        };
+        cache
+            .set_with("short".to_string(), json!(1), short)
+            .unwrap();
+        cache.set("kept".to_string(), json!(2));
+        cache.push("list", json!(1)).unwrap();
+        let list_ttl = SetOptions {
+            ttl: Some(Duration::from_millis(30)),
+            ..SetOptions::default()
+        };
+        cache
+            .push_with("grown", json!(1), list_ttl, |_, _| Ok(()))
+            .unwrap();
+        // appending keeps the array's expiry
+        cache.push("grown", json!(2)).unwrap();
+        assert_eq!(cache.get("short"), Some(json!(1)));
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(cache.scan_prefix("", None).len(), 2);
+        assert_eq!(cache.get("short"), None);
+        assert!(cache.get_raw("short").is_none());
+        assert_eq!(cache.purge_expired(), 1);
+        assert_eq!(cache.get("kept"), Some(json!(2)));
+        assert_eq!(cache.get("list"), Some(json!([1])));
+        assert_eq!(cache.stats().total.count, 2);
+    }
+
+    #[test]
+    fn the_key_filter_never_hides_a_stored_key() {
+        let cache = Cache::with_options(CacheOptions {
+            key_filter_slots: 10_000,
+            ..CacheOptions::default()
+        });
+        for i in 0..100 {
+            cache.set(format!("k{}", i), json!(i));
+        }
+        assert!((0..100).all(|i| cache.get(&format!("k{}", i)) == Some(json!(i))));
+        let absent = (0..1000)
+            .filter(|i| cache.get(&format!("absent{}", i)).is_none())
+            .count();
+        assert_eq!(absent, 1000);
+        let stats = cache.stats().key_filter.unwrap();
+        assert_eq!(stats.slots, 10_000);
+        assert_eq!(stats.negatives + stats.false_positives, 1000);
+        assert!(stats.negatives > 900, "{} negatives", stats.negatives);
+
+        // deleted keys clear their counters, so a re-added key is found again
+        cache.delete("k1");
+        assert_eq!(cache.get("k1"), None);
+        cache.set("k1".to_string(), json!("back"));
+        assert_eq!(cache.get("k1"), Some(json!("back")));
+        assert!(Cache::new().stats().key_filter.is_none());
+    }
+
+    #[test]
+    fn quotas_cap_a_namespace_and_deletes_free_room() {
+        let cache = Cache::new();
+        let in_ns = |ns: &str| SetOptions {
+            namespace: Some(ns.to_string()),
+            ..SetOptions::default()
+        };
+        cache.set_quota(
+            "a",
+            Quota {
+                max_entries: Some(2),
+                max_bytes: None,
+            },
+        );
+        cache
+            .set_with("a:1".to_string(), json!(1), in_ns("a"))
+            .unwrap();
+        cache
+            .set_with("a:2".to_string(), json!(2), in_ns("a"))
+            .unwrap();
+        // overwriting an entry doesn't add one
+        cache
+            .set_with("a:2".to_string(), json!(3), in_ns("a"))
+            .unwrap();
+        assert_eq!(
+            cache.set_with("a:3".to_string(), json!(3), in_ns("a")),
+            Err(WriteError::QuotaExceeded)
+        );
+        // other namespaces and plain keys aren't affected
+        cache
+            .set_with("b:3".to_string(), json!(3), in_ns("b"))
+            .unwrap();
+        cache.set("plain".to_string(), json!(3));
+
+        cache.delete("a:1");
+        cache
+            .set_with("a:3".to_string(), json!(3), in_ns("a"))
+            .unwrap();
+        let op = |key: &str| TxnOp {
+            key: key.to_string(),
+            value: Some(json!(4)),
+            if_version: None,
+            namespace: Some("a".to_string()),
+            ttl: None,
+            immutable: false,
+            headers: Vec::new(),
+        };
+        // the batch fails at its first op over quota and applies nothing
+        assert_eq!(cache.transact(vec![op("a:2"), op("a:4")]).unwrap_err().0, 1);
+        assert_eq!(cache.get("a:2"), Some(json!(3)));
+
+        cache.set_quota(
+            "a",
+            Quota {
+                max_entries: None,
+                max_bytes: Some("a:2".len() + "3".len() + "a:3".len() + "3".len()),
+            },
+        );
+        assert_eq!(
+            cache.set_with("a:2".to_string(), json!(33), in_ns("a")),
+            Err(WriteError::QuotaExceeded)
+        );
+        assert_eq!(
+            cache
+                .push_with("a:5", json!(1), in_ns("a"), |_, _| Ok(()))
+                .unwrap_err(),
+            WriteError::QuotaExceeded
+        );
+    }
+
+    #[test]
+    fn push_appends_and_refuses_non_arrays() {
+        let cache = Cache::new();
+        assert_eq!(cache.push("list", json!(1)).map(|(len, _)| len), Ok(1));
+        assert_eq!(cache.push("list", json!("two")).map(|(len, _)| len), Ok(2));
+        assert_eq!(cache.get("list"), Some(json!([1, "two"])));
+
+        cache.set("scalar".to_string(), json!(5));
+        assert_eq!(cache.push("scalar", json!(1)), Err(WriteError::NotAnArray));
+        assert_eq!(cache.get("scalar"), Some(json!(5)));
+    }
+
+    #[test]
+    fn push_check_sees_the_grown_array() {
+        let cache = Cache::new();
+        cache.push("list", json!(1)).unwrap();
+        let refuse_big = |array: &Value, bytes: usize| {
+            assert_eq!(bytes, array.to_string().len());
+            if bytes > 5 {
+                Err(WriteError::TooLarge)
+            } else {
+                Ok(())
+            }
+        };
+        // "[1,2]" fits, "[1,2,3]" doesn't
+        assert!(
+            cache
+                .push_with("list", json!(2), SetOptions::default(), refuse_big)
+                .is_ok()
+        );
+        assert_eq!(
+            cache.push_with("list", json!(3), SetOptions::default(), refuse_big),
+            Err(WriteError::TooLarge)
+        );
+        assert_eq!(cache.get("list"), Some(json!([1, 2])));
+    }
+
+    #[test]
+    fn list_remove_drops_every_equal_element() {
+        let cache = Cache::new();
+        cache.set("list".to_string(), json!([1, "x", 1, {"a": 1}]));
+        let before = cache.get_raw("list").unwrap().version;
+        assert_eq!(cache.list_remove("list", &json!(1)), Ok(2));
+        assert_eq!(cache.list_remove("list", &json!({"a": 1})), Ok(1));
+        assert_eq!(cache.get("list"), Some(json!(["x"])));
+        assert!(cache.get_raw("list").unwrap().version > before);
+
+        // nothing to remove leaves the version alone
+        let version = cache.get_raw("list").unwrap().version;
+        assert_eq!(cache.list_remove("list", &json!("y")), Ok(0));
+        assert_eq!(cache.get_raw("list").unwrap().version, version);
+        assert_eq!(cache.list_remove("missing", &json!(1)), Ok(0));
+
+        cache.set("scalar".to_string(), json!(5));
+        assert_eq!(
+            cache.list_remove("scalar", &json!(5)),
+            Err(WriteError::NotAnArray)
+        );
+    }
+
+    #[test]
+    fn the_transform_rewrites_values_before_they_are_stored() {
+        let cache = Cache::with_transform(Box::new(|key, mut value| {
+            if let Some(fields) = value.as_object_mut() {
+                fields.insert("stored_at".to_string(), json!(key));
+            }
+            value
+        }));
+        cache.set("a".to_string(), json!({"x": 1}));
+        assert_eq!(cache.get("a"), Some(json!({"x": 1, "stored_at": "a"})));
+        // txn writes go through it too; values it leaves alone are stored as given
+        let op = TxnOp {
+            key: "b".to_string(),
+            value: Some(json!({"y": 2})),
+            if_version: None,
+            namespace: None,
+            ttl: None,
+            immutable: false,
+            headers: Vec::new(),
+        };
+        cache.transact(vec![op]).unwrap();
+        assert_eq!(cache.get("b"), Some(json!({"y": 2, "stored_at": "b"})));
+        cache.set("c".to_string(), json!(3));
+        assert_eq!(cache.get("c"), Some(json!(3)));
+    }
+
+    #[test]
+    fn shard_stats_count_each_shards_entries_and_waits() {
+        let cache = Cache::with_shards(4);
+        let mut keys = (0..).map(|i| format!("k{}", i));
+        let a = keys.find(|key| cache.shard_index(key) == 1).unwrap();
+        let b = keys.find(|key| cache.shard_index(key) == 3).unwrap();
+        let c = keys.find(|key| cache.shard_index(key) == 3).unwrap();
+        for key in [&a, &b, &c] {
+            cache.set(key.clone(), json!(1));
+        }
+        let counts = |cache: &Cache| -> Vec<usize> {
+            cache.shard_stats().iter().map(|s| s.entries).collect()
+        };
+        assert_eq!(counts(&cache), vec![0, 1, 0, 2]);
+        cache.delete(&c);
+        assert_eq!(counts(&cache), vec![0, 1, 0, 1]);
+
+        // a write that finds its shard held counts as contended there and only there
+        let held = cache.0.shards[3].entries.lock().unwrap();
+        let writer = {
+            let cache = cache.clone();
+            let b = b.clone();
+            std::thread::spawn(move || cache.set(b, json!(2)))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        drop(held);
+        writer.join().unwrap();
+        let contended: Vec<u64> = cache.shard_stats().iter().map(|s| s.contended).collect();
+        assert_eq!(contended, vec![0, 0, 0, 1]);
+        assert_eq!(cache.get(&b), Some(json!(2)));
+    }
+
+    #[test]
+    fn only_waits_for_a_held_shard_lock_are_timed() {
+        let cache = Cache::with_shards(2);
+        for i in 0..100 {
+            cache.set(format!("k{}", i), json!(i));
+        }
+        assert_eq!(cache.lock_waits().count(), 0);
+
+        let key = (0..)
+            .map(|i| format!("k{}", i))
+            .find(|k| cache.shard_index(k) == 0)
+            .unwrap();
+        let held = cache.0.shards[0].entries.lock().unwrap();
+        let reader = {
+            let cache = cache.clone();
+            std::thread::spawn(move || cache.get(&key))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        drop(held);
+        assert!(reader.join().unwrap().is_some());
+        assert_eq!(cache.lock_waits().count(), 1);
+
+        let mut out = String::new();
+        cache.lock_waits().render_metric(&mut out, "waits", "help");
+        let sum: f64 = out
+            .lines()
+            .find_map(|line| line.strip_prefix("waits_sum "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(sum >= 0.04, "{}", out);
+        assert!(out.contains("waits_bucket{le=\"0.1\"} 1"), "{}", out);
+        assert!(out.contains("waits_bucket{le=\"0.01\"} 0"), "{}", out);
+    }
+
+    #[test]
+    fn delete_if_only_removes_the_expected_version() {
+        let cache = Cache::new();
+        let (version, _) = cache
+            .set_with("k".to_string(), json!(1), SetOptions::default())
+            .unwrap();
+        assert_eq!(
+            cache.delete_if("k", Some(version + 1)),
+            Err(WriteError::VersionMismatch {
+                current: Some(version)
+            })
+        );
+        assert_eq!(cache.get("k"), Some(json!(1)));
+        assert_eq!(cache.delete_if("k", Some(version)), Ok(1));
+        assert_eq!(
+            cache.delete_if("k", Some(version)),
+            Err(WriteError::VersionMismatch { current: None })
+        );
+        assert_eq!(cache.delete_if("k", None), Ok(0));
+    }
+
+    #[test]
+    fn self_check_finds_and_repairs_drifted_bookkeeping() {
+        let cache = Cache::with_options(CacheOptions {
+            shards: 4,
+            key_filter_slots: 1000,
+            ..CacheOptions::default()
+        });
+        cache.set_namespaced("ns", "a", json!(1)).unwrap();
+        cache.set_namespaced("ns", "b", json!(2)).unwrap();
+        let report = cache.self_check(true);
+        assert_eq!(report.entries, 2);
+        assert!(report.is_clean() && !report.repaired);
+
+        // corrupt a namespace total, strand an entry in the wrong shard and wipe the filter
+        cache
+            .namespace_usage("ns")
+            .entries
+            .store(7, Ordering::SeqCst);
+        let home = cache.shard_index("ns:a");
+        let entry = cache.0.shards[home].lock().remove("ns:a").unwrap();
+        cache.0.shards[(home + 1) % 4]
+            .lock()
+            .insert("ns:a".to_string(), entry);
+        cache.0.filter.as_ref().unwrap().recount(std::iter::empty());
+
+        let report = cache.self_check(false);
+        assert_eq!(report.misplaced, 1);
+        assert_eq!(report.key_filter_misses, 2);
+        assert_eq!(report.namespaces.len(), 1);
+        assert_eq!(report.namespaces[0].recorded.count, 7);
+        assert_eq!(report.namespaces[0].actual.count, 2);
+        assert!(!report.repaired);
+        // only a repairing check changes anything
+        assert_eq!(cache.get("ns:a"), None);
+
+        assert!(cache.self_check(true).repaired);
+        assert_eq!(cache.get("ns:a"), Some(json!(1)));
+        assert_eq!(cache.get("ns:b"), Some(json!(2)));
+        assert_eq!(cache.stats().namespaces["ns"].count, 2);
+        assert!(cache.self_check(false).is_clean());
     }
 }
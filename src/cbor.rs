@@ -0,0 +1,560 @@
+use serde_json::{Map, Number, Value};
+
+/// Content type of CBOR request and response bodies.
+pub const CBOR: &str = "application/cbor";
+
+/// Deepest nesting `decode` accepts, the same limit serde_json puts on JSON.
+const MAX_DEPTH: usize = 128;
+
+// major types, already shifted into the initial byte's top three bits
+const UNSIGNED: u8 = 0 << 5;
+const NEGATIVE: u8 = 1 << 5;
+const BYTES: u8 = 2 << 5;
+const TEXT: u8 = 3 << 5;
+const ARRAY: u8 = 4 << 5;
+const MAP: u8 = 5 << 5;
+const TAG: u8 = 6 << 5;
+const SIMPLE: u8 = 7 << 5;
+
+// RFC 8949 tags for numbers a 64-bit int or a double can't hold exactly
+const POSITIVE_BIGNUM: u64 = 2;
+const NEGATIVE_BIGNUM: u64 = 3;
+const DECIMAL_FRACTION: u64 = 4;
+
+/// Encode a value as CBOR. Numbers stay exact as with msgpack: ints and doubles where those
+/// reproduce the number digit for digit, bignums for larger integers, and decimal fractions
+/// for decimals a double would round.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(SIMPLE | 22),
+        Value::Bool(false) => out.push(SIMPLE | 20),
+        Value::Bool(true) => out.push(SIMPLE | 21),
+        Value::Number(n) => write_number(out, n),
+        Value::String(s) => {
+            write_head(out, TEXT, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            write_head(out, ARRAY, items.len() as u64);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        Value::Object(map) => {
+            write_head(out, MAP, map.len() as u64);
+            for (key, item) in map {
+                write_head(out, TEXT, key.len() as u64);
+                out.extend_from_slice(key.as_bytes());
+                write_value(out, item);
+            }
+        }
+    }
+}
+
+/// Initial byte for `major` with argument `arg`, plus the argument's following bytes if any.
+fn write_head(out: &mut Vec<u8>, major: u8, arg: u64) {
+    match arg {
+        0..=23 => out.push(major | arg as u8),
+        24..=0xff => out.extend([major | 24, arg as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((arg as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((arg as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(arg.to_be_bytes());
+        }
+    }
+}
+
+fn write_number(out: &mut Vec<u8>, n: &Number) {
+    let text = n.to_string();
+    if let Some(f) = n
+        .as_f64()
+        .filter(|_| n.as_u64().is_none() && n.as_i64().is_none())
+        .filter(|f| Number::from_f64(*f).is_some_and(|m| m.to_string() == text))
+    {
+        out.push(SIMPLE | 27);
+        out.extend(f.to_be_bytes());
+        return;
+    }
+    let (mantissa, exponent) = split_decimal(&text);
+    if exponent != 0 {
+        write_head(out, TAG, DECIMAL_FRACTION);
+        write_head(out, ARRAY, 2);
+        write_integer(out, &exponent.to_string());
+    }
+    write_integer(out, &mantissa);
+}
+
+/// `text` as an integer mantissa and a power of ten, e.g. "-1.25e3" as ("-125", 1).
+fn split_decimal(text: &str) -> (String, i64) {
+    let (number, exponent) = match text.find(['e', 'E']) {
+        Some(at) => (&text[..at], text[at + 1..].parse::<i64>().unwrap_or(0)),
+        None => (text, 0),
+    };
+    match number.split_once('.') {
+        Some((whole, fraction)) => (
+            format!("{}{}", whole, fraction),
+            exponent - fraction.len() as i64,
+        ),
+        None => (number.to_string(), exponent),
+    }
+}
+
+/// Write a decimal integer of any size: natively if it fits 64 bits, else as a bignum.
+fn write_integer(out: &mut Vec<u8>, digits: &str) {
+    let (negative, magnitude) = match digits.strip_prefix('-') {
+        Some(magnitude) => (true, magnitude),
+        None => (false, digits.strip_prefix('+').unwrap_or(digits)),
+    };
+    let mut bytes = decimal_to_bytes(magnitude);
+    // -0 is written as 0, the only integer zero CBOR has
+    let negative = negative && bytes.iter().any(|b| *b != 0);
+    // CBOR stores a negative n as -1 - n, so the magnitude written is one less
+    if negative {
+        decrement(&mut bytes);
+    }
+    let first = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    let bytes = &bytes[first..];
+    let major = if negative { NEGATIVE } else { UNSIGNED };
+    if bytes.len() <= 8 {
+        let mut arg = [0u8; 8];
+        arg[8 - bytes.len()..].copy_from_slice(bytes);
+        write_head(out, major, u64::from_be_bytes(arg));
+    } else {
+        let tag = if negative {
+            NEGATIVE_BIGNUM
+        } else {
+            POSITIVE_BIGNUM
+        };
+        write_head(out, TAG, tag);
+        write_head(out, BYTES, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+}
+
+/// Big-endian bytes of a string of decimal digits.
+fn decimal_to_bytes(digits: &str) -> Vec<u8> {
+    let mut bytes = vec![0u8];
+    for digit in digits.bytes().map(|d| u32::from(d - b'0')) {
+        // bytes = bytes * 10 + digit
+        let mut carry = digit;
+        for byte in bytes.iter_mut().rev() {
+            let n = u32::from(*byte) * 10 + carry;
+            *byte = n as u8;
+            carry = n >> 8;
+        }
+        if carry > 0 {
+            bytes.insert(0, carry as u8);
+        }
+    }
+    bytes
+}
+
+/// Decimal digits of big-endian `bytes`.
+fn bytes_to_decimal(bytes: &[u8]) -> String {
+    // little-endian base-10 digits
+    let mut digits = vec![0u8];
+    for byte in bytes {
+        // digits = digits * 256 + byte
+        let mut carry = u32::from(*byte);
+        for digit in digits.iter_mut() {
+            let n = u32::from(*digit) * 256 + carry;
+            *digit = (n % 10) as u8;
+            carry = n / 10;
+        }
+        while carry > 0 {
+            digits.push((carry % 10) as u8);
+            carry /= 10;
+        }
+    }
+    while digits.len() > 1 && digits.last() == Some(&0) {
+        digits.pop();
+    }
+    digits.iter().rev().map(|d| char::from(b'0' + d)).collect()
+}
+
+fn decrement(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut().rev() {
+        let (n, borrow) = byte.overflowing_sub(1);
+        *byte = n;
+        if !borrow {
+            return;
+        }
+    }
+}
+
+fn increment(bytes: &mut Vec<u8>) {
+    for byte in bytes.iter_mut().rev() {
+        let (n, carry) = byte.overflowing_add(1);
+        *byte = n;
+        if !carry {
+            return;
+        }
+    }
+    bytes.insert(0, 1);
+}
+
+/// Decode one CBOR data item into a value. Byte strings (outside bignums), NaN, the
+/// infinities and maps with non-text keys have no JSON form and are refused; other tags are
+/// ignored in favour of the item they wrap.
+pub fn decode(bytes: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let value = reader.value(0)?;
+    if reader.pos != bytes.len() {
+        return Err(format!(
+            "trailing bytes after the value at offset {}",
+            reader.pos
+        ));
+    }
+    Ok(value)
+}
+
+/// A number `decode` reads, before it becomes a JSON number.
+enum Integer {
+    Native(Value),
+    // decimal digits, sign included
+    Big(String),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("unexpected end of input")?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// The argument following an initial byte with additional info `info`;
+    /// None for the indefinite-length marker.
+    fn argument(&mut self, info: u8) -> Result<Option<u64>, String> {
+        let width = match info {
+            0..=23 => return Ok(Some(u64::from(info))),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            31 => return Ok(None),
+            _ => return Err(format!("reserved additional info {}", info)),
+        };
+        let mut arg = [0u8; 8];
+        arg[8 - width..].copy_from_slice(self.take(width)?);
+        Ok(Some(u64::from_be_bytes(arg)))
+    }
+
+    /// Whether the next byte is the "break" ending an indefinite-length item, consuming it if so.
+    fn at_break(&mut self) -> Result<bool, String> {
+        match self.bytes.get(self.pos) {
+            Some(0xff) => {
+                self.pos += 1;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err("unexpected end of input".to_string()),
+        }
+    }
+
+    /// Whether a container with `remaining` items left (None if indefinite) has another.
+    fn more(&mut self, remaining: Option<u64>) -> Result<bool, String> {
+        match remaining {
+            Some(n) => Ok(n > 0),
+            None => Ok(!self.at_break()?),
+        }
+    }
+
+    fn length(&mut self, arg: u64) -> Result<usize, String> {
+        usize::try_from(arg)
+            .ok()
+            .filter(|len| *len <= self.bytes.len() - self.pos)
+            .ok_or_else(|| "length runs past the end of input".to_string())
+    }
+
+    /// A byte or text string's contents; `major` says which, and chunks must match it.
+    fn string(&mut self, major: u8, arg: Option<u64>) -> Result<Vec<u8>, String> {
+        match arg {
+            Some(len) => {
+                let len = self.length(len)?;
+                Ok(self.take(len)?.to_vec())
+            }
+            None => {
+                let mut contents = Vec::new();
+                while !self.at_break()? {
+                    let initial = self.byte()?;
+                    match self.argument(initial & 0x1f)? {
+                        Some(len) if initial & 0xe0 == major => {
+                            let len = self.length(len)?;
+                            contents.extend_from_slice(self.take(len)?);
+                        }
+                        _ => return Err("malformed indefinite-length string".to_string()),
+                    }
+                }
+                Ok(contents)
+            }
+        }
+    }
+
+    fn text(&mut self, arg: Option<u64>) -> Result<String, String> {
+        String::from_utf8(self.string(TEXT, arg)?).map_err(|_| "text is not UTF-8".to_string())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("nested too deeply".to_string());
+        }
+        let initial = self.byte()?;
+        let (major, info) = (initial & 0xe0, initial & 0x1f);
+        if major == SIMPLE {
+            return self.simple(info);
+        }
+        let arg = self.argument(info)?;
+        match (major, arg) {
+            (UNSIGNED | NEGATIVE, None) => Err("integers have no indefinite form".to_string()),
+            (UNSIGNED, Some(n)) => Ok(Value::from(n)),
+            (NEGATIVE, Some(n)) => Ok(negative(n)),
+            (BYTES, _) => Err("byte strings have no JSON form".to_string()),
+            (TEXT, arg) => Ok(Value::String(self.text(arg)?)),
+            (ARRAY, arg) => {
+                let mut items = Vec::new();
+                let mut remaining = arg;
+                while self.more(remaining)? {
+                    items.push(self.value(depth + 1)?);
+                    remaining = remaining.map(|n| n - 1);
+                }
+                Ok(Value::Array(items))
+            }
+            (MAP, arg) => {
+                let mut map = Map::new();
+                let mut remaining = arg;
+                while self.more(remaining)? {
+                    let initial = self.byte()?;
+                    if initial & 0xe0 != TEXT {
+                        return Err("map keys must be text".to_string());
+                    }
+                    let len = self.argument(initial & 0x1f)?;
+                    let key = self.text(len)?;
+                    map.insert(key, self.value(depth + 1)?);
+                    remaining = remaining.map(|n| n - 1);
+                }
+                Ok(Value::Object(map))
+            }
+            (TAG, Some(POSITIVE_BIGNUM | NEGATIVE_BIGNUM)) => {
+                let integer = self.bignum(arg == Some(NEGATIVE_BIGNUM))?;
+                parse_number(&integer)
+            }
+            (TAG, Some(DECIMAL_FRACTION)) => self.decimal_fraction(),
+            (TAG, Some(_)) => self.value(depth + 1),
+            _ => Err(format!("malformed initial byte {:#04x}", initial)),
+        }
+    }
+
+    /// Content of a bignum tag: a byte string holding the magnitude (minus one if negative).
+    fn bignum(&mut self, negative: bool) -> Result<String, String> {
+        let initial = self.byte()?;
+        if initial & 0xe0 != BYTES {
+            return Err("bignums must hold a byte string".to_string());
+        }
+        let len = self.argument(initial & 0x1f)?;
+        let mut magnitude = self.string(BYTES, len)?;
+        if negative {
+            increment(&mut magnitude);
+            return Ok(format!("-{}", bytes_to_decimal(&magnitude)));
+        }
+        Ok(bytes_to_decimal(&magnitude))
+    }
+
+    /// An integer of any size, as a decimal fraction's exponent or mantissa holds it.
+    fn integer(&mut self) -> Result<Integer, String> {
+        let initial = self.byte()?;
+        let arg = self.argument(initial & 0x1f)?;
+        match (initial & 0xe0, arg) {
+            (UNSIGNED, Some(n)) => Ok(Integer::Native(Value::from(n))),
+            (NEGATIVE, Some(n)) => Ok(Integer::Native(negative(n))),
+            (TAG, Some(tag @ (POSITIVE_BIGNUM | NEGATIVE_BIGNUM))) => {
+                Ok(Integer::Big(self.bignum(tag == NEGATIVE_BIGNUM)?))
+            }
+            _ => Err("decimal fractions must hold integers".to_string()),
+        }
+    }
+
+    /// Content of a decimal fraction tag: `[exponent, mantissa]`, meaning mantissa × 10^exponent.
+    fn decimal_fraction(&mut self) -> Result<Value, String> {
+        if self.byte()? != ARRAY | 2 {
+            return Err("decimal fractions must be a two-item array".to_string());
+        }
+        let exponent = match self.integer()? {
+            Integer::Native(n) => n.as_i64().ok_or("decimal fraction exponent out of range")?,
+            Integer::Big(_) => return Err("decimal fraction exponent out of range".to_string()),
+        };
+        let mantissa = match self.integer()? {
+            Integer::Native(n) => n.to_string(),
+            Integer::Big(digits) => digits,
+        };
+        let (sign, digits) = match mantissa.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", mantissa.as_str()),
+        };
+        // place the point where the digits allow, as the number was most likely written
+        let places = exponent
+            .checked_neg()
+            .and_then(|places| usize::try_from(places).ok());
+        let text = match places {
+            Some(places) if places > 0 && places < digits.len() => {
+                let (whole, fraction) = digits.split_at(digits.len() - places);
+                format!("{}{}.{}", sign, whole, fraction)
+            }
+            Some(places) if places > 0 && places <= digits.len() + 20 => {
+                let zeros = "0".repeat(places - digits.len());
+                format!("{}0.{}{}", sign, zeros, digits)
+            }
+            _ => format!("{}{}e{}", sign, digits, exponent),
+        };
+        parse_number(&text)
+    }
+
+    fn simple(&mut self, info: u8) -> Result<Value, String> {
+        let float = match info {
+            20 => return Ok(Value::Bool(false)),
+            21 => return Ok(Value::Bool(true)),
+            22 | 23 => return Ok(Value::Null),
+            25 => {
+                let half = self.take(2)?;
+                half_to_f64(u16::from_be_bytes([half[0], half[1]]))
+            }
+            26 => {
+                let single = self.take(4)?;
+                f64::from(f32::from_be_bytes(single.try_into().unwrap()))
+            }
+            27 => f64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            _ => return Err(format!("unsupported simple value {}", info)),
+        };
+        Number::from_f64(float)
+            .map(Value::Number)
+            .ok_or_else(|| "NaN and infinities have no JSON form".to_string())
+    }
+}
+
+/// The CBOR negative integer with argument `n`, i.e. -1 - n.
+fn negative(n: u64) -> Value {
+    match i64::try_from(n) {
+        Ok(n) => Value::from(-1 - n),
+        Err(_) => parse_number(&format!("-{}", u128::from(n) + 1)).unwrap(),
+    }
+}
+
+fn parse_number(text: &str) -> Result<Value, String> {
+    serde_json::from_str::<Number>(text)
+        .map(Value::Number)
+        .map_err(|_| format!("{} is not a JSON number", text))
+}
+
+/// An IEEE 754 half-precision float as a double.
+fn half_to_f64(half: u16) -> f64 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from((half >> 10) & 0x1f);
+    let fraction = f64::from(half & 0x3ff);
+    sign * match exponent {
+        0 => fraction * 2f64.powi(-24),
+        31 if fraction == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + fraction / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn number(text: &str) -> Value {
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn encodes_as_rfc_8949_does() {
+        for (value, expected) in [
+            (json!(0), "00"),
+            (json!(23), "17"),
+            (json!(24), "1818"),
+            (json!(1000), "1903e8"),
+            (json!(-1), "20"),
+            (json!(-1000), "3903e7"),
+            (number("18446744073709551616"), "c249010000000000000000"),
+            (number("-18446744073709551617"), "c349010000000000000000"),
+            (
+                number("0.1000000000000000000001"),
+                "c48235c2493635c9adc5dea00001",
+            ),
+            (json!(1.5), "fb3ff8000000000000"),
+            (json!(null), "f6"),
+            (json!(true), "f5"),
+            (json!("a"), "6161"),
+            (json!([1, [2, 3]]), "8201820203"),
+            (json!({"a": 1}), "a1616101"),
+        ] {
+            assert_eq!(encode(&value), hex(expected), "{}", value);
+        }
+    }
+
+    #[test]
+    fn values_round_trip_with_their_exact_digits() {
+        let text = r#"{"big":[123456789012345678901234567890,-98765432109876543210],"fine":0.1000000000000000000001,"list":[1,-2,2.5,"three",null,false,{}]}"#;
+        let value = number(text);
+        let decoded = decode(&encode(&value)).unwrap();
+        assert_eq!(decoded.to_string(), text);
+    }
+
+    #[test]
+    fn decodes_forms_clients_may_send_that_encode_never_writes() {
+        // half and single precision floats, indefinite-length text and arrays
+        assert_eq!(decode(&hex("f93e00")).unwrap(), json!(1.5));
+        assert_eq!(decode(&hex("fa47c35000")).unwrap(), json!(100000.0));
+        assert_eq!(
+            decode(&hex("7f657374726561646d696e67ff")).unwrap(),
+            json!("streaming")
+        );
+        assert_eq!(
+            decode(&hex("9f018202039f0405ffff")).unwrap(),
+            json!([1, [2, 3], [4, 5]])
+        );
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        for bad in ["", "18", "62ff", "a1", "0000", "f97e00", "a10101"] {
+            assert!(decode(&hex(bad)).is_err(), "{}", bad);
+        }
+        let deep = vec![ARRAY | 1; MAX_DEPTH + 1];
+        assert!(decode(&deep).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// How often injected failures happen. Rates are probabilities from 0.0 to 1.0.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosSettings {
+    /// Answer a request with 500 instead of handling it.
+    pub error_rate: f64,
+    /// Sleep `delay_ms` before handling a request.
+    pub delay_rate: f64,
+    pub delay_ms: u64,
+    /// Fail an outgoing forward as if the owner were unreachable.
+    pub drop_forward_rate: f64,
+}
+
+/// Failure injection for exercising clients against a flaky node.
+/// Only a node started with `CHAOS=true` injects anything; all rates start at zero.
+pub struct Chaos {
+    enabled: bool,
+    settings: RwLock<ChaosSettings>,
+    // state for `random_unit`
+    rng: AtomicU64,
+}
+
+impl Chaos {
+    /// Chaos that injects only if `enabled`.
+    pub fn new(enabled: bool) -> Self {
+        Chaos {
+            enabled,
+            settings: RwLock::new(ChaosSettings::default()),
+            rng: AtomicU64::new(clock_seed()),
+        }
+    }
+
+    /// Whether this node allows failure injection at all.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Current injection rates.
+    pub fn settings(&self) -> ChaosSettings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Replace the injection rates.
+    pub fn set(&self, settings: ChaosSettings) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    /// Apply injected delay to an incoming request, then return the status to fail it with, if any.
+    pub fn before_request(&self) -> Option<u16> {
+        if !self.enabled {
+            return None;
+        }
+        let settings = self.settings();
+        if self.roll(settings.delay_rate) {
+            std::thread::sleep(Duration::from_millis(settings.delay_ms));
+        }
+        self.roll(settings.error_rate).then_some(500)
+    }
+
+    /// Whether to fail this outgoing forward.
+    pub fn drop_forward(&self) -> bool {
+        self.enabled && self.roll(self.settings().drop_forward_rate)
+    }
+
+    /// True with probability `rate`.
+    fn roll(&self, rate: f64) -> bool {
+        rate > 0.0 && random_unit(&self.rng) < rate
+    }
+}
+
+/// Seed for `random_unit`, from the clock.
+pub(crate) fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Next uniform float in [0, 1) from the splitmix64 generator `state`; good enough to spread
+/// failures or expiries, not for anything secret.
+pub(crate) fn random_unit(state: &AtomicU64) -> f64 {
+    let mut z = state
+        .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+        .wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    // top 53 bits as a uniform float in [0, 1)
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// RPC middleware that fails outgoing forwards at `drop_forward_rate`.
+pub struct ChaosMiddleware(pub Arc<Chaos>);
+
+impl ureq::Middleware for ChaosMiddleware {
+    fn handle(
+        &self,
+        request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        if self.0.drop_forward() {
+            // a 503 from "the owner" goes down the same path as a real outage
+            return ureq::Response::new(503, "Service Unavailable", "chaos: forward dropped");
+        }
+        next.handle(request)
+    }
+}
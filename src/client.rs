@@ -0,0 +1,229 @@
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::router::owner_for_key;
+use crate::rpc::encode_key;
+
+/// Why a client call didn't produce a result.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The node couldn't be reached or the connection failed mid-request.
+    Transport(String),
+    /// The node answered with an unexpected status code.
+    Status(u16),
+    /// The node's reply wasn't the JSON this client expected.
+    InvalidResponse(String),
+    /// The node refused the call with this HTTP status, for the reason given (e.g. "read-only").
+    Refused(u16, String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Transport(e) => write!(f, "request failed: {}", e),
+            ClientError::Status(code) => write!(f, "node answered {}", code),
+            ClientError::InvalidResponse(e) => write!(f, "unexpected response: {}", e),
+            ClientError::Refused(_, reason) => write!(f, "refused: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Blocking client for one node of a cluster. Any node accepts any key and forwards it to its owner.
+pub struct Client {
+    base: String,
+    agent: ureq::Agent,
+    // with `Client::routing`, the peer list from the seed node's /ring once fetched
+    ring: Option<RwLock<Option<Vec<String>>>>,
+}
+
+impl Client {
+    /// Client for the node at `addr`, given as `host:port` or `http://host:port`.
+    pub fn new(addr: &str) -> Self {
+        let addr = addr.trim_end_matches('/');
+        let base = if addr.starts_with("http://") || addr.starts_with("https://") {
+            addr.to_string()
+        } else {
+            format!("http://{}", addr)
+        };
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(5))
+            .build();
+        Client {
+            base,
+            agent,
+            ring: None,
+        }
+    }
+
+    /// Client that sends each key straight to its owner, skipping the forward hop. The node at
+    /// `addr` only seeds it: its `/ring` is fetched on first use and cached. If an owner can't
+    /// be reached the cached ring is dropped (and fetched again next call) and that call goes
+    /// through the seed node instead, as it would with `Client::new`.
+    pub fn routing(addr: &str) -> Self {
+        Client {
+            ring: Some(RwLock::new(None)),
+            ..Client::new(addr)
+        }
+    }
+
+    /// Read `key`. Returns None if the cluster doesn't hold it.
+    pub fn get(&self, key: &str) -> Result<Option<Value>, ClientError> {
+        self.routed(key, |node| self.get_from(node, key))
+    }
+
+    fn get_from(&self, node: &str, key: &str) -> Result<Option<Value>, ClientError> {
+        match self.agent.get(&url(node, &encode_key(key))).call() {
+            Ok(resp) => {
+                let text = resp
+                    .into_string()
+                    .map_err(|e| ClientError::InvalidResponse(e.to_string()))?;
+                let body: Value = serde_json::from_str(&text)
+                    .map_err(|e| ClientError::InvalidResponse(e.to_string()))?;
+                match body {
+                    Value::Object(mut map) => map.remove(key).map(Some).ok_or_else(|| {
+                        ClientError::InvalidResponse(format!("no {:?} in reply", key))
+                    }),
+                    other => Err(ClientError::InvalidResponse(other.to_string())),
+                }
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(error(e)),
+        }
+    }
+
+    /// Write `key`. Returns the entry's new version, if the node reported one.
+    pub fn set(&self, key: &str, value: Value) -> Result<Option<u64>, ClientError> {
+        self.set_with_ttl(key, value, None)
+    }
+
+    /// `set`, expiring the value after `ttl`; None leaves it to the node's `DEFAULT_TTL_MS`.
+    pub fn set_with_ttl(
+        &self,
+        key: &str,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<u64>, ClientError> {
+        self.routed(key, |node| self.set_on(node, key, &value, ttl))
+    }
+
+    fn set_on(
+        &self,
+        node: &str,
+        key: &str,
+        value: &Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<u64>, ClientError> {
+        let mut request = self
+            .agent
+            .post(&url(node, ""))
+            .set("Content-Type", "application/json");
+        if let Some(ttl) = ttl {
+            // at least 1ms, since 0 would mean never expire
+            let ms = ttl.as_millis().max(1);
+            request = request.set("X-TTL", &ms.to_string());
+        }
+        let resp = request
+            .send_string(&serde_json::json!({ key: value }).to_string())
+            .map_err(error)?;
+        Ok(resp
+            .header("ETag")
+            .and_then(|tag| tag.trim_matches('"').parse().ok()))
+    }
+
+    /// Delete `key`. Returns whether it was present.
+    pub fn delete(&self, key: &str) -> Result<bool, ClientError> {
+        self.routed(key, |node| self.delete_on(node, key))
+    }
+
+    fn delete_on(&self, node: &str, key: &str) -> Result<bool, ClientError> {
+        let resp = self
+            .agent
+            .delete(&url(node, &encode_key(key)))
+            .call()
+            .map_err(error)?;
+        let text = resp
+            .into_string()
+            .map_err(|e| ClientError::InvalidResponse(e.to_string()))?;
+        match text.trim() {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            other => Err(ClientError::InvalidResponse(other.to_string())),
+        }
+    }
+
+    /// Run `call` against the node `key` should go to, falling back to the seed node when a
+    /// routing client's owner is unreachable.
+    fn routed<T>(
+        &self,
+        key: &str,
+        call: impl Fn(&str) -> Result<T, ClientError>,
+    ) -> Result<T, ClientError> {
+        let node = self.node_for(key);
+        match call(&node) {
+            Err(ClientError::Transport(_)) if node != self.base => {
+                // the ring may be stale; refetch it next time and let the seed node forward
+                if let Some(ring) = &self.ring {
+                    *ring.write().unwrap() = None;
+                }
+                call(&self.base)
+            }
+            result => result,
+        }
+    }
+
+    /// Base URL of the owner of `key` if this client routes and knows the ring, else of the
+    /// seed node.
+    fn node_for(&self, key: &str) -> String {
+        let Some(ring) = &self.ring else {
+            return self.base.clone();
+        };
+        if ring.read().unwrap().is_none() {
+            // an unreachable seed or unknown scheme leaves the ring unset, so calls go via the seed
+            *ring.write().unwrap() = self.fetch_ring().ok();
+        }
+        match ring.read().unwrap().as_deref() {
+            Some(peers) if !peers.is_empty() => {
+                format!("http://{}", peers[owner_for_key(key, peers)])
+            }
+            _ => self.base.clone(),
+        }
+    }
+
+    /// The seed node's peer list, if it places keys the way this client computes owners.
+    fn fetch_ring(&self) -> Result<Vec<String>, ClientError> {
+        let resp = self
+            .agent
+            .get(&url(&self.base, "ring"))
+            .call()
+            .map_err(error)?;
+        let text = resp
+            .into_string()
+            .map_err(|e| ClientError::InvalidResponse(e.to_string()))?;
+        let ring: Value =
+            serde_json::from_str(&text).map_err(|e| ClientError::InvalidResponse(e.to_string()))?;
+        if ring["hash"] != "seahash" || ring["placement"] != "modulo" {
+            return Err(ClientError::InvalidResponse(format!(
+                "unknown ring {}",
+                ring
+            )));
+        }
+        serde_json::from_value(ring["peers"].clone())
+            .map_err(|e| ClientError::InvalidResponse(e.to_string()))
+    }
+}
+
+fn url(node: &str, path: &str) -> String {
+    format!("{}/{}", node, path)
+}
+
+fn error(e: ureq::Error) -> ClientError {
+    match e {
+        ureq::Error::Status(code, _) => ClientError::Status(code),
+        ureq::Error::Transport(t) => ClientError::Transport(t.to_string()),
+    }
+}
@@ -0,0 +1,197 @@
+use std::env;
+use std::str::FromStr;
+
+/// What a write of a JSON `null` value does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NullValues {
+    /// Store the null like any other value.
+    Store,
+    /// Refuse the write with 400.
+    Reject,
+    /// Delete the key instead.
+    Delete,
+}
+
+impl FromStr for NullValues {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "store" => Ok(NullValues::Store),
+            "reject" => Ok(NullValues::Reject),
+            "delete" => Ok(NullValues::Delete),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How a node answers a single-key request for a key another node owns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoutingMode {
+    /// Proxy the request to the owner and relay its answer.
+    Forward,
+    /// Answer 307 with the owner's URL in `Location`, for clients that follow redirects.
+    Redirect,
+}
+
+impl FromStr for RoutingMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "forward" => Ok(RoutingMode::Forward),
+            "redirect" => Ok(RoutingMode::Redirect),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Runtime settings for a node, read from environment variables.
+#[derive(Clone)]
+pub struct Config {
+    /// Largest serialized value a write may store (`MAX_VALUE_BYTES`, default 1 MiB).
+    pub max_value_bytes: usize,
+    /// Longest key (before any namespace prefix) a request may name (`MAX_KEY_BYTES`, default 1 KiB).
+    pub max_key_bytes: usize,
+    /// Most headers a request may carry before it gets 431 (`MAX_HEADERS`, default 100, 0 = no cap).
+    pub max_headers: usize,
+    /// Largest total size of a request's header lines before it gets 431
+    /// (`MAX_HEADER_BYTES`, default 16 KiB, 0 = no cap). tiny_http reads every header before
+    /// handing the request over, so this refuses oversized requests rather than preventing
+    /// the read; `MAX_CONNECTIONS` still bounds how many such reads run at once.
+    pub max_header_bytes: usize,
+    /// How many `Idempotency-Key` results a node remembers (`IDEMPOTENCY_CAPACITY`, default 10000).
+    pub idempotency_capacity: usize,
+    /// How long an `Idempotency-Key` result is replayed (`IDEMPOTENCY_TTL_MS`, default 10 minutes).
+    pub idempotency_ttl_ms: u64,
+    /// Requests handled at once before new ones get 503 (`MAX_CONNECTIONS`, default 1024, 0 = no cap).
+    /// Each in-flight request holds one worker thread and one client connection.
+    pub max_connections: usize,
+    /// Send forwarded writes and request forwarded reads as MessagePack (`RPC_MSGPACK`, default false).
+    /// Any node can decode msgpack; client-facing responses stay JSON either way.
+    pub rpc_msgpack: bool,
+    /// Longest a `GET /{key}?wait=MS` long poll may block (`MAX_WAIT_MS`, default 30 seconds).
+    pub max_wait_ms: u64,
+    /// Key requests slower than this are logged with their owner (`SLOW_REQUEST_MS`, default 0 = off).
+    pub slow_request_ms: u64,
+    /// Explain 400s in the response body, e.g. where a JSON body stopped parsing
+    /// (`VERBOSE_ERRORS`, default false).
+    pub verbose_errors: bool,
+    /// `max-age` sent on GETs of values written with `X-Immutable: true`
+    /// (`IMMUTABLE_MAX_AGE_S`, default one year).
+    pub immutable_max_age_s: u64,
+    /// Most RPCs this node keeps open to any single peer (`PEER_MAX_IN_FLIGHT`, default 64, 0 = no cap).
+    /// Forwarded long polls hold their slot while they wait.
+    pub peer_max_in_flight: usize,
+    /// Idle connections kept open to each peer for reuse by forwarded RPCs (`RPC_POOL_SIZE`, default 16).
+    /// ureq's own default is one, which makes concurrent forwards to one owner reconnect every time;
+    /// raise it toward `PEER_MAX_IN_FLIGHT` if owners see many short-lived connections.
+    pub rpc_pool_size: usize,
+    /// Start with writes refused, e.g. for maintenance (`READ_ONLY`, default false).
+    /// `POST /admin/readonly` switches it at runtime.
+    pub read_only: bool,
+    /// Allow failure injection through `/admin/chaos`, for testing clients (`CHAOS`, default false).
+    /// Never enable this in production.
+    pub chaos: bool,
+    /// Keep large values lz4-compressed in memory, trading CPU for RAM (`COMPRESS_VALUES`, default false).
+    pub compress_values: bool,
+    /// Values smaller than this many serialized bytes are stored uncompressed even with
+    /// `COMPRESS_VALUES` on (`COMPRESS_MIN_BYTES`, default 1 KiB).
+    pub compress_min_bytes: usize,
+    /// File every applied write and delete is appended to, one JSON line each
+    /// (`AUDIT_LOG`, default empty = no audit log).
+    pub audit_log: String,
+    /// Size at which the audit log is rotated to `{AUDIT_LOG}.1` (`AUDIT_LOG_MAX_BYTES`, default 64 MiB).
+    pub audit_log_max_bytes: u64,
+    /// What writing a `null` value does: `store` it, `reject` it with 400, or `delete` the key
+    /// (`NULL_VALUES`, default store).
+    pub null_values: NullValues,
+    /// Attempts a forwarded request makes when the owner is unreachable or answers 5xx
+    /// (`RPC_ATTEMPTS`, default 1 = no retries; 0 is taken as 1). POSTs that aren't safe to
+    /// repeat, like appends, only retry when the client sent an `Idempotency-Key`.
+    pub rpc_attempts: usize,
+    /// TTL of writes that don't send an `X-TTL` header (`DEFAULT_TTL_MS`, default 0 = never expire).
+    pub default_ttl_ms: u64,
+    /// Spread each write's TTL randomly by up to this percentage either way, so keys loaded
+    /// together with one TTL don't all expire at once (`TTL_JITTER_PCT`, default 0 = exact TTLs,
+    /// at most 100). Applied by the owner, to `X-TTL`, `X-Expire-At` and `DEFAULT_TTL_MS` alike.
+    pub ttl_jitter_pct: u64,
+    /// Counters in a Bloom filter over this node's keys, letting reads of keys it doesn't hold
+    /// skip the store's locks (`KEY_FILTER_SLOTS`, default 0 = no filter). Size it around ten per
+    /// expected key; reads of keys other nodes own are still forwarded either way.
+    pub key_filter_slots: usize,
+    /// `forward` requests for keys other nodes own, or `redirect` the client to the owner
+    /// (`ROUTING_MODE`, default forward). Redirects name the owner by its `PEERS` address, so
+    /// clients must be able to reach it. Batches, scans and `/txn` are forwarded either way.
+    pub routing_mode: RoutingMode,
+    /// Log each request's and response's body to stderr, for debugging clients
+    /// (`LOG_BODIES`, default false). Bodies may hold sensitive values; leave it off in production.
+    pub log_bodies: bool,
+    /// Longest prefix of a body `LOG_BODIES` logs (`LOG_BODY_MAX_BYTES`, default 1 KiB).
+    pub log_body_max_bytes: usize,
+    /// After starting, answer everything but `/health` with 503 until enough peers answer a
+    /// health probe, so a cold cluster doesn't fail forwards to nodes still starting
+    /// (`WAIT_FOR_PEERS`, default false).
+    pub wait_for_peers: bool,
+    /// Other peers that must answer before a `WAIT_FOR_PEERS` node serves
+    /// (`PEER_WAIT_QUORUM`, default 0 = all of them).
+    pub peer_wait_quorum: usize,
+    /// Longest a `WAIT_FOR_PEERS` node waits before serving anyway
+    /// (`PEER_WAIT_TIMEOUT_MS`, default 30 seconds).
+    pub peer_wait_timeout_ms: u64,
+    /// When this node drops an expired key it owns, tell every peer to drop its copy too, so a
+    /// stray copy stops being served within a sweep (`EXPIRY_BROADCAST`, default false).
+    /// Costs one RPC per peer for each sweep that found expiries.
+    pub expiry_broadcast: bool,
+}
+
+impl Config {
+    /// Build the config from the environment, falling back to defaults for unset or invalid values.
+    pub fn from_env() -> Self {
+        Config {
+            max_value_bytes: env_or("MAX_VALUE_BYTES", 1024 * 1024),
+            max_key_bytes: env_or("MAX_KEY_BYTES", 1024),
+            max_headers: env_or("MAX_HEADERS", 100),
+            max_header_bytes: env_or("MAX_HEADER_BYTES", 16 * 1024),
+            idempotency_capacity: env_or("IDEMPOTENCY_CAPACITY", 10_000),
+            idempotency_ttl_ms: env_or("IDEMPOTENCY_TTL_MS", 10 * 60 * 1000),
+            max_connections: env_or("MAX_CONNECTIONS", 1024),
+            rpc_msgpack: env_or("RPC_MSGPACK", false),
+            max_wait_ms: env_or("MAX_WAIT_MS", 30_000),
+            slow_request_ms: env_or("SLOW_REQUEST_MS", 0),
+            verbose_errors: env_or("VERBOSE_ERRORS", false),
+            immutable_max_age_s: env_or("IMMUTABLE_MAX_AGE_S", 365 * 24 * 60 * 60),
+            peer_max_in_flight: env_or("PEER_MAX_IN_FLIGHT", 64),
+            rpc_pool_size: env_or("RPC_POOL_SIZE", 16),
+            read_only: env_or("READ_ONLY", false),
+            chaos: env_or("CHAOS", false),
+            compress_values: env_or("COMPRESS_VALUES", false),
+            compress_min_bytes: env_or("COMPRESS_MIN_BYTES", 1024),
+            audit_log: env_or("AUDIT_LOG", String::new()),
+            audit_log_max_bytes: env_or("AUDIT_LOG_MAX_BYTES", 64 * 1024 * 1024),
+            null_values: env_or("NULL_VALUES", NullValues::Store),
+            rpc_attempts: env_or("RPC_ATTEMPTS", 1usize).max(1),
+            default_ttl_ms: env_or("DEFAULT_TTL_MS", 0),
+            ttl_jitter_pct: env_or("TTL_JITTER_PCT", 0u64).min(100),
+            key_filter_slots: env_or("KEY_FILTER_SLOTS", 0),
+            routing_mode: env_or("ROUTING_MODE", RoutingMode::Forward),
+            log_bodies: env_or("LOG_BODIES", false),
+            log_body_max_bytes: env_or("LOG_BODY_MAX_BYTES", 1024),
+            wait_for_peers: env_or("WAIT_FOR_PEERS", false),
+            peer_wait_quorum: env_or("PEER_WAIT_QUORUM", 0),
+            peer_wait_timeout_ms: env_or("PEER_WAIT_TIMEOUT_MS", 30_000),
+            expiry_broadcast: env_or("EXPIRY_BROADCAST", false),
+        }
+    }
+}
+
+/// Parse env var `name`, returning `default` when it is unset or doesn't parse.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(v) => v.trim().parse().unwrap_or_else(|_| {
+            eprintln!("ignoring invalid {}={:?}", name, v);
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,123 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use serde_json::Value;
+use tonic::{Request, Response, Status};
+
+use crate::client::ClientError;
+use crate::node::Node;
+
+/// Types and client generated from `proto/sdcs.proto`.
+pub mod proto {
+    tonic::include_proto!("sdcs.v1");
+}
+
+use proto::cache_server::{Cache, CacheServer};
+use proto::{DeleteReply, DeleteRequest, GetReply, GetRequest, SetReply, SetRequest};
+
+/// The `sdcs.v1.Cache` service, answering through a `Node` so keys are served by their owner
+/// just as over HTTP. Values travel as JSON text, since the store holds arbitrary JSON.
+struct Service {
+    node: Arc<Node>,
+}
+
+#[tonic::async_trait]
+impl Cache for Service {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetReply>, Status> {
+        let key = checked_key(request.into_inner().key)?;
+        let reply = match self.call(move |node| node.get(&key)).await? {
+            Some(value) => GetReply {
+                found: true,
+                value_json: value.to_string(),
+            },
+            None => GetReply {
+                found: false,
+                value_json: String::new(),
+            },
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetReply>, Status> {
+        let request = request.into_inner();
+        let key = checked_key(request.key)?;
+        let value: Value = serde_json::from_str(&request.value_json)
+            .map_err(|e| Status::invalid_argument(format!("value_json: {}", e)))?;
+        let version = self.call(move |node| node.set(&key, value)).await?;
+        Ok(Response::new(SetReply { version }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteReply>, Status> {
+        let key = checked_key(request.into_inner().key)?;
+        let deleted = self.call(move |node| node.delete(&key)).await?;
+        Ok(Response::new(DeleteReply { deleted }))
+    }
+}
+
+impl Service {
+    /// Run a node call on a blocking thread; forwarding to an owner blocks on HTTP.
+    async fn call<T: Send + 'static>(
+        &self,
+        call: impl FnOnce(&Node) -> Result<T, ClientError> + Send + 'static,
+    ) -> Result<T, Status> {
+        let node = Arc::clone(&self.node);
+        tokio::task::spawn_blocking(move || call(&node))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(status)
+    }
+}
+
+/// Refuse an empty key up front; the node applies the rest of the HTTP limits (`MAX_KEY_BYTES`,
+/// `MAX_VALUE_BYTES`) and its write checks, and `status` maps what it refuses.
+// Status is large, but it's what every handler returns anyway
+#[allow(clippy::result_large_err)]
+fn checked_key(key: String) -> Result<String, Status> {
+    if key.is_empty() {
+        return Err(Status::invalid_argument("key must not be empty"));
+    }
+    Ok(key)
+}
+
+/// The gRPC status for a call the node refused or an owner couldn't answer, by the HTTP status
+/// it gave if any: read-only or overloaded nodes are unavailable, schema and version conflicts
+/// a failed precondition, and oversized values or full namespaces exhausted resources.
+fn status(e: ClientError) -> Status {
+    let message = e.to_string();
+    match e {
+        ClientError::Transport(_) => Status::unavailable(message),
+        // refused by this node or by the owner, the same status means the same thing
+        ClientError::Status(code) | ClientError::Refused(code, _) => match code {
+            400 | 414 => Status::invalid_argument(message),
+            409 | 412 | 422 => Status::failed_precondition(message),
+            413 | 507 => Status::resource_exhausted(message),
+            503 => Status::unavailable(message),
+            504 => Status::deadline_exceeded(message),
+            _ => Status::internal(message),
+        },
+        ClientError::InvalidResponse(_) => Status::internal(message),
+    }
+}
+
+/// Serve gRPC for `node` on `addr`, blocking the calling thread on a runtime of its own.
+/// Returns only if the listener fails.
+pub fn serve(addr: SocketAddr, node: Node) -> Result<(), String> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let service = CacheServer::new(Service {
+        node: Arc::new(node),
+    });
+    println!("gRPC listening on {}", addr);
+    runtime
+        .block_on(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(addr),
+        )
+        .map_err(|e| e.to_string())
+}
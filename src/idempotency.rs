@@ -0,0 +1,236 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hasher;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+struct Inner<T> {
+    // each outcome with the fingerprint of the request that produced it
+    results: HashMap<String, (Instant, u64, T)>,
+    // insertion order, oldest first, used to enforce the capacity bound
+    order: VecDeque<String>,
+    // keys whose write is being applied right now; repeats wait on the key's condvar
+    in_flight: HashMap<String, Arc<Condvar>>,
+}
+
+/// A repeated `Idempotency-Key` sent with a different request than the one it first came with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyReused;
+
+/// Bounded, TTL-limited record of write outcomes (of type `T`) keyed by `Idempotency-Key`.
+/// A repeated key within the TTL gets the recorded outcome instead of re-applying the write.
+pub struct IdempotencyCache<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+// manual impl: cloning shares the record and must not require `T: Clone`
+impl<T> Clone for IdempotencyCache<T> {
+    fn clone(&self) -> Self {
+        IdempotencyCache {
+            inner: self.inner.clone(),
+            capacity: self.capacity,
+            ttl: self.ttl,
+        }
+    }
+}
+
+/// Fingerprint of a request made of `parts`, e.g. its method, path and body, for `run` to tell
+/// a genuine repeat from a different request reusing the same key.
+pub fn fingerprint(parts: &[&[u8]]) -> u64 {
+    let mut hasher = seahash::SeaHasher::new();
+    for part in parts {
+        // length-prefixed, so ("ab", "c") and ("a", "bc") differ
+        hasher.write_usize(part.len());
+        hasher.write(part);
+    }
+    hasher.finish()
+}
+
+/// Marks a key in flight for as long as its `apply` runs, even if that panics.
+struct Flight<'a, T> {
+    inner: &'a Mutex<Inner<T>>,
+    key: &'a str,
+}
+
+impl<T> Drop for Flight<'_, T> {
+    fn drop(&mut self) {
+        let mut guard = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(done) = guard.in_flight.remove(self.key) {
+            done.notify_all();
+        }
+    }
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+    /// Create a cache holding at most `capacity` results, each for `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        IdempotencyCache {
+            inner: Arc::new(Mutex::new(Inner {
+                results: HashMap::new(),
+                order: VecDeque::new(),
+                in_flight: HashMap::new(),
+            })),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Return the recorded outcome for `key`, or run `apply` and record its outcome along with
+    /// `fingerprint`. A repeat of a key whose write is still being applied waits for it rather
+    /// than applying again; writes under other keys don't wait, since `apply` runs unlocked.
+    /// Returns `KeyReused` if `key`'s outcome was recorded for a different fingerprint.
+    pub fn run(
+        &self,
+        key: &str,
+        fingerprint: u64,
+        apply: impl FnOnce() -> T,
+    ) -> Result<T, KeyReused> {
+        let mut guard = self.inner.lock().unwrap();
+        loop {
+            self.expire(&mut guard);
+            if let Some((_, recorded, outcome)) = guard.results.get(key) {
+                return if *recorded == fingerprint {
+                    Ok(outcome.clone())
+                } else {
+                    Err(KeyReused)
+                };
+            }
+            match guard.in_flight.get(key) {
+                Some(done) => {
+                    let done = Arc::clone(done);
+                    guard = done.wait(guard).unwrap();
+                }
+                None => break,
+            }
+        }
+        guard
+            .in_flight
+            .insert(key.to_string(), Arc::new(Condvar::new()));
+        drop(guard);
+
+        let flight = Flight {
+            inner: &self.inner,
+            key,
+        };
+        let outcome = apply();
+        let mut guard = self.inner.lock().unwrap();
+        if self.capacity > 0 {
+            while guard.order.len() >= self.capacity {
+                let oldest = guard.order.pop_front().unwrap();
+                guard.results.remove(&oldest);
+            }
+            guard.order.push_back(key.to_string());
+            guard.results.insert(
+                key.to_string(),
+                (Instant::now(), fingerprint, outcome.clone()),
+            );
+        }
+        // the result is recorded before repeats wake, so they find it
+        drop(guard);
+        drop(flight);
+        Ok(outcome)
+    }
+
+    /// Drop expired results from the front; everything behind them is newer.
+    fn expire(&self, guard: &mut Inner<T>) {
+        let now = Instant::now();
+        while let Some(oldest) = guard.order.front() {
+            match guard.results.get(oldest) {
+                Some((at, _, _)) if now.duration_since(*at) < self.ttl => break,
+                _ => {
+                    let oldest = guard.order.pop_front().unwrap();
+                    guard.results.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn ok(body: &str) -> (u16, String) {
+        (200, body.to_string())
+    }
+
+    #[test]
+    fn repeats_replay_the_first_outcome() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        assert_eq!(cache.run("k", 1, || ok("first")), Ok(ok("first")));
+        assert_eq!(cache.run("k", 1, || ok("second")), Ok(ok("first")));
+        assert_eq!(cache.run("other", 1, || ok("second")), Ok(ok("second")));
+    }
+
+    #[test]
+    fn reuse_for_a_different_request_is_refused() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        cache.run("k", 1, || ok("first")).unwrap();
+        assert_eq!(cache.run("k", 2, || ok("second")), Err(KeyReused));
+    }
+
+    #[test]
+    fn results_expire_and_are_bounded() {
+        let cache = IdempotencyCache::new(10, Duration::from_millis(20));
+        cache.run("k", 1, || ok("first")).unwrap();
+        thread::sleep(Duration::from_millis(40));
+        // expired: a different request may now use the key
+        assert_eq!(cache.run("k", 2, || ok("second")), Ok(ok("second")));
+
+        let cache = IdempotencyCache::new(2, Duration::from_secs(60));
+        for key in ["a", "b", "c"] {
+            cache.run(key, 1, || ok(key)).unwrap();
+        }
+        // "a" was pushed out by "c"
+        assert_eq!(cache.run("a", 1, || ok("again")), Ok(ok("again")));
+        assert_eq!(cache.run("c", 1, || ok("again")), Ok(ok("c")));
+    }
+
+    #[test]
+    fn concurrent_repeats_apply_once() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        let applied = AtomicUsize::new(0);
+        let outcomes: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        cache.run("k", 1, || {
+                            applied.fetch_add(1, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(50));
+                            ok("done")
+                        })
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(applied.load(Ordering::SeqCst), 1);
+        assert!(outcomes.iter().all(|o| *o == Ok(ok("done"))));
+    }
+
+    #[test]
+    fn other_keys_do_not_wait_on_a_slow_write() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        thread::scope(|s| {
+            s.spawn(|| {
+                cache.run("slow", 1, || {
+                    thread::sleep(Duration::from_millis(500));
+                    ok("slow")
+                })
+            });
+            thread::sleep(Duration::from_millis(50));
+            let started = Instant::now();
+            cache.run("fast", 1, || ok("fast")).unwrap();
+            assert!(started.elapsed() < Duration::from_millis(250));
+        });
+    }
+
+    #[test]
+    fn fingerprint_separates_its_parts() {
+        assert_eq!(fingerprint(&[b"a", b"b"]), fingerprint(&[b"a", b"b"]));
+        assert_ne!(fingerprint(&[b"ab", b"c"]), fingerprint(&[b"a", b"bc"]));
+    }
+}
@@ -1,2 +1,18 @@
+pub mod audit;
+pub mod bloom;
+pub mod cache;
+mod cbor;
+pub mod chaos;
+pub mod client;
+pub mod config;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod idempotency;
+pub mod memcache;
+pub mod metrics;
+pub mod node;
+pub mod router;
+mod rpc;
+pub mod schema;
 pub mod server;
-pub mod cache;
\ No newline at end of file
+pub mod transport;
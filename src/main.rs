@@ -1,7 +1,104 @@
-use baby_sdcs::server;
+use baby_sdcs::client::Client;
+#[cfg(feature = "grpc")]
+use baby_sdcs::grpc;
+use baby_sdcs::memcache;
+use baby_sdcs::node::Node;
+use baby_sdcs::server::{self, ServerContext};
 use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Build the `host:port` listen address, checking that the host is an IP and the port a number.
+fn bind_address(host: &str, port: &str) -> Result<String, String> {
+    let ip: IpAddr = host
+        .parse()
+        .map_err(|_| format!("host {:?} is not an IP address", host))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| format!("port {:?} is not a valid port", port))?;
+    Ok(SocketAddr::new(ip, port).to_string())
+}
+
+/// With `port_var` set, serve another protocol on that port plus `offset`, on the HTTP server's
+/// interface and a thread of its own. `serve` gets the address and a `Node` writing through the
+/// HTTP server's `ctx`, so its writes get the same checks (audited as from `protocol`) and are
+/// forwarded to the same peers. `serve` returns only if its listener fails.
+fn spawn_listener<E: std::fmt::Display>(
+    port_var: &str,
+    protocol: &'static str,
+    http_addr: &str,
+    offset: u16,
+    ctx: &Arc<ServerContext>,
+    serve: impl FnOnce(SocketAddr, Node) -> Result<(), E> + Send + 'static,
+) {
+    let Ok(port) = env::var(port_var) else {
+        return;
+    };
+    let Ok(port) = port.parse::<u16>() else {
+        eprintln!("invalid {}: port {:?} is not a valid port", port_var, port);
+        std::process::exit(1);
+    };
+    let mut addr: SocketAddr = http_addr.parse().expect("bind_address builds socket addresses");
+    addr.set_port(port.saturating_add(offset));
+    let node = Node::with_context(ctx.clone(), protocol);
+    std::thread::spawn(move || {
+        if let Err(e) = serve(addr, node) {
+            eprintln!("{} listener on {} failed: {}", protocol, addr, e);
+        }
+    });
+}
+
+/// Start the optional listeners next to the HTTP server at `http_addr`: gRPC on `GRPC_PORT`
+/// (with the grpc feature) and the memcached text protocol on `MEMCACHE_PORT`, each plus `offset`.
+fn spawn_listeners(http_addr: &str, offset: u16, ctx: &Arc<ServerContext>) {
+    #[cfg(feature = "grpc")]
+    spawn_listener("GRPC_PORT", "grpc", http_addr, offset, ctx, grpc::serve);
+    spawn_listener("MEMCACHE_PORT", "memcache", http_addr, offset, ctx, memcache::serve);
+}
+
+const USAGE: &str = "usage: baby_sdcs [get <url> <key> | set <url> <key> <value> | delete <url> <key>]
+with no arguments, run the server";
+
+/// Run a client subcommand against the node at `url` and return the process exit code.
+fn run_cli(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["get", url, key] => Client::new(url).get(key).map(|value| match value {
+            Some(value) => println!("{}", value),
+            None => println!("not found"),
+        }),
+        ["set", url, key, value] => {
+            // values that aren't valid JSON are stored as strings
+            let value = serde_json::from_str(value).unwrap_or_else(|_| value.to_string().into());
+            Client::new(url).set(key, value).map(|version| match version {
+                Some(version) => println!("ok (version {})", version),
+                None => println!("ok"),
+            })
+        }
+        ["delete", url, key] => Client::new(url).delete(key).map(|deleted| {
+            println!("{}", if deleted { "deleted" } else { "not found" })
+        }),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
 
 fn main() {
+    // Any arguments select a client subcommand instead of running the server
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(run_cli(&args));
+    }
+
     // If PEERS env var is set, run in container/single-node mode (useful for docker-compose).
     // PEERS should be a comma-separated list of peer addresses (e.g. server1:8001,server2:8002,server3:8003)
     if let Ok(peers_env) = env::var("PEERS") {
@@ -9,31 +106,54 @@ fn main() {
         let port = env::var("PORT").unwrap_or_else(|_| {
             // fallback to last part of first peer
             peers
-                .get(0)
-                .and_then(|p| p.split(':').last())
+                .first()
+                .and_then(|p| p.split(':').next_back())
                 .unwrap_or("8001")
                 .to_string()
         });
-    let bind_addr = format!("0.0.0.0:{}", port);
+    // BIND_HOST restricts which interface we listen on (default: all interfaces)
+    let bind_host = env::var("BIND_HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
+    let bind_addr = match bind_address(&bind_host, &port) {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("invalid BIND_HOST or PORT: {}", e);
+            std::process::exit(1);
+        }
+    };
     let name = env::var("NAME").unwrap_or_else(|_| format!("server{}", port));
-    // self_addr should match the peer entries (e.g. server1:8001)
+    // self_addr should match (or resolve to the same address as) a peer entry, e.g. server1:8001
     let self_addr = format!("{}:{}", name, port);
     let (srv, store) = server::init_server(&name, &bind_addr);
-    server::run_server(srv, &name, self_addr, peers, store);
+    let ctx = server::server_context(&name, self_addr, peers, store);
+    spawn_listeners(&bind_addr, 0, &ctx);
+    server::run_server_with_context(srv, ctx);
         return;
     }
 
     // Default local dev: spawn three HTTP servers: server1..server3 on ports 8001..8003
-    let peers: Vec<String> = (1..=3).map(|i| format!("127.0.0.1:{}", 8000 + i)).collect();
+    // (and with GRPC_PORT, gRPC on it and the next two ports; likewise MEMCACHE_PORT)
+    // DEV_HOST is the address they listen on and reach each other at (default: 127.0.0.1)
+    let dev_host = env::var("DEV_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+    let peers: Vec<String> = match (8001..=8003)
+        .map(|port: u16| bind_address(&dev_host, &port.to_string()))
+        .collect()
+    {
+        Ok(peers) => peers,
+        Err(e) => {
+            eprintln!("invalid DEV_HOST: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    for i in 1..=3 {
-        let name = format!("server{}", i);
-        let port = 8000 + i;
-        let addr = format!("127.0.0.1:{}", port);
+    for (i, addr) in peers.iter().enumerate() {
+        let name = format!("server{}", i + 1);
+        let addr = addr.clone();
         let peers = peers.clone();
         std::thread::spawn(move || {
             let (srv, store) = server::init_server(&name, &addr);
-            server::run_server(srv, &name, addr.clone(), peers, store);
+            let ctx = server::server_context(&name, addr.clone(), peers, store);
+            spawn_listeners(&addr, i as u16, &ctx);
+            server::run_server_with_context(srv, ctx);
         });
     }
 
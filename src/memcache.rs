@@ -0,0 +1,329 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::client::ClientError;
+use crate::config::Config;
+use crate::node::Node;
+
+/// Longest key the memcached protocol allows.
+const MAX_KEY_BYTES: usize = 250;
+
+/// Longest command line read before the connection is treated as broken.
+const MAX_LINE_BYTES: usize = 8 * 1024;
+
+/// `exptime` values up to this many seconds are relative; larger ones are Unix times.
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+
+/// Object key marking a stored value as bytes that aren't a plain UTF-8 string.
+const ENVELOPE: &str = "$memcached";
+
+/// Counters `stats` reports, shared by every connection.
+#[derive(Default)]
+struct Counters {
+    connections: AtomicU64,
+    cmd_get: AtomicU64,
+    cmd_set: AtomicU64,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+    delete_hits: AtomicU64,
+    delete_misses: AtomicU64,
+}
+
+/// What every connection shares.
+struct Listener {
+    node: Node,
+    counters: Counters,
+    started: Instant,
+    max_value_bytes: usize,
+}
+
+/// Serve the memcached text protocol (`get`, `set`, `delete`, `stats`, `version`, `quit`) for
+/// `node` on `addr`, one thread per connection. Keys are served by their owner just as over
+/// HTTP, and writes get the same checks (read-only, schemas, default TTL, audit log). Values
+/// with flags 0 that are UTF-8 are stored as JSON strings, so HTTP clients read them as such;
+/// other values keep their flags and bytes under a `$memcached` object. Values written over
+/// HTTP read back as their JSON text, or as the bare text of a string.
+/// Returns only if the listener fails.
+pub fn serve(addr: SocketAddr, node: Node) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("memcached protocol listening on {}", addr);
+    let shared = Arc::new(Listener {
+        node,
+        counters: Counters::default(),
+        started: Instant::now(),
+        max_value_bytes: Config::from_env().max_value_bytes,
+    });
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("memcached accept on {} failed: {}", addr, e);
+                continue;
+            }
+        };
+        let shared = Arc::clone(&shared);
+        std::thread::spawn(move || {
+            shared.counters.connections.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = serve_connection(&shared, stream)
+                && e.kind() != io::ErrorKind::UnexpectedEof
+            {
+                eprintln!("memcached connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Answer commands on one connection until the client quits or hangs up.
+fn serve_connection(shared: &Listener, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE_BYTES as u64)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Ok(());
+        }
+        let Some(command) = line.strip_suffix(b"\n") else {
+            // no newline within the limit: nothing after it could be framed reliably
+            writer.write_all(b"CLIENT_ERROR line too long\r\n")?;
+            writer.flush()?;
+            return Ok(());
+        };
+        let command = String::from_utf8_lossy(command.strip_suffix(b"\r").unwrap_or(command));
+        let words: Vec<&str> = command.split_ascii_whitespace().collect();
+        match words.as_slice() {
+            ["get" | "gets", keys @ ..] if !keys.is_empty() => get(shared, &mut writer, keys)?,
+            ["set", args @ ..] => set(shared, &mut reader, &mut writer, args)?,
+            ["delete", key] => delete(shared, &mut writer, key, false)?,
+            ["delete", key, "noreply"] => delete(shared, &mut writer, key, true)?,
+            ["stats"] => stats(shared, &mut writer)?,
+            ["version"] => writeln_crlf(
+                &mut writer,
+                &format!("VERSION {}", env!("CARGO_PKG_VERSION")),
+            )?,
+            ["quit"] => return writer.flush(),
+            [] => {}
+            _ => writer.write_all(b"ERROR\r\n")?,
+        }
+        writer.flush()?;
+    }
+}
+
+fn writeln_crlf(writer: &mut impl Write, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\r\n")
+}
+
+/// Whether `key` is one memcached clients may send: short, with no spaces or control bytes.
+fn valid_key(key: &str) -> bool {
+    key.len() <= MAX_KEY_BYTES && key.bytes().all(|b| b > b' ' && b != 0x7f)
+}
+
+fn get(shared: &Listener, writer: &mut impl Write, keys: &[&str]) -> io::Result<()> {
+    if !keys.iter().all(|key| valid_key(key)) {
+        return writer.write_all(b"CLIENT_ERROR bad command line format\r\n");
+    }
+    for key in keys {
+        shared.counters.cmd_get.fetch_add(1, Ordering::Relaxed);
+        match shared.node.get(key) {
+            Ok(Some(value)) => {
+                shared.counters.get_hits.fetch_add(1, Ordering::Relaxed);
+                let (flags, data) = from_value(value);
+                writeln_crlf(writer, &format!("VALUE {} {} {}", key, flags, data.len()))?;
+                writer.write_all(&data)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Ok(None) => {
+                shared.counters.get_misses.fetch_add(1, Ordering::Relaxed);
+            }
+            // memcached has no per-key error inside a get; fail the whole command
+            Err(e) => return writeln_crlf(writer, &server_error(e)),
+        }
+    }
+    writer.write_all(b"END\r\n")
+}
+
+/// `set <key> <flags> <exptime> <bytes> [noreply]`, followed by the data block.
+fn set(
+    shared: &Listener,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    args: &[&str],
+) -> io::Result<()> {
+    let (key, flags, exptime, len, noreply) = match args {
+        [key, flags, exptime, len] => (key, flags, exptime, len, false),
+        [key, flags, exptime, len, "noreply"] => (key, flags, exptime, len, true),
+        _ => return writer.write_all(b"ERROR\r\n"),
+    };
+    let parsed = (
+        flags.parse::<u32>(),
+        exptime.parse::<i64>(),
+        len.parse::<usize>(),
+    );
+    let (Ok(flags), Ok(exptime), Ok(len)) = parsed else {
+        return writer.write_all(b"CLIENT_ERROR bad command line format\r\n");
+    };
+    if !valid_key(key) {
+        return writer.write_all(b"CLIENT_ERROR bad command line format\r\n");
+    }
+    if len > shared.max_value_bytes {
+        // the data still follows; skip it so the next command lines up
+        io::copy(&mut reader.take(len as u64 + 2), &mut io::sink())?;
+        return writer.write_all(b"SERVER_ERROR object too large for cache\r\n");
+    }
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        return writer.write_all(b"CLIENT_ERROR bad data chunk\r\n");
+    }
+    data.truncate(len);
+    shared.counters.cmd_set.fetch_add(1, Ordering::Relaxed);
+
+    let result = match ttl(exptime) {
+        // already expired: what memcached does is store it and never return it again
+        Some(Duration::ZERO) => shared.node.delete(key).map(|_| ()),
+        ttl => shared
+            .node
+            .set_with_ttl(key, to_value(flags, data), ttl)
+            .map(|_| ()),
+    };
+    let reply = match result {
+        Ok(()) => "STORED".to_string(),
+        Err(e) => server_error(e),
+    };
+    if noreply {
+        return Ok(());
+    }
+    writeln_crlf(writer, &reply)
+}
+
+fn delete(shared: &Listener, writer: &mut impl Write, key: &str, noreply: bool) -> io::Result<()> {
+    if !valid_key(key) {
+        return writer.write_all(b"CLIENT_ERROR bad command line format\r\n");
+    }
+    let reply = match shared.node.delete(key) {
+        Ok(true) => {
+            shared.counters.delete_hits.fetch_add(1, Ordering::Relaxed);
+            "DELETED".to_string()
+        }
+        Ok(false) => {
+            shared
+                .counters
+                .delete_misses
+                .fetch_add(1, Ordering::Relaxed);
+            "NOT_FOUND".to_string()
+        }
+        Err(e) => server_error(e),
+    };
+    if noreply {
+        return Ok(());
+    }
+    writeln_crlf(writer, &reply)
+}
+
+/// `SERVER_ERROR` line for a failed call: just the reason when the node refused it, e.g.
+/// `SERVER_ERROR read-only`.
+fn server_error(e: ClientError) -> String {
+    match e {
+        ClientError::Refused(_, reason) => format!("SERVER_ERROR {}", reason),
+        e => format!("SERVER_ERROR {}", e),
+    }
+}
+
+fn stats(shared: &Listener, writer: &mut impl Write) -> io::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let counters = &shared.counters;
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let store = shared.node.store().stats();
+    let lines = [
+        ("pid", u64::from(std::process::id())),
+        ("uptime", shared.started.elapsed().as_secs()),
+        ("time", now),
+        ("total_connections", load(&counters.connections)),
+        // items this node holds itself, as /stats counts them
+        ("curr_items", store.total.count as u64),
+        ("bytes", store.total.bytes as u64),
+        ("cmd_get", load(&counters.cmd_get)),
+        ("cmd_set", load(&counters.cmd_set)),
+        ("get_hits", load(&counters.get_hits)),
+        ("get_misses", load(&counters.get_misses)),
+        ("delete_hits", load(&counters.delete_hits)),
+        ("delete_misses", load(&counters.delete_misses)),
+    ];
+    writeln_crlf(
+        writer,
+        &format!("STAT version {}", env!("CARGO_PKG_VERSION")),
+    )?;
+    for (name, value) in lines {
+        writeln_crlf(writer, &format!("STAT {} {}", name, value))?;
+    }
+    writer.write_all(b"END\r\n")
+}
+
+/// The TTL memcached's `exptime` asks for: None for 0 (no TTL of its own, so the node's
+/// `DEFAULT_TTL_MS` applies, as over HTTP), zero if it already passed.
+fn ttl(exptime: i64) -> Option<Duration> {
+    let secs = match exptime {
+        0 => return None,
+        ..0 => 0,
+        1..=MAX_RELATIVE_EXPTIME => exptime as u64,
+        at => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            (at as u64).saturating_sub(now)
+        }
+    };
+    Some(Duration::from_secs(secs))
+}
+
+/// How a memcached value is stored: a JSON string if it can be, else flags and hex bytes.
+fn to_value(flags: u32, data: Vec<u8>) -> Value {
+    match String::from_utf8(data) {
+        Ok(text) if flags == 0 => Value::String(text),
+        Ok(text) => envelope(flags, text.as_bytes()),
+        Err(e) => envelope(flags, e.as_bytes()),
+    }
+}
+
+fn envelope(flags: u32, data: &[u8]) -> Value {
+    let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+    serde_json::json!({ ENVELOPE: { "flags": flags, "hex": hex } })
+}
+
+/// Flags and bytes to answer a `get` with, undoing `to_value`.
+fn from_value(value: Value) -> (u32, Vec<u8>) {
+    if let Some(inner) = value.get(ENVELOPE)
+        && let (Some(flags), Some(hex)) = (inner["flags"].as_u64(), inner["hex"].as_str())
+        && let Some(data) = decode_hex(hex)
+    {
+        return (flags as u32, data);
+    }
+    match value {
+        Value::String(text) => (0, text.into_bytes()),
+        other => (0, other.to_string().into_bytes()),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
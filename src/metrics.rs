@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Upper bounds (in seconds) of the latency histogram buckets; anything slower lands in `+Inf`.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Upper bounds (in seconds) of the cache lock wait histogram, finer than request latency
+/// since most waits are microseconds.
+pub const LOCK_WAIT_BUCKETS: &[f64] = &[
+    0.000001, 0.000005, 0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.1, 1.0,
+];
+
+/// Key operation a latency is recorded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Get,
+    Post,
+    Delete,
+}
+
+impl Op {
+    const ALL: [Op; 3] = [Op::Get, Op::Post, Op::Delete];
+
+    fn label(self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::Post => "post",
+            Op::Delete => "delete",
+        }
+    }
+
+    fn method(self) -> &'static str {
+        match self {
+            Op::Get => "GET",
+            Op::Post => "POST",
+            Op::Delete => "DELETE",
+        }
+    }
+}
+
+/// Whether a request was served from the local store or forwarded to its owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Local,
+    Forwarded,
+}
+
+impl Route {
+    const ALL: [Route; 2] = [Route::Local, Route::Forwarded];
+
+    fn label(self) -> &'static str {
+        match self {
+            Route::Local => "local",
+            Route::Forwarded => "forwarded",
+        }
+    }
+}
+
+/// Fixed-bucket histogram, updated lock-free.
+pub struct Histogram {
+    // upper bounds in seconds, ascending
+    bounds: &'static [f64],
+    // per-bucket (not cumulative) counts; the extra last slot is `+Inf`
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    /// A histogram over `LATENCY_BUCKETS`.
+    fn default() -> Self {
+        Histogram::with_buckets(LATENCY_BUCKETS)
+    }
+}
+
+impl Histogram {
+    /// A histogram whose buckets end at `bounds` (seconds, ascending) and then `+Inf`.
+    pub fn with_buckets(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Record one observation.
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let idx = self
+            .bounds
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(self.bounds.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of observations so far.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Append this histogram's `_bucket`/`_sum`/`_count` series to `out`; `labels` may be empty.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(le) => le.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, le, cumulative
+            );
+        }
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count());
+    }
+
+    /// Append this histogram to `out` as a whole unlabelled metric, `HELP` and `TYPE` included.
+    pub fn render_metric(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        self.render(out, name, "");
+    }
+}
+
+/// How many RPC attempts to one peer got an answer and how many failed.
+#[derive(Default)]
+struct ForwardCounts {
+    ok: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Node-wide request metrics, shared by every handler thread.
+#[derive(Default)]
+pub struct Metrics {
+    // indexed [op][route] in `Op::ALL` / `Route::ALL` order
+    latency: [[Histogram; 2]; 3],
+    // requests at least this slow are logged and counted; None disables the check
+    slow_threshold: Option<Duration>,
+    slow_requests: AtomicU64,
+    // keyed by peer `host:port`; entries are only ever added, so counting takes the read lock
+    forwards: RwLock<HashMap<String, Arc<ForwardCounts>>>,
+}
+
+impl Metrics {
+    /// Metrics that log and count requests taking longer than `slow_threshold`, if set.
+    pub fn new(slow_threshold: Option<Duration>) -> Self {
+        Metrics {
+            slow_threshold,
+            ..Metrics::default()
+        }
+    }
+
+    /// How many requests exceeded the slow-request threshold.
+    pub fn slow_requests(&self) -> u64 {
+        self.slow_requests.load(Ordering::Relaxed)
+    }
+
+    /// Count one RPC attempt to `peer`: answered below 500, or failed (5xx or unreachable).
+    pub fn record_forward(&self, peer: &str, ok: bool) {
+        let known = self.forwards.read().unwrap().get(peer).cloned();
+        let counts = match known {
+            Some(counts) => counts,
+            None => self
+                .forwards
+                .write()
+                .unwrap()
+                .entry(peer.to_string())
+                .or_default()
+                .clone(),
+        };
+        let counter = if ok { &counts.ok } else { &counts.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Succeeded and failed RPC attempts per peer contacted so far.
+    pub fn forwards(&self) -> BTreeMap<String, (u64, u64)> {
+        self.forwards
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(peer, counts)| {
+                let ok = counts.ok.load(Ordering::Relaxed);
+                let failed = counts.failed.load(Ordering::Relaxed);
+                (peer.clone(), (ok, failed))
+            })
+            .collect()
+    }
+
+    /// Latency histogram for one operation and route.
+    pub fn latency(&self, op: Op, route: Route) -> &Histogram {
+        &self.latency[op as usize][route as usize]
+    }
+
+    /// Start timing an `op`; the latency is recorded when the timer drops, once its route is known.
+    pub fn start(&self, op: Op) -> Timer<'_> {
+        Timer {
+            metrics: self,
+            op,
+            route: None,
+            target: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Render every series in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let name = "sdcs_request_duration_seconds";
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP {} Key request latency by operation and whether it was forwarded.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for op in Op::ALL {
+            for route in Route::ALL {
+                let labels = format!("op=\"{}\",route=\"{}\"", op.label(), route.label());
+                self.latency(op, route).render(&mut out, name, &labels);
+            }
+        }
+        let name = "sdcs_slow_requests_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Key requests slower than SLOW_REQUEST_MS.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, self.slow_requests());
+        let name = "sdcs_peer_rpcs_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} RPC attempts to each peer, by whether the peer answered.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (peer, (ok, failed)) in self.forwards() {
+            let _ = writeln!(out, "{}{{peer=\"{}\",result=\"ok\"}} {}", name, peer, ok);
+            let _ = writeln!(
+                out,
+                "{}{{peer=\"{}\",result=\"failed\"}} {}",
+                name, peer, failed
+            );
+        }
+        out
+    }
+}
+
+/// RPC middleware that counts every attempt to a peer in `Metrics::record_forward`.
+pub struct ForwardMiddleware(pub Arc<Metrics>);
+
+impl ureq::Middleware for ForwardMiddleware {
+    fn handle(
+        &self,
+        request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        // peer URLs are always http://{peer}/..., so the authority is the peer as the router names it
+        let url = request.url();
+        let authority = url.trim_start_matches("http://");
+        let peer = authority.split('/').next().unwrap_or(authority).to_string();
+        let result = next.handle(request);
+        let ok = match &result {
+            Ok(resp) => resp.status() < 500,
+            Err(ureq::Error::Status(code, _)) => *code < 500,
+            Err(ureq::Error::Transport(_)) => false,
+        };
+        self.0.record_forward(&peer, ok);
+        result
+    }
+}
+
+/// Times one request. Requests rejected before routing never set a route and aren't recorded.
+pub struct Timer<'a> {
+    metrics: &'a Metrics,
+    op: Op,
+    route: Option<Route>,
+    // storage key and owner, kept only when a slow request would need them for its log line
+    target: Option<(String, String)>,
+    started: Instant,
+}
+
+impl Timer<'_> {
+    /// Mark how the request for `key` is being served and which peer `owner` serves it.
+    pub fn route(&mut self, route: Route, key: &str, owner: &str) {
+        self.route = Some(route);
+        if self.metrics.slow_threshold.is_some() {
+            self.target = Some((key.to_string(), owner.to_string()));
+        }
+    }
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        let Some(route) = self.route else {
+            return;
+        };
+        let elapsed = self.started.elapsed();
+        self.metrics.latency(self.op, route).observe(elapsed);
+        if let (Some(threshold), Some((key, owner))) = (self.metrics.slow_threshold, &self.target)
+            && elapsed >= threshold
+        {
+            self.metrics.slow_requests.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "warn: slow request: {} {:?} owner {} ({}) took {}ms",
+                self.op.method(),
+                key,
+                owner,
+                route.label(),
+                elapsed.as_millis()
+            );
+        }
+    }
+}
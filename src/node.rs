@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::cache::{Cache, SetOptions};
+use crate::client::{Client, ClientError};
+use crate::router::{Ownership, Router};
+use crate::server::{self, Refusal, ServerContext};
+
+/// In-process handle on one node: the same ownership decision as the HTTP handlers, but keys
+/// this node owns are read and written in its store directly, with no HTTP in between.
+/// Writes get the same checks as over HTTP (read-only, size limits, null policy, schemas,
+/// default TTL) and land in the audit log. Keys owned elsewhere are still sent to their owner
+/// over HTTP, as any client would.
+/// Useful for benchmarking the store and routing, for driving a node from tests, and for
+/// serving other protocols next to the HTTP server.
+pub struct Node {
+    ctx: Arc<ServerContext>,
+    // how this node's local writes name their client in the audit log
+    client: String,
+    // one client per other peer, for keys they own
+    clients: HashMap<String, Client>,
+}
+
+impl Node {
+    /// Node at `self_addr` in the ordered `peers` list (which includes itself), serving keys it
+    /// owns from `store`, with settings read from the environment. To sit next to a running
+    /// server, use `with_context` instead, so read-only and schema changes reach both.
+    pub fn new(self_addr: String, peers: Vec<String>, store: Cache) -> Self {
+        let ctx = server::server_context(&self_addr.clone(), self_addr, peers, store);
+        Node::with_context(ctx, "node")
+    }
+
+    /// Node sharing `ctx` with the HTTP server it was built for by `server::server_context`.
+    /// Its writes are audited as coming from `client`, e.g. the protocol they arrived over.
+    pub fn with_context(ctx: Arc<ServerContext>, client: &str) -> Self {
+        let clients = ctx
+            .router()
+            .others()
+            .map(|peer| (peer.clone(), Client::new(peer)))
+            .collect();
+        Node {
+            ctx,
+            client: client.to_string(),
+            clients,
+        }
+    }
+
+    /// The routing this node uses.
+    pub fn router(&self) -> &Router {
+        self.ctx.router()
+    }
+
+    /// This node's own store.
+    pub fn store(&self) -> &Cache {
+        self.ctx.store()
+    }
+
+    /// Read `key`. Returns None if the cluster doesn't hold it.
+    pub fn get(&self, key: &str) -> Result<Option<Value>, ClientError> {
+        server::check_key(&self.ctx, key).map_err(refused)?;
+        match self.router().resolve(key) {
+            Ownership::Local => Ok(self.store().get(key)),
+            Ownership::Remote(owner) => self.clients[owner].get(key),
+        }
+    }
+
+    /// Write `key`. Returns the entry's new version, if the owner reported one.
+    pub fn set(&self, key: &str, value: Value) -> Result<Option<u64>, ClientError> {
+        self.set_with_ttl(key, value, None)
+    }
+
+    /// `set`, expiring the value after `ttl`, or after `DEFAULT_TTL_MS` if None. A write the
+    /// node refuses fails with `ClientError::Refused`, carrying the status HTTP would answer.
+    pub fn set_with_ttl(
+        &self,
+        key: &str,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<u64>, ClientError> {
+        server::check_write(&self.ctx, key, Some(&value)).map_err(refused)?;
+        match self.router().resolve(key) {
+            Ownership::Local => {
+                let opts = SetOptions {
+                    ttl: ttl.or_else(|| server::default_ttl(&self.ctx)),
+                    ..SetOptions::default()
+                };
+                server::set_local(&self.ctx, &self.client, key, value, opts).map_err(refused)
+            }
+            Ownership::Remote(owner) => self.clients[owner].set_with_ttl(key, value, ttl),
+        }
+    }
+
+    /// Delete `key`. Returns whether it was present.
+    pub fn delete(&self, key: &str) -> Result<bool, ClientError> {
+        server::check_write(&self.ctx, key, None).map_err(refused)?;
+        match self.router().resolve(key) {
+            Ownership::Local => {
+                server::delete_local(&self.ctx, &self.client, key, None).map_err(refused)
+            }
+            Ownership::Remote(owner) => self.clients[owner].delete(key),
+        }
+    }
+}
+
+fn refused(refusal: Refusal) -> ClientError {
+    ClientError::Refused(refusal.status(), refusal.to_string())
+}
@@ -0,0 +1,161 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+/// Where a key lives relative to this node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ownership<'a> {
+    /// This node owns the key and serves it from its own store.
+    Local,
+    /// Another peer owns the key; requests for it are forwarded there.
+    Remote(&'a str),
+}
+
+/// Maps keys to their owning peer. Every handler routes through this so the
+/// local-vs-forward decision is made the same way everywhere.
+#[derive(Clone)]
+pub struct Router {
+    self_addr: String,
+    peers: Vec<String>,
+    // position of this node in `peers`, matched once up front so spellings like
+    // `localhost:8001` and `127.0.0.1:8001` don't make a node forward to itself
+    self_index: Option<usize>,
+}
+
+impl Router {
+    /// Router for the node at `self_addr` in the ordered `peers` list (which includes itself).
+    pub fn new(self_addr: String, peers: Vec<String>) -> Self {
+        // an exact match needs no name resolution (peers may not be resolvable yet at startup)
+        let self_index = peers
+            .iter()
+            .position(|p| *p == self_addr)
+            .or_else(|| peers.iter().position(|p| same_node(p, &self_addr)));
+        Router {
+            self_addr,
+            peers,
+            self_index,
+        }
+    }
+
+    /// This node's own peer address.
+    pub fn self_addr(&self) -> &str {
+        &self.self_addr
+    }
+
+    /// All peers, in the order used for ownership.
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    /// Peers other than this node.
+    pub fn others(&self) -> impl Iterator<Item = &String> {
+        self.peers
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != self.self_index)
+            .map(|(_, p)| p)
+    }
+
+    /// Peer address that owns `key`.
+    pub fn owner(&self, key: &str) -> &str {
+        &self.peers[owner_for_key(key, &self.peers)]
+    }
+
+    /// Decide whether `key` is served locally or by which remote peer.
+    pub fn resolve(&self, key: &str) -> Ownership<'_> {
+        let idx = owner_for_key(key, &self.peers);
+        if Some(idx) == self.self_index {
+            Ownership::Local
+        } else {
+            Ownership::Remote(&self.peers[idx])
+        }
+    }
+}
+
+/// Whether two `host:port` addresses name the same node once resolved.
+/// Loopback and unspecified (`0.0.0.0`) hosts all count as this machine.
+pub fn same_node(a: &str, b: &str) -> bool {
+    let resolve = |addr: &str| -> Vec<SocketAddr> {
+        addr.to_socket_addrs()
+            .map(|addrs| addrs.collect())
+            .unwrap_or_default()
+    };
+    let is_local = |ip: IpAddr| ip.is_loopback() || ip.is_unspecified();
+    let b = resolve(b);
+    resolve(a).iter().any(|x| {
+        b.iter().any(|y| {
+            x.port() == y.port() && (x.ip() == y.ip() || (is_local(x.ip()) && is_local(y.ip())))
+        })
+    })
+}
+
+/// Compute owner index for a key using a simple hash modulo number of peers.
+pub fn owner_for_key(key: &str, peers: &[String]) -> usize {
+    let h = seahash::hash(key.as_bytes());
+    (h as usize) % peers.len()
+}
+
+/// Count how many of `samples` synthetic keys each peer would own, in `peers` order.
+/// Nothing is stored; this only exercises `owner_for_key` to show how evenly keys spread.
+pub fn key_distribution(peers: &[String], samples: usize) -> Vec<usize> {
+    let mut counts = vec![0; peers.len()];
+    for i in 0..samples {
+        counts[owner_for_key(&format!("key-{}", i), peers)] += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers() -> Vec<String> {
+        ["a:1", "b:2", "c:3"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn every_node_agrees_on_the_owner() {
+        let routers: Vec<Router> = peers()
+            .into_iter()
+            .map(|me| Router::new(me, peers()))
+            .collect();
+        for i in 0..100 {
+            let key = format!("k{}", i);
+            let owner = routers[0].owner(&key).to_string();
+            for router in &routers {
+                assert_eq!(router.owner(&key), owner);
+                let expected = if router.self_addr() == owner {
+                    Ownership::Local
+                } else {
+                    Ownership::Remote(&owner)
+                };
+                assert_eq!(router.resolve(&key), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_spread_over_every_peer() {
+        let counts = key_distribution(&peers(), 3000);
+        assert_eq!(counts.iter().sum::<usize>(), 3000);
+        assert!(counts.iter().all(|&c| c > 600), "{:?}", counts);
+        assert_eq!(key_distribution(&peers(), 0), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn a_differently_spelled_self_address_still_matches() {
+        let peers: Vec<String> = ["127.0.0.1:7001", "127.0.0.1:7002"]
+            .map(String::from)
+            .to_vec();
+        for me in ["localhost:7001", "0.0.0.0:7001"] {
+            let router = Router::new(me.to_string(), peers.clone());
+            assert_eq!(router.others().collect::<Vec<_>>(), vec!["127.0.0.1:7002"]);
+            let key = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|k| owner_for_key(k, &peers) == 0)
+                .unwrap();
+            assert_eq!(router.resolve(&key), Ownership::Local);
+        }
+        assert!(same_node("localhost:7001", "127.0.0.1:7001"));
+        assert!(!same_node("127.0.0.1:7001", "127.0.0.1:7002"));
+        assert!(!same_node("unresolvable.invalid:7001", "127.0.0.1:7001"));
+    }
+}
@@ -0,0 +1,520 @@
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use serde::de::DeserializeOwned;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Condvar, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::cbor::{self, CBOR};
+use crate::transport::{RpcReply, Transport};
+
+/// Content type of MessagePack-encoded peer traffic.
+pub const MSGPACK: &str = "application/msgpack";
+
+/// Set on a 503 a node sends because it is at its request limit, as opposed to e.g. read-only.
+/// Nodes forwarding to it stop retrying and shed their own forwards to it for its `Retry-After`.
+pub const OVERLOADED_HEADER: &str = "X-SDCS-Overloaded";
+
+/// Longest a peer's `Retry-After` may hold off forwards to it.
+const MAX_PEER_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Why a body couldn't be decoded, with the failing line and column for JSON.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub message: String,
+    pub position: Option<(usize, usize)>,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(e: serde_json::Error) -> Self {
+        DecodeError {
+            message: e.to_string(),
+            position: Some((e.line(), e.column())),
+        }
+    }
+}
+
+/// Decode a JSON or (when `content_type` says so) MessagePack or CBOR body.
+pub fn decode_value<T: DeserializeOwned>(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<T, DecodeError> {
+    if content_type.is_some_and(|ct| ct.starts_with(MSGPACK)) {
+        rmp_serde::from_slice(body).map_err(|e| DecodeError {
+            message: e.to_string(),
+            position: None,
+        })
+    } else if content_type.is_some_and(|ct| ct.starts_with(CBOR)) {
+        let value = cbor::decode(body).map_err(|message| DecodeError {
+            message,
+            position: None,
+        })?;
+        serde_json::from_value(value).map_err(|e| DecodeError {
+            message: e.to_string(),
+            position: None,
+        })
+    } else {
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+/// Bytes escaped when a key goes into a URL path: all but RFC 3986's unreserved characters,
+/// so a slash inside a key travels as `%2F`.
+const KEY_ESCAPES: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// `key` percent-encoded for use as a URL path segment.
+pub fn encode_key(key: &str) -> String {
+    utf8_percent_encode(key, KEY_ESCAPES).to_string()
+}
+
+/// Percent-decode a key taken from a URL path. Err(()) if the result isn't UTF-8.
+pub fn decode_key(encoded: &str) -> Result<String, ()> {
+    percent_decode_str(encoded)
+        .decode_utf8()
+        .map(|key| key.into_owned())
+        .map_err(|_| ())
+}
+
+/// Encode a value as MessagePack.
+pub fn encode_msgpack(value: &Value) -> Vec<u8> {
+    rmp_serde::to_vec(&Msgpack(value)).expect("JSON values always encode as msgpack")
+}
+
+/// Key serde_json (with `arbitrary_precision`) reads a one-entry map under as an exact number.
+const EXACT_NUMBER: &str = "$serde_json::private::Number";
+
+/// A value as `encode_msgpack` writes it. Numbers a msgpack int or float reproduces digit for
+/// digit are sent as one; the rest (integers past 64 bits, decimals a float would round) go as
+/// a one-entry `EXACT_NUMBER` map, which decoding back into a `Value` turns into the same
+/// number. Serializing a `Value` directly would send those as a struct msgpack can't tell apart
+/// from an array.
+struct Msgpack<'a>(&'a Value);
+
+impl Serialize for Msgpack<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Number(n) => {
+                let text = n.to_string();
+                if let Some(u) = n.as_u64().filter(|u| u.to_string() == text) {
+                    s.serialize_u64(u)
+                } else if let Some(i) = n.as_i64().filter(|i| i.to_string() == text) {
+                    s.serialize_i64(i)
+                } else if let Some(f) = n.as_f64().filter(|f| {
+                    serde_json::Number::from_f64(*f).is_some_and(|m| m.to_string() == text)
+                }) {
+                    s.serialize_f64(f)
+                } else {
+                    let mut map = s.serialize_map(Some(1))?;
+                    map.serialize_entry(EXACT_NUMBER, &text)?;
+                    map.end()
+                }
+            }
+            Value::Array(items) => s.collect_seq(items.iter().map(Msgpack)),
+            Value::Object(map) => s.collect_map(map.iter().map(|(k, v)| (k, Msgpack(v)))),
+            other => other.serialize(s),
+        }
+    }
+}
+
+/// Make up to `attempts` calls, retrying when the peer is unreachable or answers 5xx (but 507).
+/// Any other reply (e.g. 404) is returned at once; Err(()) means every attempt failed.
+fn with_retry(
+    method: &str,
+    url: &str,
+    attempts: usize,
+    call: impl Fn() -> Result<RpcReply, String>,
+) -> Result<RpcReply, ()> {
+    for i in 0..attempts {
+        match call() {
+            // an overloaded peer asked for room; retrying at once would only add to its load
+            Ok(reply) if reply.status == 503 && reply.header(OVERLOADED_HEADER).is_some() => {
+                return Ok(reply);
+            }
+            // treat 5xx as transient; retry, except 507 (a full namespace stays full)
+            Ok(reply) if reply.status >= 500 && reply.status != 507 => {
+                eprintln!(
+                    "RPC {} to {} attempt {} got {} — retrying",
+                    method,
+                    url,
+                    i + 1,
+                    reply.status
+                );
+            }
+            Ok(reply) => return Ok(reply),
+            Err(e) => {
+                eprintln!("RPC {} to {} attempt {} failed: {}", method, url, i + 1, e);
+            }
+        }
+        sleep(Duration::from_millis(50));
+    }
+    Err(())
+}
+
+// helper: try GET with retries over the node's transport. Return Ok(reply) when owner replies or Err(()) on total failure.
+// `timeout` overrides the transport's short default for requests the owner may hold open (long polls).
+pub fn rpc_get_with_retry(
+    transport: &dyn Transport,
+    url: &str,
+    headers: &[(&str, &str)],
+    timeout: Option<Duration>,
+    attempts: usize,
+) -> Result<RpcReply, ()> {
+    with_retry("GET", url, attempts, || {
+        transport.get(url, headers, timeout)
+    })
+}
+
+pub fn rpc_delete_with_retry(
+    transport: &dyn Transport,
+    url: &str,
+    headers: &[(&str, &str)],
+    attempts: usize,
+) -> Result<RpcReply, ()> {
+    with_retry("DELETE", url, attempts, || transport.delete(url, headers))
+}
+
+/// POST `body` to `url`; it is sent as JSON unless `headers` set another Content-Type.
+/// A POST that isn't `idempotent` (e.g. an append) could be applied twice if an attempt reached
+/// the owner but its reply was lost, so it only retries when it carries an `Idempotency-Key`
+/// the owner dedupes by.
+pub fn rpc_post_with_retry(
+    transport: &dyn Transport,
+    url: &str,
+    body: &[u8],
+    headers: &[(&str, &str)],
+    attempts: usize,
+    idempotent: bool,
+) -> Result<RpcReply, ()> {
+    let dedupable = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Idempotency-Key"));
+    let attempts = if idempotent || dedupable {
+        attempts
+    } else {
+        attempts.min(1)
+    };
+    with_retry("POST", url, attempts, || transport.post(url, body, headers))
+}
+
+/// Caps how many RPCs this node has outstanding to any one peer, so a hot owner isn't flooded,
+/// and holds off RPCs to a peer that answered it was overloaded until its `Retry-After` passes.
+pub struct PeerLimiter {
+    limit: usize,
+    in_flight: Mutex<HashMap<String, usize>>,
+    freed: Condvar,
+    // peers that answered with `OVERLOADED_HEADER`, and when RPCs to them may resume
+    backoff: Mutex<HashMap<String, Instant>>,
+}
+
+/// One outstanding RPC to a peer; frees its slot when dropped.
+pub struct PeerPermit<'a> {
+    limiter: &'a PeerLimiter,
+    peer: String,
+}
+
+impl PeerLimiter {
+    /// Allow at most `limit` concurrent RPCs per peer (0 = no cap).
+    pub fn new(limit: usize) -> Self {
+        PeerLimiter {
+            limit,
+            in_flight: Mutex::new(HashMap::new()),
+            freed: Condvar::new(),
+            backoff: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a slot for an RPC to `peer`, waiting up to `wait` for one to free up.
+    /// Returns None if the peer stayed at its limit, or at once while it is backed off.
+    pub fn acquire(&self, peer: &str, wait: Duration) -> Option<PeerPermit<'_>> {
+        if self.backoff_left(peer).is_some() {
+            return None;
+        }
+        let deadline = Instant::now() + wait;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while self.limit > 0 && in_flight.get(peer).copied().unwrap_or(0) >= self.limit {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return None;
+            }
+            in_flight = self.freed.wait_timeout(in_flight, left).unwrap().0;
+        }
+        *in_flight.entry(peer.to_string()).or_default() += 1;
+        Some(PeerPermit {
+            limiter: self,
+            peer: peer.to_string(),
+        })
+    }
+
+    /// Current outstanding RPCs per peer that has had any.
+    pub fn in_flight(&self) -> HashMap<String, usize> {
+        self.in_flight.lock().unwrap().clone()
+    }
+
+    /// How long RPCs to `peer` are still held off, if they are.
+    fn backoff_left(&self, peer: &str) -> Option<Duration> {
+        let mut backoff = self.backoff.lock().unwrap();
+        let left = backoff
+            .get(peer)?
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero());
+        if left.is_none() {
+            backoff.remove(peer);
+        }
+        left
+    }
+
+    /// Milliseconds each backed-off peer has left before RPCs to it resume.
+    pub fn backoffs(&self) -> HashMap<String, u64> {
+        let now = Instant::now();
+        self.backoff
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(peer, until)| (peer.clone(), (*until - now).as_millis() as u64))
+            .collect()
+    }
+}
+
+impl PeerPermit<'_> {
+    /// Note the peer's reply to this RPC: if it said it's overloaded, hold off further RPCs to
+    /// it for its `Retry-After` (one second if absent, at most `MAX_PEER_BACKOFF`).
+    pub fn observe(&self, reply: &RpcReply) {
+        if reply.status != 503 || reply.header(OVERLOADED_HEADER).is_none() {
+            return;
+        }
+        let wait = reply
+            .header("Retry-After")
+            .and_then(|v| v.trim().parse().ok())
+            .map_or(Duration::from_secs(1), Duration::from_secs)
+            .min(MAX_PEER_BACKOFF);
+        eprintln!("{} is overloaded — backing off for {:?}", self.peer, wait);
+        let until = Instant::now() + wait;
+        let mut backoff = self.limiter.backoff.lock().unwrap();
+        let entry = backoff.entry(self.peer.clone()).or_insert(until);
+        *entry = (*entry).max(until);
+    }
+}
+
+impl Drop for PeerPermit<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.in_flight.lock().unwrap();
+        if let Some(n) = in_flight.get_mut(&self.peer) {
+            *n -= 1;
+        }
+        self.limiter.freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn an_overloaded_reply_backs_its_peer_off() {
+        let reply = |status, headers: &[(&str, &str)]| RpcReply {
+            status,
+            body: Vec::new(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        let limiter = PeerLimiter::new(0);
+        let permit = limiter.acquire("a", Duration::ZERO).unwrap();
+        // a plain 503 (read-only, say) is no reason to hold off
+        permit.observe(&reply(503, &[("Retry-After", "5")]));
+        permit.observe(&reply(200, &[(OVERLOADED_HEADER, "1")]));
+        assert!(limiter.backoffs().is_empty());
+        permit.observe(&reply(
+            503,
+            &[(OVERLOADED_HEADER, "1"), ("Retry-After", "60")],
+        ));
+        drop(permit);
+        // capped at MAX_PEER_BACKOFF, and other peers are unaffected
+        let left = limiter.backoffs()["a"];
+        assert!(left > 9_000 && left <= 10_000, "{}", left);
+        assert!(limiter.acquire("a", Duration::from_secs(1)).is_none());
+        assert!(limiter.acquire("b", Duration::ZERO).is_some());
+    }
+
+    #[test]
+    fn an_overloaded_reply_is_not_retried() {
+        let calls = AtomicUsize::new(0);
+        let reply = with_retry("GET", "http://a/k", 3, || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(RpcReply {
+                status: 503,
+                body: Vec::new(),
+                headers: vec![(OVERLOADED_HEADER.to_string(), "1".to_string())],
+            })
+        });
+        assert_eq!(reply.unwrap().status, 503);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn peer_limiter_caps_each_peer_separately() {
+        let limiter = PeerLimiter::new(1);
+        let first = limiter.acquire("a", Duration::ZERO).unwrap();
+        assert!(limiter.acquire("a", Duration::from_millis(20)).is_none());
+        let other = limiter.acquire("b", Duration::ZERO).unwrap();
+        assert_eq!(limiter.in_flight()["a"], 1);
+        drop(first);
+        drop(other);
+        assert_eq!(limiter.in_flight()["a"], 0);
+        // a freed slot goes to whoever is queued for it
+        let held = limiter.acquire("a", Duration::ZERO).unwrap();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| limiter.acquire("a", Duration::from_secs(5)).is_some());
+            std::thread::sleep(Duration::from_millis(20));
+            drop(held);
+            assert!(waiter.join().unwrap());
+        });
+        // 0 means no cap
+        let unlimited = PeerLimiter::new(0);
+        let permits: Vec<_> = (0..3)
+            .map(|_| unlimited.acquire("a", Duration::ZERO))
+            .collect();
+        assert!(permits.iter().all(Option::is_some));
+    }
+
+    /// Answers each call with the next of `replies` (a status, or None for no reply).
+    struct Scripted {
+        replies: Mutex<Vec<Option<u16>>>,
+        calls: AtomicUsize,
+    }
+
+    impl Scripted {
+        fn new(mut replies: Vec<Option<u16>>) -> Self {
+            replies.reverse();
+            Scripted {
+                replies: Mutex::new(replies),
+                calls: AtomicUsize::new(0),
+            }
+        }
+
+        fn next(&self) -> Result<RpcReply, String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match self.replies.lock().unwrap().pop().flatten() {
+                Some(status) => Ok(RpcReply {
+                    status,
+                    body: Vec::new(),
+                    headers: Vec::new(),
+                }),
+                None => Err("unreachable".to_string()),
+            }
+        }
+    }
+
+    impl Transport for Scripted {
+        fn get(
+            &self,
+            _: &str,
+            _: &[(&str, &str)],
+            _: Option<Duration>,
+        ) -> Result<RpcReply, String> {
+            self.next()
+        }
+
+        fn post(&self, _: &str, _: &[u8], _: &[(&str, &str)]) -> Result<RpcReply, String> {
+            self.next()
+        }
+
+        fn delete(&self, _: &str, _: &[(&str, &str)]) -> Result<RpcReply, String> {
+            self.next()
+        }
+    }
+
+    #[test]
+    fn retries_go_through_the_transport_until_a_non_5xx_reply() {
+        let transport = Scripted::new(vec![None, Some(503), Some(404)]);
+        let reply = rpc_get_with_retry(&transport, "http://peer/k", &[], None, 3).unwrap();
+        assert_eq!(reply.status, 404);
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 3);
+
+        let transport = Scripted::new(vec![Some(200), Some(200)]);
+        assert!(rpc_delete_with_retry(&transport, "http://peer/k", &[], 3).is_ok());
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 1);
+
+        let transport = Scripted::new(vec![Some(502), None]);
+        assert!(rpc_post_with_retry(&transport, "http://peer/", b"{}", &[], 2, true).is_err());
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn posts_that_are_unsafe_to_repeat_retry_only_with_an_idempotency_key() {
+        let transport = Scripted::new(vec![None, Some(200)]);
+        assert!(
+            rpc_post_with_retry(&transport, "http://peer/push/k", b"1", &[], 3, false).is_err()
+        );
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 1);
+
+        let transport = Scripted::new(vec![None, Some(200)]);
+        let keyed = [("idempotency-key", "once")];
+        let reply = rpc_post_with_retry(&transport, "http://peer/push/k", b"1", &keyed, 3, false);
+        assert_eq!(reply.unwrap().status, 200);
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn keys_round_trip_through_percent_encoding() {
+        for key in ["plain", "my key", "a/b", "ümlaut?&=#", "50%"] {
+            let encoded = encode_key(key);
+            assert!(!encoded.contains(['/', ' ', '?', '#']), "{}", encoded);
+            assert_eq!(decode_key(&encoded).as_deref(), Ok(key));
+        }
+        assert_eq!(encode_key("a/b c"), "a%2Fb%20c");
+        assert_eq!(decode_key("%ff"), Err(()));
+    }
+
+    #[test]
+    fn values_decode_by_content_type() {
+        let value = json!({"k": [1, "two", {"three": null}]});
+        let packed = encode_msgpack(&value);
+        let unpacked: Value = decode_value(Some(MSGPACK), &packed).unwrap();
+        assert_eq!(unpacked, value);
+        let text = value.to_string();
+        let parsed: Value = decode_value(None, text.as_bytes()).unwrap();
+        assert_eq!(parsed, value);
+        let parsed: Value = decode_value(Some("application/json"), text.as_bytes()).unwrap();
+        assert_eq!(parsed, value);
+        // msgpack isn't JSON
+        assert!(decode_value::<Value>(None, &packed).is_err());
+    }
+
+    #[test]
+    fn numbers_keep_their_exact_digits_through_msgpack() {
+        let text = r#"[123456789012345678901234567890,0.1000000000000000000001,-7,2.5,18446744073709551615]"#;
+        let value: Value = serde_json::from_str(text).unwrap();
+        let unpacked: Value = decode_value(Some(MSGPACK), &encode_msgpack(&value)).unwrap();
+        assert_eq!(unpacked.to_string(), text);
+        // numbers msgpack can hold exactly still go as plain msgpack numbers
+        let plain: Value = rmp_serde::from_slice(&encode_msgpack(&json!([-7, 2.5]))).unwrap();
+        assert_eq!(plain, json!([-7, 2.5]));
+    }
+
+    #[test]
+    fn json_decode_errors_carry_their_position() {
+        let e = decode_value::<Value>(None, b"{\n  \"k\": tru }").unwrap_err();
+        assert_eq!(e.position, Some((2, 11)));
+        assert!(e.to_string().contains("line 2"), "{}", e);
+        let e = decode_value::<Value>(Some(MSGPACK), b"\xc1").unwrap_err();
+        assert_eq!(e.position, None);
+    }
+}
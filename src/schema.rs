@@ -0,0 +1,51 @@
+use std::sync::{Arc, RwLock};
+
+use serde_json::Value;
+
+/// A key prefix and the compiled schema values under it must match.
+type PrefixSchema = (String, Arc<jsonschema::Validator>);
+
+/// JSON Schemas that values must match, each registered for a key prefix.
+/// Cloning shares the registry.
+#[derive(Clone, Default)]
+pub struct SchemaRegistry {
+    // kept sorted longest prefix first, so the most specific schema wins
+    schemas: Arc<RwLock<Vec<PrefixSchema>>>,
+}
+
+impl SchemaRegistry {
+    /// Require values under keys starting with `prefix` to match `schema`, replacing any schema
+    /// already registered for exactly that prefix. Returns Err if `schema` isn't a valid schema.
+    pub fn register(&self, prefix: &str, schema: &Value) -> Result<(), String> {
+        let validator = jsonschema::validator_for(schema).map_err(|e| e.to_string())?;
+        let mut schemas = self.schemas.write().unwrap();
+        schemas.retain(|(p, _)| p != prefix);
+        schemas.push((prefix.to_string(), Arc::new(validator)));
+        schemas.sort_by_key(|(p, _)| std::cmp::Reverse(p.len()));
+        Ok(())
+    }
+
+    /// Check `value` against the schema registered for the longest prefix of `key`, if any.
+    /// Returns every violation found.
+    pub fn check(&self, key: &str, value: &Value) -> Result<(), Vec<String>> {
+        let validator = {
+            let schemas = self.schemas.read().unwrap();
+            match schemas
+                .iter()
+                .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            {
+                Some((_, validator)) => validator.clone(),
+                None => return Ok(()),
+            }
+        };
+        let violations: Vec<String> = validator
+            .iter_errors(value)
+            .map(|e| e.to_string())
+            .collect();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
@@ -1,320 +1,3266 @@
-use crate::cache::Cache;
+use crate::audit::AuditLog;
+use crate::cache::{
+    Cache, CacheOptions, Export, ExportedEntry, Quota, SetOptions, TxnOp, WriteError,
+};
+use crate::cbor::{self, CBOR};
+use crate::chaos::{self, Chaos, ChaosMiddleware, ChaosSettings};
+use crate::config::{Config, NullValues, RoutingMode};
+use crate::idempotency::{self, IdempotencyCache, KeyReused};
+use crate::metrics::{ForwardMiddleware, Metrics, Op, Route};
+use crate::router::{Ownership, Router, key_distribution};
+use crate::rpc::{
+    self, DecodeError, MSGPACK, PeerLimiter, PeerPermit, rpc_delete_with_retry, rpc_get_with_retry,
+    rpc_post_with_retry,
+};
+use crate::schema::SchemaRegistry;
+use crate::transport::{RpcReply, Transport};
+use flate2::bufread::MultiGzDecoder;
+use flate2::read::GzEncoder;
 use serde_json::Value;
-use std::sync::Arc;
-use std::thread::sleep;
-use std::time::Duration;
-
-// helper: try GET with retries using a shared Agent. Return Ok((status_code, body)) when owner replies or Err(()) on total failure.
-fn rpc_get_with_retry(
-    agent: &ureq::Agent,
-    url: &str,
-    attempts: usize,
-) -> Result<(u16, String), ()> {
-    let mut i = 0;
-
-    while i < attempts {
-        match agent.get(url).call() {
-            Ok(resp) => {
-                let status = resp.status() as u16;
-                let body = resp.into_string().unwrap_or_default();
-                if status >= 500 {
-                    // treat 5xx as transient; retry
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Starts an HTTP server bound to `addr`. This returns the tiny_http::Server which the caller
+/// should pass to `run_server` to begin serving requests.
+pub fn init_server(_name: &str, addr: &str) -> (tiny_http::Server, Cache) {
+    let server =
+        tiny_http::Server::http(addr).unwrap_or_else(|e| panic!("failed to bind {}: {}", addr, e));
+    let config = Config::from_env();
+    let store = Cache::with_options(CacheOptions {
+        compress_min_bytes: config.compress_values.then_some(config.compress_min_bytes),
+        key_filter_slots: config.key_filter_slots,
+        record_expiries: config.expiry_broadcast,
+        ..CacheOptions::default()
+    });
+    println!("listening on http://{}", addr);
+    (server, store)
+}
+
+/// Per-node state handed to every request handler, shared by reference rather than copied.
+/// Built by `server_context`; the HTTP server and any `Node` made from it share one.
+pub struct ServerContext {
+    name: String,
+    router: Router,
+    store: Cache,
+    // how RPCs reach peers; a ureq agent unless the caller supplied its own
+    transport: Box<dyn Transport>,
+    config: Config,
+    idempotency: IdempotencyCache<WriteOutcome>,
+    metrics: Arc<Metrics>,
+    peer_limits: Arc<PeerLimiter>,
+    // refuse writes (maintenance); starts from READ_ONLY, flipped by POST /admin/readonly
+    read_only: Arc<AtomicBool>,
+    schemas: SchemaRegistry,
+    chaos: Arc<Chaos>,
+    audit: Option<AuditLog>,
+    // state for TTL_JITTER_PCT's random spread
+    ttl_jitter: AtomicU64,
+    // set while POST /admin/rehash runs, so a second one is refused rather than racing it
+    rehashing: AtomicBool,
+    // false while WAIT_FOR_PEERS holds off serving at startup
+    ready: AtomicBool,
+    // each peer's latest health probe result, which /metrics renders without probing
+    peer_health: Mutex<HashMap<String, PeerHealth>>,
+}
+
+/// What this node last learned about a peer from probing its `/health`.
+#[derive(Clone, Copy, Default)]
+struct PeerHealth {
+    // whether the latest probe got an answer
+    up: bool,
+    // when a probe last got one, if ever
+    last_seen: Option<SystemTime>,
+}
+
+/// Body and new entry version of a locally applied write, or why it was refused.
+type WriteOutcome = Result<(String, Option<u64>), Refusal>;
+
+impl ServerContext {
+    /// The routing this node uses.
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    /// This node's own store.
+    pub fn store(&self) -> &Cache {
+        &self.store
+    }
+}
+
+/// Value of query parameter `name` in a raw `a=1&b=2` query string.
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+thread_local! {
+    // the node and "METHOD /url" of the request this thread serves and how much of each body
+    // to log, set only with LOG_BODIES on (every request gets a thread of its own)
+    static BODY_LOG: RefCell<Option<(String, String, usize)>> = const { RefCell::new(None) };
+    // this node's address and, once a key request is routed, the key's owner, which every
+    // response of this thread names in `HANDLED_BY_HEADER` and `OWNER_HEADER`
+    static RESPONDER: RefCell<(String, Option<String>)> = const { RefCell::new((String::new(), None)) };
+}
+
+/// Names the node that answered the client, which forwarded the request if it isn't the owner.
+const HANDLED_BY_HEADER: &str = "X-SDCS-Handled-By";
+/// Names the node owning the key a key request was for.
+const OWNER_HEADER: &str = "X-SDCS-Owner";
+
+/// Record `owner` as the owner of the key this thread's request is for.
+fn note_owner(owner: &str) {
+    RESPONDER.with_borrow_mut(|(_, noted)| *noted = Some(owner.to_string()));
+}
+
+/// Answer `req` with `resp`, adding `HANDLED_BY_HEADER` and, for key requests, `OWNER_HEADER`.
+/// Every response goes through here; a client that hung up is nothing to act on.
+fn respond<R: Read>(req: tiny_http::Request, mut resp: tiny_http::Response<R>) {
+    RESPONDER.with_borrow(|(handled_by, owner)| {
+        let tags = [
+            (HANDLED_BY_HEADER, Some(handled_by)),
+            (OWNER_HEADER, owner.as_ref()),
+        ];
+        for (name, value) in tags {
+            if let Some(value) = value.filter(|v| !v.is_empty())
+                && let Ok(header) = tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes())
+            {
+                resp.add_header(header);
+            }
+        }
+    });
+    let _ = req.respond(resp);
+}
+
+/// With `LOG_BODIES` on, log `body` as this thread's request or response body, cut to
+/// `LOG_BODY_MAX_BYTES`.
+fn log_body(kind: &str, body: &[u8]) {
+    BODY_LOG.with_borrow(|log| {
+        if let Some((node, request, max)) = log {
+            let shown = String::from_utf8_lossy(&body[..body.len().min(*max)]);
+            let cut = if body.len() > *max { "..." } else { "" };
+            eprintln!(
+                "{}: debug: {} {} body ({} bytes): {}{}",
+                node,
+                request,
+                kind,
+                body.len(),
+                shown,
+                cut
+            );
+        }
+    });
+}
+
+/// Helper to create JSON response with appropriate headers
+fn json_response(status: u16, body: String) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    log_body("response", body.as_bytes());
+    tiny_http::Response::from_string(body)
+        .with_status_code(status)
+        .with_header(
+            tiny_http::Header::from_bytes(b"Content-Type", b"application/json; charset=utf-8")
+                .unwrap(),
+        )
+}
+
+/// Whether the client asked for indented JSON with `?pretty=true`.
+fn wants_pretty(query: &str) -> bool {
+    matches!(query_param(query, "pretty"), Some("true" | "1"))
+}
+
+/// Re-indent the JSON document `body` when `pretty` is set; left untouched otherwise.
+fn pretty_json(body: String, pretty: bool) -> String {
+    if !pretty {
+        return body;
+    }
+    match serde_json::from_str::<Value>(&body) {
+        Ok(value) => serde_json::to_string_pretty(&value).unwrap(),
+        Err(_) => body,
+    }
+}
+
+/// How long a forward queues for a free slot to a busy owner before it is shed with 503.
+/// Matches the internal RPC timeouts, so a queued forward costs about as much as a slow one.
+const PEER_QUEUE_WAIT: Duration = Duration::from_millis(100);
+
+/// Marks a request one node sent to another on a client's behalf; the receiver must not re-forward it.
+const FORWARDED_HEADER: &str = "X-SDCS-Forwarded";
+
+/// Set on forwarded writes to the original client's address, so the owner's audit log names the
+/// client rather than the forwarding node. Only read alongside `FORWARDED_HEADER`.
+const CLIENT_HEADER: &str = "X-Forwarded-For";
+
+/// POST routes that change no stored data, so a read-only node still serves them. Expiry of
+/// copies goes on while read-only, like expiry itself.
+const READ_ONLY_EXEMPT: &[&str] = &[
+    "/admin/readonly",
+    "/admin/schema",
+    "/admin/quota",
+    "/admin/selfcheck",
+    "/admin/chaos",
+    "/admin/expired",
+];
+
+/// How often expired entries nobody has read since are dropped from the store.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often peers are probed in the background, for /health and the gauges in /metrics.
+const PEER_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a `WAIT_FOR_PEERS` node re-probes its peers while it waits to serve.
+const PEER_WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Per-write TTL in milliseconds; 0 means the value never expires.
+const TTL_HEADER: &str = "X-TTL";
+/// Per-write absolute expiry as an RFC 3339 timestamp, in place of `X-TTL`.
+const EXPIRE_AT_HEADER: &str = "X-Expire-At";
+
+/// Response headers a write attaches to its value, as a JSON object of name to value.
+/// GETs of the value send them, and echo this header so forwarding nodes can relay them.
+const STORE_HEADERS_HEADER: &str = "X-Store-Headers";
+/// Most headers one value may carry, and the largest their names and values may total.
+const MAX_STORED_HEADERS: usize = 32;
+const MAX_STORED_HEADER_BYTES: usize = 8 * 1024;
+/// Headers the server sets itself or that change how the response is framed; values can't carry them.
+const MANAGED_HEADERS: &[&str] = &[
+    "Accept-Ranges",
+    "Cache-Control",
+    "Connection",
+    "Content-Encoding",
+    "Content-Length",
+    "Content-Range",
+    "Content-Type",
+    "Date",
+    "ETag",
+    "Keep-Alive",
+    "Location",
+    "Retry-After",
+    "Server",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    STORE_HEADERS_HEADER,
+    HANDLED_BY_HEADER,
+    OWNER_HEADER,
+];
+
+/// Milliseconds the client will wait for an answer. Forwards carry what's left of it, so the
+/// owner doesn't start work the client has already given up on.
+const TIMEOUT_HEADER: &str = "X-Timeout-Ms";
+
+/// Headers copied from an owner's reply onto the response sent back to the client.
+const RELAYED_HEADERS: &[&str] = &[
+    "ETag",
+    "Cache-Control",
+    "Accept-Ranges",
+    "Content-Range",
+    "Retry-After",
+];
+
+/// Build the client response for an owner's reply, keeping the headers in `RELAYED_HEADERS`.
+/// `pretty` indents the JSON body for the client, which gets it re-encoded in `codec`.
+fn forwarded_response(
+    reply: RpcReply,
+    pretty: bool,
+    codec: Codec,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    // owners may answer in msgpack; clients get whichever codec they asked for
+    let body = match reply.header("Content-Type") {
+        Some(ct) if ct.starts_with(MSGPACK) => match reply.value() {
+            Ok(value) => value.to_string(),
+            Err(e) => {
+                eprintln!("undecodable msgpack reply: {}", e);
+                return json_response(502, String::new());
+            }
+        },
+        _ => String::from_utf8_lossy(&reply.body).into_owned(),
+    };
+    let mut resp = value_response(reply.status, pretty_json(body, pretty), codec);
+    for (name, value) in &reply.headers {
+        if let Some(canonical) = RELAYED_HEADERS
+            .iter()
+            .find(|h| h.eq_ignore_ascii_case(name))
+            && let Ok(header) =
+                tiny_http::Header::from_bytes(canonical.as_bytes(), value.as_bytes())
+        {
+            resp.add_header(header);
+        }
+    }
+    // checked again here rather than trusting the owner to have validated them
+    if let Some(stored) = reply.header(STORE_HEADERS_HEADER)
+        && let Ok(headers) = parse_store_headers(stored)
+    {
+        resp = with_stored_headers(resp, &headers);
+    }
+    resp
+}
+
+/// Parse an `X-Store-Headers` value into header pairs; see `store_headers`.
+fn parse_store_headers(text: &str) -> Result<Vec<(String, String)>, String> {
+    match serde_json::from_str(text) {
+        Ok(Value::Object(object)) => store_headers(object),
+        _ => Err(format!("{} must be a JSON object", STORE_HEADERS_HEADER)),
+    }
+}
+
+/// Check headers a value should be served with. Names must be HTTP tokens other than the
+/// `MANAGED_HEADERS`; values must be strings of visible ASCII, spaces and tabs, so neither can
+/// smuggle a line break into the response.
+fn store_headers(object: serde_json::Map<String, Value>) -> Result<Vec<(String, String)>, String> {
+    if object.len() > MAX_STORED_HEADERS {
+        return Err(format!("at most {} stored headers", MAX_STORED_HEADERS));
+    }
+    let mut total = 0;
+    let mut headers = Vec::with_capacity(object.len());
+    for (name, value) in object {
+        let is_token = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !is_token {
+            return Err(format!("invalid header name {:?}", name));
+        }
+        if MANAGED_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(&name))
+        {
+            return Err(format!("{} is set by the server", name));
+        }
+        let Value::String(value) = value else {
+            return Err(format!("header {} must have a string value", name));
+        };
+        if !value
+            .bytes()
+            .all(|b| b == b'\t' || (b' '..=b'~').contains(&b))
+        {
+            return Err(format!("invalid value for header {}", name));
+        }
+        total += name.len() + value.len();
+        if total > MAX_STORED_HEADER_BYTES {
+            return Err(format!(
+                "stored headers exceed {} bytes",
+                MAX_STORED_HEADER_BYTES
+            ));
+        }
+        headers.push((name, value));
+    }
+    Ok(headers)
+}
+
+/// `headers` as the JSON object `X-Store-Headers` carries.
+fn stored_headers_json(headers: &[(String, String)]) -> Value {
+    headers
+        .iter()
+        .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// `resp` with a value's stored headers added, plus `X-Store-Headers` listing them.
+fn with_stored_headers<R: Read>(
+    mut resp: tiny_http::Response<R>,
+    headers: &[(String, String)],
+) -> tiny_http::Response<R> {
+    if headers.is_empty() {
+        return resp;
+    }
+    for (name, value) in headers {
+        if let Ok(header) = tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+            resp.add_header(header);
+        }
+    }
+    let listed = stored_headers_json(headers).to_string();
+    if let Ok(header) =
+        tiny_http::Header::from_bytes(STORE_HEADERS_HEADER.as_bytes(), listed.as_bytes())
+    {
+        resp.add_header(header);
+    }
+    resp
+}
+
+/// 503 response telling the client to wait `retry_after` (rounded up to whole seconds) before retrying.
+/// Every 503 should go through here (or carry `retry_after_header`) so clients get backoff guidance.
+fn unavailable_response(retry_after: Duration) -> tiny_http::Response<std::io::Empty> {
+    tiny_http::Response::empty(503).with_header(retry_after_header(retry_after))
+}
+
+/// `Retry-After` header for `retry_after`, rounded up to whole seconds (at least one).
+fn retry_after_header(retry_after: Duration) -> tiny_http::Header {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    tiny_http::Header::from_bytes(b"Retry-After", secs.max(1).to_string().as_bytes()).unwrap()
+}
+
+/// `ETag` header carrying an entry version.
+fn etag_header(version: u64) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(b"ETag", format!("\"{}\"", version).as_bytes()).unwrap()
+}
+
+/// 422 listing why a value didn't match its key's registered schema.
+fn schema_violation_response(
+    violations: Vec<String>,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let body = serde_json::json!({ "error": "schema violation", "violations": violations });
+    json_response(422, body.to_string())
+}
+
+/// Resolve a single `bytes=` range against a `len`-byte value into inclusive offsets.
+/// Ok(None) means serve the whole value (a range form we don't handle, e.g. several ranges);
+/// Err(()) means nothing in the range exists.
+fn parse_range(value: &str, len: usize) -> Result<Option<(usize, usize)>, ()> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let parsed = match (start.is_empty(), end.is_empty()) {
+        // bytes=-N: the last N bytes
+        (true, false) => match end.parse::<usize>() {
+            Ok(0) | Err(_) => return Err(()),
+            Ok(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+        },
+        // bytes=A-: from A to the end
+        (false, true) => match start.parse::<usize>() {
+            Ok(a) => (a, len.saturating_sub(1)),
+            Err(_) => return Ok(None),
+        },
+        (false, false) => match (start.parse::<usize>(), end.parse::<usize>()) {
+            (Ok(a), Ok(b)) if a <= b => (a, b.min(len.saturating_sub(1))),
+            _ => return Ok(None),
+        },
+        (true, true) => return Ok(None),
+    };
+    if len == 0 || parsed.0 >= len {
+        return Err(());
+    }
+    Ok(Some(parsed))
+}
+
+/// 206 with the requested bytes of `raw`, or 416 if the range is unsatisfiable.
+fn range_response(
+    raw: &str,
+    range: &str,
+    version: u64,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let len = raw.len();
+    match parse_range(range, len) {
+        Ok(Some((start, end))) => {
+            log_body("response", &raw.as_bytes()[start..=end]);
+            let content_range = format!("bytes {}-{}/{}", start, end, len);
+            tiny_http::Response::from_data(raw.as_bytes()[start..=end].to_vec())
+                .with_status_code(206)
+                .with_header(
+                    tiny_http::Header::from_bytes(b"Content-Range", content_range.as_bytes())
+                        .unwrap(),
+                )
+                .with_header(etag_header(version))
+        }
+        Ok(None) => {
+            log_body("response", raw.as_bytes());
+            tiny_http::Response::from_data(raw.as_bytes().to_vec())
+                .with_header(
+                    tiny_http::Header::from_bytes(
+                        b"Content-Type",
+                        b"application/json; charset=utf-8",
+                    )
+                    .unwrap(),
+                )
+                .with_header(etag_header(version))
+        }
+        Err(()) => {
+            let content_range = format!("bytes */{}", len);
+            tiny_http::Response::from_data(Vec::new())
+                .with_status_code(416)
+                .with_header(
+                    tiny_http::Header::from_bytes(b"Content-Range", content_range.as_bytes())
+                        .unwrap(),
+                )
+        }
+    }
+}
+
+/// `Cache-Control` for a GET: HTTP caches may keep write-once values, everything else must revalidate.
+fn cache_control_header(ctx: &ServerContext, immutable: bool) -> tiny_http::Header {
+    let value = if immutable {
+        format!("public, max-age={}", ctx.config.immutable_max_age_s)
+    } else {
+        "no-cache".to_string()
+    };
+    tiny_http::Header::from_bytes(b"Cache-Control", value.as_bytes()).unwrap()
+}
+
+/// Parse an `If-Match` value into the entry version it names.
+/// Returns Err(()) when the value is not a version this node could have issued.
+fn parse_if_match(value: &str) -> Result<u64, ()> {
+    let value = value.trim();
+    let value = value.strip_prefix("W/").unwrap_or(value);
+    value.trim_matches('"').parse().map_err(|_| ())
+}
+
+/// TTL for a write: `X-TTL: MS` if given (0 = never expire), the time left until
+/// `X-Expire-At` (an RFC 3339 timestamp) if that is, else `DEFAULT_TTL_MS`.
+/// Returns Err with the reason when a header doesn't parse, both are sent, or the time has passed.
+fn write_ttl(
+    req: &tiny_http::Request,
+    ctx: &ServerContext,
+) -> Result<Option<Duration>, &'static str> {
+    let ms = match (
+        header_value(req, TTL_HEADER),
+        header_value(req, EXPIRE_AT_HEADER),
+    ) {
+        (Some(_), Some(_)) => return Err("send either X-TTL or X-Expire-At, not both"),
+        (Some(v), None) => v
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| "X-TTL must be a number of milliseconds")?,
+        (None, Some(v)) => {
+            let at = parse_rfc3339(v.trim()).ok_or("X-Expire-At must be an RFC 3339 timestamp")?;
+            // converted to a plain TTL here, so forwards and the owner's expiry work as for X-TTL
+            match at.duration_since(SystemTime::now()) {
+                Ok(left) if left >= Duration::from_millis(1) => return Ok(Some(left)),
+                _ => return Err("X-Expire-At is in the past"),
+            }
+        }
+        (None, None) => ctx.config.default_ttl_ms,
+    };
+    Ok((ms > 0).then(|| Duration::from_millis(ms)))
+}
+
+/// `ttl` as this node stores it: randomly stretched or shrunk by up to `TTL_JITTER_PCT`.
+/// Only the owner calls this, just before storing, so a forwarded write is spread once.
+fn jittered(ctx: &ServerContext, ttl: Option<Duration>) -> Option<Duration> {
+    let pct = ctx.config.ttl_jitter_pct;
+    if pct == 0 {
+        return ttl;
+    }
+    ttl.map(|ttl| {
+        // uniform in [-pct%, +pct%); never down to 0, which would mean "no TTL" once forwarded
+        let factor = 1.0 + (chaos::random_unit(&ctx.ttl_jitter) * 2.0 - 1.0) * pct as f64 / 100.0;
+        ttl.mul_f64(factor).max(Duration::from_millis(1))
+    })
+}
+
+/// Parse an RFC 3339 timestamp such as `2024-06-01T00:00:00Z` or
+/// `2024-06-01T02:00:00.5+02:00`. Returns None if it isn't one.
+fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    let b = s.as_bytes();
+    let num = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = b.get(range)?;
+        digits
+            .iter()
+            .all(u8::is_ascii_digit)
+            .then(|| digits.iter().fold(0, |n, d| n * 10 + i64::from(d - b'0')))
+    };
+    if b.len() < 20
+        || (b[4], b[7], b[13], b[16]) != (b'-', b'-', b':', b':')
+        || !matches!(b[10], b'T' | b't' | b' ')
+    {
+        return None;
+    }
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [
+        31,
+        if leap { 29 } else { 28 },
+        31,
+        30,
+        31,
+        30,
+        31,
+        31,
+        30,
+        31,
+        30,
+        31,
+    ];
+    if !(1..=12).contains(&month)
+        || !(1..=month_days[month as usize - 1]).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // optional fraction, then Z or a +HH:MM / -HH:MM offset
+    let mut rest = &s[19..];
+    let mut nanos = 0u32;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        let padded = format!("{:0<9}", &fraction[..len.min(9)]);
+        nanos = padded.parse().ok()?;
+        rest = &fraction[len..];
+    }
+    let offset = match rest.as_bytes() {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] => {
+            let field = |a: u8, b: u8| -> Option<i64> {
+                (a.is_ascii_digit() && b.is_ascii_digit())
+                    .then(|| i64::from(a - b'0') * 10 + i64::from(b - b'0'))
+            };
+            let (h, m) = (field(*h1, *h2)?, field(*m1, *m2)?);
+            if h > 23 || m > 59 {
+                return None;
+            }
+            let offset = h * 3600 + m * 60;
+            if *sign == b'-' { -offset } else { offset }
+        }
+        _ => return None,
+    };
+
+    // days since 1970-01-01 for the proleptic Gregorian date (Howard Hinnant's days_from_civil)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    // anything before the epoch is long past; report it as the epoch itself
+    let since_epoch = u64::try_from(secs).map_or(Duration::ZERO, |secs| Duration::new(secs, nanos));
+    Some(UNIX_EPOCH + since_epoch)
+}
+
+/// `X-TTL` value that makes the owner apply `ttl` exactly, whatever its own default.
+fn ttl_header_value(ttl: Option<Duration>) -> String {
+    ttl.map_or(0, |ttl| ttl.as_millis()).to_string()
+}
+
+/// When a request's time budget runs out: `X-Timeout-Ms: MS` past now, if the client (or the
+/// peer that forwarded it) gave one. Read it as soon as the request is picked up.
+fn request_deadline(req: &tiny_http::Request) -> Option<Instant> {
+    let ms = header_value(req, TIMEOUT_HEADER)?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(Instant::now() + Duration::from_millis(ms))
+}
+
+/// `X-Timeout-Ms` value handing an owner what's left of `deadline` (0 once it has passed, which
+/// the owner refuses), or empty without a deadline, which the owner ignores.
+fn timeout_header_value(deadline: Option<Instant>) -> String {
+    deadline.map_or(String::new(), |at| {
+        at.saturating_duration_since(Instant::now())
+            .as_millis()
+            .to_string()
+    })
+}
+
+/// Wire format of a value response.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Json,
+    /// What peers ask for with `RPC_MSGPACK` on; clients may too.
+    Msgpack,
+    Cbor,
+}
+
+/// The codec the request's `Accept` header asks for, JSON unless it names msgpack or CBOR.
+fn response_codec(req: &tiny_http::Request) -> Codec {
+    match header_value(req, "Accept") {
+        Some(accept) if accept.contains(MSGPACK) => Codec::Msgpack,
+        Some(accept) if accept.contains(CBOR) => Codec::Cbor,
+        _ => Codec::Json,
+    }
+}
+
+/// Response carrying the JSON document `body`, re-encoded in `codec` if that isn't JSON.
+fn value_response(
+    status: u16,
+    body: String,
+    codec: Codec,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let (content_type, encode): (&str, fn(&Value) -> Vec<u8>) = match codec {
+        Codec::Json => return json_response(status, body),
+        Codec::Msgpack => (MSGPACK, rpc::encode_msgpack),
+        Codec::Cbor => (CBOR, cbor::encode),
+    };
+    match serde_json::from_str::<Value>(&body) {
+        Ok(value) => {
+            log_body("response", body.as_bytes());
+            tiny_http::Response::from_data(encode(&value))
+                .with_status_code(status)
+                .with_header(
+                    tiny_http::Header::from_bytes(b"Content-Type", content_type.as_bytes())
+                        .unwrap(),
+                )
+        }
+        Err(_) => json_response(status, body),
+    }
+}
+
+/// Value of the first request header called `name`, if present.
+fn header_value(req: &tiny_http::Request, name: &str) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+/// Who asked for a change: the client a peer forwarded it for, else the connection's address.
+/// `X-Forwarded-For` is only believed on requests a peer forwarded, so a client can't name
+/// someone else in the audit log.
+fn client_id(req: &tiny_http::Request) -> String {
+    let forwarded_for = header_value(req, FORWARDED_HEADER)
+        .and_then(|_| header_value(req, CLIENT_HEADER))
+        .filter(|client| !client.is_empty());
+    match forwarded_for {
+        Some(client) => client,
+        None => req.remote_addr().to_string(),
+    }
+}
+
+/// Append a change to the audit log, if this node keeps one.
+fn audit(ctx: &ServerContext, client: &str, op: &str, skey: &str, old: bool, new: bool) {
+    if let Some(log) = &ctx.audit {
+        log.record(client, op, skey, old, new);
+    }
+}
+
+/// Why a write was refused, whichever front end it came in on. HTTP answers it with
+/// `refusal_response`; other front ends go by its status and message.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Refusal {
+    /// The node is read-only (`READ_ONLY` or POST /admin/readonly).
+    ReadOnly,
+    /// The key is longer than `MAX_KEY_BYTES`.
+    KeyTooLong,
+    /// The value is larger than `MAX_VALUE_BYTES`.
+    TooLarge,
+    /// A null value under `NULL_VALUES=reject`.
+    NullValue,
+    /// The value doesn't match its key's schema, for the reasons given.
+    Schema(Vec<String>),
+    /// `If-Match` named a version the entry isn't at.
+    VersionMismatch,
+    /// The namespace is at its quota.
+    QuotaExceeded,
+    /// A list operation found a value that isn't an array.
+    NotAnArray,
+}
+
+impl Refusal {
+    /// The HTTP status the refusal is answered with.
+    pub(crate) fn status(&self) -> u16 {
+        match self {
+            Refusal::ReadOnly => 503,
+            Refusal::KeyTooLong | Refusal::NullValue => 400,
+            Refusal::TooLarge => 413,
+            Refusal::Schema(_) => 422,
+            Refusal::VersionMismatch => 412,
+            Refusal::QuotaExceeded => 507,
+            Refusal::NotAnArray => 409,
+        }
+    }
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::ReadOnly => write!(f, "read-only"),
+            Refusal::KeyTooLong => write!(f, "key too long"),
+            Refusal::TooLarge => write!(f, "value too large"),
+            Refusal::NullValue => write!(f, "null values are not accepted"),
+            Refusal::Schema(violations) => write!(f, "schema violation: {}", violations.join("; ")),
+            Refusal::VersionMismatch => write!(f, "version mismatch"),
+            Refusal::QuotaExceeded => write!(f, "{}", QUOTA_EXCEEDED),
+            Refusal::NotAnArray => write!(f, "value is not an array"),
+        }
+    }
+}
+
+impl From<WriteError> for Refusal {
+    fn from(e: WriteError) -> Self {
+        match e {
+            WriteError::VersionMismatch { .. } => Refusal::VersionMismatch,
+            WriteError::QuotaExceeded => Refusal::QuotaExceeded,
+            WriteError::NotAnArray => Refusal::NotAnArray,
+            WriteError::TooLarge => Refusal::TooLarge,
+            WriteError::Rejected(violations) => Refusal::Schema(violations),
+        }
+    }
+}
+
+/// HTTP answer to a refused write.
+fn refusal_response(
+    ctx: &ServerContext,
+    refusal: &Refusal,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let status = refusal.status();
+    match refusal {
+        Refusal::ReadOnly => {
+            let body = serde_json::json!({ "error": "read-only" }).to_string();
+            // maintenance windows last a while; have clients back off for a few seconds
+            json_response(status, body).with_header(retry_after_header(Duration::from_secs(5)))
+        }
+        Refusal::KeyTooLong | Refusal::NullValue => {
+            bad_request(ctx, serde_json::json!({ "error": refusal.to_string() }))
+        }
+        Refusal::TooLarge | Refusal::VersionMismatch => {
+            tiny_http::Response::from_data(Vec::new()).with_status_code(status)
+        }
+        Refusal::Schema(violations) => schema_violation_response(violations.clone()),
+        Refusal::QuotaExceeded | Refusal::NotAnArray => {
+            let body = serde_json::json!({ "error": refusal.to_string() });
+            json_response(status, body.to_string())
+        }
+    }
+}
+
+/// Checks a write gets on whichever node it arrives at, before it's applied or forwarded:
+/// read-only, `MAX_KEY_BYTES`, `MAX_VALUE_BYTES` and `NULL_VALUES=reject`. `value` is None for
+/// a delete.
+pub(crate) fn check_write(
+    ctx: &ServerContext,
+    key: &str,
+    value: Option<&Value>,
+) -> Result<(), Refusal> {
+    if ctx.read_only.load(Ordering::SeqCst) {
+        return Err(Refusal::ReadOnly);
+    }
+    check_key(ctx, key)?;
+    let Some(value) = value else {
+        return Ok(());
+    };
+    let value_len = serde_json::to_string(value).map(|s| s.len()).unwrap_or(0);
+    if value_len > ctx.config.max_value_bytes {
+        return Err(Refusal::TooLarge);
+    }
+    if value.is_null() && ctx.config.null_values == NullValues::Reject {
+        return Err(Refusal::NullValue);
+    }
+    Ok(())
+}
+
+/// Whether `key` is short enough to read or write: at most `MAX_KEY_BYTES`.
+pub(crate) fn check_key(ctx: &ServerContext, key: &str) -> Result<(), Refusal> {
+    if key.len() > ctx.config.max_key_bytes {
+        return Err(Refusal::KeyTooLong);
+    }
+    Ok(())
+}
+
+/// The TTL a write that asks for none gets: `DEFAULT_TTL_MS`, if set.
+pub(crate) fn default_ttl(ctx: &ServerContext) -> Option<Duration> {
+    let ms = ctx.config.default_ttl_ms;
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+/// Store `value` under `key`, which this node owns, once `check_write` passed it: applies
+/// `NULL_VALUES=delete`, the key's schema and TTL jitter, and records the change in the audit
+/// log under `client`. `opts.ttl` is the TTL asked for, else `default_ttl`. Returns the entry's
+/// new version, or None when a null deleted the key.
+pub(crate) fn set_local(
+    ctx: &ServerContext,
+    client: &str,
+    key: &str,
+    value: Value,
+    opts: SetOptions,
+) -> Result<Option<u64>, Refusal> {
+    let skey = storage_key(opts.namespace.as_deref(), key);
+    // before the schema check: a null that deletes the key stores nothing for it to judge
+    if value.is_null() && ctx.config.null_values == NullValues::Delete {
+        // a one-op batch, so If-Match still guards the delete
+        let op = TxnOp {
+            key: skey.clone(),
+            value: None,
+            if_version: opts.if_version,
+            namespace: opts.namespace,
+            ttl: None,
+            immutable: false,
+            headers: Vec::new(),
+        };
+        let results = ctx
+            .store
+            .transact(vec![op])
+            .map_err(|(_, e)| Refusal::from(e))?;
+        audit(ctx, client, "delete", &skey, results[0].1, false);
+        return Ok(None);
+    }
+    ctx.schemas.check(key, &value).map_err(Refusal::Schema)?;
+    let opts = SetOptions {
+        ttl: jittered(ctx, opts.ttl),
+        ..opts
+    };
+    let (version, replaced) = ctx.store.set_with(skey.clone(), value, opts)?;
+    audit(ctx, client, "set", &skey, replaced, true);
+    Ok(Some(version))
+}
+
+/// Delete `skey`, which this node owns, if it's at `if_version` when one is given, recording
+/// the change in the audit log under `client`. Returns whether the key was present.
+pub(crate) fn delete_local(
+    ctx: &ServerContext,
+    client: &str,
+    skey: &str,
+    if_version: Option<u64>,
+) -> Result<bool, Refusal> {
+    let removed = ctx.store.delete_if(skey, if_version)? == 1;
+    audit(ctx, client, "delete", skey, removed, false);
+    Ok(removed)
+}
+
+/// Whether the request's headers exceed `MAX_HEADERS` or `MAX_HEADER_BYTES`.
+fn headers_too_large(req: &tiny_http::Request, config: &Config) -> bool {
+    let headers = req.headers();
+    if config.max_headers > 0 && headers.len() > config.max_headers {
+        return true;
+    }
+    // each line as sent: "Name: value\r\n"
+    let bytes: usize = headers
+        .iter()
+        .map(|h| h.field.as_str().len() + h.value.len() + 4)
+        .sum();
+    config.max_header_bytes > 0 && bytes > config.max_header_bytes
+}
+
+/// Storage key for `key`, prefixed with the namespace when one is given.
+fn storage_key(namespace: Option<&str>, key: &str) -> String {
+    match namespace {
+        Some(ns) => Cache::namespaced_key(ns, key),
+        None => key.to_string(),
+    }
+}
+
+/// URL of `path` on `owner`, keeping the namespace so the owner stores under the same key.
+fn peer_url(owner: &str, namespace: Option<&str>, path: &str) -> String {
+    match namespace {
+        Some(ns) => format!("http://{}/ns/{}/{}", owner, ns, path),
+        None => format!("http://{}/{}", owner, path),
+    }
+}
+
+/// A slot for forwarding `req` to `owner`, queueing up to `PEER_QUEUE_WAIT` for one. If the
+/// owner is at its RPC limit or backed off, sheds `req` with 503 and returns None.
+fn peer_permit<'a>(
+    req: tiny_http::Request,
+    ctx: &'a ServerContext,
+    owner: &str,
+) -> Option<(tiny_http::Request, PeerPermit<'a>)> {
+    match ctx.peer_limits.acquire(owner, PEER_QUEUE_WAIT) {
+        Some(permit) => Some((req, permit)),
+        None => {
+            eprintln!(
+                "{}: {} is at its RPC limit or backed off — shedding",
+                ctx.name, owner
+            );
+            respond(req, unavailable_response(PEER_QUEUE_WAIT));
+            None
+        }
+    }
+}
+
+/// 307 sending the client to `url` on a key's owner, keeping the method and body.
+fn redirect_response(url: &str) -> tiny_http::Response<std::io::Empty> {
+    tiny_http::Response::empty(307)
+        .with_header(tiny_http::Header::from_bytes(b"Location", url.as_bytes()).unwrap())
+}
+
+/// Handle POST / - write/update cache
+fn handle_post(req: tiny_http::Request, ctx: &ServerContext, namespace: Option<&str>) {
+    let mut req = req; // Mutable needed for as_reader()
+    let mut timer = ctx.metrics.start(Op::Post);
+    let deadline = request_deadline(&req);
+
+    // Read and parse the JSON object (peers may send msgpack)
+    let map: serde_json::Map<String, Value> = match read_body(&mut req, ctx) {
+        Ok(m) => m,
+        Err(e) => {
+            respond(req, bad_request(ctx, decode_error_detail(&e)));
+            return;
+        }
+    };
+
+    // Validate single key constraint
+    if map.len() != 1 {
+        respond(
+            req,
+            bad_request(
+                ctx,
+                serde_json::json!({ "error": "body must be an object with exactly one key" }),
+            ),
+        );
+        return;
+    }
+
+    let (key, value) = map.into_iter().next().unwrap();
+    // Refuse what the owner would refuse anyway before storing or forwarding
+    if let Err(refusal) = check_write(ctx, &key, Some(&value)) {
+        respond(req, refusal_response(ctx, &refusal));
+        return;
+    }
+
+    let idempotency_key = header_value(&req, "Idempotency-Key");
+    let if_match = header_value(&req, "If-Match");
+    let immutable = header_value(&req, "X-Immutable").is_some_and(|v| v.trim() == "true");
+    let client = client_id(&req);
+    let ttl = match write_ttl(&req, ctx) {
+        Ok(ttl) => ttl,
+        Err(error) => {
+            respond(req, bad_request(ctx, serde_json::json!({ "error": error })));
+            return;
+        }
+    };
+    let store_headers_value = header_value(&req, STORE_HEADERS_HEADER);
+    let stored_headers = match store_headers_value.as_deref().map(parse_store_headers) {
+        None => Vec::new(),
+        Some(Ok(headers)) => headers,
+        Some(Err(error)) => {
+            respond(req, bad_request(ctx, serde_json::json!({ "error": error })));
+            return;
+        }
+    };
+
+    let skey = storage_key(namespace, &key);
+    match ctx.router.resolve(&skey) {
+        Ownership::Local => {
+            timer.route(Route::Local, &skey, ctx.router.self_addr());
+            note_owner(ctx.router.self_addr());
+            let if_version = match if_match.as_deref().map(parse_if_match) {
+                None => None,
+                Some(Ok(version)) => Some(version),
+                Some(Err(())) => {
+                    respond(req, tiny_http::Response::empty(412));
+                    return;
+                }
+            };
+            // fingerprinted as the same logical request whether the client or a peer sent it
+            let keyed = idempotency_key.as_ref().map(|ik| {
+                let body = serde_json::json!({ &key: value }).to_string();
+                let fingerprint = idempotency::fingerprint(&[
+                    b"POST",
+                    namespace.unwrap_or("").as_bytes(),
+                    b"/",
+                    body.as_bytes(),
+                ]);
+                (storage_key(namespace, ik), fingerprint)
+            });
+            // Store locally; a repeated Idempotency-Key replays the first result instead
+            let apply = || {
+                let response_body =
+                    serde_json::to_string(&serde_json::json!({&key: value})).unwrap();
+                let opts = SetOptions {
+                    namespace: namespace.map(str::to_string),
+                    if_version,
+                    immutable,
+                    ttl,
+                    headers: stored_headers.clone(),
+                    ..SetOptions::default()
+                };
+                set_local(ctx, &client, &key, value, opts).map(|version| (response_body, version))
+            };
+            let outcome = match keyed {
+                Some((ik, fingerprint)) => ctx.idempotency.run(&ik, fingerprint, apply),
+                None => Ok(apply()),
+            };
+            match outcome {
+                Ok(Ok((text, Some(version)))) => {
+                    let resp = value_response(200, text, response_codec(&req));
+                    respond(req, resp.with_header(etag_header(version)));
+                }
+                // a null that deleted the key: there's no entry to tag
+                Ok(Ok((text, None))) => {
+                    let resp = value_response(200, text, response_codec(&req));
+                    respond(req, resp);
+                }
+                Ok(Err(refusal)) => {
+                    respond(req, refusal_response(ctx, &refusal));
+                }
+                Err(KeyReused) => {
+                    let detail = serde_json::json!({
+                        "error": "Idempotency-Key was already used for a different request"
+                    });
+                    respond(req, json_response(422, detail.to_string()));
+                }
+            }
+        }
+        Ownership::Remote(owner) => {
+            timer.route(Route::Forwarded, &skey, owner);
+            note_owner(owner);
+            // Forward to owner, which records the Idempotency-Key result and checks If-Match
+            let url = peer_url(owner, namespace, "");
+            if ctx.config.routing_mode == RoutingMode::Redirect {
+                respond(req, redirect_response(&url));
+                return;
+            }
+            let ttl_ms = ttl_header_value(ttl);
+            let timeout_ms = timeout_header_value(deadline);
+            let mut headers: Vec<(&str, &str)> = vec![
+                (FORWARDED_HEADER, "1"),
+                (CLIENT_HEADER, &client),
+                (TTL_HEADER, &ttl_ms),
+                (TIMEOUT_HEADER, &timeout_ms),
+            ];
+            if let Some(ik) = &idempotency_key {
+                headers.push(("Idempotency-Key", ik));
+            }
+            if let Some(im) = &if_match {
+                headers.push(("If-Match", im));
+            }
+            if immutable {
+                headers.push(("X-Immutable", "true"));
+            }
+            if let Some(stored) = &store_headers_value {
+                headers.push((STORE_HEADERS_HEADER, stored));
+            }
+            let envelope = serde_json::json!({ key: value });
+            let body = if ctx.config.rpc_msgpack {
+                headers.push(("Content-Type", MSGPACK));
+                headers.push(("Accept", MSGPACK));
+                rpc::encode_msgpack(&envelope)
+            } else {
+                envelope.to_string().into_bytes()
+            };
+            let Some((req, permit)) = peer_permit(req, ctx, owner) else {
+                return;
+            };
+            // Repeating a plain set is harmless; a conditional one would trip over its own write
+            let idempotent = if_match.is_none();
+            let attempts = ctx.config.rpc_attempts;
+            match rpc_post_with_retry(&*ctx.transport, &url, &body, &headers, attempts, idempotent)
+                .inspect(|reply| permit.observe(reply))
+            {
+                Ok(reply) => {
+                    let codec = response_codec(&req);
+                    respond(req, forwarded_response(reply, false, codec));
+                }
+                Err(_) => {
+                    eprintln!("{}: RPC POST to {} failed after retries", ctx.name, url);
+                    respond(req, tiny_http::Response::empty(502));
+                }
+            }
+        }
+    }
+}
+
+/// Handle GET /{key} - read from cache
+fn handle_get(
+    req: tiny_http::Request,
+    ctx: &ServerContext,
+    namespace: Option<&str>,
+    key: &str,
+    query: &str,
+) {
+    let mut timer = ctx.metrics.start(Op::Get);
+    let deadline = request_deadline(&req);
+    let pretty = wants_pretty(query);
+    // ?meta=true wraps the value as {"value": ..., "meta": {...}}
+    let with_meta = matches!(query_param(query, "meta"), Some("true" | "1"));
+    // ?default=V answers an absent key with 200 {"key": V} instead of 404, without storing it;
+    // V is JSON, or taken as a string if it doesn't parse. ?missing=null means ?default=null.
+    let default_param = query_param(query, "default");
+    let fallback = match (default_param, query_param(query, "missing")) {
+        (Some(raw), _) => match rpc::decode_key(raw) {
+            Ok(text) => Some(serde_json::from_str(&text).unwrap_or(Value::String(text))),
+            Err(()) => {
+                respond(req, tiny_http::Response::empty(400));
+                return;
+            }
+        },
+        (None, Some("null")) => Some(Value::Null),
+        _ => None,
+    };
+    if key.is_empty() {
+        respond(req, tiny_http::Response::empty(400));
+        return;
+    }
+    if key.len() > ctx.config.max_key_bytes {
+        respond(req, tiny_http::Response::empty(414));
+        return;
+    }
+    if let Some(prefix) = key.strip_suffix('*') {
+        handle_wildcard_get(req, ctx, namespace, key, prefix, pretty);
+        return;
+    }
+
+    // Range: bytes=... selects bytes of the value's serialized JSON (not the {"key": value} envelope)
+    let range = header_value(&req, "Range");
+
+    // ?wait=MS long-polls: block until the key is written or the wait runs out
+    let wait_ms = match query_param(query, "wait").map(str::parse::<u64>) {
+        None => None,
+        Some(Ok(ms)) => Some(ms.min(ctx.config.max_wait_ms)),
+        Some(Err(_)) => {
+            respond(req, tiny_http::Response::empty(400));
+            return;
+        }
+    };
+
+    let skey = storage_key(namespace, key);
+    match ctx.router.resolve(&skey) {
+        Ownership::Local => {
+            timer.route(Route::Local, &skey, ctx.router.self_addr());
+            note_owner(ctx.router.self_addr());
+            // Local lookup
+            let found = match wait_ms {
+                Some(ms) => ctx.store.wait_for_raw(&skey, Duration::from_millis(ms)),
+                None => ctx.store.get_raw(&skey),
+            };
+            match (found, &range) {
+                (Some(entry), Some(range)) => {
+                    let resp = range_response(&entry.raw, range, entry.version);
+                    respond(req, with_stored_headers(resp, &entry.headers));
+                }
+                (Some(entry), None) => {
+                    // splice the stored JSON in directly rather than parsing and re-serializing it
+                    let response_body = if with_meta {
+                        let meta = serde_json::json!({
+                            "version": entry.version,
+                            "etag": format!("\"{}\"", entry.version),
+                            "bytes": entry.raw.len(),
+                            "immutable": entry.immutable,
+                            "headers": stored_headers_json(&entry.headers),
+                            "ttl_ms": entry.expires_at.map(|at| {
+                                at.saturating_duration_since(Instant::now()).as_millis() as u64
+                            }),
+                        });
+                        format!("{{\"value\":{},\"meta\":{}}}", entry.raw, meta)
+                    } else {
+                        format!("{{{}:{}}}", Value::from(key), entry.raw)
+                    };
+                    let response_body = pretty_json(response_body, pretty);
+                    let resp = value_response(200, response_body, response_codec(&req))
+                        .with_header(etag_header(entry.version))
+                        .with_header(cache_control_header(ctx, entry.immutable))
+                        .with_header(
+                            tiny_http::Header::from_bytes(b"Accept-Ranges", b"bytes").unwrap(),
+                        );
+                    respond(req, with_stored_headers(resp, &entry.headers));
+                }
+                (None, _) => match fallback {
+                    Some(fallback) => {
+                        let body = serde_json::json!({ key: fallback }).to_string();
+                        let resp =
+                            value_response(200, pretty_json(body, pretty), response_codec(&req));
+                        respond(req, resp);
+                    }
+                    None => {
+                        respond(req, tiny_http::Response::empty(404));
+                    }
+                },
+            }
+        }
+        Ownership::Remote(owner) => {
+            timer.route(Route::Forwarded, &skey, owner);
+            note_owner(owner);
+            if ctx.config.routing_mode == RoutingMode::Redirect {
+                let path = match query {
+                    "" => rpc::encode_key(key),
+                    query => format!("{}?{}", rpc::encode_key(key), query),
+                };
+                respond(req, redirect_response(&peer_url(owner, namespace, &path)));
+                return;
+            }
+            // Forward to owner; a long poll waits there, so give the RPC time to match
+            let mut params = Vec::new();
+            if let Some(ms) = wait_ms {
+                params.push(format!("wait={}", ms));
+            }
+            if with_meta {
+                params.push("meta=true".to_string());
+            }
+            match default_param {
+                Some(raw) => params.push(format!("default={}", raw)),
+                None if fallback.is_some() => params.push("missing=null".to_string()),
+                None => {}
+            }
+            let url = if params.is_empty() {
+                peer_url(owner, namespace, &rpc::encode_key(key))
+            } else {
+                let path = format!("{}?{}", rpc::encode_key(key), params.join("&"));
+                peer_url(owner, namespace, &path)
+            };
+            let timeout = wait_ms.map(|ms| Duration::from_millis(ms + 1000));
+            let timeout_ms = timeout_header_value(deadline);
+            let mut headers: Vec<(&str, &str)> = vec![(TIMEOUT_HEADER, &timeout_ms)];
+            if ctx.config.rpc_msgpack {
+                headers.push(("Accept", MSGPACK));
+            }
+            if let Some(range) = &range {
+                headers.push(("Range", range));
+            }
+            let Some((req, permit)) = peer_permit(req, ctx, owner) else {
+                return;
+            };
+            match rpc_get_with_retry(
+                &*ctx.transport,
+                &url,
+                &headers,
+                timeout,
+                ctx.config.rpc_attempts,
+            )
+            .inspect(|reply| permit.observe(reply))
+            {
+                Ok(reply) if reply.status == 200 => {
+                    let codec = response_codec(&req);
+                    respond(req, forwarded_response(reply, pretty, codec));
+                }
+                Ok(reply) if matches!(reply.status, 206 | 416) => {
+                    respond(req, forwarded_response(reply, false, Codec::Json));
+                }
+                Ok(_) | Err(_) => {
+                    // Any non-200 or failure → 404 (hide internal errors from client)
+                    eprintln!("{}: RPC GET to {} failed — returning 404", ctx.name, url);
+                    respond(req, tiny_http::Response::empty(404));
+                }
+            }
+        }
+    }
+}
+
+/// Handle DELETE /{prefix}* - remove every key starting with `prefix` from all nodes, reporting
+/// `{"deleted": N, "nodes": {addr: n}}` (207 with a null count for any node that didn't answer)
+fn handle_prefix_delete(
+    req: tiny_http::Request,
+    ctx: &ServerContext,
+    namespace: Option<&str>,
+    pattern: &str,
+    prefix: &str,
+) {
+    let deadline = request_deadline(&req);
+    // an empty prefix would wipe the cluster
+    if prefix.is_empty() {
+        let detail = serde_json::json!({ "error": "prefix must not be empty" });
+        respond(req, bad_request(ctx, detail));
+        return;
+    }
+    let client = client_id(&req);
+    let removed = ctx
+        .store
+        .delete_prefix(&storage_key(namespace, prefix), namespace);
+    for skey in &removed {
+        audit(ctx, &client, "delete", skey, true, false);
+    }
+    let mut deleted = removed.len() as u64;
+    let mut nodes = serde_json::Map::new();
+    nodes.insert(ctx.router.self_addr().to_string(), Value::from(deleted));
+
+    // matching keys live on every owner (and strays anywhere), so each peer clears its own;
+    // a forwarded delete stays local
+    let mut complete = true;
+    if header_value(&req, FORWARDED_HEADER).is_none() {
+        for peer in ctx.router.others() {
+            let url = peer_url(peer, namespace, &rpc::encode_key(pattern));
+            let timeout_ms = timeout_header_value(deadline);
+            let headers = [
+                (FORWARDED_HEADER, "1"),
+                (CLIENT_HEADER, client.as_str()),
+                (TIMEOUT_HEADER, timeout_ms.as_str()),
+            ];
+            let count =
+                rpc_delete_with_retry(&*ctx.transport, &url, &headers, ctx.config.rpc_attempts)
+                    .ok()
+                    .filter(|r| r.status == 200)
+                    .and_then(|r| r.value().ok())
+                    .and_then(|report| report["deleted"].as_u64());
+            match count {
+                Some(n) => deleted += n,
+                None => {
                     eprintln!(
-                        "RPC GET to {} attempt {} got {} — retrying",
-                        url,
-                        i + 1,
-                        status
+                        "{}: RPC DELETE to {} failed — prefix delete incomplete",
+                        ctx.name, url
                     );
-                } else {
-                    return Ok((status, body));
+                    complete = false;
                 }
             }
-            Err(ureq::Error::Status(code, resp)) => {
-                let body = resp.into_string().unwrap_or_default();
-                if code >= 500 {
+            nodes.insert(peer.clone(), count.map_or(Value::Null, Value::from));
+        }
+    }
+    let status = if complete { 200 } else { 207 };
+    let report = serde_json::json!({ "deleted": deleted, "nodes": nodes });
+    respond(req, json_response(status, report.to_string()));
+}
+
+/// Handle GET /{prefix}* - every key starting with `prefix`, gathered from all nodes into one object
+fn handle_wildcard_get(
+    req: tiny_http::Request,
+    ctx: &ServerContext,
+    namespace: Option<&str>,
+    pattern: &str,
+    prefix: &str,
+    pretty: bool,
+) {
+    let deadline = request_deadline(&req);
+    let skey_prefix = storage_key(namespace, prefix);
+    let strip = skey_prefix.len() - prefix.len();
+    let mut found: serde_json::Map<String, Value> = ctx
+        .store
+        .scan_prefix(&skey_prefix, namespace)
+        .into_iter()
+        .map(|(skey, value)| (skey[strip..].to_string(), value))
+        .collect();
+
+    // Keys hash anywhere, so ask every peer for its local matches; a forwarded scan stays local
+    if header_value(&req, FORWARDED_HEADER).is_none() {
+        for peer in ctx.router.others() {
+            let url = peer_url(peer, namespace, &rpc::encode_key(pattern));
+            let timeout_ms = timeout_header_value(deadline);
+            let reply = rpc_get_with_retry(
+                &*ctx.transport,
+                &url,
+                &[(FORWARDED_HEADER, "1"), (TIMEOUT_HEADER, &timeout_ms)],
+                None,
+                ctx.config.rpc_attempts,
+            )
+            .ok()
+            .filter(|r| r.status == 200)
+            .and_then(|r| r.value().ok());
+            match reply {
+                Some(Value::Object(matches)) => found.extend(matches),
+                _ => {
+                    // a partial answer would look like those keys don't exist
                     eprintln!(
-                        "RPC GET to {} attempt {} got {} — retrying",
-                        url,
-                        i + 1,
-                        code
+                        "{}: RPC GET to {} failed — wildcard incomplete",
+                        ctx.name, url
                     );
-                } else {
-                    // forward non-5xx (e.g., 404) immediately
-                    return Ok((code as u16, body));
+                    respond(req, tiny_http::Response::empty(502));
+                    return;
                 }
             }
-            Err(e) => {
-                eprintln!("RPC GET to {} attempt {} failed: {}", url, i + 1, e);
+        }
+    }
+    let body = pretty_json(Value::Object(found).to_string(), pretty);
+    respond(req, json_response(200, body));
+}
+
+/// Handle DELETE /{key} - remove from cache
+fn handle_delete(req: tiny_http::Request, ctx: &ServerContext, namespace: Option<&str>, key: &str) {
+    let mut timer = ctx.metrics.start(Op::Delete);
+    let deadline = request_deadline(&req);
+    if key.is_empty() {
+        respond(req, tiny_http::Response::empty(400));
+        return;
+    }
+    match check_write(ctx, key, None) {
+        Ok(()) => {}
+        // the key is the whole path here
+        Err(Refusal::KeyTooLong) => {
+            respond(req, tiny_http::Response::empty(414));
+            return;
+        }
+        Err(refusal) => {
+            respond(req, refusal_response(ctx, &refusal));
+            return;
+        }
+    }
+    if let Some(prefix) = key.strip_suffix('*') {
+        handle_prefix_delete(req, ctx, namespace, key, prefix);
+        return;
+    }
+
+    let if_match = header_value(&req, "If-Match");
+    let skey = storage_key(namespace, key);
+    match ctx.router.resolve(&skey) {
+        Ownership::Local => {
+            timer.route(Route::Local, &skey, ctx.router.self_addr());
+            note_owner(ctx.router.self_addr());
+            let if_version = match if_match.as_deref().map(parse_if_match) {
+                None => None,
+                Some(Ok(version)) => Some(version),
+                Some(Err(())) => {
+                    respond(req, tiny_http::Response::empty(412));
+                    return;
+                }
+            };
+            // Local delete; If-Match is checked under the same shard lock
+            match delete_local(ctx, &client_id(&req), &skey, if_version) {
+                Ok(removed) => {
+                    respond(req, json_response(200, u8::from(removed).to_string()));
+                }
+                Err(refusal) => {
+                    respond(req, refusal_response(ctx, &refusal));
+                }
+            }
+        }
+        Ownership::Remote(owner) => {
+            timer.route(Route::Forwarded, &skey, owner);
+            note_owner(owner);
+            // Forward to owner
+            let url = peer_url(owner, namespace, &rpc::encode_key(key));
+            if ctx.config.routing_mode == RoutingMode::Redirect {
+                respond(req, redirect_response(&url));
+                return;
+            }
+            let Some((req, permit)) = peer_permit(req, ctx, owner) else {
+                return;
+            };
+            let client = client_id(&req);
+            let timeout_ms = timeout_header_value(deadline);
+            let mut headers = vec![
+                (FORWARDED_HEADER, "1"),
+                (CLIENT_HEADER, client.as_str()),
+                (TIMEOUT_HEADER, timeout_ms.as_str()),
+            ];
+            if let Some(im) = &if_match {
+                headers.push(("If-Match", im));
+            }
+            // a conditional delete whose reply was lost would fail its own retry with 412
+            let attempts = if if_match.is_some() {
+                1
+            } else {
+                ctx.config.rpc_attempts
+            };
+            match rpc_delete_with_retry(&*ctx.transport, &url, &headers, attempts)
+                .inspect(|reply| permit.observe(reply))
+            {
+                Ok(reply) => {
+                    respond(req, forwarded_response(reply, false, Codec::Json));
+                }
+                Err(_) => {
+                    eprintln!("{}: RPC DELETE to {} failed after retries", ctx.name, url);
+                    respond(req, tiny_http::Response::empty(502));
+                }
+            }
+        }
+    }
+}
+
+/// Array operations on a single key, named by their route (`POST /{op}/{key}`).
+#[derive(Clone, Copy)]
+enum ListOp {
+    /// Append the body to the array.
+    Push,
+    /// Remove every element equal to the body.
+    Remove,
+}
+
+impl ListOp {
+    fn route(self) -> &'static str {
+        match self {
+            ListOp::Push => "push",
+            ListOp::Remove => "lrem",
+        }
+    }
+}
+
+/// Handle POST /push/{key} and POST /lrem/{key} - append to or remove from an array value
+fn handle_list(
+    req: tiny_http::Request,
+    ctx: &ServerContext,
+    namespace: Option<&str>,
+    key: &str,
+    op: ListOp,
+) {
+    let mut req = req;
+    let mut timer = ctx.metrics.start(Op::Post);
+    let deadline = request_deadline(&req);
+    if key.is_empty() {
+        respond(req, tiny_http::Response::empty(400));
+        return;
+    }
+    if key.len() > ctx.config.max_key_bytes {
+        respond(req, tiny_http::Response::empty(414));
+        return;
+    }
+    let item: Value = match read_body(&mut req, ctx) {
+        Ok(item) => item,
+        Err(e) => {
+            respond(req, bad_request(ctx, decode_error_detail(&e)));
+            return;
+        }
+    };
+    if item.to_string().len() > ctx.config.max_value_bytes {
+        respond(req, tiny_http::Response::empty(413));
+        return;
+    }
+
+    let idempotency_key = header_value(&req, "Idempotency-Key");
+    let client = client_id(&req);
+    let ttl = match write_ttl(&req, ctx) {
+        Ok(ttl) => ttl,
+        Err(error) => {
+            respond(req, bad_request(ctx, serde_json::json!({ "error": error })));
+            return;
+        }
+    };
+    let skey = storage_key(namespace, key);
+    match ctx.router.resolve(&skey) {
+        Ownership::Local => {
+            timer.route(Route::Local, &skey, ctx.router.self_addr());
+            note_owner(ctx.router.self_addr());
+            let keyed = idempotency_key.as_ref().map(|ik| {
+                let path = format!("/{}/{}", op.route(), key);
+                let fingerprint = idempotency::fingerprint(&[
+                    b"POST",
+                    namespace.unwrap_or("").as_bytes(),
+                    path.as_bytes(),
+                    item.to_string().as_bytes(),
+                ]);
+                (storage_key(namespace, ik), fingerprint)
+            });
+            // a repeated Idempotency-Key replays the first result, so a retried append lands once
+            let apply = || match op {
+                ListOp::Push => {
+                    let opts = SetOptions {
+                        namespace: namespace.map(str::to_string),
+                        ttl: jittered(ctx, ttl),
+                        ..SetOptions::default()
+                    };
+                    // the whole grown array is checked, as a set of it would be
+                    let check = |array: &Value, bytes: usize| {
+                        if bytes > ctx.config.max_value_bytes {
+                            return Err(WriteError::TooLarge);
+                        }
+                        ctx.schemas.check(key, array).map_err(WriteError::Rejected)
+                    };
+                    ctx.store
+                        .push_with(&skey, item, opts, check)
+                        .map(|(len, version, existed)| {
+                            audit(ctx, &client, op.route(), &skey, existed, true);
+                            let body = serde_json::json!({ "length": len });
+                            (body.to_string(), Some(version))
+                        })
+                        .map_err(Refusal::from)
+                }
+                ListOp::Remove => ctx
+                    .store
+                    .list_remove(&skey, &item)
+                    .map(|removed| {
+                        if removed > 0 {
+                            audit(ctx, &client, op.route(), &skey, true, true);
+                        }
+                        (serde_json::json!({ "removed": removed }).to_string(), None)
+                    })
+                    .map_err(Refusal::from),
+            };
+            let outcome = match keyed {
+                Some((ik, fingerprint)) => ctx.idempotency.run(&ik, fingerprint, apply),
+                None => Ok(apply()),
+            };
+            match outcome {
+                Ok(Ok((body, version))) => {
+                    let resp = value_response(200, body, response_codec(&req));
+                    match version {
+                        Some(version) => respond(req, resp.with_header(etag_header(version))),
+                        None => respond(req, resp),
+                    }
+                }
+                Ok(Err(refusal)) => {
+                    respond(req, refusal_response(ctx, &refusal));
+                }
+                Err(KeyReused) => {
+                    let detail = serde_json::json!({
+                        "error": "Idempotency-Key was already used for a different request"
+                    });
+                    respond(req, json_response(422, detail.to_string()));
+                }
+            }
+        }
+        Ownership::Remote(owner) => {
+            timer.route(Route::Forwarded, &skey, owner);
+            note_owner(owner);
+            let url = peer_url(
+                owner,
+                namespace,
+                &format!("{}/{}", op.route(), rpc::encode_key(key)),
+            );
+            if ctx.config.routing_mode == RoutingMode::Redirect {
+                respond(req, redirect_response(&url));
+                return;
+            }
+            let ttl_ms = ttl_header_value(ttl);
+            let timeout_ms = timeout_header_value(deadline);
+            let mut headers: Vec<(&str, &str)> = vec![
+                (FORWARDED_HEADER, "1"),
+                (CLIENT_HEADER, &client),
+                (TTL_HEADER, &ttl_ms),
+                (TIMEOUT_HEADER, &timeout_ms),
+            ];
+            // the owner dedupes by it, which also lets a forwarded append retry
+            if let Some(ik) = &idempotency_key {
+                headers.push(("Idempotency-Key", ik));
+            }
+            let body = if ctx.config.rpc_msgpack {
+                headers.push(("Content-Type", MSGPACK));
+                headers.push(("Accept", MSGPACK));
+                rpc::encode_msgpack(&item)
+            } else {
+                item.to_string().into_bytes()
+            };
+            let Some((req, permit)) = peer_permit(req, ctx, owner) else {
+                return;
+            };
+            // Removing elements twice changes nothing; appending twice would duplicate the item
+            let idempotent = matches!(op, ListOp::Remove);
+            let attempts = ctx.config.rpc_attempts;
+            match rpc_post_with_retry(&*ctx.transport, &url, &body, &headers, attempts, idempotent)
+                .inspect(|reply| permit.observe(reply))
+            {
+                Ok(reply) => {
+                    let codec = response_codec(&req);
+                    respond(req, forwarded_response(reply, false, codec));
+                }
+                Err(_) => {
+                    eprintln!("{}: RPC POST to {} failed after retries", ctx.name, url);
+                    respond(req, tiny_http::Response::empty(502));
+                }
+            }
+        }
+    }
+}
+
+/// Read a JSON (or peer msgpack) request body into `T`.
+fn read_body<T: serde::de::DeserializeOwned>(
+    req: &mut tiny_http::Request,
+    ctx: &ServerContext,
+) -> Result<T, DecodeError> {
+    let mut body = Vec::new();
+    if let Err(e) = req.as_reader().read_to_end(&mut body) {
+        eprintln!("{}: failed to read body: {}", ctx.name, e);
+        return Err(DecodeError {
+            message: format!("failed to read body: {}", e),
+            position: None,
+        });
+    }
+    log_body("request", &body);
+    rpc::decode_value(header_value(req, "Content-Type").as_deref(), &body)
+}
+
+/// Error detail for a body that didn't decode, with where JSON parsing stopped.
+fn decode_error_detail(e: &DecodeError) -> Value {
+    match e.position {
+        Some((line, column)) => {
+            serde_json::json!({ "error": e.message, "line": line, "column": column })
+        }
+        None => serde_json::json!({ "error": e.message }),
+    }
+}
+
+/// 400 response, carrying the `detail` JSON only when `VERBOSE_ERRORS` is on.
+fn bad_request(
+    ctx: &ServerContext,
+    detail: Value,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    if ctx.config.verbose_errors {
+        json_response(400, detail.to_string())
+    } else {
+        tiny_http::Response::from_data(Vec::new()).with_status_code(400)
+    }
+}
+
+/// Group `keys` by where they are owned (keys from a forwarded batch all stay local).
+fn group_by_owner<'a>(
+    ctx: &'a ServerContext,
+    namespace: Option<&str>,
+    keys: impl IntoIterator<Item = String>,
+    forwarded: bool,
+) -> Vec<(Ownership<'a>, Vec<String>)> {
+    let mut groups: Vec<(Ownership, Vec<String>)> = Vec::new();
+    for key in keys {
+        let owner = if forwarded {
+            Ownership::Local
+        } else {
+            ctx.router.resolve(&storage_key(namespace, &key))
+        };
+        match groups.iter_mut().find(|(o, _)| *o == owner) {
+            Some((_, group)) => group.push(key),
+            None => groups.push((owner, vec![key])),
+        }
+    }
+    groups
+}
+
+/// Per-key batch error for a write its namespace's quota refused.
+const QUOTA_EXCEEDED: &str = "namespace quota exceeded";
+
+/// Overall status of a batch from its per-key results, where a failed key carries an "error":
+/// 200 if no key failed, 207 Multi-Status if some did, and if all did, 502 when their owners
+/// were unreachable, 507 when their namespace was full, or 400 when the keys were refused.
+fn batch_status(results: &serde_json::Map<String, Value>) -> u16 {
+    let errors: Vec<&Value> = results.values().filter_map(|r| r.get("error")).collect();
+    if errors.is_empty() {
+        200
+    } else if errors.len() < results.len() {
+        207
+    } else if errors.iter().all(|e| *e == "owner unreachable") {
+        502
+    } else if errors.iter().all(|e| *e == QUOTA_EXCEEDED) {
+        507
+    } else {
+        400
+    }
+}
+
+/// Handle POST /mdel - delete a JSON array of keys, one batch per owner
+fn handle_mdel(req: tiny_http::Request, ctx: &ServerContext, namespace: Option<&str>) {
+    let mut req = req;
+    let deadline = request_deadline(&req);
+    let keys: Vec<String> = match read_body(&mut req, ctx) {
+        Ok(keys) => keys,
+        Err(e) => {
+            respond(req, bad_request(ctx, decode_error_detail(&e)));
+            return;
+        }
+    };
+    let forwarded = header_value(&req, FORWARDED_HEADER).is_some();
+    let client = client_id(&req);
+
+    let mut results = serde_json::Map::new();
+    let (keys, too_long): (Vec<String>, Vec<String>) = keys
+        .into_iter()
+        .partition(|key| key.len() <= ctx.config.max_key_bytes);
+    for key in too_long {
+        results.insert(
+            key,
+            serde_json::json!({ "deleted": false, "error": "key too long" }),
+        );
+    }
+    for (ownership, keys) in group_by_owner(ctx, namespace, keys, forwarded) {
+        let owner = match ownership {
+            Ownership::Local => {
+                for key in keys {
+                    let skey = storage_key(namespace, &key);
+                    let deleted = ctx.store.delete(&skey) == 1;
+                    audit(ctx, &client, "delete", &skey, deleted, false);
+                    results.insert(key, serde_json::json!({ "deleted": deleted }));
+                }
+                continue;
+            }
+            Ownership::Remote(owner) => owner,
+        };
+
+        // One batch per remote owner; keys it doesn't confirm are reported as failed, not deleted
+        let url = peer_url(owner, namespace, "mdel");
+        let timeout_ms = timeout_header_value(deadline);
+        let forward_headers = [
+            (FORWARDED_HEADER, "1"),
+            (CLIENT_HEADER, client.as_str()),
+            (TIMEOUT_HEADER, timeout_ms.as_str()),
+        ];
+        let body = serde_json::to_vec(&keys).unwrap();
+        let reply = ctx
+            .peer_limits
+            .acquire(owner, PEER_QUEUE_WAIT)
+            .and_then(|permit| {
+                let attempts = ctx.config.rpc_attempts;
+                rpc_post_with_retry(
+                    &*ctx.transport,
+                    &url,
+                    &body,
+                    &forward_headers,
+                    attempts,
+                    true,
+                )
+                .inspect(|reply| permit.observe(reply))
+                .ok()
+            })
+            // a mixed or failed batch still reports which keys it applied
+            .filter(|r| matches!(r.status, 200 | 207 | 400 | 502 | 507))
+            .and_then(|r| r.value().ok());
+        if reply.is_none() {
+            eprintln!("{}: RPC POST to {} failed", ctx.name, url);
+        }
+        for key in keys {
+            let result = match reply.as_ref().and_then(|r| r.get(&key)) {
+                Some(result) => result.clone(),
+                None => serde_json::json!({ "deleted": false, "error": "owner unreachable" }),
+            };
+            results.insert(key, result);
+        }
+    }
+    let status = batch_status(&results);
+    respond(
+        req,
+        json_response(status, Value::Object(results).to_string()),
+    );
+}
+
+/// Handle POST /mput - write a JSON object of many keys, one batch per owner
+fn handle_mput(req: tiny_http::Request, ctx: &ServerContext, namespace: Option<&str>) {
+    let mut req = req;
+    let deadline = request_deadline(&req);
+    let mut entries: serde_json::Map<String, Value> = match read_body(&mut req, ctx) {
+        Ok(entries) => entries,
+        Err(e) => {
+            respond(req, bad_request(ctx, decode_error_detail(&e)));
+            return;
+        }
+    };
+    let forwarded = header_value(&req, FORWARDED_HEADER).is_some();
+
+    let client = client_id(&req);
+    let ttl = match write_ttl(&req, ctx) {
+        Ok(ttl) => ttl,
+        Err(error) => {
+            respond(req, bad_request(ctx, serde_json::json!({ "error": error })));
+            return;
+        }
+    };
+
+    let mut results = serde_json::Map::new();
+    let (keys, too_long): (Vec<String>, Vec<String>) = entries
+        .keys()
+        .cloned()
+        .partition(|key| key.len() <= ctx.config.max_key_bytes);
+    for key in too_long {
+        results.insert(
+            key,
+            serde_json::json!({ "written": false, "error": "key too long" }),
+        );
+    }
+    for (ownership, keys) in group_by_owner(ctx, namespace, keys, forwarded) {
+        let owner = match ownership {
+            Ownership::Local => {
+                for key in keys {
+                    let value = entries.remove(&key).unwrap();
+                    let result = if value.to_string().len() > ctx.config.max_value_bytes {
+                        serde_json::json!({ "written": false, "error": "value too large" })
+                    } else if value.is_null() && ctx.config.null_values == NullValues::Reject {
+                        serde_json::json!({ "written": false, "error": "null value" })
+                    } else if value.is_null() && ctx.config.null_values == NullValues::Delete {
+                        let skey = storage_key(namespace, &key);
+                        let deleted = ctx.store.delete(&skey) == 1;
+                        audit(ctx, &client, "delete", &skey, deleted, false);
+                        serde_json::json!({ "written": false, "deleted": deleted })
+                    } else if let Err(violations) = ctx.schemas.check(&key, &value) {
+                        serde_json::json!({
+                            "written": false,
+                            "error": "schema violation",
+                            "violations": violations,
+                        })
+                    } else {
+                        let opts = SetOptions {
+                            namespace: namespace.map(str::to_string),
+                            ttl: jittered(ctx, ttl),
+                            ..SetOptions::default()
+                        };
+                        let skey = storage_key(namespace, &key);
+                        match ctx.store.set_with(skey.clone(), value, opts) {
+                            Ok((_, replaced)) => {
+                                audit(ctx, &client, "set", &skey, replaced, true);
+                                serde_json::json!({ "written": true })
+                            }
+                            Err(WriteError::QuotaExceeded) => serde_json::json!({
+                                "written": false,
+                                "error": QUOTA_EXCEEDED,
+                            }),
+                            Err(e) => serde_json::json!({
+                                "written": false,
+                                "error": Refusal::from(e).to_string(),
+                            }),
+                        }
+                    };
+                    results.insert(key, result);
+                }
+                continue;
+            }
+            Ownership::Remote(owner) => owner,
+        };
+
+        // One batch per remote owner; keys it doesn't confirm are reported as failed, not written
+        let url = peer_url(owner, namespace, "mput");
+        let ttl_ms = ttl_header_value(ttl);
+        let timeout_ms = timeout_header_value(deadline);
+        let forward_headers = [
+            (FORWARDED_HEADER, "1"),
+            (CLIENT_HEADER, client.as_str()),
+            (TTL_HEADER, ttl_ms.as_str()),
+            (TIMEOUT_HEADER, timeout_ms.as_str()),
+        ];
+        let batch: serde_json::Map<String, Value> = keys
+            .iter()
+            .map(|key| (key.clone(), entries.remove(key).unwrap()))
+            .collect();
+        let body = Value::Object(batch).to_string().into_bytes();
+        let reply = ctx
+            .peer_limits
+            .acquire(owner, PEER_QUEUE_WAIT)
+            .and_then(|permit| {
+                let attempts = ctx.config.rpc_attempts;
+                rpc_post_with_retry(
+                    &*ctx.transport,
+                    &url,
+                    &body,
+                    &forward_headers,
+                    attempts,
+                    true,
+                )
+                .inspect(|reply| permit.observe(reply))
+                .ok()
+            })
+            // a mixed or failed batch still reports which keys it applied
+            .filter(|r| matches!(r.status, 200 | 207 | 400 | 502 | 507))
+            .and_then(|r| r.value().ok());
+        if reply.is_none() {
+            eprintln!("{}: RPC POST to {} failed", ctx.name, url);
+        }
+        for key in keys {
+            let result = match reply.as_ref().and_then(|r| r.get(&key)) {
+                Some(result) => result.clone(),
+                None => serde_json::json!({ "written": false, "error": "owner unreachable" }),
+            };
+            results.insert(key, result);
+        }
+    }
+    let status = batch_status(&results);
+    respond(
+        req,
+        json_response(status, Value::Object(results).to_string()),
+    );
+}
+
+/// One operation of a POST /txn body.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum TxnRequestOp {
+    /// Set, optionally `immutable` and with `headers` to serve it with, as `X-Immutable` and
+    /// `X-Store-Headers` do on POST /.
+    Set {
+        key: String,
+        value: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        immutable: bool,
+        #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+        headers: serde_json::Map<String, Value>,
+    },
+    Delete {
+        key: String,
+    },
+    /// Set, but only if the key is currently at `version`.
+    Cas {
+        key: String,
+        version: u64,
+        value: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        immutable: bool,
+        #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+        headers: serde_json::Map<String, Value>,
+    },
+}
+
+impl TxnRequestOp {
+    fn key(&self) -> &str {
+        match self {
+            TxnRequestOp::Set { key, .. }
+            | TxnRequestOp::Delete { key }
+            | TxnRequestOp::Cas { key, .. } => key,
+        }
+    }
+
+    fn value(&self) -> Option<&Value> {
+        match self {
+            TxnRequestOp::Set { value, .. } | TxnRequestOp::Cas { value, .. } => Some(value),
+            TxnRequestOp::Delete { .. } => None,
+        }
+    }
+
+    fn headers(&self) -> Option<&serde_json::Map<String, Value>> {
+        match self {
+            TxnRequestOp::Set { headers, .. } | TxnRequestOp::Cas { headers, .. } => Some(headers),
+            TxnRequestOp::Delete { .. } => None,
+        }
+    }
+}
+
+/// Handle POST /txn - apply a JSON array of set/delete/cas ops atomically; every key must
+/// belong to the same owner, since no lock spans nodes
+fn handle_txn(req: tiny_http::Request, ctx: &ServerContext, namespace: Option<&str>) {
+    let mut req = req;
+    let deadline = request_deadline(&req);
+    let ops: Vec<TxnRequestOp> = match read_body(&mut req, ctx) {
+        Ok(ops) => ops,
+        Err(e) => {
+            respond(req, bad_request(ctx, decode_error_detail(&e)));
+            return;
+        }
+    };
+    if ops.is_empty() {
+        respond(
+            req,
+            bad_request(
+                ctx,
+                serde_json::json!({ "error": "transaction has no ops" }),
+            ),
+        );
+        return;
+    }
+    // Refuse the whole batch up front; nothing is applied unless every op can be
+    for op in &ops {
+        if op.key().len() > ctx.config.max_key_bytes {
+            respond(
+                req,
+                bad_request(
+                    ctx,
+                    serde_json::json!({ "error": "key too long", "key": op.key() }),
+                ),
+            );
+            return;
+        }
+        let Some(value) = op.value() else {
+            continue;
+        };
+        if value.is_null() && ctx.config.null_values == NullValues::Reject {
+            respond(
+                req,
+                bad_request(
+                    ctx,
+                    serde_json::json!({ "error": "null values are not accepted", "key": op.key() }),
+                ),
+            );
+            return;
+        }
+        if value.to_string().len() > ctx.config.max_value_bytes {
+            respond(req, tiny_http::Response::empty(413));
+            return;
+        }
+        let deletes = value.is_null() && ctx.config.null_values == NullValues::Delete;
+        if !deletes && let Err(violations) = ctx.schemas.check(op.key(), value) {
+            respond(req, schema_violation_response(violations));
+            return;
+        }
+        if let Some(headers) = op.headers()
+            && let Err(error) = store_headers(headers.clone())
+        {
+            let detail = serde_json::json!({ "error": error, "key": op.key() });
+            respond(req, bad_request(ctx, detail));
+            return;
+        }
+    }
+
+    let forwarded = header_value(&req, FORWARDED_HEADER).is_some();
+    let client = client_id(&req);
+    let ttl = match write_ttl(&req, ctx) {
+        Ok(ttl) => ttl,
+        Err(error) => {
+            respond(req, bad_request(ctx, serde_json::json!({ "error": error })));
+            return;
+        }
+    };
+    let keys = ops.iter().map(|op| op.key().to_string());
+    let mut groups = group_by_owner(ctx, namespace, keys, forwarded);
+    if groups.len() > 1 {
+        let owners: serde_json::Map<String, Value> = groups
+            .iter()
+            .flat_map(|(ownership, keys)| {
+                let owner = match ownership {
+                    Ownership::Local => ctx.router.self_addr(),
+                    Ownership::Remote(owner) => owner,
+                };
+                keys.iter()
+                    .map(move |key| (key.clone(), Value::from(owner)))
+            })
+            .collect();
+        let body = serde_json::json!({
+            "error": "transaction keys span multiple owners",
+            "owners": owners,
+        });
+        respond(req, json_response(409, body.to_string()));
+        return;
+    }
+
+    match groups.pop().unwrap().0 {
+        Ownership::Local => {
+            // with NULL_VALUES=delete, writing null deletes the key
+            let deletes_null = ctx.config.null_values == NullValues::Delete;
+            let audited: Vec<(String, &str)> = ops
+                .iter()
+                .map(|op| {
+                    let name = match op {
+                        _ if deletes_null && op.value().is_some_and(Value::is_null) => "delete",
+                        TxnRequestOp::Set { .. } => "set",
+                        TxnRequestOp::Delete { .. } => "delete",
+                        TxnRequestOp::Cas { .. } => "cas",
+                    };
+                    (storage_key(namespace, op.key()), name)
+                })
+                .collect();
+            let batch = ops
+                .into_iter()
+                .map(|op| {
+                    let (key, value, if_version, immutable, headers) = match op {
+                        TxnRequestOp::Set {
+                            key,
+                            value,
+                            immutable,
+                            headers,
+                        } => (key, Some(value), None, immutable, headers),
+                        TxnRequestOp::Delete { key } => {
+                            (key, None, None, false, Default::default())
+                        }
+                        TxnRequestOp::Cas {
+                            key,
+                            version,
+                            value,
+                            immutable,
+                            headers,
+                        } => (key, Some(value), Some(version), immutable, headers),
+                    };
+                    TxnOp {
+                        key: storage_key(namespace, &key),
+                        value: value.filter(|v| !(deletes_null && v.is_null())),
+                        if_version,
+                        namespace: namespace.map(str::to_string),
+                        ttl: jittered(ctx, ttl),
+                        immutable,
+                        // checked with the rest of the batch above
+                        headers: store_headers(headers).unwrap_or_default(),
+                    }
+                })
+                .collect();
+            match ctx.store.transact(batch) {
+                Ok(results) => {
+                    for ((skey, name), (version, old)) in audited.iter().zip(&results) {
+                        audit(ctx, &client, name, skey, *old, version.is_some());
+                    }
+                    let versions: Vec<Option<u64>> = results.iter().map(|(v, _)| *v).collect();
+                    let body = serde_json::json!({ "committed": true, "versions": versions });
+                    respond(req, json_response(200, body.to_string()));
+                }
+                Err((index, WriteError::VersionMismatch { current })) => {
+                    let body = serde_json::json!({
+                        "committed": false,
+                        "error": "version mismatch",
+                        "op": index,
+                        "current": current,
+                    });
+                    respond(req, json_response(412, body.to_string()));
+                }
+                Err((index, WriteError::QuotaExceeded)) => {
+                    let body = serde_json::json!({
+                        "committed": false,
+                        "error": QUOTA_EXCEEDED,
+                        "op": index,
+                    });
+                    respond(req, json_response(507, body.to_string()));
+                }
+                Err((index, error)) => {
+                    // list refusals; transactions don't touch lists, but say so if one turns up
+                    let error = match error {
+                        WriteError::TooLarge => "value too large".to_string(),
+                        WriteError::Rejected(violations) => violations.join("; "),
+                        _ => "value is not an array".to_string(),
+                    };
+                    let body = serde_json::json!({
+                        "committed": false,
+                        "error": error,
+                        "op": index,
+                    });
+                    respond(req, json_response(409, body.to_string()));
+                }
+            }
+        }
+        Ownership::Remote(owner) => {
+            // The owner applies the batch as-is and answers for itself
+            let url = peer_url(owner, namespace, "txn");
+            let ttl_ms = ttl_header_value(ttl);
+            let timeout_ms = timeout_header_value(deadline);
+            let forward_headers = [
+                (FORWARDED_HEADER, "1"),
+                (CLIENT_HEADER, client.as_str()),
+                (TTL_HEADER, ttl_ms.as_str()),
+                (TIMEOUT_HEADER, timeout_ms.as_str()),
+            ];
+            let body = serde_json::to_vec(&ops).unwrap();
+            let reply = ctx
+                .peer_limits
+                .acquire(owner, PEER_QUEUE_WAIT)
+                .and_then(|permit| {
+                    // a cas op could fail against the batch's own first application
+                    let attempts = ctx.config.rpc_attempts;
+                    rpc_post_with_retry(
+                        &*ctx.transport,
+                        &url,
+                        &body,
+                        &forward_headers,
+                        attempts,
+                        false,
+                    )
+                    .inspect(|reply| permit.observe(reply))
+                    .ok()
+                });
+            match reply {
+                Some(reply) => {
+                    respond(req, forwarded_response(reply, false, Codec::Json));
+                }
+                None => {
+                    eprintln!("{}: RPC POST to {} failed", ctx.name, url);
+                    respond(req, tiny_http::Response::empty(502));
+                }
             }
         }
-        sleep(Duration::from_millis(50));
-        i += 1;
     }
-    Err(())
 }
 
-fn rpc_delete_with_retry(
-    agent: &ureq::Agent,
-    url: &str,
-    attempts: usize,
-) -> Result<(u16, String), ()> {
-    let mut i = 0;
+/// Handle GET / - describe the node and its endpoints
+fn handle_index(req: tiny_http::Request, name: &str, query: &str) {
+    let index = serde_json::json!({
+        "node": name,
+        "endpoints": [
+            "GET / - this index",
+            "GET /health - store and peer reachability check (503 if degraded, or starting under WAIT_FOR_PEERS); ?shallow=true just answers",
+            "GET /stats - key count and size, overall, per namespace and per shard, and RPCs in flight per peer",
+            "GET /metrics - per-operation latency histograms and per-peer up gauges (Prometheus text format)",
+            "POST /admin/readonly - {\"read_only\": bool} freezes or resumes writes on every node",
+            "POST /admin/schema - {\"prefix\", \"schema\"}: writes under the prefix must match the JSON Schema (422 otherwise)",
+            "POST /admin/quota - {\"namespace\", \"max_entries\", \"max_bytes\"}: caps per node; writes over them get 507",
+            "POST /admin/invalidate/{key} - drop a key from every node, owner or not",
+            "POST /admin/expired - drop copies of keys their owner expired (sent by EXPIRY_BROADCAST nodes)",
+            "?dry_run=true on /admin/invalidate, /admin/rehash and /admin/restore - report what would change, changing nothing",
+            "GET /admin/dump?format=ndjson - stream this node's entries, one {\"key\", \"value\"} per line; ?compress=gzip gzips it",
+            "POST /admin/restore - store the lines of an /admin/dump body (plain or gzipped) on this node; ?keep_existing=true skips keys it holds",
+            "POST /admin/rehash - move keys this node holds but doesn't own to their owners",
+            "POST /admin/selfcheck - check this node's counters against its entries; ?repair=true fixes drift",
+            "GET/POST /admin/chaos - failure-injection rates (only on nodes started with CHAOS=true)",
+            "GET /admin/distribution?samples=N - how N synthetic keys would spread over peers",
+            "GET /ring - peer list and hashing scheme, for clients that route keys to owners themselves",
+            "GET /owner/{key} - which peer owns a key",
+            "GET /{prefix}* - every key starting with prefix, from all nodes",
+            "GET /{key} - read a key; ?wait=MS blocks until it is written, ?meta=true adds version and size",
+            "GET /{key} with Range: bytes=A-B - those bytes of the value's JSON (206, or 416 if out of range)",
+            "GET /{key}?default=V - answer an absent key with 200 and V (JSON, else a string) instead of 404; ?missing=null is ?default=null",
+            "POST / - write a single {\"key\": value} object; X-Immutable: true lets HTTP caches keep it",
+            "POST / with X-Store-Headers: {\"Name\": \"value\"} - response headers GETs of the value send back",
+            "DELETE /{key} - remove a key; with If-Match: \"VERSION\" only at that version (412 otherwise)",
+            "DELETE /{prefix}* - remove every key starting with prefix, from all nodes, reporting the count per node",
+            "POST /push/{key} - append the JSON body to the array at key (409 if not an array); an Idempotency-Key makes a repeat replay the first result",
+            "POST /lrem/{key} - remove elements equal to the JSON body from the array at key",
+            "POST /mdel - remove a JSON array of keys, reporting {\"deleted\": bool} per key (207 if only some succeed)",
+            "POST /mput - write a JSON object of keys, reporting {\"written\": bool} per key (207 if only some succeed)",
+            "POST /txn - apply a JSON array of {\"op\": \"set\"|\"delete\"|\"cas\", ...} atomically (409 if keys span owners); sets take \"immutable\" and \"headers\" as POST / does",
+            "?pretty=true - indent JSON from GET /{key}, /, /stats, /admin/distribution and /admin/selfcheck",
+            "Content-Type / Accept: application/cbor (or application/msgpack) - key reads and writes in that format instead of JSON",
+            "/ns/{namespace}/... or X-Namespace header - scope a key operation to a namespace",
+            "X-Timeout-Ms: MS header - time budget, passed on to owners; a request arriving with none left gets 504",
+        ],
+    });
+    let body = pretty_json(index.to_string(), wants_pretty(query));
+    respond(req, json_response(200, body));
+}
+
+/// Handle GET /health - check the store and peer reachability; `?shallow=true` only confirms the node answers
+fn handle_health(req: tiny_http::Request, ctx: &ServerContext, query: &str) {
+    if matches!(query_param(query, "shallow"), Some("true" | "1")) {
+        respond(
+            req,
+            json_response(200, "{\"status\": \"ok\"}\n".to_string()),
+        );
+        return;
+    }
+
+    if !ctx.ready.load(Ordering::SeqCst) {
+        let report = serde_json::json!({ "status": "starting" });
+        let body = pretty_json(report.to_string(), wants_pretty(query));
+        let resp = json_response(503, body).with_header(retry_after_header(PEER_WAIT_INTERVAL));
+        respond(req, resp);
+        return;
+    }
+    let store_ok = ctx.store.is_healthy();
+    let keys = if store_ok {
+        Some(ctx.store.stats().total.count)
+    } else {
+        None
+    };
+    // as the background checker last found them, so polling /health sends no RPCs
+    let peers = ctx.router.others().count();
+    let reachable = {
+        let health = ctx.peer_health.lock().unwrap();
+        ctx.router
+            .others()
+            .filter(|peer| health.get(*peer).is_some_and(|state| state.up))
+            .count()
+    };
+
+    // a node cut off from every peer can only serve the keys it owns itself
+    let healthy = store_ok && (peers == 0 || reachable > 0);
+    let report = serde_json::json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "store": if store_ok { "ok" } else { "poisoned" },
+        "keys": keys,
+        "peers": { "reachable": reachable, "total": peers },
+    });
+    let body = pretty_json(report.to_string(), wants_pretty(query));
+    if healthy {
+        respond(req, json_response(200, body));
+    } else {
+        // the checker's next probe may already see peers back
+        let resp = json_response(503, body).with_header(retry_after_header(PEER_CHECK_INTERVAL));
+        respond(req, resp);
+    }
+}
+
+/// Ask every other peer's `/health?shallow=true` whether it answers, recording the result as
+/// its `PeerHealth`. Peers are probed in parallel so one slow peer only costs a single RPC timeout.
+fn probe_peers(ctx: &ServerContext) -> Vec<(&String, bool)> {
+    let probes = std::thread::scope(|scope| {
+        let probes: Vec<_> = ctx
+            .router
+            .others()
+            .map(|peer| {
+                let url = format!("http://{}/health?shallow=true", peer);
+                let probe = scope.spawn(move || {
+                    rpc_get_with_retry(&*ctx.transport, &url, &[], None, 1)
+                        .is_ok_and(|r| r.status == 200)
+                });
+                (peer, probe)
+            })
+            .collect();
+        probes
+            .into_iter()
+            .map(|(peer, probe)| (peer, probe.join().unwrap_or(false)))
+            .collect::<Vec<_>>()
+    });
+    let now = SystemTime::now();
+    let mut health = ctx.peer_health.lock().unwrap();
+    for (peer, up) in &probes {
+        let entry = health.entry(peer.to_string()).or_default();
+        entry.up = *up;
+        if *up {
+            entry.last_seen = Some(now);
+        }
+    }
+    probes
+}
+
+/// Probe peers until `PEER_WAIT_QUORUM` of them answer or `PEER_WAIT_TIMEOUT_MS` passes,
+/// then let the node serve.
+fn wait_for_peers(ctx: &ServerContext) {
+    let started = Instant::now();
+    let timeout = Duration::from_millis(ctx.config.peer_wait_timeout_ms);
+    let others = ctx.router.others().count();
+    let quorum = match ctx.config.peer_wait_quorum {
+        0 => others,
+        n => n.min(others),
+    };
+    eprintln!(
+        "{}: waiting for {} of {} peers before serving",
+        ctx.name, quorum, others
+    );
+    loop {
+        let reachable = probe_peers(ctx).iter().filter(|(_, up)| *up).count();
+        if reachable >= quorum {
+            eprintln!(
+                "{}: {} of {} peers reachable after {:?} — serving",
+                ctx.name,
+                reachable,
+                others,
+                started.elapsed()
+            );
+            break;
+        }
+        if started.elapsed() >= timeout {
+            eprintln!(
+                "{}: only {} of {} peers reachable after {:?} — serving anyway",
+                ctx.name, reachable, others, timeout
+            );
+            break;
+        }
+        std::thread::sleep(PEER_WAIT_INTERVAL);
+    }
+    ctx.ready.store(true, Ordering::SeqCst);
+}
+
+/// Handle GET /stats - report what this node stores locally
+fn handle_stats(req: tiny_http::Request, ctx: &ServerContext, query: &str) {
+    let mut stats = serde_json::to_value(ctx.store.stats()).unwrap();
+    stats["node"] = Value::String(ctx.name.clone());
+    stats["shards"] = serde_json::to_value(ctx.store.shard_stats()).unwrap();
+    stats["peer_in_flight"] = serde_json::to_value(ctx.peer_limits.in_flight()).unwrap();
+    stats["peer_backoff_ms"] = serde_json::to_value(ctx.peer_limits.backoffs()).unwrap();
+    let forwards: serde_json::Map<String, Value> = ctx
+        .metrics
+        .forwards()
+        .into_iter()
+        .map(|(peer, (ok, failed))| (peer, serde_json::json!({ "ok": ok, "failed": failed })))
+        .collect();
+    stats["peer_rpcs"] = Value::Object(forwards);
+    let body = pretty_json(stats.to_string(), wants_pretty(query));
+    respond(req, json_response(200, body));
+}
+
+/// Handle GET /metrics - request latency histograms in the Prometheus text format
+fn handle_metrics(req: tiny_http::Request, ctx: &ServerContext) {
+    let mut body = ctx.metrics.render();
+    ctx.store.lock_waits().render_metric(
+        &mut body,
+        "sdcs_cache_lock_wait_seconds",
+        "Time cache operations waited for a shard lock another one held (uncontended locks aren't counted).",
+    );
+    // from the background checker's latest probes, so a scrape never waits on a slow peer;
+    // peers it hasn't probed yet are left out
+    let health: Vec<(String, PeerHealth)> = {
+        let health = ctx.peer_health.lock().unwrap();
+        ctx.router
+            .others()
+            .filter_map(|peer| Some((peer.clone(), *health.get(peer)?)))
+            .collect()
+    };
+    body.push_str(
+        "# HELP sdcs_peer_up Whether the peer answered this node's latest health probe.\n",
+    );
+    body.push_str("# TYPE sdcs_peer_up gauge\n");
+    for (peer, state) in &health {
+        body.push_str(&format!(
+            "sdcs_peer_up{{peer=\"{}\"}} {}\n",
+            peer,
+            u8::from(state.up)
+        ));
+    }
+    let name = "sdcs_peer_last_seen_timestamp_seconds";
+    body.push_str(&format!(
+        "# HELP {} When the peer last answered a health probe from this node (absent if never).\n",
+        name
+    ));
+    body.push_str(&format!("# TYPE {} gauge\n", name));
+    for (peer, state) in &health {
+        if let Some(at) = state.last_seen {
+            let secs = at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            body.push_str(&format!("{}{{peer=\"{}\"}} {:.3}\n", name, peer, secs));
+        }
+    }
+    let resp = tiny_http::Response::from_string(body).with_header(
+        tiny_http::Header::from_bytes(b"Content-Type", b"text/plain; version=0.0.4").unwrap(),
+    );
+    respond(req, resp);
+}
+
+/// Handle POST /admin/readonly - set `{"read_only": bool}` on this node and every peer
+fn handle_read_only(req: tiny_http::Request, ctx: &ServerContext) {
+    let mut req = req;
+    let read_only = match read_body::<Value>(&mut req, ctx)
+        .ok()
+        .and_then(|body| body.get("read_only").and_then(Value::as_bool))
+    {
+        Some(read_only) => read_only,
+        None => {
+            let detail = serde_json::json!({ "error": "expected {\"read_only\": bool}" });
+            respond(req, bad_request(ctx, detail));
+            return;
+        }
+    };
+    ctx.read_only.store(read_only, Ordering::SeqCst);
+    eprintln!(
+        "{}: read-only {}",
+        ctx.name,
+        if read_only { "on" } else { "off" }
+    );
+
+    let body = serde_json::json!({ "read_only": read_only });
+    let peers = broadcast_admin(&req, ctx, "admin/readonly", &body);
+    let report = serde_json::json!({ "read_only": read_only, "peers": peers });
+    respond(req, json_response(200, report.to_string()));
+}
+
+/// Repeat an admin change `body` on every other peer's `path`, reporting whether each applied it.
+/// A forwarded admin request stops at its receiver; only the node the admin called fans out.
+fn broadcast_admin(
+    req: &tiny_http::Request,
+    ctx: &ServerContext,
+    path: &str,
+    body: &Value,
+) -> serde_json::Map<String, Value> {
+    broadcast_admin_replies(req, ctx, path, body, |reply| {
+        Value::from(reply.is_some_and(|r| r.status == 200))
+    })
+}
+
+/// `broadcast_admin`, reporting each peer as `summarize` renders its reply (None if unreachable).
+fn broadcast_admin_replies(
+    req: &tiny_http::Request,
+    ctx: &ServerContext,
+    path: &str,
+    body: &Value,
+    summarize: impl Fn(Option<RpcReply>) -> Value,
+) -> serde_json::Map<String, Value> {
+    let mut peers = serde_json::Map::new();
+    if header_value(req, FORWARDED_HEADER).is_some() {
+        return peers;
+    }
+    let body = body.to_string();
+    for peer in ctx.router.others() {
+        let url = format!("http://{}/{}", peer, path);
+        let reply = rpc_post_with_retry(
+            &*ctx.transport,
+            &url,
+            body.as_bytes(),
+            &[(FORWARDED_HEADER, "1")],
+            ctx.config.rpc_attempts,
+            true,
+        );
+        peers.insert(peer.clone(), summarize(reply.ok()));
+    }
+    peers
+}
+
+/// Handle POST /admin/invalidate/{key} - drop the key from this node's store and every peer's,
+/// owner or not, so no node holds a copy afterwards. Unlike DELETE, which only reaches the owner,
+/// this also clears strays, e.g. a copy a forwarded batch left on a node that no longer owns it.
+/// `?dry_run=true` removes nothing and reports which nodes hold the key as `would_remove`.
+fn handle_invalidate(
+    req: tiny_http::Request,
+    ctx: &ServerContext,
+    namespace: Option<&str>,
+    key: &str,
+    query: &str,
+) {
+    if key.is_empty() {
+        respond(req, tiny_http::Response::empty(400));
+        return;
+    }
+    let skey = storage_key(namespace, key);
+    let path = match namespace {
+        Some(ns) => format!("ns/{}/admin/invalidate/{}", ns, rpc::encode_key(key)),
+        None => format!("admin/invalidate/{}", rpc::encode_key(key)),
+    };
+    if query_param(query, "dry_run") == Some("true") {
+        let held = ctx.store.get_raw(&skey).is_some();
+        // each peer's own dry-run answer; null for a peer that couldn't say
+        let peers = broadcast_admin_replies(
+            &req,
+            ctx,
+            &format!("{}?dry_run=true", path),
+            &serde_json::json!({}),
+            |reply| {
+                reply
+                    .filter(|r| r.status == 200)
+                    .and_then(|r| serde_json::from_slice::<Value>(&r.body).ok())
+                    .map_or(Value::Null, |report| report["would_remove"].clone())
+            },
+        );
+        let report = serde_json::json!({
+            "key": key,
+            "would_remove": held,
+            "peers": peers,
+            "dry_run": true,
+        });
+        respond(req, json_response(200, report.to_string()));
+        return;
+    }
+    let removed = ctx.store.delete(&skey) == 1;
+    if removed {
+        audit(ctx, &client_id(&req), "invalidate", &skey, true, false);
+    }
+    let peers = broadcast_admin(&req, ctx, &path, &serde_json::json!({}));
+    let report = serde_json::json!({ "key": key, "removed": removed, "peers": peers });
+    respond(req, json_response(200, report.to_string()));
+}
+
+/// Tell every peer which of this node's own keys expired since the last sweep, so they drop any
+/// copies they hold (`EXPIRY_BROADCAST`). A peer that misses a batch keeps its copy until it
+/// expires there or is invalidated; batches aren't retried.
+fn broadcast_expiries(ctx: &ServerContext) {
+    let keys: Vec<String> = ctx
+        .store
+        .take_expired()
+        .into_iter()
+        .filter(|key| ctx.router.resolve(key) == Ownership::Local)
+        .collect();
+    if keys.is_empty() {
+        return;
+    }
+    let body = serde_json::to_vec(&keys).unwrap();
+    for peer in ctx.router.others() {
+        let url = format!("http://{}/admin/expired", peer);
+        let sent = ctx
+            .peer_limits
+            .acquire(peer, PEER_QUEUE_WAIT)
+            .and_then(|permit| {
+                rpc_post_with_retry(
+                    &*ctx.transport,
+                    &url,
+                    &body,
+                    &[(FORWARDED_HEADER, "1")],
+                    ctx.config.rpc_attempts,
+                    true,
+                )
+                .inspect(|reply| permit.observe(reply))
+                .ok()
+            })
+            .is_some_and(|reply| reply.status == 200);
+        if !sent {
+            eprintln!(
+                "{}: couldn't tell {} about {} expired keys",
+                ctx.name,
+                peer,
+                keys.len()
+            );
+        }
+    }
+}
+
+/// Handle POST /admin/expired - drop this node's copies of the listed storage keys, which their
+/// owner reports it expired. Keys this node owns itself are left alone, since its copy is the
+/// one that counts.
+fn handle_expired(req: tiny_http::Request, ctx: &ServerContext) {
+    let mut req = req;
+    let keys: Vec<String> = match read_body(&mut req, ctx) {
+        Ok(keys) => keys,
+        Err(e) => {
+            respond(req, bad_request(ctx, decode_error_detail(&e)));
+            return;
+        }
+    };
+    let dropped = keys
+        .iter()
+        .filter(|key| ctx.router.resolve(key) != Ownership::Local)
+        .filter(|key| ctx.store.delete(key) == 1)
+        .count();
+    let report = serde_json::json!({ "dropped": dropped });
+    respond(req, json_response(200, report.to_string()));
+}
 
-    while i < attempts {
-        match agent.delete(url).call() {
-            Ok(resp) => {
-                let status = resp.status() as u16;
-                let body = resp.into_string().unwrap_or_default();
-                if status >= 500 {
-                    eprintln!(
-                        "RPC DELETE to {} attempt {} got {} — retrying",
-                        url,
-                        i + 1,
-                        status
-                    );
-                } else {
-                    return Ok((status, body));
-                }
-            }
-            Err(ureq::Error::Status(code, resp)) => {
-                let body = resp.into_string().unwrap_or_default();
-                if code >= 500 {
-                    eprintln!(
-                        "RPC DELETE to {} attempt {} got {} — retrying",
-                        url,
-                        i + 1,
-                        code
-                    );
-                } else {
-                    return Ok((code as u16, body));
+/// Handle POST /admin/selfcheck - check this node's store bookkeeping (namespace totals, expired
+/// entries, shard placement, key filter) against what it holds; `?repair=true` also fixes it
+fn handle_self_check(req: tiny_http::Request, ctx: &ServerContext, query: &str) {
+    let repair = query_param(query, "repair") == Some("true");
+    let report = ctx.store.self_check(repair);
+    if !report.is_clean() {
+        eprintln!(
+            "{}: selfcheck found {} expired, {} misplaced, {} namespace totals off, {} filter misses{}",
+            ctx.name,
+            report.expired,
+            report.misplaced,
+            report.namespaces.len(),
+            report.key_filter_misses,
+            if report.repaired { " — repaired" } else { "" }
+        );
+    }
+    let body = pretty_json(serde_json::to_string(&report).unwrap(), wants_pretty(query));
+    respond(req, json_response(200, body));
+}
+
+/// One line of a `/admin/dump` stream, as `/admin/restore` reads it back.
+#[derive(serde::Deserialize)]
+struct DumpLine {
+    /// Storage key, namespace prefix included.
+    key: String,
+    value: Value,
+    #[serde(default)]
+    namespace: Option<String>,
+    #[serde(default)]
+    immutable: bool,
+    /// Stored response headers, as `X-Store-Headers` would send them.
+    #[serde(default)]
+    headers: serde_json::Map<String, Value>,
+    /// Time the value had left when dumped; absent if it never expires.
+    #[serde(default)]
+    ttl_ms: Option<u64>,
+}
+
+/// NDJSON text of one exported entry, the stored JSON spliced in without re-encoding it.
+fn dump_line(exported: &ExportedEntry) -> Vec<u8> {
+    let entry = &exported.entry;
+    let mut line = format!(
+        "{{\"key\":{},\"value\":{}",
+        serde_json::to_string(&exported.key).unwrap(),
+        entry.raw
+    );
+    if let Some(ns) = &exported.namespace {
+        line.push_str(&format!(
+            ",\"namespace\":{}",
+            serde_json::to_string(ns).unwrap()
+        ));
+    }
+    if entry.immutable {
+        line.push_str(",\"immutable\":true");
+    }
+    if !entry.headers.is_empty() {
+        line.push_str(&format!(
+            ",\"headers\":{}",
+            stored_headers_json(&entry.headers)
+        ));
+    }
+    if let Some(at) = entry.expires_at {
+        // at least 1ms, since 0 would restore the value without a TTL
+        let left = at
+            .saturating_duration_since(Instant::now())
+            .as_millis()
+            .max(1);
+        line.push_str(&format!(",\"ttl_ms\":{}", left));
+    }
+    line.push_str("}\n");
+    line.into_bytes()
+}
+
+/// Response body streaming a store export as NDJSON, one entry rendered at a time.
+struct DumpReader {
+    entries: Export,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for DumpReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.line.len() {
+            match self.entries.next() {
+                Some(exported) => {
+                    self.line = dump_line(&exported);
+                    self.pos = 0;
                 }
-            }
-            Err(e) => {
-                eprintln!("RPC DELETE to {} attempt {} failed: {}", url, i + 1, e);
+                None => return Ok(0),
             }
         }
-        sleep(Duration::from_millis(50));
-        i += 1;
+        let n = buf.len().min(self.line.len() - self.pos);
+        buf[..n].copy_from_slice(&self.line[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
     }
-    Err(())
 }
 
-fn rpc_post_with_retry(
-    agent: &ureq::Agent,
-    url: &str,
-    body: &str,
-    attempts: usize,
-) -> Result<(u16, String), ()> {
-    let mut i = 0;
+/// Whether an `Accept-Encoding` value allows gzip.
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        parts
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case("gzip"))
+            && !parts.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0))
+    })
+}
 
-    while i < attempts {
-        match agent
-            .post(url)
-            .set("Content-Type", "application/json; charset=utf-8")
-            .send_string(body)
-        {
-            Ok(resp) => {
-                let status = resp.status() as u16;
-                let body = resp.into_string().unwrap_or_default();
-                if status >= 500 {
-                    eprintln!(
-                        "RPC POST to {} attempt {} got {} — retrying",
-                        url,
-                        i + 1,
-                        status
-                    );
-                } else {
-                    return Ok((status, body));
-                }
+/// Handle GET /admin/dump?format=ndjson - stream every entry this node holds, one
+/// `{"key", "value"}` object per line (plus `namespace`, `immutable` and `ttl_ms` when set).
+/// The body is sent chunked as it is produced, so memory use doesn't grow with the store.
+/// `?compress=gzip` or `Accept-Encoding: gzip` gzips the stream (`Content-Encoding: gzip`).
+fn handle_dump(req: tiny_http::Request, ctx: &ServerContext, query: &str) {
+    if query_param(query, "format").is_some_and(|f| f != "ndjson") {
+        let detail = serde_json::json!({ "error": "only format=ndjson is supported" });
+        respond(req, bad_request(ctx, detail));
+        return;
+    }
+    let gzip = match query_param(query, "compress") {
+        Some("gzip") => true,
+        None => header_value(&req, "Accept-Encoding").is_some_and(|v| accepts_gzip(&v)),
+        Some(_) => {
+            let detail = serde_json::json!({ "error": "only compress=gzip is supported" });
+            respond(req, bad_request(ctx, detail));
+            return;
+        }
+    };
+    let lines = DumpReader {
+        entries: ctx.store.export(),
+        line: Vec::new(),
+        pos: 0,
+    };
+    let mut headers =
+        vec![tiny_http::Header::from_bytes(b"Content-Type", b"application/x-ndjson").unwrap()];
+    let body: Box<dyn Read + Send> = if gzip {
+        headers.push(tiny_http::Header::from_bytes(b"Content-Encoding", b"gzip").unwrap());
+        Box::new(GzEncoder::new(lines, flate2::Compression::default()))
+    } else {
+        Box::new(lines)
+    };
+    let resp = tiny_http::Response::new(tiny_http::StatusCode(200), headers, body, None, None);
+    respond(req, resp);
+}
+
+/// Handle POST /admin/restore - store every line of a `/admin/dump` NDJSON body on this node,
+/// as read, under the keys it names. Keys aren't routed: restore a dump into the node at the
+/// same place in PEERS as the one it came from, or run `/admin/rehash` after. Entries get fresh
+/// versions; with `?keep_existing=true`, keys the node already holds are left as they are.
+/// `?dry_run=true` reads the whole body and stores nothing, reporting how many keys would be
+/// written (`would_restore`, of which `would_replace` are already held) or skipped
+/// (`would_keep`); quota refusals aren't predicted.
+/// A gzipped body (e.g. a dump saved with `?compress=gzip`) is recognised and unpacked.
+/// A line that doesn't parse stops the restore with 400, keeping the lines before it.
+fn handle_restore(req: tiny_http::Request, ctx: &ServerContext, query: &str) {
+    let mut req = req;
+    let client = client_id(&req);
+    let keep_existing = query_param(query, "keep_existing") == Some("true");
+    let dry_run = query_param(query, "dry_run") == Some("true");
+    let (mut restored, mut rejected, mut kept) = (0usize, 0usize, 0usize);
+    let mut replaced = 0usize;
+    let mut failure = None;
+    let mut body = BufReader::new(req.as_reader());
+    // sniff the gzip magic rather than trust Content-Encoding, which `curl --data-binary` won't set
+    let gzipped = body
+        .fill_buf()
+        .is_ok_and(|start| start.starts_with(&[0x1f, 0x8b]));
+    let body: Box<dyn BufRead> = if gzipped {
+        Box::new(BufReader::new(MultiGzDecoder::new(body)))
+    } else {
+        Box::new(body)
+    };
+    for (i, line) in body.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{}: failed to read restore body: {}", ctx.name, e);
+                failure = Some(format!("line {}: {}", i + 1, e));
+                break;
             }
-            Err(ureq::Error::Status(code, resp)) => {
-                let body = resp.into_string().unwrap_or_default();
-                if code >= 500 {
-                    eprintln!(
-                        "RPC POST to {} attempt {} got {} — retrying",
-                        url,
-                        i + 1,
-                        code
-                    );
-                } else {
-                    return Ok((code as u16, body));
-                }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut entry: DumpLine = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                failure = Some(format!("line {}: {}", i + 1, e));
+                break;
             }
+        };
+        let headers = match store_headers(std::mem::take(&mut entry.headers)) {
+            Ok(headers) => headers,
             Err(e) => {
-                eprintln!("RPC POST to {} attempt {} failed: {}", url, i + 1, e);
+                failure = Some(format!("line {}: {}", i + 1, e));
+                break;
             }
+        };
+        if dry_run {
+            match ctx.store.get_raw(&entry.key) {
+                Some(_) if keep_existing => kept += 1,
+                Some(_) => {
+                    restored += 1;
+                    replaced += 1;
+                }
+                None => restored += 1,
+            }
+            continue;
+        }
+        let opts = SetOptions {
+            namespace: entry.namespace,
+            if_absent: keep_existing,
+            immutable: entry.immutable,
+            ttl: entry.ttl_ms.map(Duration::from_millis),
+            headers,
+            ..SetOptions::default()
+        };
+        match ctx.store.set_with(entry.key.clone(), entry.value, opts) {
+            Ok((_, replaced)) => {
+                audit(ctx, &client, "restore", &entry.key, replaced, true);
+                restored += 1;
+            }
+            Err(WriteError::VersionMismatch { .. }) => kept += 1,
+            // over a namespace quota; the rest of the dump may still fit
+            Err(_) => rejected += 1,
+        }
+    }
+    let mut report = if dry_run {
+        serde_json::json!({
+            "would_restore": restored,
+            "would_replace": replaced,
+            "would_keep": kept,
+            "dry_run": true,
+        })
+    } else {
+        serde_json::json!({ "restored": restored, "rejected": rejected, "kept": kept })
+    };
+    match failure {
+        Some(error) => {
+            report["error"] = Value::from(error);
+            respond(req, json_response(400, report.to_string()));
+        }
+        None => {
+            respond(req, json_response(200, report.to_string()));
         }
-        sleep(Duration::from_millis(50));
-        i += 1;
     }
-    Err(())
-}
-
-/// Starts an HTTP server bound to `addr`. This returns the tiny_http::Server which the caller
-/// should pass to `run_server` to begin serving requests.
-pub fn init_server(_name: &str, addr: &str) -> (tiny_http::Server, Cache) {
-    let server = tiny_http::Server::http(addr).expect(&format!("failed to bind {}", addr));
-    let store = Cache::new();
-    println!("listening on http://{}", addr);
-    (server, store)
 }
 
-/// Compute owner index for a key using a simple hash modulo number of peers.
-fn owner_for_key(key: &str, peers: &[String]) -> usize {
-    let h = seahash::hash(key.as_bytes());
-    (h as usize) % peers.len()
-}
+/// Keys `/admin/rehash` sends an owner in one `/admin/restore` call.
+const REHASH_BATCH: usize = 500;
 
-/// Helper to create JSON response with appropriate headers
-fn json_response(status: u16, body: String) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
-    tiny_http::Response::from_string(body)
-        .with_status_code(status)
-        .with_header(
-            tiny_http::Header::from_bytes(b"Content-Type", b"application/json; charset=utf-8")
-                .unwrap(),
-        )
+/// Keys `/admin/rehash` is about to send one owner: their NDJSON lines, and each key with the
+/// version sent so a key rewritten meanwhile isn't dropped.
+#[derive(Default)]
+struct RehashBatch {
+    body: Vec<u8>,
+    keys: Vec<(String, u64)>,
 }
 
-/// Handle POST / - write/update cache
-fn handle_post(
-    req: tiny_http::Request,
-    name: &str,
-    self_addr: &str,
-    peers: &[String],
-    store: &Cache,
-    agent: &ureq::Agent,
-) {
-    let mut req = req; // Mutable needed for as_reader()
+/// Clears `ServerContext::rehashing` when a rehash ends, however it ends.
+struct Rehashing<'a>(&'a AtomicBool);
 
-    // Read request body
-    let mut body = String::new();
-    if let Err(e) = req.as_reader().read_to_string(&mut body) {
-        eprintln!("{}: failed to read body: {}", name, e);
-        let _ = req.respond(tiny_http::Response::empty(400));
-        return;
+impl Drop for Rehashing<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
     }
+}
 
-    // Parse JSON object
-    let map = match serde_json::from_str::<serde_json::Map<String, Value>>(&body) {
-        Ok(m) => m,
-        Err(_) => {
-            let _ = req.respond(tiny_http::Response::empty(400));
-            return;
+/// Handle POST /admin/rehash - move every key this node holds but doesn't own (say, restored
+/// under another PEERS list, or left behind by a forwarded batch) to its owner. Keys go over in
+/// `/admin/restore?keep_existing=true` batches, so a copy the owner already has wins; each
+/// moved key is then dropped here unless it was rewritten meanwhile. Only one runs at a time.
+/// `?dry_run=true` moves nothing and reports how many keys would go to each owner.
+fn handle_rehash(req: tiny_http::Request, ctx: &ServerContext, query: &str) {
+    if query_param(query, "dry_run") == Some("true") {
+        let (mut checked, mut owners) = (0usize, serde_json::Map::new());
+        for exported in ctx.store.export() {
+            checked += 1;
+            if let Ownership::Remote(owner) = ctx.router.resolve(&exported.key) {
+                let count = owners.entry(owner.to_string()).or_insert(Value::from(0));
+                *count = Value::from(count.as_u64().unwrap_or(0) + 1);
+            }
         }
-    };
-
-    // Validate single key constraint
-    if map.len() != 1 {
-        let _ = req.respond(tiny_http::Response::empty(400));
+        let would_move: u64 = owners.values().filter_map(Value::as_u64).sum();
+        let report = serde_json::json!({
+            "checked": checked,
+            "would_move": would_move,
+            "owners": owners,
+            "dry_run": true,
+        });
+        respond(req, json_response(200, report.to_string()));
         return;
     }
+    if ctx
+        .rehashing
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        let body = serde_json::json!({ "error": "a rehash is already running" });
+        respond(req, json_response(409, body.to_string()));
+        return;
+    }
+    let _running = Rehashing(&ctx.rehashing);
+    let client = client_id(&req);
 
-    let (key, value) = map.into_iter().next().unwrap();
-    let owner_idx = owner_for_key(&key, peers);
-    let owner = &peers[owner_idx];
-
-    if owner == self_addr {
-        // Store locally
-        store.set(key.clone(), value.clone());
-        let response_body = serde_json::to_string(&serde_json::json!({key: value})).unwrap();
-        let _ = req.respond(json_response(200, response_body));
-    } else {
-        // Forward to owner
-        let url = format!("http://{}/", owner);
-        match rpc_post_with_retry(agent, &url, &body, 1) {
-            Ok((status, text)) => {
-                let _ = req.respond(json_response(status, text));
+    let mut batches: HashMap<String, RehashBatch> = HashMap::new();
+    let (mut checked, mut moved, mut failed) = (0usize, 0usize, 0usize);
+    let mut send = |owner: &str, batch: RehashBatch| {
+        let url = format!("http://{}/admin/restore?keep_existing=true", owner);
+        let headers = [
+            (FORWARDED_HEADER, "1"),
+            (CLIENT_HEADER, client.as_str()),
+            ("Content-Type", "application/x-ndjson"),
+        ];
+        let attempts = ctx.config.rpc_attempts;
+        // storing only absent keys makes a repeated batch harmless
+        match rpc_post_with_retry(&*ctx.transport, &url, &batch.body, &headers, attempts, true) {
+            Ok(reply) if reply.status == 200 => {
+                for (key, version) in batch.keys {
+                    // a key rewritten since it was sent stays, for the next rehash to move
+                    if ctx.store.delete_if(&key, Some(version)) == Ok(1) {
+                        audit(ctx, &client, "rehash", &key, true, false);
+                        moved += 1;
+                    }
+                }
             }
-            Err(_) => {
-                eprintln!("{}: RPC POST to {} failed after retries", name, url);
-                let _ = req.respond(tiny_http::Response::empty(502));
+            _ => {
+                eprintln!("{}: rehash batch to {} failed", ctx.name, owner);
+                failed += batch.keys.len();
             }
         }
+    };
+    for exported in ctx.store.export() {
+        checked += 1;
+        let Ownership::Remote(owner) = ctx.router.resolve(&exported.key) else {
+            continue;
+        };
+        let batch = batches.entry(owner.to_string()).or_default();
+        batch.body.extend(dump_line(&exported));
+        batch.keys.push((exported.key, exported.entry.version));
+        if batch.keys.len() >= REHASH_BATCH {
+            send(owner, batches.remove(owner).unwrap());
+        }
+    }
+    for (owner, batch) in batches {
+        send(&owner, batch);
     }
+
+    if moved > 0 || failed > 0 {
+        eprintln!(
+            "{}: rehash moved {} keys, {} failed",
+            ctx.name, moved, failed
+        );
+    }
+    let report = serde_json::json!({ "checked": checked, "moved": moved, "failed": failed });
+    respond(req, json_response(200, report.to_string()));
 }
 
-/// Handle GET /{key} - read from cache
-fn handle_get(
-    req: tiny_http::Request,
-    name: &str,
-    self_addr: &str,
-    peers: &[String],
-    store: &Cache,
-    key: &str,
-    agent: &ureq::Agent,
-) {
-    if key.is_empty() {
-        let _ = req.respond(tiny_http::Response::empty(400));
+/// Handle POST /admin/schema - require values under `{"prefix": ...}` to match `{"schema": ...}`
+/// on this node and every peer
+fn handle_schema(req: tiny_http::Request, ctx: &ServerContext) {
+    let mut req = req;
+    let body: Value = match read_body(&mut req, ctx) {
+        Ok(body) => body,
+        Err(e) => {
+            respond(req, bad_request(ctx, decode_error_detail(&e)));
+            return;
+        }
+    };
+    let (Some(prefix), Some(schema)) = (
+        body.get("prefix").and_then(Value::as_str),
+        body.get("schema"),
+    ) else {
+        let detail =
+            serde_json::json!({ "error": "expected {\"prefix\": string, \"schema\": object}" });
+        respond(req, bad_request(ctx, detail));
+        return;
+    };
+    if let Err(e) = ctx.schemas.register(prefix, schema) {
+        // always explained: the admin needs to know what's wrong with the schema
+        let detail = serde_json::json!({ "error": format!("invalid schema: {}", e) });
+        respond(req, json_response(400, detail.to_string()));
         return;
     }
+    let peers = broadcast_admin(&req, ctx, "admin/schema", &body);
+    let report = serde_json::json!({ "prefix": prefix, "peers": peers });
+    respond(req, json_response(200, report.to_string()));
+}
 
-    let owner_idx = owner_for_key(key, peers);
-    let owner = &peers[owner_idx];
+/// Body of POST /admin/quota.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct QuotaRequest {
+    namespace: String,
+    #[serde(flatten)]
+    quota: Quota,
+}
 
-    if owner == self_addr {
-        // Local lookup
-        if let Some(value) = store.get(key) {
-            let response_body = serde_json::to_string(&serde_json::json!({key: value})).unwrap();
-            let _ = req.respond(json_response(200, response_body));
-        } else {
-            let _ = req.respond(tiny_http::Response::empty(404));
+/// Handle POST /admin/quota - cap `{"namespace": ...}` at `max_entries` and/or `max_bytes`.
+/// Each node enforces the caps on the keys it owns, so across the cluster a namespace can hold
+/// up to its quota on every node; writes over it get 507 rather than pushing out other data.
+fn handle_quota(req: tiny_http::Request, ctx: &ServerContext) {
+    let mut req = req;
+    let body: QuotaRequest = match read_body(&mut req, ctx) {
+        Ok(body) => body,
+        Err(e) => {
+            respond(req, bad_request(ctx, decode_error_detail(&e)));
+            return;
         }
-    } else {
-        // Forward to owner
-        let url = format!("http://{}/{}", owner, key);
-        match rpc_get_with_retry(agent, &url, 1) {
-            Ok((200, text)) => {
-                let _ = req.respond(json_response(200, text));
-            }
-            Ok(_) | Err(_) => {
-                // Any non-200 or failure → 404 (hide internal errors from client)
-                eprintln!("{}: RPC GET to {} failed — returning 404", name, url);
-                let _ = req.respond(tiny_http::Response::empty(404));
+    };
+    if body.namespace.is_empty() || body.namespace.contains(':') || body.namespace.contains('/') {
+        let detail = serde_json::json!({ "error": "invalid namespace" });
+        respond(req, bad_request(ctx, detail));
+        return;
+    }
+    ctx.store.set_quota(&body.namespace, body.quota);
+    let body = serde_json::to_value(&body).unwrap();
+    let peers = broadcast_admin(&req, ctx, "admin/quota", &body);
+    let mut report = body;
+    report["peers"] = Value::Object(peers);
+    respond(req, json_response(200, report.to_string()));
+}
+
+/// Handle GET/POST /admin/chaos - show or replace this node's failure-injection rates (CHAOS=true only)
+fn handle_chaos(req: tiny_http::Request, ctx: &ServerContext) {
+    let mut req = req;
+    if !ctx.chaos.enabled() {
+        respond(req, tiny_http::Response::empty(404));
+        return;
+    }
+    if req.method() == &tiny_http::Method::Post {
+        match read_body::<ChaosSettings>(&mut req, ctx) {
+            Ok(settings) => ctx.chaos.set(settings),
+            Err(e) => {
+                respond(req, bad_request(ctx, decode_error_detail(&e)));
+                return;
             }
         }
     }
+    let settings = serde_json::to_string(&ctx.chaos.settings()).unwrap();
+    respond(req, json_response(200, settings));
 }
 
-/// Handle DELETE /{key} - remove from cache
-fn handle_delete(
-    req: tiny_http::Request,
-    name: &str,
-    self_addr: &str,
-    peers: &[String],
-    store: &Cache,
-    key: &str,
-    agent: &ureq::Agent,
-) {
+/// Handle GET /admin/distribution?samples=N - report how N synthetic keys would spread over peers
+fn handle_distribution(req: tiny_http::Request, ctx: &ServerContext, query: &str) {
+    let samples = match query_param(query, "samples").map(str::parse::<usize>) {
+        None => 10_000,
+        Some(Ok(n)) if n <= 1_000_000 => n,
+        Some(_) => {
+            respond(req, tiny_http::Response::empty(400));
+            return;
+        }
+    };
+    let peers = ctx.router.peers();
+    let counts = key_distribution(peers, samples);
+    let expected = samples as f64 / peers.len() as f64;
+    let max = counts.iter().copied().max().unwrap_or(0) as f64;
+    let per_peer: serde_json::Map<String, Value> = peers
+        .iter()
+        .zip(&counts)
+        .map(|(peer, count)| (peer.clone(), Value::from(*count)))
+        .collect();
+    let report = serde_json::json!({
+        "samples": samples,
+        "peers": per_peer,
+        // busiest peer relative to a perfectly even split (1.0 = no skew)
+        "max_skew": if samples == 0 { 1.0 } else { max / expected },
+    });
+    let body = pretty_json(report.to_string(), wants_pretty(query));
+    respond(req, json_response(200, body));
+}
+
+/// Handle GET /ring - what a client needs to compute key owners itself and skip the forward hop
+fn handle_ring(req: tiny_http::Request, ctx: &ServerContext, query: &str) {
+    let ring = serde_json::json!({
+        // owner index = seahash(storage key) mod peer count; storage key is "{namespace}:{key}"
+        "hash": "seahash",
+        "placement": "modulo",
+        "peers": ctx.router.peers(),
+    });
+    let body = pretty_json(ring.to_string(), wants_pretty(query));
+    respond(req, json_response(200, body));
+}
+
+/// Handle GET /owner/{key} - which peer owns a key, without reading or storing anything
+fn handle_owner(req: tiny_http::Request, ctx: &ServerContext, namespace: Option<&str>, key: &str) {
     if key.is_empty() {
-        let _ = req.respond(tiny_http::Response::empty(400));
+        respond(req, tiny_http::Response::empty(400));
         return;
     }
+    let skey = storage_key(namespace, key);
+    let owner = ctx.router.owner(&skey);
+    let body = serde_json::json!({
+        "key": key,
+        "storage_key": skey,
+        "owner": owner,
+        "local": ctx.router.resolve(&skey) == Ownership::Local,
+    });
+    respond(req, json_response(200, body.to_string()));
+}
 
-    let owner_idx = owner_for_key(key, peers);
-    let owner = &peers[owner_idx];
+/// Split a `/ns/{namespace}/...` prefix off `url`, falling back to the `X-Namespace` header.
+/// Returns the namespace (if any) and the remaining path, or Err(()) for an invalid namespace.
+fn split_namespace(req: &tiny_http::Request, url: &str) -> Result<(Option<String>, String), ()> {
+    let (namespace, path) = match url.strip_prefix("/ns/") {
+        Some(rest) => {
+            let (ns, path) = rest.split_once('/').unwrap_or((rest, ""));
+            (Some(ns.to_string()), format!("/{}", path))
+        }
+        None => (header_value(req, "X-Namespace"), url.to_string()),
+    };
+    // ':' separates namespace from key in storage and '/' can't be forwarded in the path
+    if let Some(ns) = &namespace
+        && (ns.is_empty() || ns.contains(':') || ns.contains('/'))
+    {
+        return Err(());
+    }
+    Ok((namespace, path))
+}
 
-    if owner == self_addr {
-        // Local delete
-        let removed = store.delete(key);
-        let _ = req.respond(json_response(200, removed.to_string()));
-    } else {
-        // Forward to owner
-        let url = format!("http://{}/{}", owner, key);
-        match rpc_delete_with_retry(agent, &url, 1) {
-            Ok((status, text)) => {
-                let _ = req.respond(json_response(status, text));
-            }
-            Err(_) => {
-                eprintln!("{}: RPC DELETE to {} failed after retries", name, url);
-                let _ = req.respond(tiny_http::Response::empty(502));
-            }
+/// Percent-decode the key in the rest of a request path and hand it to `handle`, or answer
+/// 400 if it doesn't decode to UTF-8. `/my%20key` names `my key` and `%2F` a slash inside a key.
+/// The path after the route's prefix is the key exactly, so a trailing slash is part of it:
+/// `/a/` names the key `a/`, not `a`.
+fn with_path_key(
+    req: tiny_http::Request,
+    encoded: &str,
+    handle: impl FnOnce(tiny_http::Request, &str),
+) {
+    match rpc::decode_key(encoded) {
+        Ok(key) => handle(req, &key),
+        Err(()) => {
+            respond(req, tiny_http::Response::empty(400));
         }
     }
 }
 
-/// Handle GET /health - health check endpoint
-fn handle_health(req: tiny_http::Request) {
-    let _ = req.respond(json_response(200, "{\"status\": \"ok\"}\n".to_string()));
+/// Counts a request as in flight until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 /// Run the server loop. `name` is the server name (for logs), `peers` is the ordered list of peer base URLs
@@ -326,62 +3272,399 @@ pub fn run_server(
     peers: Vec<String>,
     store: Cache,
 ) {
-    println!("{} running on {} with peers: {:?}", name, self_addr, peers);
+    serve(&server, server_context(name, self_addr, peers, store));
+}
+
+/// Like `run_server`, but serving the node state built by `server_context`, so front ends
+/// made from the same context (see `Node::with_context`) share its switches and write checks.
+pub fn run_server_with_context(server: tiny_http::Server, ctx: Arc<ServerContext>) {
+    serve(&server, ctx);
+}
+
+/// A server loop running on a background thread, as started by `spawn_server`.
+pub struct ServerHandle {
+    server: Arc<tiny_http::Server>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Address the server is listening on.
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.server.server_addr()
+    }
+
+    /// Stop accepting requests, wait for in-flight ones to finish, and close the listener.
+    pub fn shutdown(self) {
+        self.server.unblock();
+        let _ = self.thread.join();
+        // dropping the last reference to the server closes its listening socket
+    }
+}
+
+/// Like `run_server`, but peers are reached through `transport` instead of HTTP over ureq.
+pub fn run_server_with_transport(
+    server: tiny_http::Server,
+    name: &str,
+    self_addr: String,
+    peers: Vec<String>,
+    store: Cache,
+    transport: Box<dyn Transport>,
+) {
+    let ctx = build_context(name, self_addr, peers, store, Some(transport));
+    serve(&server, ctx);
+}
+
+/// Like `run_server`, but runs the loop on a background thread and returns a handle to stop it.
+pub fn spawn_server(
+    server: tiny_http::Server,
+    name: &str,
+    self_addr: String,
+    peers: Vec<String>,
+    store: Cache,
+) -> ServerHandle {
+    spawn(server, name, self_addr, peers, store, None)
+}
+
+/// `spawn_server` with peers reached through `transport`, e.g. an in-memory one whose peers
+/// fail on cue.
+pub fn spawn_server_with_transport(
+    server: tiny_http::Server,
+    name: &str,
+    self_addr: String,
+    peers: Vec<String>,
+    store: Cache,
+    transport: Box<dyn Transport>,
+) -> ServerHandle {
+    spawn(server, name, self_addr, peers, store, Some(transport))
+}
+
+fn spawn(
+    server: tiny_http::Server,
+    name: &str,
+    self_addr: String,
+    peers: Vec<String>,
+    store: Cache,
+    transport: Option<Box<dyn Transport>>,
+) -> ServerHandle {
+    let server = Arc::new(server);
+    let ctx = build_context(name, self_addr, peers, store, transport);
+    let thread = {
+        let server = server.clone();
+        std::thread::spawn(move || serve(&server, ctx))
+    };
+    ServerHandle { server, thread }
+}
+
+/// State for node `name` at `self_addr` in the ordered `peers` list (which includes itself),
+/// serving keys it owns from `store`, with settings read from the environment. Nothing is
+/// served until it's passed to `run_server_with_context`.
+pub fn server_context(
+    name: &str,
+    self_addr: String,
+    peers: Vec<String>,
+    store: Cache,
+) -> Arc<ServerContext> {
+    build_context(name, self_addr, peers, store, None)
+}
+
+fn build_context(
+    name: &str,
+    self_addr: String,
+    peers: Vec<String>,
+    store: Cache,
+    transport: Option<Box<dyn Transport>>,
+) -> Arc<ServerContext> {
+    let config = Config::from_env();
+    let chaos = Arc::new(Chaos::new(config.chaos));
+    let slow_request = match config.slow_request_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+    let metrics = Arc::new(Metrics::new(slow_request));
     // Build a shared HTTP Agent for connection pooling and lower latency.
-    let agent = Arc::new(
-        ureq::AgentBuilder::new()
-            .timeout_connect(Duration::from_millis(100))
-            .timeout_read(Duration::from_millis(100))
-            .timeout_write(Duration::from_millis(100))
-            .build(),
+    let mut agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_millis(100))
+        .timeout_read(Duration::from_millis(100))
+        .timeout_write(Duration::from_millis(100))
+        .max_idle_connections_per_host(config.rpc_pool_size)
+        .max_idle_connections(config.rpc_pool_size * peers.len());
+    if chaos.enabled() {
+        eprintln!(
+            "{}: CHAOS enabled — failures can be injected via /admin/chaos",
+            name
+        );
+        // outermost, so a forward chaos drops never reaches the peer metrics below it
+        agent = agent.middleware(ChaosMiddleware(chaos.clone()));
+    }
+    let agent = agent.middleware(ForwardMiddleware(metrics.clone()));
+    let transport = transport.unwrap_or_else(|| Box::new(agent.build()));
+    let idempotency = IdempotencyCache::new(
+        config.idempotency_capacity,
+        Duration::from_millis(config.idempotency_ttl_ms),
+    );
+    let peer_limits = Arc::new(PeerLimiter::new(config.peer_max_in_flight));
+    let config_read_only = config.read_only;
+    let config_wait_for_peers = config.wait_for_peers;
+    let audit = (!config.audit_log.is_empty()).then(|| {
+        AuditLog::open(&config.audit_log, config.audit_log_max_bytes)
+            .unwrap_or_else(|e| panic!("failed to open audit log {}: {}", config.audit_log, e))
+    });
+    Arc::new(ServerContext {
+        name: name.to_string(),
+        router: Router::new(self_addr, peers),
+        store,
+        transport,
+        config,
+        idempotency,
+        metrics,
+        peer_limits,
+        read_only: Arc::new(AtomicBool::new(config_read_only)),
+        rehashing: AtomicBool::new(false),
+        ready: AtomicBool::new(!config_wait_for_peers),
+        peer_health: Mutex::new(HashMap::new()),
+        ttl_jitter: AtomicU64::new(chaos::clock_seed()),
+        schemas: SchemaRegistry::default(),
+        chaos,
+        audit,
+    })
+}
+
+/// The request loop behind `run_server` and `spawn_server`; returns once the server is unblocked
+/// and every request it accepted has been answered.
+fn serve(server: &tiny_http::Server, ctx: Arc<ServerContext>) {
+    let name = ctx.name.as_str();
+    println!(
+        "{} running on {} with peers: {:?}",
+        name,
+        ctx.router.self_addr(),
+        ctx.router.peers()
     );
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    if !ctx.ready.load(Ordering::SeqCst) {
+        let ctx = Arc::clone(&ctx);
+        std::thread::spawn(move || wait_for_peers(&ctx));
+    }
+
+    // Expired keys already read as absent; sweep the ones nobody touches so they free their memory
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let ctx = Arc::clone(&ctx);
+        let running = running.clone();
+        std::thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                std::thread::sleep(EXPIRY_SWEEP_INTERVAL);
+                ctx.store.purge_expired();
+                if ctx.config.expiry_broadcast {
+                    broadcast_expiries(&ctx);
+                }
+            }
+        });
+    }
+
+    // Keep each peer's health current for /health and /metrics, off the request path
+    {
+        let ctx = Arc::clone(&ctx);
+        let running = running.clone();
+        std::thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                probe_peers(&ctx);
+                std::thread::sleep(PEER_CHECK_INTERVAL);
+            }
+        });
+    }
 
+    // answers the accept loop gives itself, like 503s when full, name this node too
+    RESPONDER.set((ctx.router.self_addr().to_string(), None));
     for request in server.incoming_requests() {
+        // Shed load instead of spawning unbounded worker threads
+        let cap = ctx.config.max_connections;
+        if cap > 0 && in_flight.load(Ordering::SeqCst) >= cap {
+            eprintln!("{}: {} requests in flight — rejecting", name, cap);
+            // in-flight requests are short, so a second is usually enough for capacity to free up
+            let resp = unavailable_response(Duration::from_secs(1)).with_header(
+                tiny_http::Header::from_bytes(rpc::OVERLOADED_HEADER.as_bytes(), b"1").unwrap(),
+            );
+            respond(request, resp);
+            continue;
+        }
+        if headers_too_large(&request, &ctx.config) {
+            respond(request, tiny_http::Response::empty(431));
+            continue;
+        }
+        // peers waiting for this node probe /health, so it keeps answering that while it waits
+        if !ctx.ready.load(Ordering::SeqCst) && request.url().split('?').next() != Some("/health") {
+            respond(request, unavailable_response(PEER_WAIT_INTERVAL));
+            continue;
+        }
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight(in_flight.clone());
+
         let method = request.method().as_str().to_string();
         let url = request.url().to_string();
-        let peers = peers.clone();
-        let store = store.clone();
-        let name = name.to_string();
-        let self_addr = self_addr.clone();
-        let agent = agent.clone();
+        // a reference count bump, not a copy of the peer list and config
+        let ctx = Arc::clone(&ctx);
 
         std::thread::spawn(move || {
-            // Route request to appropriate handler
-            match (method.as_str(), url.as_str()) {
-                ("POST", "/") => {
-                    handle_post(request, &name, &self_addr, &peers, &store, agent.as_ref());
-                }
-                ("GET", "/health") => {
-                    handle_health(request);
-                }
-                ("GET", path) => {
-                    let key = path.trim_start_matches('/');
-                    handle_get(
-                        request,
-                        &name,
-                        &self_addr,
-                        &peers,
-                        &store,
-                        key,
-                        agent.as_ref(),
-                    );
+            let _guard = guard;
+            // a panicking handler drops its request mid-unwind, which answers the client 500;
+            // catch it here only to say which request it was
+            let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                RESPONDER.set((ctx.router.self_addr().to_string(), None));
+                if ctx.config.log_bodies {
+                    let request = format!("{} {}", method, url);
+                    let max = ctx.config.log_body_max_bytes;
+                    BODY_LOG.set(Some((ctx.name.clone(), request, max)));
                 }
-                ("DELETE", path) => {
-                    let key = path.trim_start_matches('/');
-                    handle_delete(
-                        request,
-                        &name,
-                        &self_addr,
-                        &peers,
-                        &store,
-                        key,
-                        agent.as_ref(),
-                    );
+                let (url, query) = url.split_once('?').unwrap_or((&url, ""));
+                let (namespace, path) = match split_namespace(&request, url) {
+                    Ok(parts) => parts,
+                    Err(()) => {
+                        respond(request, tiny_http::Response::empty(400));
+                        return;
+                    }
+                };
+                let namespace = namespace.as_deref();
+
+                // A budget already spent (say, by a forward that queued too long) isn't worth starting
+                if request_deadline(&request).is_some_and(|at| at <= Instant::now()) {
+                    respond(request, tiny_http::Response::empty(504));
+                    return;
                 }
-                _ => {
-                    let _ = request.respond(tiny_http::Response::empty(405));
+
+                // Injected failures spare /admin/ so injection can always be switched back off
+                if !path.starts_with("/admin/")
+                    && let Some(status) = ctx.chaos.before_request()
+                {
+                    respond(request, tiny_http::Response::empty(status));
+                    return;
+                }
+
+                // Read-only nodes refuse writes whether they come from clients or forwarding peers
+                // (admin switches, including turning read-only off, and dry runs stay available)
+                let is_write = matches!(method.as_str(), "POST" | "DELETE")
+                    && !(method == "POST" && READ_ONLY_EXEMPT.contains(&path.as_str()))
+                    && query_param(query, "dry_run") != Some("true");
+                if is_write && ctx.read_only.load(Ordering::SeqCst) {
+                    respond(request, refusal_response(&ctx, &Refusal::ReadOnly));
+                    return;
                 }
+
+                // Route request to appropriate handler
+                match (method.as_str(), path.as_str()) {
+                    ("POST", "/") => {
+                        handle_post(request, &ctx, namespace);
+                    }
+                    ("POST", "/mdel") => {
+                        handle_mdel(request, &ctx, namespace);
+                    }
+                    ("POST", "/mput") => {
+                        handle_mput(request, &ctx, namespace);
+                    }
+                    ("POST", "/txn") => {
+                        handle_txn(request, &ctx, namespace);
+                    }
+                    ("POST", path) if path.starts_with("/push/") => {
+                        with_path_key(request, &path["/push/".len()..], |req, key| {
+                            handle_list(req, &ctx, namespace, key, ListOp::Push)
+                        });
+                    }
+                    ("POST", path) if path.starts_with("/lrem/") => {
+                        with_path_key(request, &path["/lrem/".len()..], |req, key| {
+                            handle_list(req, &ctx, namespace, key, ListOp::Remove)
+                        });
+                    }
+                    ("POST", "/admin/readonly") if namespace.is_none() => {
+                        handle_read_only(request, &ctx);
+                    }
+                    ("POST", "/admin/schema") if namespace.is_none() => {
+                        handle_schema(request, &ctx);
+                    }
+                    ("POST", "/admin/quota") if namespace.is_none() => {
+                        handle_quota(request, &ctx);
+                    }
+                    ("POST", path) if path.starts_with("/admin/invalidate/") => {
+                        with_path_key(request, &path["/admin/invalidate/".len()..], |req, key| {
+                            handle_invalidate(req, &ctx, namespace, key, query)
+                        });
+                    }
+                    ("POST", "/admin/expired") if namespace.is_none() => {
+                        handle_expired(request, &ctx);
+                    }
+                    ("GET", "/admin/dump") if namespace.is_none() => {
+                        handle_dump(request, &ctx, query);
+                    }
+                    ("POST", "/admin/rehash") if namespace.is_none() => {
+                        handle_rehash(request, &ctx, query);
+                    }
+                    ("POST", "/admin/restore") if namespace.is_none() => {
+                        handle_restore(request, &ctx, query);
+                    }
+                    ("POST", "/admin/selfcheck") if namespace.is_none() => {
+                        handle_self_check(request, &ctx, query);
+                    }
+                    ("GET" | "POST", "/admin/chaos") if namespace.is_none() => {
+                        handle_chaos(request, &ctx);
+                    }
+                    ("GET", "/") if namespace.is_none() => {
+                        handle_index(request, &ctx.name, query);
+                    }
+                    ("GET", "/health") if namespace.is_none() => {
+                        handle_health(request, &ctx, query);
+                    }
+                    ("GET", "/stats") if namespace.is_none() => {
+                        handle_stats(request, &ctx, query);
+                    }
+                    ("GET", "/metrics") if namespace.is_none() => {
+                        handle_metrics(request, &ctx);
+                    }
+                    ("GET", "/admin/distribution") if namespace.is_none() => {
+                        handle_distribution(request, &ctx, query);
+                    }
+                    ("GET", "/ring") if namespace.is_none() => {
+                        handle_ring(request, &ctx, query);
+                    }
+                    ("GET", path) if path.starts_with("/owner/") => {
+                        with_path_key(request, &path["/owner/".len()..], |req, key| {
+                            handle_owner(req, &ctx, namespace, key)
+                        });
+                    }
+                    ("GET", path) => {
+                        with_path_key(
+                            request,
+                            path.strip_prefix('/').unwrap_or(path),
+                            |req, key| handle_get(req, &ctx, namespace, key, query),
+                        );
+                    }
+                    ("DELETE", path) => {
+                        with_path_key(
+                            request,
+                            path.strip_prefix('/').unwrap_or(path),
+                            |req, key| handle_delete(req, &ctx, namespace, key),
+                        );
+                    }
+                    _ => {
+                        respond(request, tiny_http::Response::empty(405));
+                    }
+                }
+            }));
+            if let Err(panic) = handled {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown cause");
+                eprintln!(
+                    "{}: handler for {} {} panicked: {}",
+                    ctx.name, method, url, message
+                );
             }
         });
     }
+
+    // unblocked: let the workers already running finish before reporting the loop done
+    while in_flight.load(Ordering::SeqCst) > 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    running.store(false, Ordering::SeqCst);
 }
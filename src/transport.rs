@@ -0,0 +1,112 @@
+use std::io::Read;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::rpc::decode_value;
+
+/// Status, body and headers an owner returned for a forwarded request.
+pub struct RpcReply {
+    pub status: u16,
+    pub body: Vec<u8>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RpcReply {
+    fn from_response(resp: ureq::Response) -> Self {
+        let headers = resp
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = resp.header(&name)?.to_string();
+                Some((name, value))
+            })
+            .collect();
+        let status = resp.status();
+        let mut body = Vec::new();
+        let _ = resp.into_reader().read_to_end(&mut body);
+        RpcReply {
+            status,
+            body,
+            headers,
+        }
+    }
+
+    /// Value of reply header `name`, if present.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Decode the body as JSON or MessagePack according to its Content-Type.
+    pub fn value(&self) -> Result<Value, String> {
+        decode_value(self.header("Content-Type"), &self.body).map_err(|e| e.to_string())
+    }
+}
+
+/// How a node sends requests to its peers. Any status the peer answers with, 5xx included,
+/// is an `Ok` reply; `Err` means no reply arrived (unreachable, timed out) and says why.
+/// Retries are layered on top by the RPC helpers, so an implementation makes one attempt per call.
+///
+/// `ureq::Agent` is the default; an in-memory implementation lets tests make peers fail on cue.
+/// The ureq middleware a server installs (chaos, per-peer counters) only applies to the default.
+pub trait Transport: Send + Sync {
+    /// GET `url`. `timeout`, if given, replaces the transport's default for requests the owner
+    /// may hold open (long polls).
+    fn get(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+        timeout: Option<Duration>,
+    ) -> Result<RpcReply, String>;
+
+    /// POST `body` to `url`; it is JSON unless `headers` set another Content-Type.
+    fn post(&self, url: &str, body: &[u8], headers: &[(&str, &str)]) -> Result<RpcReply, String>;
+
+    /// DELETE `url`.
+    fn delete(&self, url: &str, headers: &[(&str, &str)]) -> Result<RpcReply, String>;
+}
+
+impl Transport for ureq::Agent {
+    fn get(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+        timeout: Option<Duration>,
+    ) -> Result<RpcReply, String> {
+        let mut rpc = with_headers(self.get(url), headers);
+        if let Some(timeout) = timeout {
+            rpc = rpc.timeout(timeout);
+        }
+        reply(rpc.call())
+    }
+
+    fn post(&self, url: &str, body: &[u8], headers: &[(&str, &str)]) -> Result<RpcReply, String> {
+        let rpc = self
+            .post(url)
+            .set("Content-Type", "application/json; charset=utf-8");
+        reply(with_headers(rpc, headers).send_bytes(body))
+    }
+
+    fn delete(&self, url: &str, headers: &[(&str, &str)]) -> Result<RpcReply, String> {
+        reply(with_headers(self.delete(url), headers).call())
+    }
+}
+
+/// Attach `headers` to an outgoing request.
+fn with_headers(mut rpc: ureq::Request, headers: &[(&str, &str)]) -> ureq::Request {
+    for (name, value) in headers {
+        rpc = rpc.set(name, value);
+    }
+    rpc
+}
+
+/// Treat error statuses as replies like any other; only transport failures are errors.
+fn reply(result: Result<ureq::Response, ureq::Error>) -> Result<RpcReply, String> {
+    match result {
+        Ok(resp) | Err(ureq::Error::Status(_, resp)) => Ok(RpcReply::from_response(resp)),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -0,0 +1,454 @@
+//! The HTTP API of a single node.
+
+mod common;
+
+use baby_sdcs::server;
+use common::{call_with, get, json, metric, node, node_with, post, request};
+
+#[test]
+fn root_serves_the_endpoint_index() {
+    let addr = node();
+    let (status, body) = get(&addr, "/");
+    assert_eq!(status, 200);
+    let index = json(&body);
+    assert_eq!(index["node"], "server1");
+    assert!(index["endpoints"].as_array().unwrap().len() >= 5);
+
+    // other paths are still key lookups
+    assert_eq!(get(&addr, "/somekey").0, 404);
+    assert_eq!(post(&addr, "/", r#"{"somekey": 1}"#).0, 200);
+    assert_eq!(
+        get(&addr, "/somekey"),
+        (200, r#"{"somekey":1}"#.to_string())
+    );
+}
+
+#[test]
+fn values_over_max_value_bytes_are_refused() {
+    let addr = node_with(&[("MAX_VALUE_BYTES", "10")]);
+    assert_eq!(post(&addr, "/", r#"{"k": "0123456789"}"#).0, 413);
+    assert_eq!(get(&addr, "/k").0, 404);
+    // the limit counts the serialized value, quotes included
+    assert_eq!(post(&addr, "/", r#"{"k": "01234567"}"#).0, 200);
+}
+
+#[test]
+fn namespaces_keep_the_same_key_apart() {
+    let addr = node();
+    assert_eq!(post(&addr, "/ns/a/", r#"{"k": "in a"}"#).0, 200);
+    let header = [("X-Namespace", "b")];
+    assert_eq!(
+        call_with("POST", &addr, "/", &header, Some(r#"{"k": "in b"}"#)).0,
+        200
+    );
+
+    assert_eq!(get(&addr, "/ns/a/k"), (200, r#"{"k":"in a"}"#.to_string()));
+    assert_eq!(
+        call_with("GET", &addr, "/k", &header, None).1,
+        r#"{"k":"in b"}"#
+    );
+    assert_eq!(get(&addr, "/k").0, 404);
+
+    let stats = json(&get(&addr, "/stats").1);
+    assert_eq!(stats["count"], 2);
+    assert_eq!(stats["namespaces"]["a"]["count"], 1);
+    assert_eq!(stats["namespaces"]["b"]["count"], 1);
+    let shards = stats["shards"].as_array().unwrap();
+    let in_shards: u64 = shards.iter().map(|s| s["entries"].as_u64().unwrap()).sum();
+    assert_eq!(in_shards, 2);
+}
+
+#[test]
+fn query_strings_are_not_part_of_the_key() {
+    let addr = node();
+    assert_eq!(post(&addr, "/", r#"{"k": 1}"#).0, 200);
+    assert_eq!(get(&addr, "/k?x=1"), (200, r#"{"k":1}"#.to_string()));
+}
+
+#[test]
+fn requests_beyond_max_connections_get_503() {
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    let addr = node_with(&[("MAX_CONNECTIONS", "1")]);
+    // let the worker that answered the startup check finish, so it doesn't count as in flight
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    // a write whose body hasn't arrived keeps its worker busy (tiny_http only hands over
+    // requests with small bodies once they are read, hence the padding)
+    let body = format!("{:<4096}", r#"{"k":1}"#);
+    let mut slow = TcpStream::connect(&*addr).unwrap();
+    let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len());
+    slow.write_all(head.as_bytes()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let shed = request("GET", &addr, "/k", &[], None);
+    assert_eq!(shed.status(), 503);
+    assert_eq!(shed.header("Retry-After"), Some("1"));
+
+    slow.write_all(body.as_bytes()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert_eq!(get(&addr, "/k"), (200, r#"{"k":1}"#.to_string()));
+}
+
+#[test]
+fn reads_return_the_stored_json_unchanged() {
+    let addr = node();
+    let value = r#"{"empty":{},"nested":{"list":[1,2.5,"three",null,true]}}"#;
+    assert_eq!(post(&addr, "/", &format!(r#"{{"k": {}}}"#, value)).0, 200);
+    assert_eq!(get(&addr, "/k").1, format!(r#"{{"k":{}}}"#, value));
+}
+
+#[test]
+fn shutdown_finishes_in_flight_requests_then_stops_listening() {
+    let (srv, store) = server::init_server("embedded", "127.0.0.1:0");
+    let addr = srv.server_addr().to_string();
+    let handle = server::spawn_server(srv, "embedded", addr.clone(), vec![addr.clone()], store);
+    assert_eq!(post(&addr, "/", r#"{"k": 1}"#).0, 200);
+
+    let poll = std::thread::spawn({
+        let addr = addr.clone();
+        move || get(&addr, "/missing?wait=300")
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    handle.shutdown();
+    // the long poll that was running when shutdown began still got its answer
+    assert_eq!(poll.join().unwrap().0, 404);
+    // a connection that raced the listener's close is accepted but never answered, so don't wait long
+    let agent = ureq::AgentBuilder::new()
+        .timeout(std::time::Duration::from_secs(2))
+        .build();
+    assert!(agent.get(&format!("http://{}/k", addr)).call().is_err());
+}
+
+#[test]
+fn keys_over_max_key_bytes_are_refused_everywhere() {
+    let addr = node_with(&[("MAX_KEY_BYTES", "8")]);
+    let long = "k".repeat(9);
+    assert_eq!(post(&addr, "/", &format!(r#"{{"{}": 1}}"#, long)).0, 400);
+    assert_eq!(get(&addr, &format!("/{}", long)).0, 414);
+    assert_eq!(common::delete(&addr, &format!("/{}", long)).0, 414);
+    assert_eq!(post(&addr, &format!("/push/{}", long), "1").0, 414);
+    let mput = json(&post(&addr, "/mput", &format!(r#"{{"{}": 1, "short": 2}}"#, long)).1);
+    assert_eq!(mput[&long]["error"], "key too long");
+    assert_eq!(mput["short"]["written"], true);
+    let mdel = json(&post(&addr, "/mdel", &format!(r#"["{}"]"#, long)).1);
+    assert_eq!(mdel[&long]["error"], "key too long");
+
+    // exactly at the limit is fine
+    assert_eq!(post(&addr, "/", r#"{"kkkkkkkk": 1}"#).0, 200);
+}
+
+#[test]
+fn requests_over_slow_request_ms_are_counted() {
+    let addr = node_with(&[("SLOW_REQUEST_MS", "100")]);
+    assert_eq!(metric(&addr, "sdcs_slow_requests_total"), Some(0.0));
+    get(&addr, "/fast");
+    // a long poll that runs out is as slow as its wait
+    get(&addr, "/slow?wait=150");
+    assert_eq!(metric(&addr, "sdcs_slow_requests_total"), Some(1.0));
+}
+
+#[test]
+fn metrics_expose_the_cache_lock_wait_histogram() {
+    let addr = node();
+    assert_eq!(
+        metric(&addr, "sdcs_cache_lock_wait_seconds_count"),
+        Some(0.0)
+    );
+    let (_, body) = get(&addr, "/metrics");
+    assert!(body.contains("# TYPE sdcs_cache_lock_wait_seconds histogram"));
+    assert_eq!(
+        metric(&addr, r#"sdcs_cache_lock_wait_seconds_bucket{le="+Inf"}"#),
+        Some(0.0)
+    );
+}
+
+#[test]
+fn ttl_jitter_spreads_same_ttl_writes_within_its_bound() {
+    let ttl_left = |addr: &str, key: &str| {
+        let meta = json(&get(addr, &format!("/{}?meta=true", key)).1);
+        meta["meta"]["ttl_ms"].as_u64().unwrap()
+    };
+    let addr = node_with(&[("TTL_JITTER_PCT", "20")]);
+    let ttl = [("X-TTL", "10000")];
+    let left: Vec<u64> = (0..50)
+        .map(|i| {
+            let key = format!("j{}", i);
+            let body = format!(r#"{{"{}": {}}}"#, key, i);
+            assert_eq!(call_with("POST", &addr, "/", &ttl, Some(&body)).0, 200);
+            ttl_left(&addr, &key)
+        })
+        .collect();
+    // reads come a moment after each write, so allow for the time they took
+    assert!(
+        left.iter().all(|&ms| (7_900..=12_000).contains(&ms)),
+        "{:?}",
+        left
+    );
+    let (min, max) = (left.iter().min().unwrap(), left.iter().max().unwrap());
+    assert!(max - min > 1_000, "{:?}", left);
+
+    // without it every write keeps its TTL
+    let exact = node();
+    assert_eq!(
+        call_with("POST", &exact, "/", &ttl, Some(r#"{"e": 1}"#)).0,
+        200
+    );
+    assert!((9_900..=10_000).contains(&ttl_left(&exact, "e")));
+}
+
+#[test]
+fn verbose_errors_explain_where_a_body_stopped_parsing() {
+    let quiet = node();
+    assert_eq!(post(&quiet, "/", r#"{"k": }"#), (400, String::new()));
+
+    let verbose = node_with(&[("VERBOSE_ERRORS", "true")]);
+    let (status, body) = post(&verbose, "/", "{\n  \"k\": }");
+    assert_eq!(status, 400);
+    let detail = json(&body);
+    assert_eq!(detail["line"], 2);
+    assert_eq!(detail["column"], 8);
+    assert!(detail["error"].as_str().unwrap().contains("expected value"));
+}
+
+#[test]
+fn compressed_values_read_back_unchanged() {
+    let addr = node_with(&[("COMPRESS_VALUES", "true"), ("COMPRESS_MIN_BYTES", "16")]);
+    let text = "x".repeat(5000);
+    assert_eq!(
+        post(&addr, "/", &format!(r#"{{"big": "{}"}}"#, text)).0,
+        200
+    );
+    assert_eq!(
+        get(&addr, "/big"),
+        (200, format!(r#"{{"big":"{}"}}"#, text))
+    );
+    let stats = json(&get(&addr, "/stats").1);
+    assert!(stats["bytes"].as_u64().unwrap() < 1000);
+}
+
+#[test]
+fn the_audit_log_rotates_past_its_size_limit() {
+    let log = std::env::temp_dir().join(format!("sdcs-rotate-{}.log", std::process::id()));
+    let rotated = log.with_extension("log.1");
+    let _ = std::fs::remove_file(&log);
+    let _ = std::fs::remove_file(&rotated);
+    let addr = node_with(&[
+        ("AUDIT_LOG", log.to_str().unwrap()),
+        ("AUDIT_LOG_MAX_BYTES", "200"),
+    ]);
+    for i in 0..5 {
+        assert_eq!(post(&addr, "/", &format!(r#"{{"k{}": 1}}"#, i)).0, 200);
+    }
+    let current = std::fs::read_to_string(&log).unwrap();
+    let previous = std::fs::read_to_string(&rotated).unwrap();
+    let _ = std::fs::remove_file(&log);
+    let _ = std::fs::remove_file(&rotated);
+    assert!(current.len() <= 200 && previous.len() <= 200);
+    // the newest change is in the current file
+    assert_eq!(json(current.lines().last().unwrap())["key"], "k4");
+}
+
+#[test]
+fn the_key_filter_answers_absent_keys_and_reports_in_stats() {
+    let addr = node_with(&[("KEY_FILTER_SLOTS", "1000")]);
+    assert_eq!(post(&addr, "/", r#"{"present": 1}"#).0, 200);
+    assert_eq!(get(&addr, "/present").0, 200);
+    assert_eq!(get(&addr, "/absent").0, 404);
+    let filter = &json(&get(&addr, "/stats").1)["key_filter"];
+    assert_eq!(filter["slots"], 1000);
+    let answered =
+        filter["negatives"].as_u64().unwrap() + filter["false_positives"].as_u64().unwrap();
+    assert_eq!(answered, 1);
+
+    let plain = node();
+    assert!(json(&get(&plain, "/stats").1).get("key_filter").is_none());
+}
+
+#[test]
+fn selfcheck_reports_a_clean_store_even_while_read_only() {
+    let addr = node_with(&[("READ_ONLY", "true"), ("KEY_FILTER_SLOTS", "100")]);
+    let (status, body) = post(&addr, "/admin/selfcheck?repair=true", "");
+    assert_eq!(status, 200);
+    let report = json(&body);
+    assert_eq!(report["entries"], 0);
+    assert_eq!(report["key_filter_misses"], 0);
+    assert_eq!(report["namespaces"], serde_json::json!([]));
+    assert_eq!(report["repaired"], false);
+}
+
+#[test]
+fn an_ndjson_dump_restores_into_a_fresh_node() {
+    let source = node();
+    let batch: serde_json::Map<String, serde_json::Value> = (0..2000)
+        .map(|i| (format!("k{}", i), serde_json::json!({ "n": i })))
+        .collect();
+    let batch = serde_json::Value::Object(batch).to_string();
+    assert_eq!(post(&source, "/mput", &batch).0, 200);
+    assert_eq!(post(&source, "/ns/a/", r#"{"scoped": [1]}"#).0, 200);
+    let ttl = [("X-TTL", "60000"), ("X-Immutable", "true")];
+    assert_eq!(
+        call_with("POST", &source, "/", &ttl, Some(r#"{"kept": "x"}"#)).0,
+        200
+    );
+
+    let (status, dump) = get(&source, "/admin/dump?format=ndjson");
+    assert_eq!(status, 200);
+    let lines: Vec<serde_json::Value> = dump.lines().map(json).collect();
+    assert_eq!(lines.len(), 2002);
+    let kept = lines.iter().find(|l| l["key"] == "kept").unwrap();
+    assert_eq!(kept["immutable"], true);
+    assert!(kept["ttl_ms"].as_u64().unwrap() <= 60000);
+    assert_eq!(get(&source, "/admin/dump?format=csv").0, 400);
+
+    let fresh = node();
+    let (status, body) = post(&fresh, "/admin/restore", &dump);
+    assert_eq!(status, 200);
+    assert_eq!(
+        json(&body),
+        serde_json::json!({"restored": 2002, "rejected": 0, "kept": 0})
+    );
+    assert_eq!(get(&fresh, "/k1999").1, r#"{"k1999":{"n":1999}}"#);
+    assert_eq!(get(&fresh, "/ns/a/scoped").1, r#"{"scoped":[1]}"#);
+    let stats = json(&get(&fresh, "/stats").1);
+    assert_eq!(stats["count"], 2002);
+    assert_eq!(stats["namespaces"]["a"]["count"], 1);
+    let meta = json(&get(&fresh, "/kept?meta=true").1);
+    assert_eq!(meta["meta"]["immutable"], true);
+    assert!(meta["meta"]["ttl_ms"].is_u64());
+
+    // a line that doesn't parse stops the restore, keeping what came before it
+    let (status, body) = post(
+        &node(),
+        "/admin/restore",
+        "{\"key\":\"a\",\"value\":1}\nnope\n",
+    );
+    assert_eq!(status, 400);
+    assert_eq!(json(&body)["restored"], 1);
+}
+
+#[test]
+fn a_dry_run_restore_counts_what_it_would_write_and_stores_nothing() {
+    let addr = node();
+    assert_eq!(post(&addr, "/", r#"{"a": "old"}"#).0, 200);
+    let dump = "{\"key\":\"a\",\"value\":1}\n{\"key\":\"b\",\"value\":2}\n";
+    let (status, body) = post(&addr, "/admin/restore?dry_run=true", dump);
+    assert_eq!(status, 200);
+    assert_eq!(
+        json(&body),
+        serde_json::json!({"would_restore": 2, "would_replace": 1, "would_keep": 0, "dry_run": true})
+    );
+    let report = json(
+        &post(
+            &addr,
+            "/admin/restore?dry_run=true&keep_existing=true",
+            dump,
+        )
+        .1,
+    );
+    assert_eq!(
+        (
+            report["would_restore"].as_u64(),
+            report["would_keep"].as_u64()
+        ),
+        (Some(1), Some(1))
+    );
+    assert_eq!(get(&addr, "/a").1, r#"{"a":"old"}"#);
+    assert_eq!(get(&addr, "/b").0, 404);
+
+    // dry runs change nothing, so a read-only node still answers them
+    assert_eq!(
+        post(&addr, "/admin/readonly", r#"{"read_only": true}"#).0,
+        200
+    );
+    assert_eq!(post(&addr, "/admin/restore?dry_run=true", dump).0, 200);
+    assert_eq!(post(&addr, "/admin/rehash?dry_run=true", "").0, 200);
+    assert_eq!(post(&addr, "/admin/invalidate/a?dry_run=true", "").0, 200);
+    assert_eq!(post(&addr, "/admin/restore", dump).0, 503);
+    assert_eq!(post(&addr, "/admin/restore?dry_run=false", dump).0, 503);
+}
+
+#[test]
+fn a_gzipped_dump_restores_into_a_fresh_node() {
+    use std::io::{Read, Write};
+    let source = node();
+    let batch: serde_json::Map<String, serde_json::Value> = (0..500)
+        .map(|i| {
+            (
+                format!("z{}", i),
+                serde_json::json!({ "text": "compressible ".repeat(8) }),
+            )
+        })
+        .collect();
+    let batch = serde_json::Value::Object(batch).to_string();
+    assert_eq!(post(&source, "/mput", &batch).0, 200);
+    let sorted_lines = |text: &str| {
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        lines.sort();
+        lines
+    };
+
+    // by hand, as ureq would unpack it: HTTP/1.0 gets the body unchunked, up to the close
+    let mut conn = std::net::TcpStream::connect(&*source).unwrap();
+    conn.write_all(b"GET /admin/dump?compress=gzip HTTP/1.0\r\n\r\n")
+        .unwrap();
+    let mut raw = Vec::new();
+    conn.read_to_end(&mut raw).unwrap();
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&raw[..split]).to_ascii_lowercase();
+    assert!(head.contains("content-encoding: gzip"), "{}", head);
+    let gzipped = raw[split + 4..].to_vec();
+    let mut plain = String::new();
+    flate2::read::GzDecoder::new(&gzipped[..])
+        .read_to_string(&mut plain)
+        .unwrap();
+    assert!(gzipped.len() * 4 < plain.len());
+    let expected = get(&source, "/admin/dump?format=ndjson").1;
+    assert_eq!(sorted_lines(&plain), sorted_lines(&expected));
+    assert_eq!(get(&source, "/admin/dump?compress=zip").0, 400);
+
+    let fresh = node();
+    let resp = ureq::post(&format!("http://{}/admin/restore", &*fresh))
+        .send_bytes(&gzipped)
+        .unwrap();
+    let mut body = String::new();
+    resp.into_reader().read_to_string(&mut body).unwrap();
+    assert_eq!(json(&body)["restored"], 500);
+    assert_eq!(json(&get(&fresh, "/stats").1)["count"], 500);
+    assert_eq!(get(&fresh, "/z42").1, get(&source, "/z42").1);
+}
+
+#[test]
+fn a_panicking_handler_answers_500_and_the_node_keeps_serving() {
+    use baby_sdcs::cache::Cache;
+
+    let (srv, _) = server::init_server("panics", "127.0.0.1:0");
+    let addr = srv.server_addr().to_string();
+    let store = Cache::with_transform(Box::new(|key, value| {
+        assert_ne!(key, "boom", "transform refused the key");
+        value
+    }));
+    let handle = server::spawn_server(srv, "panics", addr.clone(), vec![addr.clone()], store);
+
+    assert_eq!(post(&addr, "/", r#"{"boom": 1}"#).0, 500);
+    assert_eq!(post(&addr, "/", r#"{"fine": 1}"#).0, 200);
+    assert_eq!(get(&addr, "/fine"), (200, r#"{"fine":1}"#.to_string()));
+    handle.shutdown();
+}
+
+#[test]
+fn oversized_or_too_many_headers_get_431() {
+    let addr = node_with(&[("MAX_HEADERS", "10"), ("MAX_HEADER_BYTES", "2048")]);
+    assert_eq!(call_with("GET", &addr, "/", &[("X-A", "1")], None).0, 200);
+    let big = "v".repeat(4096);
+    assert_eq!(
+        call_with("GET", &addr, "/", &[("X-Big", &big)], None).0,
+        431
+    );
+    let names: Vec<String> = (0..20).map(|i| format!("X-H{}", i)).collect();
+    let many: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "1")).collect();
+    assert_eq!(
+        call_with("POST", &addr, "/", &many, Some(r#"{"k": 1}"#)).0,
+        431
+    );
+    assert_eq!(get(&addr, "/k").0, 404);
+}
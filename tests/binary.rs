@@ -0,0 +1,168 @@
+//! The `baby_sdcs` binary, run as its own process.
+
+mod common;
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// A port nothing is listening on right now.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn binary() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_baby_sdcs"))
+}
+
+/// The running server process, killed when dropped.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Poll `url` until the server answers, or give up after a few seconds.
+fn wait_for(url: &str) -> ureq::Response {
+    let started = Instant::now();
+    loop {
+        match ureq::get(url).call() {
+            Ok(resp) => return resp,
+            Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("{}: {}", url, e),
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+#[test]
+fn container_mode_binds_bind_host() {
+    let port = free_port().to_string();
+    let peer = format!("127.0.0.1:{}", port);
+    let child = binary()
+        .env("PEERS", &peer)
+        .env("PORT", &port)
+        .env("BIND_HOST", "127.0.0.1")
+        .env("NAME", "127.0.0.1")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let _running = Running(child);
+    assert_eq!(wait_for(&format!("http://{}/health", peer)).status(), 200);
+}
+
+#[test]
+fn an_invalid_bind_host_exits_with_an_error() {
+    let output = binary()
+        .env("PEERS", "127.0.0.1:1")
+        .env("PORT", "1")
+        .env("BIND_HOST", "not-an-ip")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("\"not-an-ip\" is not an IP address"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn an_invalid_dev_host_exits_with_an_error() {
+    let output = binary().env("DEV_HOST", "localhost").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid DEV_HOST"), "{}", stderr);
+}
+
+/// Run a CLI subcommand and return its exit code and stdout.
+fn cli(args: &[&str]) -> (Option<i32>, String) {
+    let output = binary().args(args).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.code(), stdout)
+}
+
+#[test]
+fn cli_subcommands_talk_to_a_node() {
+    let node = common::node();
+    assert_eq!(
+        cli(&["get", &node, "k"]),
+        (Some(0), "not found".to_string())
+    );
+    let (code, out) = cli(&["set", &node, "k", r#"{"a":1}"#]);
+    assert_eq!(code, Some(0));
+    assert!(out.starts_with("ok (version "), "{}", out);
+    assert_eq!(
+        cli(&["get", &node, "k"]),
+        (Some(0), r#"{"a":1}"#.to_string())
+    );
+    // not JSON, so stored as a string
+    cli(&["set", &node, "s", "plain text"]);
+    assert_eq!(
+        cli(&["get", &node, "s"]),
+        (Some(0), r#""plain text""#.to_string())
+    );
+    assert_eq!(
+        cli(&["delete", &node, "k"]),
+        (Some(0), "deleted".to_string())
+    );
+    assert_eq!(
+        cli(&["delete", &node, "k"]),
+        (Some(0), "not found".to_string())
+    );
+
+    assert_eq!(cli(&["get", &common::unused_addr(), "k"]).0, Some(1));
+    assert_eq!(cli(&["frobnicate"]).0, Some(2));
+}
+
+/// Run a server with `env` set, POST `body` to it, and return what it logged to stderr.
+fn stderr_after_post(env: &[(&str, &str)], body: &str) -> String {
+    let port = free_port().to_string();
+    let peer = format!("127.0.0.1:{}", port);
+    let mut command = binary();
+    command
+        .env("PEERS", &peer)
+        .env("PORT", &port)
+        .env("BIND_HOST", "127.0.0.1")
+        .env("NAME", "127.0.0.1")
+        .envs(env.iter().copied())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let mut running = Running(command.spawn().unwrap());
+    wait_for(&format!("http://{}/health?shallow=true", peer));
+    let (status, _) = common::post(&peer, "/", body);
+    assert_eq!(status, 200);
+    let _ = running.0.kill();
+    let mut stderr = String::new();
+    std::io::Read::read_to_string(running.0.stderr.as_mut().unwrap(), &mut stderr).unwrap();
+    stderr
+}
+
+#[test]
+fn log_bodies_logs_truncated_bodies_only_when_on() {
+    let body = format!(r#"{{"logged": "{}"}}"#, "x".repeat(100));
+    let on = stderr_after_post(
+        &[("LOG_BODIES", "true"), ("LOG_BODY_MAX_BYTES", "20")],
+        &body,
+    );
+    let line = on
+        .lines()
+        .find(|line| line.contains("POST / request body"))
+        .unwrap_or_else(|| panic!("request body not logged: {}", on));
+    assert!(
+        line.ends_with(&format!("({} bytes): {}...", body.len(), &body[..20])),
+        "{}",
+        line
+    );
+    assert!(on.contains("POST / response body"), "{}", on);
+
+    let off = stderr_after_post(&[], &body);
+    assert!(!off.contains("logged"), "{}", off);
+}
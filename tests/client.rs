@@ -0,0 +1,83 @@
+//! The blocking `Client`, against a running cluster.
+
+mod common;
+
+use baby_sdcs::client::{Client, ClientError};
+use common::{cluster, cluster_with_down, key_owned_by, metric};
+use serde_json::json;
+
+#[test]
+fn client_reads_writes_and_deletes_through_any_node() {
+    let peers = cluster(2);
+    let client = Client::new(&format!("http://{}/", peers[0]));
+    let key = key_owned_by(1, &peers, "c");
+
+    assert_eq!(client.get(&key).unwrap(), None);
+    let version = client.set(&key, json!({"a": [1, 2]})).unwrap();
+    assert!(version.is_some());
+    assert_eq!(client.get(&key).unwrap(), Some(json!({"a": [1, 2]})));
+    // a bare host:port works too
+    assert_eq!(
+        Client::new(&peers[1]).get(&key).unwrap(),
+        Some(json!({"a": [1, 2]}))
+    );
+
+    assert!(client.delete(&key).unwrap());
+    assert!(!client.delete(&key).unwrap());
+    assert_eq!(client.get(&key).unwrap(), None);
+}
+
+#[test]
+fn client_reports_refusals_and_unreachable_nodes() {
+    let peers = cluster(1);
+    let client = Client::new(&peers[0]);
+    let long = "k".repeat(2000);
+    assert!(matches!(client.get(&long), Err(ClientError::Status(414))));
+    let down = Client::new(&common::unused_addr());
+    assert!(matches!(down.get("k"), Err(ClientError::Transport(_))));
+}
+
+#[test]
+fn a_routing_client_goes_straight_to_owners() {
+    let peers = cluster(2);
+    let client = Client::routing(&peers[0]);
+    let key = key_owned_by(1, &peers, "direct");
+    client.set(&key, json!(1)).unwrap();
+    assert_eq!(client.get(&key).unwrap(), Some(json!(1)));
+    assert!(client.delete(&key).unwrap());
+
+    // the seed node served only the ring and forwarded nothing
+    let forwarded = |op: &str| {
+        let series = format!(
+            "sdcs_request_duration_seconds_count{{op=\"{}\",route=\"forwarded\"}}",
+            op
+        );
+        metric(&peers[0], &series)
+    };
+    for op in ["get", "post", "delete"] {
+        assert_eq!(forwarded(op), Some(0.0), "{}", op);
+    }
+    let local = |addr: &str| {
+        metric(
+            addr,
+            "sdcs_request_duration_seconds_count{op=\"get\",route=\"local\"}",
+        )
+    };
+    assert_eq!(local(&peers[1]), Some(1.0));
+}
+
+#[test]
+fn a_routing_client_falls_back_to_the_seed_when_an_owner_is_unreachable() {
+    // the third peer is listed but down, so the seed can't reach it either
+    let peers = cluster_with_down(2, 1, &[]);
+    let client = Client::routing(&peers[0]);
+    let up = key_owned_by(1, &peers, "up");
+    let down = key_owned_by(2, &peers, "down");
+    client.set(&up, json!(1)).unwrap();
+    // the seed node answers for the unreachable owner, as a plain client would see it
+    assert!(matches!(
+        client.set(&down, json!(1)),
+        Err(ClientError::Status(502))
+    ));
+    assert_eq!(client.get(&up).unwrap(), Some(json!(1)));
+}
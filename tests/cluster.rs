@@ -0,0 +1,1777 @@
+//! Behaviour that spans nodes: forwarding to owners and cluster-wide operations.
+
+mod common;
+
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use baby_sdcs::server;
+use baby_sdcs::transport::{RpcReply, Transport};
+
+use common::{
+    call_with, cluster, cluster_with, cluster_with_down, delete, get, json, key_owned_by, metric,
+    post, request,
+};
+
+#[test]
+fn idempotency_keys_replay_through_any_node() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "ik");
+    let body = format!(r#"{{"{}": 1}}"#, key);
+    let headers = [("Idempotency-Key", "write-1")];
+
+    // first sent to the owner, then repeated through the other node after an overwrite
+    assert_eq!(
+        call_with("POST", &peers[1], "/", &headers, Some(&body)).0,
+        200
+    );
+    assert_eq!(post(&peers[1], "/", &format!(r#"{{"{}": 2}}"#, key)).0, 200);
+    let replay = call_with("POST", &peers[0], "/", &headers, Some(&body));
+    assert_eq!(replay, (200, format!(r#"{{"{}":1}}"#, key)));
+    assert_eq!(
+        get(&peers[0], &format!("/{}", key)).1,
+        format!(r#"{{"{}":2}}"#, key)
+    );
+
+    // the same key with a different write is refused rather than replayed
+    let other = format!(r#"{{"{}": 3}}"#, key);
+    assert_eq!(
+        call_with("POST", &peers[0], "/", &headers, Some(&other)).0,
+        422
+    );
+}
+
+#[test]
+fn distribution_report_counts_every_sample() {
+    let peers = cluster(3);
+    let (status, body) = get(&peers[0], "/admin/distribution?samples=3000");
+    assert_eq!(status, 200);
+    let report = json(&body);
+    assert_eq!(report["samples"], 3000);
+    let counts: Vec<u64> = peers
+        .iter()
+        .map(|p| report["peers"][p].as_u64().unwrap())
+        .collect();
+    assert_eq!(counts.iter().sum::<u64>(), 3000);
+    // no peer is left out or gets most of the keys
+    assert!(counts.iter().all(|&c| c > 600 && c < 1400), "{:?}", counts);
+    assert!(report["max_skew"].as_f64().unwrap() < 1.4);
+
+    assert_eq!(get(&peers[0], "/admin/distribution?samples=2000000").0, 400);
+    assert_eq!(get(&peers[0], "/admin/distribution?samples=x").0, 400);
+}
+
+#[test]
+fn if_match_guards_writes_on_the_owner() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "cas");
+    let body = |v: u32| format!(r#"{{"{}": {}}}"#, key, v);
+
+    // written through the other node, so the ETag and the 412 are relayed back
+    let first = request("POST", &peers[0], "/", &[], Some(&body(1)));
+    assert_eq!(first.status(), 200);
+    let etag = first.header("ETag").unwrap().to_string();
+
+    let stale = [("If-Match", "\"999999\"")];
+    assert_eq!(
+        call_with("POST", &peers[0], "/", &stale, Some(&body(2))).0,
+        412
+    );
+    assert_eq!(
+        call_with(
+            "POST",
+            &peers[0],
+            "/",
+            &[("If-Match", "nope")],
+            Some(&body(2))
+        )
+        .0,
+        412
+    );
+
+    let second = request(
+        "POST",
+        &peers[0],
+        "/",
+        &[("If-Match", &etag)],
+        Some(&body(2)),
+    );
+    assert_eq!(second.status(), 200);
+    assert_ne!(second.header("ETag").unwrap(), etag);
+    // the old version no longer matches
+    assert_eq!(
+        call_with(
+            "POST",
+            &peers[1],
+            "/",
+            &[("If-Match", &etag)],
+            Some(&body(3))
+        )
+        .0,
+        412
+    );
+    assert_eq!(
+        get(&peers[1], &format!("/{}", key)).1,
+        format!(r#"{{"{}":2}}"#, key)
+    );
+}
+
+#[test]
+fn msgpack_peer_traffic_stays_json_for_clients() {
+    let peers = cluster_with(2, &[("RPC_MSGPACK", "true")]);
+    let key = key_owned_by(1, &peers, "mp");
+    let body = format!(r#"{{"{}": {{"list": [1, "two", null]}}}}"#, key);
+    let expected = format!(r#"{{"{}":{{"list":[1,"two",null]}}}}"#, key);
+
+    let write = request("POST", &peers[0], "/", &[], Some(&body));
+    assert_eq!(write.status(), 200);
+    assert!(write.header("Content-Type").unwrap().contains("json"));
+    assert_eq!(write.into_string().unwrap(), expected);
+    assert_eq!(get(&peers[0], &format!("/{}", key)), (200, expected));
+
+    // what a peer sees: the owner takes and answers msgpack when asked to
+    // (not a `Value`: with arbitrary_precision its numbers serialize as a struct)
+    let packed = rmp_serde::to_vec(&std::collections::HashMap::from([(&key, 7)])).unwrap();
+    let owner = format!("http://{}/", peers[1]);
+    let resp = ureq::post(&owner)
+        .set("Content-Type", "application/msgpack")
+        .send_bytes(&packed)
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = ureq::get(&format!("{}{}", owner, key))
+        .set("Accept", "application/msgpack")
+        .call()
+        .unwrap();
+    assert_eq!(resp.header("Content-Type"), Some("application/msgpack"));
+    let mut bytes = Vec::new();
+    resp.into_reader().read_to_end(&mut bytes).unwrap();
+    let value: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(value, serde_json::json!({ &key: 7 }));
+}
+
+#[test]
+fn big_and_precise_numbers_keep_their_digits_through_json_and_msgpack_forwards() {
+    let number = "[123456789012345678901234567890,0.1000000000000000000001]";
+    for env in [[("RPC_MSGPACK", "false")], [("RPC_MSGPACK", "true")]] {
+        let peers = cluster_with(2, &env);
+        let key = key_owned_by(1, &peers, "num");
+        assert_eq!(
+            post(&peers[0], "/", &format!(r#"{{"{}": {}}}"#, key, number)).0,
+            200
+        );
+        let expected = format!(r#"{{"{}":{}}}"#, key, number);
+        assert_eq!(
+            get(&peers[0], &format!("/{}", key)),
+            (200, expected),
+            "{:?}",
+            env
+        );
+    }
+}
+
+#[test]
+fn cbor_values_round_trip_through_any_node() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "cb");
+    assert!(key.len() < 24);
+    // {key: {"n": 1, "s": "x"}} in CBOR, by hand
+    let mut cbor = vec![0xa1, 0x60 | key.len() as u8];
+    cbor.extend(key.as_bytes());
+    cbor.extend([0xa2, 0x61, b'n', 0x01, 0x61, b's', 0x61, b'x']);
+    let resp = ureq::post(&format!("http://{}/", peers[0]))
+        .set("Content-Type", "application/cbor")
+        .set("Accept", "application/cbor")
+        .send_bytes(&cbor)
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.header("Content-Type"), Some("application/cbor"));
+
+    for addr in peers.iter() {
+        let resp = ureq::get(&format!("http://{}/{}", addr, key))
+            .set("Accept", "application/cbor")
+            .call()
+            .unwrap();
+        assert_eq!(resp.header("Content-Type"), Some("application/cbor"));
+        let mut bytes = Vec::new();
+        resp.into_reader().read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, cbor);
+    }
+    // JSON clients see the same value
+    assert_eq!(
+        get(&peers[0], &format!("/{}", key)).1,
+        format!(r#"{{"{}":{{"n":1,"s":"x"}}}}"#, key)
+    );
+    // a body that isn't CBOR is refused like bad JSON
+    let bad = ureq::post(&format!("http://{}/", peers[0]))
+        .set("Content-Type", "application/cbor")
+        .send_bytes(&[0xa1]);
+    assert!(matches!(bad, Err(ureq::Error::Status(400, _))));
+}
+
+#[test]
+fn wait_for_peers_holds_off_traffic_until_peers_answer() {
+    let env = [
+        ("WAIT_FOR_PEERS", "true"),
+        ("PEER_WAIT_TIMEOUT_MS", "30000"),
+    ];
+    let peers = cluster_with_down(1, 1, &env);
+    let resp = request("GET", &peers[0], "/k", &[], None);
+    assert_eq!(resp.status(), 503);
+    assert!(resp.header("Retry-After").is_some());
+    let (status, body) = get(&peers[0], "/health");
+    assert_eq!(status, 503);
+    assert_eq!(json(&body)["status"], "starting");
+    // peers that are waiting themselves can still tell this one is up
+    assert_eq!(get(&peers[0], "/health?shallow=true").0, 200);
+
+    let late = common::start_down(&peers, 1);
+    let started = Instant::now();
+    while get(&peers[0], "/k").0 == 503 {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "never started serving"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(get(&peers[0], "/health").0, 200);
+    late.shutdown();
+}
+
+#[test]
+fn wait_for_peers_serves_anyway_once_its_timeout_passes() {
+    let env = [("WAIT_FOR_PEERS", "true"), ("PEER_WAIT_TIMEOUT_MS", "300")];
+    let peers = cluster_with_down(1, 1, &env);
+    let started = Instant::now();
+    assert_eq!(get(&peers[0], "/k").0, 503);
+    while get(&peers[0], "/k").0 == 503 {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "never started serving"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(get(&peers[0], "/k").0, 404);
+}
+
+#[test]
+fn prefix_deletes_reach_every_node_and_spare_other_keys() {
+    let peers = cluster(3);
+    let doomed: Vec<String> = (0..3).map(|i| key_owned_by(i, &peers, "user:1:")).collect();
+    let kept: Vec<String> = (0..3).map(|i| key_owned_by(i, &peers, "user:2:")).collect();
+    for key in doomed.iter().chain(&kept) {
+        assert_eq!(post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, key)).0, 200);
+    }
+    // a tenant's keys are stored as "user:..." too, but only its own prefix deletes reach them
+    assert_eq!(post(&peers[0], "/ns/user/", r#"{"1:x": 1}"#).0, 200);
+
+    let (status, body) = delete(&peers[1], "/user:1:*");
+    assert_eq!(status, 200);
+    let report = json(&body);
+    assert_eq!(report["deleted"], 3);
+    for addr in peers.iter() {
+        assert_eq!(report["nodes"][addr], 1);
+    }
+    for key in &doomed {
+        assert_eq!(get(&peers[0], &format!("/{}", key)).0, 404);
+    }
+    for key in &kept {
+        assert_eq!(get(&peers[2], &format!("/{}", key)).0, 200);
+    }
+    assert_eq!(get(&peers[0], "/ns/user/1:x").0, 200);
+    assert_eq!(json(&delete(&peers[0], "/user:1:*").1)["deleted"], 0);
+    assert_eq!(delete(&peers[0], "/*").0, 400);
+}
+
+#[test]
+fn mdel_deletes_across_owners_and_reports_each_key() {
+    let peers = cluster(3);
+    let keys: Vec<String> = (0..3).map(|i| key_owned_by(i, &peers, "md")).collect();
+    for key in &keys {
+        assert_eq!(post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, key)).0, 200);
+    }
+    let body = serde_json::json!([keys[0], keys[1], keys[2], "missing"]).to_string();
+    let (status, reply) = post(&peers[0], "/mdel", &body);
+    assert_eq!(status, 200);
+    let reply = json(&reply);
+    for key in &keys {
+        assert_eq!(reply[key]["deleted"], true, "{}", key);
+        assert_eq!(get(&peers[2], &format!("/{}", key)).0, 404);
+    }
+    assert_eq!(reply["missing"]["deleted"], false);
+    assert_eq!(post(&peers[0], "/mdel", r#"{"not": "a list"}"#).0, 400);
+}
+
+#[test]
+fn mdel_reports_keys_of_an_unreachable_owner_as_not_deleted() {
+    let peers = cluster_with_down(1, 1, &[]);
+    let local = key_owned_by(0, &peers, "md");
+    let remote = key_owned_by(1, &peers, "md");
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, local)).0,
+        200
+    );
+
+    let body = serde_json::json!([local, remote]).to_string();
+    let (status, reply) = post(&peers[0], "/mdel", &body);
+    assert_eq!(status, 207);
+    let reply = json(&reply);
+    assert_eq!(reply[&local]["deleted"], true);
+    assert_eq!(reply[&remote]["deleted"], false);
+    assert_eq!(reply[&remote]["error"], "owner unreachable");
+    let only_remote = serde_json::json!([remote]).to_string();
+    assert_eq!(post(&peers[0], "/mdel", &only_remote).0, 502);
+}
+
+#[test]
+fn long_poll_through_a_peer_returns_when_the_key_is_written() {
+    let peers = cluster_with(2, &[("MAX_WAIT_MS", "5000")]);
+    let key = key_owned_by(1, &peers, "lp");
+    let path = format!("/{}?wait=3000", key);
+    let started = Instant::now();
+    let reply = std::thread::scope(|s| {
+        let poll = s.spawn(|| get(&peers[0], &path));
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!poll.is_finished());
+        assert_eq!(
+            post(&peers[1], "/", &format!(r#"{{"{}": "here"}}"#, key)).0,
+            200
+        );
+        poll.join().unwrap()
+    });
+    assert_eq!(reply, (200, format!(r#"{{"{}":"here"}}"#, key)));
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn long_poll_times_out_with_404() {
+    let peers = cluster_with(1, &[("MAX_WAIT_MS", "100")]);
+    let started = Instant::now();
+    // capped at MAX_WAIT_MS
+    assert_eq!(get(&peers[0], "/missing?wait=10000").0, 404);
+    let waited = started.elapsed();
+    assert!(waited >= Duration::from_millis(100) && waited < Duration::from_secs(2));
+    assert_eq!(get(&peers[0], "/missing?wait=soon").0, 400);
+}
+
+#[test]
+fn metrics_split_latency_by_operation_and_route() {
+    let peers = cluster(2);
+    let local = key_owned_by(0, &peers, "m");
+    let remote = key_owned_by(1, &peers, "m");
+    post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, local));
+    post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, remote));
+    get(&peers[0], &format!("/{}", remote));
+    get(&peers[0], &format!("/{}", remote));
+
+    let count = |op: &str, route: &str| {
+        let series = format!(
+            "sdcs_request_duration_seconds_count{{op=\"{}\",route=\"{}\"}}",
+            op, route
+        );
+        metric(&peers[0], &series)
+    };
+    assert_eq!(count("post", "local"), Some(1.0));
+    assert_eq!(count("post", "forwarded"), Some(1.0));
+    assert_eq!(count("get", "forwarded"), Some(2.0));
+    assert_eq!(count("delete", "local"), Some(0.0));
+    let inf = "sdcs_request_duration_seconds_bucket{op=\"get\",route=\"forwarded\",le=\"+Inf\"}";
+    assert_eq!(metric(&peers[0], inf), Some(2.0));
+}
+
+#[test]
+fn pretty_indents_local_and_forwarded_reads() {
+    let peers = cluster(2);
+    for owner in 0..2 {
+        let key = key_owned_by(owner, &peers, "p");
+        post(&peers[0], "/", &format!(r#"{{"{}": {{"a": 1}}}}"#, key));
+        let (status, body) = get(&peers[0], &format!("/{}?pretty=true", key));
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            format!("{{\n  \"{}\": {{\n    \"a\": 1\n  }}\n}}", key)
+        );
+        // compact unless asked
+        assert_eq!(
+            get(&peers[0], &format!("/{}", key)).1,
+            format!(r#"{{"{}":{{"a":1}}}}"#, key)
+        );
+    }
+    assert!(
+        get(&peers[0], "/stats?pretty=1")
+            .1
+            .contains("\n  \"count\"")
+    );
+}
+
+#[test]
+fn push_appends_on_the_owner_and_checks_the_grown_array() {
+    let peers = cluster_with(2, &[("MAX_VALUE_BYTES", "8")]);
+    let key = key_owned_by(1, &peers, "list");
+    let push = |item: &str| post(&peers[0], &format!("/push/{}", key), item);
+
+    assert_eq!(push("1"), (200, r#"{"length":1}"#.to_string()));
+    assert_eq!(push("22"), (200, r#"{"length":2}"#.to_string()));
+    // each item is small, but [1,22,333] is over the limit
+    assert_eq!(push("333").0, 413);
+    assert_eq!(
+        get(&peers[1], &format!("/{}", key)).1,
+        format!(r#"{{"{}":[1,22]}}"#, key)
+    );
+
+    let scalar = key_owned_by(1, &peers, "scalar");
+    post(&peers[0], "/", &format!(r#"{{"{}": 5}}"#, scalar));
+    assert_eq!(post(&peers[0], &format!("/push/{}", scalar), "1").0, 409);
+}
+
+#[test]
+fn lrem_removes_on_the_owner() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "list");
+    post(
+        &peers[0],
+        "/",
+        &format!(r#"{{"{}": ["a", "b", "a"]}}"#, key),
+    );
+    let lrem = format!("/lrem/{}", key);
+    assert_eq!(
+        post(&peers[0], &lrem, r#""a""#),
+        (200, r#"{"removed":2}"#.to_string())
+    );
+    assert_eq!(
+        post(&peers[0], &lrem, r#""a""#),
+        (200, r#"{"removed":0}"#.to_string())
+    );
+    assert_eq!(
+        get(&peers[0], &format!("/{}", key)).1,
+        format!(r#"{{"{}":["b"]}}"#, key)
+    );
+}
+
+#[test]
+fn health_reports_reachable_peers() {
+    let peers = cluster(2);
+    post(&peers[0], "/", r#"{"k": 1}"#);
+    let (status, body) = get(&peers[0], "/health");
+    assert_eq!(status, 200);
+    let report = json(&body);
+    assert_eq!(report["status"], "ok");
+    assert_eq!(report["store"], "ok");
+    assert_eq!(report["peers"]["reachable"], 1);
+    assert_eq!(report["peers"]["total"], 1);
+}
+
+#[test]
+fn health_is_degraded_when_no_peer_answers() {
+    let peers = cluster_with_down(1, 2, &[]);
+    let resp = request("GET", &peers[0], "/health", &[], None);
+    assert_eq!(resp.status(), 503);
+    assert!(resp.header("Retry-After").is_some());
+    let report = json(&resp.into_string().unwrap());
+    assert_eq!(report["status"], "degraded");
+    assert_eq!(report["peers"]["reachable"], 0);
+    assert_eq!(report["peers"]["total"], 2);
+    // the shallow check only says the process is up
+    assert_eq!(get(&peers[0], "/health?shallow=true").0, 200);
+}
+
+#[test]
+fn peer_gauges_show_reachable_peers_up_and_down_ones_down() {
+    let peers = cluster_with_down(2, 1, &[]);
+    let up = format!(r#"sdcs_peer_up{{peer="{}"}}"#, peers[1]);
+    let down = format!(r#"sdcs_peer_up{{peer="{}"}}"#, peers[2]);
+    // peers are probed in the background, starting as the node does
+    let started = Instant::now();
+    while metric(&peers[0], &down).is_none() && started.elapsed() < Duration::from_secs(2) {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(metric(&peers[0], &up), Some(1.0));
+    assert_eq!(metric(&peers[0], &down), Some(0.0));
+    let seen = |peer: &str| {
+        let series = format!(
+            r#"sdcs_peer_last_seen_timestamp_seconds{{peer="{}"}}"#,
+            peer
+        );
+        metric(&peers[0], &series)
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    assert!((now - seen(&peers[1]).unwrap()).abs() < 10.0);
+    assert_eq!(seen(&peers[2]), None);
+    // /health reads the same probes
+    let report = json(&get(&peers[0], "/health").1);
+    assert_eq!(report["peers"]["reachable"], 1);
+    assert_eq!(report["peers"]["total"], 2);
+}
+
+#[test]
+fn mput_writes_across_owners_and_reports_each_key() {
+    let peers = cluster_with_down(2, 1, &[("MAX_VALUE_BYTES", "10")]);
+    let keys: Vec<String> = (0..3).map(|i| key_owned_by(i, &peers, "mp")).collect();
+    let body = serde_json::json!({
+        &keys[0]: 1,
+        &keys[1]: {"a": 2},
+        &keys[2]: 3,
+        "big": "far too long for the limit",
+    });
+    let (status, reply) = post(&peers[0], "/mput", &body.to_string());
+    // some keys were written and some weren't
+    assert_eq!(status, 207);
+    let reply = json(&reply);
+    assert_eq!(reply[&keys[0]]["written"], true);
+    assert_eq!(reply[&keys[1]]["written"], true);
+    // the third owner never started
+    assert_eq!(reply[&keys[2]]["written"], false);
+    assert_eq!(reply[&keys[2]]["error"], "owner unreachable");
+    assert_eq!(reply["big"]["written"], false);
+
+    assert_eq!(
+        get(&peers[0], &format!("/{}", keys[1])).1,
+        format!(r#"{{"{}":{{"a":2}}}}"#, keys[1])
+    );
+    assert_eq!(
+        get(&peers[1], &format!("/{}", keys[0])).1,
+        format!(r#"{{"{}":1}}"#, keys[0])
+    );
+    assert_eq!(post(&peers[0], "/mput", "[1, 2]").0, 400);
+    // every key refused, or every owner down
+    assert_eq!(
+        post(
+            &peers[0],
+            "/mput",
+            r#"{"big": "far too long for the limit"}"#
+        )
+        .0,
+        400
+    );
+    let unreachable = serde_json::json!({ &keys[2]: 3 }).to_string();
+    assert_eq!(post(&peers[0], "/mput", &unreachable).0, 502);
+}
+
+#[test]
+fn immutable_values_are_cacheable_through_any_node() {
+    let peers = cluster_with(2, &[("IMMUTABLE_MAX_AGE_S", "600")]);
+    let fixed = key_owned_by(1, &peers, "fixed");
+    let plain = key_owned_by(1, &peers, "plain");
+    let immutable = [("X-Immutable", "true")];
+    let body = format!(r#"{{"{}": 1}}"#, fixed);
+    assert_eq!(
+        call_with("POST", &peers[0], "/", &immutable, Some(&body)).0,
+        200
+    );
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": 2}}"#, plain)).0,
+        200
+    );
+
+    for addr in peers.iter() {
+        let resp = request("GET", addr, &format!("/{}", fixed), &[], None);
+        assert_eq!(resp.header("Cache-Control"), Some("public, max-age=600"));
+        let resp = request("GET", addr, &format!("/{}", plain), &[], None);
+        assert_eq!(resp.header("Cache-Control"), Some("no-cache"));
+    }
+}
+
+#[test]
+fn forwards_beyond_peer_max_in_flight_are_shed() {
+    let peers = cluster_with(2, &[("PEER_MAX_IN_FLIGHT", "1")]);
+    let key = key_owned_by(1, &peers, "held");
+    let stats = || json(&get(&peers[0], "/stats").1);
+
+    std::thread::scope(|s| {
+        // a forwarded long poll holds the only slot to the owner while it waits
+        let poll = s.spawn(|| get(&peers[0], &format!("/{}?wait=5000", key)));
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(stats()["peer_in_flight"][&peers[1]], 1);
+        let shed = request("GET", &peers[0], &format!("/{}", key), &[], None);
+        assert_eq!(shed.status(), 503);
+        assert!(shed.header("Retry-After").is_some());
+
+        assert_eq!(post(&peers[1], "/", &format!(r#"{{"{}": 1}}"#, key)).0, 200);
+        assert_eq!(poll.join().unwrap().0, 200);
+    });
+    assert_eq!(stats()["peer_in_flight"][&peers[1]], 0);
+}
+
+#[test]
+fn meta_reads_report_version_and_size_through_any_node() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "meta");
+    let write = request(
+        "POST",
+        &peers[1],
+        "/",
+        &[],
+        Some(&format!(r#"{{"{}": [1, 2]}}"#, key)),
+    );
+    let etag = write.header("ETag").unwrap().to_string();
+
+    for addr in peers.iter() {
+        let (status, body) = get(addr, &format!("/{}?meta=true", key));
+        assert_eq!(status, 200);
+        let body = json(&body);
+        assert_eq!(body["value"], serde_json::json!([1, 2]));
+        assert_eq!(body["meta"]["etag"], etag.as_str());
+        assert_eq!(body["meta"]["bytes"], "[1,2]".len());
+        assert_eq!(body["meta"]["immutable"], false);
+        assert!(body["meta"]["version"].is_u64());
+    }
+    // without it the plain envelope is unchanged
+    assert_eq!(
+        json(&get(&peers[0], &format!("/{}", key)).1)[&key],
+        serde_json::json!([1, 2])
+    );
+}
+
+#[test]
+fn forwards_work_with_any_rpc_pool_size() {
+    for size in ["0", "4"] {
+        let peers = cluster_with(2, &[("RPC_POOL_SIZE", size)]);
+        let key = key_owned_by(1, &peers, "pool");
+        // repeated forwards to one owner reuse pooled connections when there are any
+        for i in 0..8 {
+            let body = format!(r#"{{"{}": {}}}"#, key, i);
+            assert_eq!(post(&peers[0], "/", &body).0, 200, "RPC_POOL_SIZE={}", size);
+        }
+        assert_eq!(
+            get(&peers[0], &format!("/{}", key)),
+            (200, format!(r#"{{"{}":7}}"#, key))
+        );
+    }
+}
+
+#[test]
+fn read_only_refuses_writes_on_every_node_until_switched_off() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "ro");
+    let write = format!(r#"{{"{}": 1}}"#, key);
+    assert_eq!(post(&peers[1], "/", &write).0, 200);
+
+    let (status, body) = post(&peers[0], "/admin/readonly", r#"{"read_only": true}"#);
+    assert_eq!(status, 200);
+    assert_eq!(json(&body)["peers"][&peers[1]], true);
+    for addr in peers.iter() {
+        let refused = request("POST", addr, "/", &[], Some(&write));
+        assert_eq!(refused.status(), 503);
+        assert_eq!(refused.header("Retry-After"), Some("5"));
+        assert_eq!(
+            call_with("DELETE", addr, &format!("/{}", key), &[], None).0,
+            503
+        );
+        // reads still work
+        assert_eq!(get(addr, &format!("/{}", key)).0, 200);
+    }
+
+    // the switch itself stays available on a read-only node
+    assert_eq!(
+        post(&peers[1], "/admin/readonly", r#"{"read_only": false}"#).0,
+        200
+    );
+    assert_eq!(post(&peers[0], "/", &write).0, 200);
+    assert_eq!(post(&peers[0], "/admin/readonly", "{}").0, 400);
+}
+
+#[test]
+fn read_only_can_be_set_at_startup() {
+    let peers = cluster_with(1, &[("READ_ONLY", "true")]);
+    assert_eq!(post(&peers[0], "/", r#"{"k": 1}"#).0, 503);
+}
+
+#[test]
+fn schemas_guard_writes_on_every_node() {
+    let peers = cluster(2);
+    let schema = r#"{"prefix": "user:", "schema": {"type": "object", "required": ["name"]}}"#;
+    let (status, body) = post(&peers[0], "/admin/schema", schema);
+    assert_eq!(status, 200);
+    assert_eq!(json(&body)["peers"][&peers[1]], true);
+
+    for i in 0..2 {
+        let key = key_owned_by(i, &peers, "user:");
+        let (status, body) = post(&peers[0], "/", &format!(r#"{{"{}": {{"age": 3}}}}"#, key));
+        assert_eq!(status, 422);
+        assert_eq!(json(&body)["error"], "schema violation");
+        let ok = format!(r#"{{"{}": {{"name": "ada"}}}}"#, key);
+        assert_eq!(post(&peers[0], "/", &ok).0, 200);
+    }
+    // other keys aren't checked, and a more specific prefix wins
+    assert_eq!(post(&peers[0], "/", r#"{"other": 1}"#).0, 200);
+    let list = r#"{"prefix": "user:list", "schema": {"type": "array", "maxItems": 1}}"#;
+    assert_eq!(post(&peers[0], "/admin/schema", list).0, 200);
+    assert_eq!(post(&peers[0], "/push/user:list", "1").0, 200);
+    assert_eq!(post(&peers[0], "/push/user:list", "2").0, 422);
+
+    let (status, body) = post(
+        &peers[0],
+        "/mput",
+        r#"{"user:a": {}, "user:b": {"name": "b"}}"#,
+    );
+    assert_eq!(status, 207);
+    let body = json(&body);
+    assert_eq!(body["user:a"]["written"], false);
+    assert_eq!(body["user:b"]["written"], true);
+
+    let bad = r#"{"prefix": "x", "schema": {"type": 5}}"#;
+    assert_eq!(post(&peers[0], "/admin/schema", bad).0, 400);
+}
+
+#[test]
+fn wildcard_gets_gather_matches_from_every_node() {
+    let peers = cluster(2);
+    let mine = key_owned_by(0, &peers, "w:");
+    let theirs = key_owned_by(1, &peers, "w:");
+    for key in [&mine, &theirs] {
+        assert_eq!(post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, key)).0, 200);
+    }
+    assert_eq!(post(&peers[0], "/", r#"{"elsewhere": 1}"#).0, 200);
+    // a namespace whose storage keys share the prefix stays out of it
+    assert_eq!(post(&peers[0], "/ns/w/", r#"{"hidden": 1}"#).0, 200);
+
+    for addr in peers.iter() {
+        let (status, body) = get(addr, "/w:*");
+        assert_eq!(status, 200);
+        let found = json(&body);
+        let mut keys: Vec<_> = found.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        let mut expected = vec![mine.clone(), theirs.clone()];
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+    assert_eq!(
+        json(&get(&peers[1], "/ns/w/*").1),
+        serde_json::json!({"hidden": 1})
+    );
+}
+
+#[test]
+fn wildcard_gets_fail_rather_than_answer_partly() {
+    let peers = cluster_with_down(1, 1, &[]);
+    assert_eq!(get(&peers[0], "/w:*").0, 502);
+}
+
+#[test]
+fn chaos_injects_failures_only_when_enabled() {
+    let plain = cluster(1);
+    assert_eq!(get(&plain[0], "/admin/chaos").0, 404);
+    assert_eq!(
+        post(&plain[0], "/admin/chaos", r#"{"error_rate": 1.0}"#).0,
+        404
+    );
+
+    let peers = cluster_with(2, &[("CHAOS", "true")]);
+    let key = key_owned_by(1, &peers, "chaos");
+    let write = format!(r#"{{"{}": 1}}"#, key);
+    assert_eq!(json(&get(&peers[0], "/admin/chaos").1)["error_rate"], 0.0);
+
+    assert_eq!(
+        post(&peers[0], "/admin/chaos", r#"{"drop_forward_rate": 1.0}"#).0,
+        200
+    );
+    assert_eq!(post(&peers[0], "/", &write).0, 502);
+    assert_eq!(post(&peers[1], "/", &write).0, 200);
+
+    let (status, body) = post(&peers[0], "/admin/chaos", r#"{"error_rate": 1.0}"#);
+    assert_eq!(status, 200);
+    assert_eq!(json(&body)["drop_forward_rate"], 0.0);
+    assert_eq!(get(&peers[0], &format!("/{}", key)).0, 500);
+    // the switch itself is never failed, so injection can be turned off again
+    assert_eq!(post(&peers[0], "/admin/chaos", "{}").0, 200);
+    assert_eq!(get(&peers[0], &format!("/{}", key)).0, 200);
+}
+
+#[test]
+fn range_reads_return_bytes_of_the_value_through_any_node() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "range");
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": "0123456789"}}"#, key)).0,
+        200
+    );
+    let path = format!("/{}", key);
+
+    for addr in peers.iter() {
+        let plain = request("GET", addr, &path, &[], None);
+        assert_eq!(plain.header("Accept-Ranges"), Some("bytes"));
+
+        let part = request("GET", addr, &path, &[("Range", "bytes=1-3")], None);
+        assert_eq!(part.status(), 206);
+        assert_eq!(part.header("Content-Range"), Some("bytes 1-3/12"));
+        assert_eq!(part.into_string().unwrap(), "012");
+
+        let tail = request("GET", addr, &path, &[("Range", "bytes=-2")], None);
+        assert_eq!(tail.into_string().unwrap(), "9\"");
+
+        let past = request("GET", addr, &path, &[("Range", "bytes=20-")], None);
+        assert_eq!(past.status(), 416);
+        assert_eq!(past.header("Content-Range"), Some("bytes */12"));
+
+        // several ranges aren't supported, so the whole value comes back
+        let many = request("GET", addr, &path, &[("Range", "bytes=0-1,4-5")], None);
+        assert_eq!(many.status(), 200);
+        assert_eq!(many.into_string().unwrap(), r#""0123456789""#);
+    }
+}
+
+#[test]
+fn missing_null_answers_absent_keys_through_any_node() {
+    let peers = cluster(2);
+    for i in 0..2 {
+        let key = key_owned_by(i, &peers, "absent");
+        let path = format!("/{}", key);
+        assert_eq!(get(&peers[0], &path).0, 404);
+        let (status, body) = get(&peers[0], &format!("{}?missing=null", path));
+        assert_eq!(status, 200);
+        assert_eq!(json(&body), serde_json::json!({ key: null }));
+    }
+}
+
+#[test]
+fn rpcs_are_counted_per_peer_by_outcome() {
+    let peers = cluster_with_down(2, 1, &[]);
+    let up = key_owned_by(1, &peers, "up");
+    let down = key_owned_by(2, &peers, "down");
+    assert_eq!(post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, up)).0, 200);
+    assert_eq!(get(&peers[0], &format!("/{}", up)).0, 200);
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, down)).0,
+        502
+    );
+
+    let stats = json(&get(&peers[0], "/stats").1);
+    // the write and the read, plus any background health probes
+    assert!(stats["peer_rpcs"][&peers[1]]["ok"].as_u64().unwrap() >= 2);
+    assert_eq!(stats["peer_rpcs"][&peers[1]]["failed"], 0);
+    assert!(stats["peer_rpcs"][&peers[2]]["failed"].as_u64().unwrap() >= 1);
+    assert_eq!(stats["peer_rpcs"][&peers[2]]["ok"], 0);
+    let series = format!(
+        "sdcs_peer_rpcs_total{{peer=\"{}\",result=\"ok\"}}",
+        peers[1]
+    );
+    assert!(metric(&peers[0], &series).unwrap() >= 2.0);
+}
+
+#[test]
+fn txn_applies_a_single_owner_batch_atomically_through_any_node() {
+    let peers = cluster(2);
+    let a = key_owned_by(1, &peers, "txn-a");
+    let b = key_owned_by(1, &peers, "txn-b");
+    let set = format!(
+        r#"[{{"op": "set", "key": "{}", "value": 1, "immutable": true}}, {{"op": "set", "key": "{}", "value": 2}}]"#,
+        a, b
+    );
+    let (status, body) = post(&peers[0], "/txn", &set);
+    assert_eq!(status, 200);
+    let body = json(&body);
+    assert_eq!(body["committed"], true);
+    let version = body["versions"][1].as_u64().unwrap();
+    let read = request("GET", &peers[0], &format!("/{}", a), &[], None);
+    assert!(read.header("Cache-Control").unwrap().starts_with("public"));
+
+    // a stale cas refuses the whole batch, including the delete before it
+    let stale = format!(
+        r#"[{{"op": "delete", "key": "{}"}}, {{"op": "cas", "key": "{}", "version": {}, "value": 3}}]"#,
+        a,
+        b,
+        version + 100
+    );
+    let (status, body) = post(&peers[0], "/txn", &stale);
+    assert_eq!(status, 412);
+    assert_eq!(json(&body)["op"], 1);
+    assert_eq!(get(&peers[0], &format!("/{}", a)).0, 200);
+
+    let other = key_owned_by(0, &peers, "txn-c");
+    let split = format!(
+        r#"[{{"op": "delete", "key": "{}"}}, {{"op": "delete", "key": "{}"}}]"#,
+        a, other
+    );
+    let (status, body) = post(&peers[0], "/txn", &split);
+    assert_eq!(status, 409);
+    assert_eq!(json(&body)["owners"][&other], peers[0].as_str());
+    assert_eq!(post(&peers[0], "/txn", "[]").0, 400);
+}
+
+#[test]
+fn the_owner_audits_changes_under_the_real_client() {
+    let log = std::env::temp_dir().join(format!("sdcs-audit-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let peers = cluster_with(2, &[("AUDIT_LOG", log.to_str().unwrap())]);
+    let key = key_owned_by(1, &peers, "audited");
+
+    // a client can't put someone else's name in the log
+    let spoofed = [("X-Forwarded-For", "someone-else")];
+    let body = format!(r#"{{"{}": 1}}"#, key);
+    assert_eq!(
+        call_with("POST", &peers[0], "/", &spoofed, Some(&body)).0,
+        200
+    );
+    assert_eq!(
+        call_with("POST", &peers[1], "/", &spoofed, Some(&body)).0,
+        200
+    );
+    assert_eq!(
+        call_with("DELETE", &peers[0], &format!("/{}", key), &[], None).0,
+        200
+    );
+
+    let records: Vec<serde_json::Value> = std::fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(json)
+        .collect();
+    let _ = std::fs::remove_file(&log);
+    let summary: Vec<_> = records
+        .iter()
+        .map(|r| {
+            (
+                r["op"].as_str().unwrap(),
+                r["old"].as_bool(),
+                r["new"].as_bool(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("set", Some(false), Some(true)),
+            ("set", Some(true), Some(true)),
+            ("delete", Some(true), Some(false)),
+        ]
+    );
+    for record in &records {
+        assert_eq!(record["key"], key.as_str());
+        assert!(record["client"].as_str().unwrap().starts_with("127.0.0.1:"));
+    }
+}
+
+#[test]
+fn ring_and_owner_let_clients_route_keys_themselves() {
+    let peers = cluster(3);
+    let ring = json(&get(&peers[1], "/ring").1);
+    assert_eq!(ring["hash"], "seahash");
+    assert_eq!(ring["placement"], "modulo");
+    let listed: Vec<String> = serde_json::from_value(ring["peers"].clone()).unwrap();
+    assert_eq!(listed, peers.to_vec());
+
+    for (path, skey) in [("/owner/k1", "k1"), ("/ns/a/owner/k1", "a:k1")] {
+        // what a client computes from /ring
+        let expected = (seahash::hash(skey.as_bytes()) as usize) % listed.len();
+        let owner = json(&get(&peers[0], path).1);
+        assert_eq!(owner["storage_key"], skey);
+        assert_eq!(owner["owner"], listed[expected].as_str());
+        assert_eq!(owner["local"], expected == 0);
+    }
+    assert_eq!(get(&peers[0], "/owner/").0, 400);
+}
+
+#[test]
+fn null_values_are_stored_rejected_or_deleted_through_any_node() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "null");
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": null}}"#, key)).0,
+        200
+    );
+    let stored = json(&get(&peers[0], &format!("/{}", key)).1);
+    assert_eq!(stored, serde_json::json!({ &key: null }));
+    drop(peers);
+
+    let peers = cluster_with(2, &[("NULL_VALUES", "reject")]);
+    let key = key_owned_by(1, &peers, "null");
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": null}}"#, key)).0,
+        400
+    );
+    let body = json(&post(&peers[0], "/mput", &format!(r#"{{"{}": null}}"#, key)).1);
+    assert_eq!(body[&key]["written"], false);
+    let txn = format!(r#"[{{"op": "set", "key": "{}", "value": null}}]"#, key);
+    assert_eq!(post(&peers[0], "/txn", &txn).0, 400);
+    assert_eq!(get(&peers[0], &format!("/{}", key)).0, 404);
+    drop(peers);
+
+    let peers = cluster_with(2, &[("NULL_VALUES", "delete")]);
+    // a schema that would refuse null doesn't stand in the way of the delete
+    let schema = r#"{"prefix": "null", "schema": {"type": "object"}}"#;
+    assert_eq!(post(&peers[0], "/admin/schema", schema).0, 200);
+    let key = key_owned_by(1, &peers, "null");
+    let path = format!("/{}", key);
+    for (route, body) in [
+        ("/", format!(r#"{{"{}": null}}"#, key)),
+        ("/mput", format!(r#"{{"{}": null}}"#, key)),
+        (
+            "/txn",
+            format!(r#"[{{"op": "set", "key": "{}", "value": null}}]"#, key),
+        ),
+    ] {
+        assert_eq!(
+            post(&peers[0], "/", &format!(r#"{{"{}": {{}}}}"#, key)).0,
+            200
+        );
+        assert_eq!(post(&peers[0], route, &body).0, 200, "{}", route);
+        assert_eq!(get(&peers[0], &path).0, 404, "{}", route);
+    }
+}
+
+/// A transport whose peers never answer.
+struct Unreachable;
+
+impl Transport for Unreachable {
+    fn get(&self, _: &str, _: &[(&str, &str)], _: Option<Duration>) -> Result<RpcReply, String> {
+        Err("unreachable".to_string())
+    }
+
+    fn post(&self, _: &str, _: &[u8], _: &[(&str, &str)]) -> Result<RpcReply, String> {
+        Err("unreachable".to_string())
+    }
+
+    fn delete(&self, _: &str, _: &[(&str, &str)]) -> Result<RpcReply, String> {
+        Err("unreachable".to_string())
+    }
+}
+
+#[test]
+fn forwards_go_through_the_servers_transport() {
+    let peers = cluster(2);
+    // a third view of the same two-node cluster, reaching its peers through `Unreachable`
+    let (srv, store) = server::init_server("cut-off", "127.0.0.1:0");
+    let addr = srv.server_addr().to_string();
+    let mut view = peers.to_vec();
+    view[0] = addr.clone();
+    let handle = server::spawn_server_with_transport(
+        srv,
+        "cut-off",
+        addr.clone(),
+        view,
+        store,
+        Box::new(Unreachable),
+    );
+
+    let local = key_owned_by(0, &peers, "t");
+    let remote = key_owned_by(1, &peers, "t");
+    assert_eq!(post(&addr, "/", &format!(r#"{{"{}": 1}}"#, local)).0, 200);
+    assert_eq!(get(&addr, &format!("/{}", local)).0, 200);
+    // the real peer is up, but this node can't reach it
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, remote)).0,
+        200
+    );
+    // reads hide the failure as a miss
+    assert_eq!(get(&addr, &format!("/{}", remote)).0, 404);
+    assert_eq!(post(&addr, "/", &format!(r#"{{"{}": 2}}"#, remote)).0, 502);
+    handle.shutdown();
+}
+
+#[test]
+fn keyed_appends_land_once_and_rpc_attempts_of_zero_still_forward() {
+    let peers = cluster_with(2, &[("RPC_ATTEMPTS", "0")]);
+    let key = key_owned_by(1, &peers, "appended");
+    let path = format!("/push/{}", key);
+    let keyed = [("Idempotency-Key", "append-once")];
+    for _ in 0..2 {
+        let (status, body) = call_with("POST", &peers[0], &path, &keyed, Some("\"x\""));
+        assert_eq!((status, body.as_str()), (200, r#"{"length":1}"#));
+    }
+    let other = call_with("POST", &peers[0], &path, &keyed, Some("\"y\"")).0;
+    assert_eq!(other, 422);
+    assert_eq!(
+        json(&get(&peers[1], &format!("/{}", key)).1),
+        serde_json::json!({ &key: ["x"] })
+    );
+}
+
+#[test]
+fn values_expire_after_their_ttl_through_any_node() {
+    let peers = cluster_with(2, &[("DEFAULT_TTL_MS", "150")]);
+    let expiring = key_owned_by(1, &peers, "ttl");
+    let kept = key_owned_by(1, &peers, "kept");
+    let write = |key: &str, ttl: Option<&str>| {
+        let headers: Vec<(&str, &str)> = ttl.map(|ttl| ("X-TTL", ttl)).into_iter().collect();
+        let body = format!(r#"{{"{}": 1}}"#, key);
+        call_with("POST", &peers[0], "/", &headers, Some(&body)).0
+    };
+    assert_eq!(write(&expiring, None), 200);
+    // 0 keeps the value, whatever the default
+    assert_eq!(write(&kept, Some("0")), 200);
+    assert_eq!(write(&kept, Some("soon")), 400);
+    assert_eq!(get(&peers[1], &format!("/{}", expiring)).0, 200);
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(get(&peers[0], &format!("/{}", expiring)).0, 404);
+    assert_eq!(get(&peers[0], &format!("/{}", kept)).0, 200);
+}
+
+#[test]
+fn encoded_keys_reach_their_owner_intact() {
+    let peers = cluster(2);
+    // a key with a space, a slash and a question mark, owned by the second node
+    let key = (0..)
+        .map(|i| format!("a/b c?{}", i))
+        .find(|key| common::owner_index(key, &peers) == 1)
+        .unwrap();
+    let encoded = key
+        .replace('/', "%2F")
+        .replace(' ', "%20")
+        .replace('?', "%3F");
+    let body = serde_json::json!({ &key: "v" }).to_string();
+    assert_eq!(post(&peers[0], "/", &body).0, 200);
+    for addr in peers.iter() {
+        let (status, body) = get(addr, &format!("/{}", encoded));
+        assert_eq!(status, 200);
+        assert_eq!(json(&body), serde_json::json!({ &key: "v" }));
+    }
+    assert_eq!(get(&peers[0], "/%ff").0, 400);
+    assert_eq!(
+        call_with("DELETE", &peers[0], &format!("/{}", encoded), &[], None).0,
+        200
+    );
+    assert_eq!(get(&peers[1], &format!("/{}", encoded)).0, 404);
+}
+
+#[test]
+fn quotas_refuse_writes_past_them_on_every_node() {
+    let peers = cluster_with(2, &[("READ_ONLY", "true")]);
+    // set even while read-only: a quota stores nothing
+    let quota = r#"{"namespace": "t", "max_entries": 1}"#;
+    let (status, body) = post(&peers[0], "/admin/quota", quota);
+    assert_eq!(status, 200);
+    assert_eq!(json(&body)["peers"][&peers[1]], true);
+    for addr in peers.iter() {
+        post(addr, "/admin/readonly", r#"{"read_only": false}"#);
+    }
+
+    // two keys whose storage keys the second node owns
+    let mut keys = (0..)
+        .map(|i| format!("q{}", i))
+        .filter(|k| common::owner_index(&format!("t:{}", k), &peers) == 1);
+    let (first, second) = (keys.next().unwrap(), keys.next().unwrap());
+    let write = |key: &str| post(&peers[0], "/ns/t/", &format!(r#"{{"{}": 1}}"#, key));
+    assert_eq!(write(&first).0, 200);
+    let (status, body) = write(&second);
+    assert_eq!(status, 507);
+    assert_eq!(json(&body)["error"], "namespace quota exceeded");
+    // the other namespace has no quota
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, second)).0,
+        200
+    );
+
+    let batch = format!(r#"{{"{}": 2}}"#, second);
+    let (status, body) = post(&peers[0], "/ns/t/mput", &batch);
+    assert_eq!(status, 507);
+    assert_eq!(json(&body)[&second]["error"], "namespace quota exceeded");
+    let txn = format!(r#"[{{"op": "set", "key": "{}", "value": 2}}]"#, second);
+    assert_eq!(post(&peers[0], "/ns/t/txn", &txn).0, 507);
+    assert_eq!(
+        post(&peers[0], &format!("/ns/t/push/{}", second), "1").0,
+        507
+    );
+
+    assert_eq!(
+        call_with("DELETE", &peers[0], &format!("/ns/t/{}", first), &[], None).0,
+        200
+    );
+    assert_eq!(write(&second).0, 200);
+    assert_eq!(
+        post(&peers[0], "/admin/quota", r#"{"namespace": "a:b"}"#).0,
+        400
+    );
+}
+
+#[test]
+fn redirect_mode_sends_clients_to_the_owner_and_forward_mode_proxies() {
+    let peers = cluster_with(2, &[("ROUTING_MODE", "redirect")]);
+    let key = key_owned_by(1, &peers, "rd");
+    assert_eq!(post(&peers[1], "/", &format!(r#"{{"{}": 1}}"#, key)).0, 200);
+    let no_redirects = ureq::AgentBuilder::new().redirects(0).build();
+    let resp = no_redirects
+        .get(&format!("http://{}/{}?meta=true", peers[0], key))
+        .call()
+        .unwrap();
+    assert_eq!(resp.status(), 307);
+    assert_eq!(
+        resp.header("Location"),
+        Some(format!("http://{}/{}?meta=true", peers[1], key).as_str())
+    );
+    // a client that follows it reads from the owner; keys the node owns are answered in place
+    assert_eq!(json(&get(&peers[0], &format!("/{}", key)).1)[&key], 1);
+    let local = key_owned_by(0, &peers, "rd");
+    let resp = no_redirects
+        .get(&format!("http://{}/{}", peers[0], local))
+        .call();
+    assert!(matches!(resp, Err(ureq::Error::Status(404, _))));
+
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "fw");
+    assert_eq!(post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, key)).0, 200);
+    let resp = no_redirects
+        .get(&format!("http://{}/{}", peers[0], key))
+        .call()
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(json(&resp.into_string().unwrap())[&key], 1);
+}
+
+#[test]
+fn invalidate_drops_a_key_from_every_node_owner_or_not() {
+    let peers = cluster(3);
+    let key = key_owned_by(1, &peers, "inv");
+    let body = format!(r#"{{"{}": 1}}"#, key);
+    assert_eq!(post(&peers[0], "/", &body).0, 200);
+    // a forwarded batch is stored where it lands, leaving a stray copy on a non-owner
+    let forwarded = [("X-SDCS-Forwarded", "1")];
+    assert_eq!(
+        call_with("POST", &peers[2], "/mput", &forwarded, Some(&body)).0,
+        200
+    );
+    let strays = |addr: &str| {
+        let (_, body) = call_with("GET", addr, &format!("/{}*", key), &forwarded, None);
+        json(&body)
+    };
+    assert_eq!(strays(&peers[2])[&key], 1);
+
+    let (status, body) = post(&peers[0], &format!("/admin/invalidate/{}", key), "");
+    assert_eq!(status, 200);
+    let report = json(&body);
+    assert_eq!(report["removed"], false);
+    assert_eq!(report["peers"][&peers[1]], true);
+    assert_eq!(report["peers"][&peers[2]], true);
+    for addr in peers.iter() {
+        assert_eq!(get(addr, &format!("/{}", key)).0, 404);
+        assert_eq!(strays(addr), serde_json::json!({}));
+    }
+}
+
+#[test]
+fn rehash_moves_keys_restored_under_another_peers_list_to_their_owners() {
+    // a one-node cluster owns everything; its dump then lands on the first of two nodes
+    let single = common::node();
+    let batch: serde_json::Map<String, serde_json::Value> =
+        (0..50).map(|i| (format!("rh{}", i), i.into())).collect();
+    let batch = serde_json::Value::Object(batch);
+    assert_eq!(post(&single, "/mput", &batch.to_string()).0, 200);
+    let (_, dump) = get(&single, "/admin/dump?format=ndjson");
+
+    let peers = cluster(2);
+    assert_eq!(post(&peers[0], "/admin/restore", &dump).0, 200);
+    let held = |addr: &str| {
+        let forwarded = [("X-SDCS-Forwarded", "1")];
+        json(&call_with("GET", addr, "/rh*", &forwarded, None).1)
+    };
+    assert_eq!(held(&peers[0]).as_object().unwrap().len(), 50);
+    // the owner already holds a newer copy of one misplaced key: that copy wins
+    let kept = batch
+        .as_object()
+        .unwrap()
+        .keys()
+        .find(|k| common::owner_index(k, &peers) == 1)
+        .unwrap();
+    assert_eq!(
+        post(&peers[0], "/", &format!(r#"{{"{}": "newer"}}"#, kept)).0,
+        200
+    );
+
+    let (status, body) = post(&peers[0], "/admin/rehash", "");
+    assert_eq!(status, 200);
+    let report = json(&body);
+    let misplaced = batch
+        .as_object()
+        .unwrap()
+        .keys()
+        .filter(|k| common::owner_index(k, &peers) == 1)
+        .count();
+    assert!(misplaced > 0);
+    assert_eq!(report["checked"], 50);
+    assert_eq!(report["moved"], misplaced);
+    assert_eq!(report["failed"], 0);
+
+    let (here, there) = (held(&peers[0]), held(&peers[1]));
+    assert_eq!(here.as_object().unwrap().len(), 50 - misplaced);
+    assert_eq!(there.as_object().unwrap().len(), misplaced);
+    assert_eq!(there[kept.as_str()], "newer");
+    for (key, value) in batch.as_object().unwrap() {
+        let expected = if key == kept {
+            "newer".into()
+        } else {
+            value.clone()
+        };
+        assert_eq!(json(&get(&peers[0], &format!("/{}", key)).1)[key], expected);
+    }
+    // nothing is left to move
+    let report = json(&post(&peers[0], "/admin/rehash", "").1);
+    assert_eq!(report["moved"], 0);
+}
+
+#[test]
+fn rehashed_keys_are_audited_on_their_owner_under_the_rehashing_client() {
+    let log = std::env::temp_dir().join(format!("sdcs-rehash-audit-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let peers = cluster_with(2, &[("AUDIT_LOG", log.to_str().unwrap())]);
+    let key = key_owned_by(1, &peers, "rha");
+    // a forwarded batch is stored where it lands, leaving the key on a non-owner
+    let forwarded = [("X-SDCS-Forwarded", "1")];
+    let body = format!(r#"{{"{}": 1}}"#, key);
+    assert_eq!(
+        call_with("POST", &peers[0], "/mput", &forwarded, Some(&body)).0,
+        200
+    );
+
+    let ops = [("X-SDCS-Forwarded", "1"), ("X-Forwarded-For", "ops")];
+    let (status, body) = call_with("POST", &peers[0], "/admin/rehash", &ops, Some(""));
+    assert_eq!(status, 200);
+    assert_eq!(json(&body)["moved"], 1);
+    let records: Vec<serde_json::Value> = std::fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(json)
+        .collect();
+    let _ = std::fs::remove_file(&log);
+    let restored: Vec<_> = records.iter().filter(|r| r["op"] == "restore").collect();
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored[0]["key"], key.as_str());
+    assert_eq!(restored[0]["client"], "ops");
+}
+
+#[test]
+fn dry_runs_report_what_invalidate_and_rehash_would_do_and_change_nothing() {
+    let peers = cluster(3);
+    let forwarded = [("X-SDCS-Forwarded", "1")];
+    let held = |addr: &str| {
+        let (_, body) = call_with("GET", addr, "/dr*", &forwarded, None);
+        json(&body).as_object().unwrap().len()
+    };
+    // forwarded batches are stored where they land: five keys owned elsewhere sit on peers[0]
+    let owned_by = |owner: usize, n: usize| -> Vec<String> {
+        (0..)
+            .map(|i| format!("dr{}", i))
+            .filter(|key| common::owner_index(key, &peers) == owner)
+            .take(n)
+            .collect()
+    };
+    let strays = [owned_by(1, 3), owned_by(2, 2)].concat();
+    let batch: serde_json::Map<String, serde_json::Value> =
+        strays.iter().map(|k| (k.clone(), 1.into())).collect();
+    let batch = serde_json::Value::Object(batch).to_string();
+    assert_eq!(
+        call_with("POST", &peers[0], "/mput", &forwarded, Some(&batch)).0,
+        200
+    );
+    assert_eq!(held(&peers[0]), 5);
+
+    let (status, body) = post(&peers[0], "/admin/rehash?dry_run=true", "");
+    assert_eq!(status, 200);
+    let report = json(&body);
+    assert_eq!(report["dry_run"], true);
+    assert_eq!(report["would_move"], 5);
+    let to = |i: usize| {
+        strays
+            .iter()
+            .filter(|k| common::owner_index(k, &peers) == i)
+            .count()
+    };
+    assert_eq!(report["owners"][&peers[1]], to(1));
+    assert_eq!(report["owners"][&peers[2]], to(2));
+    assert_eq!(
+        (held(&peers[0]), held(&peers[1]), held(&peers[2])),
+        (5, 0, 0)
+    );
+
+    let key = &strays[0];
+    let (status, body) = post(
+        &peers[1],
+        &format!("/admin/invalidate/{}?dry_run=true", key),
+        "",
+    );
+    assert_eq!(status, 200);
+    let report = json(&body);
+    assert_eq!(report["would_remove"], false);
+    assert_eq!(report["peers"][&peers[0]], true);
+    assert_eq!(report["peers"][&peers[2]], false);
+    assert_eq!(held(&peers[0]), 5);
+}
+
+#[test]
+fn stored_headers_come_back_on_gets_through_any_node() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "sh");
+    let body = format!(r#"{{"{}": "<p>hi</p>"}}"#, key);
+    let stored = r#"{"X-Page": "home", "Vary": "Accept-Language"}"#;
+    let headers = [("X-Store-Headers", stored)];
+    assert_eq!(
+        call_with("POST", &peers[0], "/", &headers, Some(&body)).0,
+        200
+    );
+    for addr in peers.iter() {
+        let resp = request("GET", addr, &format!("/{}", key), &[], None);
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.header("X-Page"), Some("home"));
+        assert_eq!(resp.header("Vary"), Some("Accept-Language"));
+        assert!(
+            resp.header("Content-Type")
+                .unwrap()
+                .starts_with("application/json")
+        );
+    }
+    let meta = json(&get(&peers[0], &format!("/{}?meta=true", key)).1);
+    assert_eq!(meta["meta"]["headers"]["X-Page"], "home");
+
+    // nothing that could forge a header line or override the server's own gets stored
+    for bad in [
+        r#"{"Content-Type": "text/html"}"#,
+        r#"{"Bad Name": "x"}"#,
+        "{\"X-Evil\": \"a\\r\\nSet-Cookie: b\"}",
+        r#"{"X-Number": 1}"#,
+        r#"["X-Page"]"#,
+    ] {
+        let headers = [("X-Store-Headers", bad)];
+        assert_eq!(
+            call_with("POST", &peers[0], "/", &headers, Some(&body)).0,
+            400,
+            "{}",
+            bad
+        );
+    }
+    let resp = request("GET", &peers[0], &format!("/{}", key), &[], None);
+    assert_eq!(resp.header("X-Page"), Some("home"));
+    assert_eq!(resp.header("Set-Cookie"), None);
+
+    // txn sets take them too, checked like the header before anything applies
+    let txn = format!(
+        r#"[{{"op": "set", "key": "{}", "value": 2, "headers": {{"X-Page": "txn"}}}}]"#,
+        key
+    );
+    assert_eq!(post(&peers[0], "/txn", &txn).0, 200);
+    let resp = request("GET", &peers[0], &format!("/{}", key), &[], None);
+    assert_eq!(resp.header("X-Page"), Some("txn"));
+    let bad = format!(
+        r#"[{{"op": "set", "key": "{}", "value": 3, "headers": {{"Content-Type": "text/html"}}}}]"#,
+        key
+    );
+    assert_eq!(post(&peers[0], "/txn", &bad).0, 400);
+    assert_eq!(json(&get(&peers[1], &format!("/{}", key)).1)[&key], 2);
+}
+
+/// Forwards with a plain agent, remembering the `X-Timeout-Ms` each request carried.
+struct RecordsTimeouts(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+impl RecordsTimeouts {
+    fn note(&self, headers: &[(&str, &str)]) {
+        if let Some((_, ms)) = headers.iter().find(|(name, _)| *name == "X-Timeout-Ms") {
+            self.0.lock().unwrap().push(ms.to_string());
+        }
+    }
+}
+
+impl Transport for RecordsTimeouts {
+    fn get(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+        timeout: Option<Duration>,
+    ) -> Result<RpcReply, String> {
+        self.note(headers);
+        Transport::get(&ureq::agent(), url, headers, timeout)
+    }
+
+    fn post(&self, url: &str, body: &[u8], headers: &[(&str, &str)]) -> Result<RpcReply, String> {
+        self.note(headers);
+        Transport::post(&ureq::agent(), url, body, headers)
+    }
+
+    fn delete(&self, url: &str, headers: &[(&str, &str)]) -> Result<RpcReply, String> {
+        self.note(headers);
+        Transport::delete(&ureq::agent(), url, headers)
+    }
+}
+
+#[test]
+fn forwards_carry_the_remaining_budget_and_owners_refuse_spent_ones() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "budget");
+    let body = format!(r#"{{"{}": 1}}"#, key);
+
+    // a forward whose budget ran out on the way is refused before the owner does anything
+    let spent = [("X-SDCS-Forwarded", "1"), ("X-Timeout-Ms", "0")];
+    assert_eq!(
+        call_with("POST", &peers[1], "/", &spent, Some(&body)).0,
+        504
+    );
+    assert_eq!(get(&peers[1], &format!("/{}", key)).0, 404);
+
+    // a node forwarding a request hands on what's left of its budget, not a fresh one
+    let sent = std::sync::Arc::default();
+    let (srv, store) = server::init_server("recorder", "127.0.0.1:0");
+    let addr = srv.server_addr().to_string();
+    let mut view = peers.to_vec();
+    view[0] = addr.clone();
+    let handle = server::spawn_server_with_transport(
+        srv,
+        "recorder",
+        addr.clone(),
+        view,
+        store,
+        Box::new(RecordsTimeouts(std::sync::Arc::clone(&sent))),
+    );
+    let budget = [("X-Timeout-Ms", "5000")];
+    assert_eq!(call_with("POST", &addr, "/", &budget, Some(&body)).0, 200);
+    assert_eq!(
+        call_with("GET", &addr, &format!("/{}", key), &budget, None).0,
+        200
+    );
+    // without a budget none is made up
+    assert_eq!(get(&addr, &format!("/{}", key)).0, 200);
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 3);
+    for ms in &sent[..2] {
+        let ms: u64 = ms.parse().unwrap();
+        assert!(ms <= 5000 && ms > 4000, "forwarded {} ms", ms);
+    }
+    assert_eq!(sent[2], "");
+    handle.shutdown();
+}
+
+#[test]
+fn misses_answer_the_default_given_through_any_node() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "dflt");
+    for addr in peers.iter() {
+        let path = |query: &str| format!("/{}{}", key, query);
+        assert_eq!(get(addr, &path("")).0, 404);
+        let (status, body) = get(addr, &path("?default=5"));
+        assert_eq!(status, 200);
+        assert_eq!(json(&body), serde_json::json!({ &key: 5 }));
+        // JSON if it parses, a string otherwise
+        let (_, body) = get(addr, &path("?default=%7B%22a%22%3A%5B1%5D%7D"));
+        assert_eq!(json(&body), serde_json::json!({ &key: {"a": [1]} }));
+        let (_, body) = get(addr, &path("?default=plain%20text"));
+        assert_eq!(json(&body), serde_json::json!({ &key: "plain text" }));
+        assert_eq!(get(addr, &path("?default=%ff")).0, 400);
+    }
+    // the default is never stored, and a stored value wins over it
+    assert_eq!(get(&peers[1], &format!("/{}", key)).0, 404);
+    assert_eq!(post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, key)).0, 200);
+    let (_, body) = get(&peers[0], &format!("/{}?default=5", key));
+    assert_eq!(json(&body), serde_json::json!({ &key: 1 }));
+}
+
+#[test]
+fn if_match_guards_deletes_on_the_owner() {
+    let peers = cluster(2);
+    for owner in 0..2 {
+        // deleted through the first node: locally, then forwarded
+        let key = key_owned_by(owner, &peers, "cad");
+        let path = format!("/{}", key);
+        let written = request(
+            "POST",
+            &peers[0],
+            "/",
+            &[],
+            Some(&format!(r#"{{"{}": 1}}"#, key)),
+        );
+        let etag = written.header("ETag").unwrap().to_string();
+
+        for stale in ["\"999999\"", "nope"] {
+            let (status, _) = call_with("DELETE", &peers[0], &path, &[("If-Match", stale)], None);
+            assert_eq!(status, 412);
+        }
+        assert_eq!(get(&peers[owner], &path).0, 200);
+
+        let (status, body) = call_with("DELETE", &peers[0], &path, &[("If-Match", &etag)], None);
+        assert_eq!((status, body.as_str()), (200, "1"));
+        assert_eq!(get(&peers[owner], &path).0, 404);
+        // nothing is at that version any more
+        let (status, _) = call_with("DELETE", &peers[0], &path, &[("If-Match", &etag)], None);
+        assert_eq!(status, 412);
+    }
+}
+
+/// `at` as an RFC 3339 UTC timestamp with milliseconds.
+fn rfc3339(at: std::time::SystemTime) -> String {
+    let since = at.duration_since(std::time::UNIX_EPOCH).unwrap();
+    let (days, secs) = ((since.as_secs() / 86_400) as i64, since.as_secs() % 86_400);
+    // the Gregorian date `days` after 1970-01-01 (Howard Hinnant's civil_from_days)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since.subsec_millis()
+    )
+}
+
+#[test]
+fn expire_at_sets_an_absolute_expiry_through_any_node() {
+    let peers = cluster_with(2, &[("VERBOSE_ERRORS", "true")]);
+    let key = key_owned_by(1, &peers, "at");
+    let body = format!(r#"{{"{}": 1}}"#, key);
+    let write = |headers: &[(&str, &str)]| call_with("POST", &peers[0], "/", headers, Some(&body));
+
+    let soon = rfc3339(std::time::SystemTime::now() + Duration::from_millis(400));
+    assert_eq!(write(&[("X-Expire-At", &soon)]).0, 200);
+    let meta = json(&get(&peers[1], &format!("/{}?meta=true", key)).1);
+    assert!(meta["meta"]["ttl_ms"].as_u64().unwrap() <= 400);
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(get(&peers[0], &format!("/{}", key)).0, 404);
+
+    // an offset and a fraction are honoured like Z
+    assert_eq!(
+        write(&[("X-Expire-At", "2999-01-01T02:00:00.5+02:00")]).0,
+        200
+    );
+    let meta = json(&get(&peers[0], &format!("/{}?meta=true", key)).1);
+    assert!(meta["meta"]["ttl_ms"].as_u64().unwrap() > 1 << 40);
+
+    for bad in [
+        [("X-Expire-At", "2001-01-01T00:00:00Z"), ("X-A", "")],
+        [("X-Expire-At", "tomorrow"), ("X-A", "")],
+        [("X-Expire-At", "2001-02-30T00:00:00Z"), ("X-A", "")],
+        [("X-Expire-At", "2999-01-01T00:00:00Z"), ("X-TTL", "1000")],
+    ] {
+        let (status, body) = write(&bad);
+        assert_eq!(status, 400, "{:?}", bad);
+        assert!(json(&body)["error"].is_string());
+    }
+    // the refused writes left the stored value alone
+    assert_eq!(get(&peers[1], &format!("/{}", key)).0, 200);
+}
+
+/// A transport whose peers all answer that they are overloaded, counting the writes it sends.
+#[derive(Clone, Default)]
+struct Overloaded {
+    calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl Overloaded {
+    fn reply(&self) -> Result<RpcReply, String> {
+        Ok(RpcReply {
+            status: 503,
+            body: Vec::new(),
+            headers: vec![
+                ("X-SDCS-Overloaded".to_string(), "1".to_string()),
+                ("Retry-After".to_string(), "5".to_string()),
+            ],
+        })
+    }
+}
+
+impl Transport for Overloaded {
+    // health probes go through here too, so only writes are counted
+    fn get(&self, _: &str, _: &[(&str, &str)], _: Option<Duration>) -> Result<RpcReply, String> {
+        self.reply()
+    }
+
+    fn post(&self, _: &str, _: &[u8], _: &[(&str, &str)]) -> Result<RpcReply, String> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.reply()
+    }
+
+    fn delete(&self, _: &str, _: &[(&str, &str)]) -> Result<RpcReply, String> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.reply()
+    }
+}
+
+#[test]
+fn an_overloaded_owner_is_backed_off_instead_of_retried() {
+    let owner = common::unused_addr();
+    let (srv, store) = server::init_server("coordinator", "127.0.0.1:0");
+    let addr = srv.server_addr().to_string();
+    let peers = vec![addr.clone(), owner.clone()];
+    let transport = Overloaded::default();
+    let handle = server::spawn_server_with_transport(
+        srv,
+        "coordinator",
+        addr.clone(),
+        peers.clone(),
+        store,
+        Box::new(transport.clone()),
+    );
+    let key = key_owned_by(1, &peers, "hot");
+
+    // the owner's 503 comes back as is, without the usual retries
+    let (status, _) = post(&addr, "/", &format!(r#"{{"{}": 1}}"#, key));
+    assert_eq!(status, 503);
+    assert_eq!(transport.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    let stats = json(&get(&addr, "/stats").1);
+    assert!(stats["peer_backoff_ms"][owner.as_str()].as_u64().unwrap() > 0);
+
+    // while backed off, forwards to it are shed here and never sent
+    assert_eq!(post(&addr, "/", &format!(r#"{{"{}": 2}}"#, key)).0, 503);
+    assert_eq!(delete(&addr, &format!("/{}", key)).0, 503);
+    assert_eq!(transport.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    // keys this node owns are served as usual
+    let local = key_owned_by(0, &peers, "cool");
+    assert_eq!(post(&addr, "/", &format!(r#"{{"{}": 1}}"#, local)).0, 200);
+    handle.shutdown();
+}
+
+#[test]
+fn responses_name_the_node_that_answered_and_the_keys_owner() {
+    let peers = cluster(2);
+    let key = key_owned_by(1, &peers, "tagged");
+    assert_eq!(post(&peers[0], "/", &format!(r#"{{"{}": 1}}"#, key)).0, 200);
+
+    // forwarded: the first node answers for the second
+    let forwarded = request("GET", &peers[0], &format!("/{}", key), &[], None);
+    assert_eq!(forwarded.status(), 200);
+    assert_eq!(
+        forwarded.header("X-SDCS-Handled-By"),
+        Some(peers[0].as_str())
+    );
+    assert_eq!(forwarded.header("X-SDCS-Owner"), Some(peers[1].as_str()));
+    let direct = request("GET", &peers[1], &format!("/{}", key), &[], None);
+    assert_eq!(direct.header("X-SDCS-Handled-By"), Some(peers[1].as_str()));
+    assert_eq!(direct.header("X-SDCS-Owner"), Some(peers[1].as_str()));
+    // misses name the owner too; requests for no key name only the node
+    let miss = request("GET", &peers[0], &format!("/{}-gone", key), &[], None);
+    assert_eq!(miss.status(), 404);
+    assert!(miss.header("X-SDCS-Owner").is_some());
+    let stats = request("GET", &peers[0], "/stats", &[], None);
+    assert_eq!(stats.header("X-SDCS-Handled-By"), Some(peers[0].as_str()));
+    assert_eq!(stats.header("X-SDCS-Owner"), None);
+}
+
+#[test]
+fn an_owners_expiry_reaches_stray_copies_only_with_expiry_broadcast() {
+    let forwarded = [("X-SDCS-Forwarded", "1")];
+    for broadcast in [true, false] {
+        let env = [("EXPIRY_BROADCAST", if broadcast { "true" } else { "false" })];
+        let peers = cluster_with(2, &env);
+        let key = key_owned_by(1, &peers, "brief");
+        let body = format!(r#"{{"{}": 1}}"#, key);
+        let ttl = [("X-TTL", "200")];
+        assert_eq!(call_with("POST", &peers[1], "/", &ttl, Some(&body)).0, 200);
+        // a forwarded batch is stored where it lands, leaving a copy without the owner's TTL
+        assert_eq!(
+            call_with("POST", &peers[0], "/mput", &forwarded, Some(&body)).0,
+            200
+        );
+        let stray = || {
+            let (_, body) = call_with("GET", &peers[0], &format!("/{}*", key), &forwarded, None);
+            json(&body).get(&key).is_some()
+        };
+        assert!(stray());
+        // expiry of copies goes on while read-only
+        let read_only = r#"{"read_only": true}"#;
+        assert_eq!(post(&peers[0], "/admin/readonly", read_only).0, 200);
+
+        // the owner's sweep runs every second
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while stray() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(stray(), !broadcast);
+        assert_eq!(get(&peers[1], &format!("/{}", key)).0, 404);
+    }
+}
@@ -0,0 +1,212 @@
+//! Helpers shared by the integration tests: nodes on ephemeral local ports, and a small
+//! HTTP client that hands back the status and body of every response, errors included.
+#![allow(dead_code)]
+
+use baby_sdcs::router;
+use baby_sdcs::server::{self, ServerHandle};
+use std::ops::Deref;
+use std::sync::Mutex;
+
+// nodes read their settings from the environment as they start, so starts take turns
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Nodes started with `spawn_server`, stopped again when dropped. Derefs to their addresses,
+/// in peer order, so `&cluster[0]` is the first node's.
+pub struct Cluster {
+    addrs: Vec<String>,
+    handles: Vec<ServerHandle>,
+}
+
+impl Deref for Cluster {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.addrs
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for handle in self.handles.drain(..) {
+            handle.shutdown();
+        }
+    }
+}
+
+/// A single node, peer to nothing but itself. Derefs to its address.
+pub struct Node(Cluster);
+
+impl Deref for Node {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0[0]
+    }
+}
+
+/// Start `n` nodes on `127.0.0.1` that know each other as peers. All of them are bound before
+/// any starts serving.
+pub fn cluster(n: usize) -> Cluster {
+    cluster_with(n, &[])
+}
+
+/// Like `cluster`, with the environment variables in `env` set while the nodes start.
+pub fn cluster_with(n: usize, env: &[(&str, &str)]) -> Cluster {
+    start(n, 0, env)
+}
+
+/// `n` running nodes followed by `down` peers that never start, so their keys are unreachable.
+pub fn cluster_with_down(n: usize, down: usize, env: &[(&str, &str)]) -> Cluster {
+    start(n, down, env)
+}
+
+/// Bring up the node at `peers[index]`, one `cluster_with_down` left down, with the default
+/// settings. It stops when the returned handle is shut down.
+pub fn start_down(peers: &[String], index: usize) -> ServerHandle {
+    let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    let name = format!("server{}", index + 1);
+    let (srv, store) = server::init_server(&name, &peers[index]);
+    let handle = server::spawn_server(srv, &name, peers[index].clone(), peers.to_vec(), store);
+    get(&peers[index], "/");
+    handle
+}
+
+/// An address on this machine nothing listens on.
+pub fn unused_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn start(n: usize, down: usize, env: &[(&str, &str)]) -> Cluster {
+    let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    for (name, value) in env {
+        // SAFETY: every test that touches the environment holds ENV
+        unsafe { std::env::set_var(name, value) };
+    }
+    let servers: Vec<_> = (1..=n)
+        .map(|i| server::init_server(&format!("server{}", i), "127.0.0.1:0"))
+        .collect();
+    let addrs: Vec<String> = servers
+        .iter()
+        .map(|(srv, _)| srv.server_addr().to_string())
+        .chain((0..down).map(|_| unused_addr()))
+        .collect();
+    let handles = servers
+        .into_iter()
+        .enumerate()
+        .map(|(i, (srv, store))| {
+            let name = format!("server{}", i + 1);
+            server::spawn_server(srv, &name, addrs[i].clone(), addrs.clone(), store)
+        })
+        .collect();
+    // once a node answers it has read its config
+    for addr in &addrs[..n] {
+        get(addr, "/");
+    }
+    for (name, _) in env {
+        unsafe { std::env::remove_var(name) };
+    }
+    Cluster { addrs, handles }
+}
+
+/// Run `f` with the environment variables in `env` set, e.g. to build a `Node` with settings.
+pub fn with_env<T>(env: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+    let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    for (name, value) in env {
+        // SAFETY: every test that touches the environment holds ENV
+        unsafe { std::env::set_var(name, value) };
+    }
+    let result = f();
+    for (name, _) in env {
+        unsafe { std::env::remove_var(name) };
+    }
+    result
+}
+
+/// A single node.
+pub fn node() -> Node {
+    Node(cluster(1))
+}
+
+/// A single node started with `env` set.
+pub fn node_with(env: &[(&str, &str)]) -> Node {
+    Node(cluster_with(1, env))
+}
+
+/// Send `method path` to the node at `addr` and return the status and body.
+pub fn call(method: &str, addr: &str, path: &str, body: Option<&str>) -> (u16, String) {
+    call_with(method, addr, path, &[], body)
+}
+
+/// Like `call`, sending `headers` along.
+pub fn call_with(
+    method: &str,
+    addr: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&str>,
+) -> (u16, String) {
+    let resp = request(method, addr, path, headers, body);
+    (resp.status(), resp.into_string().unwrap())
+}
+
+/// Like `call_with`, returning the whole response so its headers can be checked too.
+pub fn request(
+    method: &str,
+    addr: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&str>,
+) -> ureq::Response {
+    let mut req = ureq::request(method, &format!("http://{}{}", addr, path));
+    for (name, value) in headers {
+        req = req.set(name, value);
+    }
+    let result = match body {
+        Some(body) => req.send_string(body),
+        None => req.call(),
+    };
+    match result {
+        Ok(resp) => resp,
+        Err(ureq::Error::Status(_, resp)) => resp,
+        Err(e) => panic!("{} {} on {} failed: {}", method, path, addr, e),
+    }
+}
+
+pub fn get(addr: &str, path: &str) -> (u16, String) {
+    call("GET", addr, path, None)
+}
+
+pub fn post(addr: &str, path: &str, body: &str) -> (u16, String) {
+    call("POST", addr, path, Some(body))
+}
+
+pub fn delete(addr: &str, path: &str) -> (u16, String) {
+    call("DELETE", addr, path, None)
+}
+
+/// Parse a response body as JSON.
+pub fn json(body: &str) -> serde_json::Value {
+    serde_json::from_str(body).unwrap_or_else(|e| panic!("{:?} is not JSON: {}", body, e))
+}
+
+/// Index into `peers` of the node that owns `key`.
+pub fn owner_index(key: &str, peers: &[String]) -> usize {
+    router::owner_for_key(key, peers)
+}
+
+/// A key owned by the node at `index` of `peers`, named `prefix` plus a number.
+pub fn key_owned_by(index: usize, peers: &[String], prefix: &str) -> String {
+    (0..)
+        .map(|i| format!("{}{}", prefix, i))
+        .find(|key| owner_index(key, peers) == index)
+        .unwrap()
+}
+
+/// Value of the Prometheus series `series` (name plus labels, exactly as rendered) in `/metrics`.
+pub fn metric(addr: &str, series: &str) -> Option<f64> {
+    let (_, text) = get(addr, "/metrics");
+    text.lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .map(|value| value.parse().unwrap())
+}
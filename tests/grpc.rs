@@ -0,0 +1,105 @@
+//! The gRPC front end, driven through its generated client.
+#![cfg(feature = "grpc")]
+
+mod common;
+
+use baby_sdcs::grpc::{self, proto};
+use baby_sdcs::node::Node;
+use baby_sdcs::server;
+use common::{cluster, get, json, key_owned_by, post, unused_addr};
+use proto::cache_client::CacheClient;
+use std::net::SocketAddr;
+use tonic::Code;
+use tonic::transport::Channel;
+
+/// Connect to the gRPC listener at `addr`, waiting for it to come up.
+async fn connect(addr: SocketAddr) -> CacheClient<Channel> {
+    for _ in 0..50 {
+        if let Ok(client) = CacheClient::connect(format!("http://{}", addr)).await {
+            return client;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    panic!("no gRPC listener on {}", addr);
+}
+
+fn get_request(key: &str) -> proto::GetRequest {
+    proto::GetRequest {
+        key: key.to_string(),
+    }
+}
+
+fn set_request(key: &str, value_json: &str) -> proto::SetRequest {
+    proto::SetRequest {
+        key: key.to_string(),
+        value_json: value_json.to_string(),
+    }
+}
+
+/// The code a call failed with.
+fn code<T>(result: Result<T, tonic::Status>) -> Code {
+    result.map(|_| ()).unwrap_err().code()
+}
+
+#[test]
+fn grpc_serves_the_nodes_keys_and_forwards_the_rest() {
+    let peers = cluster(2);
+    // a gRPC view of the first node, with a store of its own
+    let (srv, store) = server::init_server("grpc", "127.0.0.1:0");
+    let http = srv.server_addr().to_string();
+    let mut view = peers.to_vec();
+    view[0] = http.clone();
+    let ctx = server::server_context("grpc", http.clone(), view.clone(), store);
+    let node = Node::with_context(ctx.clone(), "grpc");
+    std::thread::spawn(move || server::run_server_with_context(srv, ctx));
+    let addr: SocketAddr = unused_addr().parse().unwrap();
+    std::thread::spawn(move || grpc::serve(addr, node));
+
+    let local = key_owned_by(0, &view, "g");
+    let remote = key_owned_by(1, &view, "g");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut client = connect(addr).await;
+        for key in [&local, &remote] {
+            let set = client.set(set_request(key, r#"{"a": [1]}"#)).await.unwrap();
+            assert!(set.into_inner().version.is_some());
+            let got = client.get(get_request(key)).await.unwrap().into_inner();
+            assert!(got.found);
+            assert_eq!(json(&got.value_json), serde_json::json!({"a": [1]}));
+        }
+        // each key landed on its owner, visible over HTTP
+        assert_eq!(get(&http, &format!("/{}", local)).0, 200);
+        assert_eq!(get(&peers[1], &format!("/{}", remote)).0, 200);
+
+        let deleted = client
+            .delete(proto::DeleteRequest {
+                key: remote.clone(),
+            })
+            .await
+            .unwrap();
+        assert!(deleted.into_inner().deleted);
+        let got = client.get(get_request(&remote)).await.unwrap().into_inner();
+        assert!(!got.found);
+        assert_eq!(got.value_json, "");
+
+        // what HTTP would refuse, gRPC refuses with the matching code
+        assert_eq!(
+            code(client.get(get_request("")).await),
+            Code::InvalidArgument
+        );
+        let long = "k".repeat(10_000);
+        assert_eq!(
+            code(client.get(get_request(&long)).await),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            code(client.set(set_request(&local, "{not json")).await),
+            Code::InvalidArgument
+        );
+        post(&http, "/admin/readonly", r#"{"read_only": true}"#);
+        assert_eq!(
+            code(client.set(set_request(&local, "1")).await),
+            Code::Unavailable
+        );
+    });
+}
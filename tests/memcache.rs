@@ -0,0 +1,137 @@
+//! The memcached text protocol listener, driven with raw protocol bytes.
+
+mod common;
+
+use baby_sdcs::memcache;
+use baby_sdcs::node::Node;
+use baby_sdcs::server;
+use common::{cluster, get, json, key_owned_by, post, unused_addr};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A connection to a memcached listener.
+struct Conn(BufReader<TcpStream>);
+
+impl Conn {
+    /// Connect to the listener at `addr`, waiting for it to come up.
+    fn open(addr: SocketAddr) -> Conn {
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(addr) {
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                return Conn(BufReader::new(stream));
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("no memcached listener on {}", addr);
+    }
+
+    /// Send `bytes` and return the reply: one line, or through `END` for retrievals.
+    fn send(&mut self, bytes: &[u8]) -> String {
+        self.0.get_mut().write_all(bytes).unwrap();
+        let mut reply = String::new();
+        loop {
+            let read = self.0.read_line(&mut reply).unwrap();
+            assert!(read > 0, "connection closed after {:?}", reply);
+            let last = reply.lines().last().unwrap_or_default();
+            if !(reply.starts_with("VALUE") || reply.starts_with("STAT")) || last == "END" {
+                return reply;
+            }
+        }
+    }
+}
+
+/// A memcached listener in front of the first of `peers`, replaced by a node of its own.
+/// Returns the listener's address and that node's HTTP address and peer list.
+fn listener(peers: &[String]) -> (SocketAddr, String, Vec<String>) {
+    let (srv, store) = server::init_server("memcache", "127.0.0.1:0");
+    let http = srv.server_addr().to_string();
+    let mut view = peers.to_vec();
+    view[0] = http.clone();
+    let ctx = server::server_context("memcache", http.clone(), view.clone(), store);
+    let node = Node::with_context(ctx.clone(), "memcache");
+    std::thread::spawn(move || server::run_server_with_context(srv, ctx));
+    let addr: SocketAddr = unused_addr().parse().unwrap();
+    std::thread::spawn(move || memcache::serve(addr, node));
+    (addr, http, view)
+}
+
+#[test]
+fn raw_sets_and_gets_round_trip_through_their_owners() {
+    let peers = cluster(2);
+    let (addr, http, view) = listener(&peers);
+    let mut conn = Conn::open(addr);
+
+    for key in [key_owned_by(0, &view, "m"), key_owned_by(1, &view, "m")] {
+        let set = format!("set {} 0 0 5\r\nhello\r\n", key);
+        assert_eq!(conn.send(set.as_bytes()), "STORED\r\n");
+        let got = conn.send(format!("get {}\r\n", key).as_bytes());
+        assert_eq!(got, format!("VALUE {} 0 5\r\nhello\r\nEND\r\n", key));
+        // text lands as a JSON string, readable over HTTP
+        let (status, body) = get(&http, &format!("/{}", key));
+        assert_eq!(status, 200);
+        assert_eq!(json(&body)[key.as_str()], "hello");
+        assert_eq!(
+            conn.send(format!("delete {}\r\n", key).as_bytes()),
+            "DELETED\r\n"
+        );
+        assert_eq!(
+            conn.send(format!("delete {}\r\n", key).as_bytes()),
+            "NOT_FOUND\r\n"
+        );
+    }
+}
+
+#[test]
+fn flags_and_bytes_come_back_as_written() {
+    let peers = cluster(1);
+    let (addr, http, _) = listener(&peers);
+    let mut conn = Conn::open(addr);
+
+    let mut set = b"set blob 42 0 4\r\n".to_vec();
+    set.extend_from_slice(&[0xff, 0x00, b'a', 0x80]);
+    set.extend_from_slice(b"\r\n");
+    assert_eq!(conn.send(&set), "STORED\r\n");
+    conn.0.get_mut().write_all(b"get blob\r\n").unwrap();
+    let mut header = String::new();
+    conn.0.read_line(&mut header).unwrap();
+    assert_eq!(header, "VALUE blob 42 4\r\n");
+    let mut data = Vec::new();
+    conn.0.read_until(b'\n', &mut data).unwrap();
+    assert_eq!(data, [0xff, 0x00, b'a', 0x80, b'\r', b'\n']);
+    let mut end = String::new();
+    conn.0.read_line(&mut end).unwrap();
+    assert_eq!(end, "END\r\n");
+
+    // values written over HTTP read back as their JSON text
+    assert_eq!(post(&http, "/", r#"{"doc": {"a": 1}}"#).0, 200);
+    let got = conn.send(b"get doc missing\r\n");
+    assert_eq!(got, "VALUE doc 0 7\r\n{\"a\":1}\r\nEND\r\n");
+}
+
+#[test]
+fn expiry_times_and_refusals_follow_the_node() {
+    let peers = cluster(1);
+    let (addr, http, _) = listener(&peers);
+    let mut conn = Conn::open(addr);
+
+    assert_eq!(conn.send(b"set brief 0 1 1\r\nx\r\n"), "STORED\r\n");
+    assert_eq!(conn.send(b"set gone 0 -1 1\r\nx\r\n"), "STORED\r\n");
+    assert_eq!(conn.send(b"get gone\r\n"), "END\r\n");
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(conn.send(b"get brief\r\n"), "END\r\n");
+
+    // refused writes say why, and the connection carries on
+    assert_eq!(
+        post(&http, "/admin/readonly", r#"{"read_only": true}"#).0,
+        200
+    );
+    assert_eq!(
+        conn.send(b"set k 0 0 1\r\nx\r\n"),
+        "SERVER_ERROR read-only\r\n"
+    );
+    assert_eq!(conn.send(b"bogus\r\n"), "ERROR\r\n");
+    assert!(conn.send(b"stats\r\n").contains("STAT cmd_set 3\r\n"));
+}
@@ -0,0 +1,106 @@
+//! The in-process `Node`, on its own and next to running servers.
+
+mod common;
+
+use baby_sdcs::cache::Cache;
+use baby_sdcs::client::ClientError;
+use baby_sdcs::node::Node;
+use baby_sdcs::server;
+use common::{cluster, get, json, key_owned_by, post, unused_addr};
+use serde_json::json;
+
+#[test]
+fn node_serves_its_own_keys_locally_and_forwards_the_rest() {
+    let peers = cluster(2);
+    // stands in for the first node, with a store of its own
+    let node = Node::new(peers[0].clone(), peers.to_vec(), Cache::new());
+    let local = key_owned_by(0, &peers, "local");
+    let remote = key_owned_by(1, &peers, "remote");
+
+    assert!(node.set(&local, json!(1)).unwrap().is_some());
+    assert_eq!(node.store().get(&local), Some(json!(1)));
+    // the running first node never saw it
+    assert_eq!(get(&peers[0], &format!("/{}", local)).0, 404);
+
+    node.set(&remote, json!([2])).unwrap();
+    assert_eq!(node.get(&remote).unwrap(), Some(json!([2])));
+    assert_eq!(
+        json(&get(&peers[1], &format!("/{}", remote)).1),
+        json!({ &remote: [2] })
+    );
+    assert!(node.delete(&remote).unwrap());
+    assert!(node.delete(&local).unwrap());
+    assert_eq!(node.get(&local).unwrap(), None);
+}
+
+#[test]
+fn node_writes_get_the_same_checks_as_http() {
+    let addr = unused_addr();
+    let node = Node::new(addr.clone(), vec![addr], Cache::new());
+    let long = "k".repeat(2000);
+    assert!(matches!(
+        node.set(&long, json!(1)),
+        Err(ClientError::Refused(400, _))
+    ));
+    assert!(matches!(
+        node.delete(&long),
+        Err(ClientError::Refused(400, _))
+    ));
+    // reads only check the key
+    assert!(matches!(node.get(&long), Err(ClientError::Refused(400, _))));
+    let big = json!("v".repeat(2 * 1024 * 1024));
+    assert!(matches!(
+        node.set("big", big),
+        Err(ClientError::Refused(413, _))
+    ));
+    assert_eq!(node.get("big").unwrap(), None);
+}
+
+#[test]
+fn a_node_sharing_a_servers_context_follows_its_switches() {
+    let (srv, store) = server::init_server("shared", "127.0.0.1:0");
+    let addr = srv.server_addr().to_string();
+    let ctx = server::server_context("shared", addr.clone(), vec![addr.clone()], store);
+    let node = Node::with_context(ctx.clone(), "test");
+    std::thread::spawn(move || server::run_server_with_context(srv, ctx));
+
+    node.set("k", json!(1)).unwrap();
+    assert_eq!(json(&get(&addr, "/k").1), json!({"k": 1}));
+    let (status, _) = post(&addr, "/admin/readonly", r#"{"read_only": true}"#);
+    assert_eq!(status, 200);
+    match node.set("k", json!(2)) {
+        Err(ClientError::Refused(503, reason)) => assert_eq!(reason, "read-only"),
+        other => panic!("expected a read-only refusal, got {:?}", other),
+    }
+    assert!(matches!(
+        node.delete("k"),
+        Err(ClientError::Refused(503, _))
+    ));
+    assert_eq!(node.get("k").unwrap(), Some(json!(1)));
+}
+
+#[test]
+fn node_writes_get_the_servers_ttl_jitter() {
+    let addr = unused_addr();
+    let env = [("TTL_JITTER_PCT", "20"), ("DEFAULT_TTL_MS", "10000")];
+    let node = common::with_env(&env, || {
+        Node::new(addr.clone(), vec![addr.clone()], Cache::new())
+    });
+    let start = std::time::Instant::now();
+    for i in 0..50 {
+        node.set(&format!("j{}", i), json!(i)).unwrap();
+    }
+    let left: Vec<u128> = node
+        .store()
+        .export()
+        .map(|exported| (exported.entry.expires_at.unwrap() - start).as_millis())
+        .collect();
+    assert_eq!(left.len(), 50);
+    assert!(
+        left.iter().all(|&ms| (8_000..=12_100).contains(&ms)),
+        "{:?}",
+        left
+    );
+    let (min, max) = (left.iter().min().unwrap(), left.iter().max().unwrap());
+    assert!(max - min > 1_000, "{:?}", left);
+}